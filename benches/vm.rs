@@ -0,0 +1,57 @@
+use std::{fmt::Write, io::Cursor, rc::Rc};
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use kailua::{parse::ParseProto, vm::ExeState};
+
+/// A chunk that copies a long string between locals and globals.
+fn moves() -> ParseProto {
+    let mut src = String::from("local s = \"a string too long to be stored inline\"\n");
+    for i in 0..100 {
+        writeln!(src, "local l{i} = s").unwrap();
+        writeln!(src, "g{i} = l{i}").unwrap();
+        writeln!(src, "h{i} = g{i}").unwrap();
+    }
+    ParseProto::load(Cursor::new(src.into_bytes())).unwrap()
+}
+
+/// A chunk that calls library functions through the fields of their
+/// tables, in a loop.
+fn library_calls() -> ParseProto {
+    let src = "local s = 'abc'\n\
+               for i = 1, 1000 do\n\
+               local n = string.len(s)\n\
+               local u = string.upper(s)\n\
+               local r = math.random(i)\n\
+               end\n";
+    ParseProto::load(Cursor::new(src.as_bytes().to_vec())).unwrap()
+}
+
+/// A chunk that calls Lua functions, recursively, with fixed and variable
+/// arguments.
+fn lua_calls() -> ParseProto {
+    let src =
+        "local function fib(n) if n < 2 then return n end return fib(n - 1) + fib(n - 2) end\n\
+               local function sum(a, ...) if a == nil then return 0 end return a + sum(...) end\n\
+               local n = fib(15)\n\
+               for i = 1, 100 do n = n + sum(1, 2, 3, 4, 5) end\n";
+    ParseProto::load(Cursor::new(src.as_bytes().to_vec())).unwrap()
+}
+
+fn execute(c: &mut Criterion) {
+    let proto = Rc::new(moves());
+    let mut state = ExeState::new();
+    c.bench_function("execute moves", |b| {
+        b.iter(|| state.execute(proto.clone()).unwrap())
+    });
+    let proto = Rc::new(library_calls());
+    c.bench_function("execute library calls", |b| {
+        b.iter(|| state.execute(proto.clone()).unwrap())
+    });
+    let proto = Rc::new(lua_calls());
+    c.bench_function("execute Lua calls", |b| {
+        b.iter(|| state.execute(proto.clone()).unwrap())
+    });
+}
+
+criterion_group!(benches, execute);
+criterion_main!(benches);
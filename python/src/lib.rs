@@ -0,0 +1,172 @@
+//! Python bindings: `kailua.Lua` is a state to run Lua in, with values
+//! converted to and from Python objects.
+//!
+//! | Lua      | Python                                   |
+//! |----------|------------------------------------------|
+//! | nil      | `None`                                   |
+//! | boolean  | `bool`                                   |
+//! | integer  | `int`                                    |
+//! | float    | `float`                                  |
+//! | string   | `str`, or `bytes` if it is not UTF-8     |
+//! | table    | `dict`, from a `dict`, `list` or `tuple` |
+//!
+//! Functions do not convert. Lua errors raise `kailua.LuaError`.
+
+use std::{cell::RefCell, rc::Rc};
+
+use kailua::{
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+use pyo3::{
+    create_exception,
+    exceptions::{PyException, PyTypeError},
+    prelude::*,
+    types::{PyBytes, PyDict, PyList, PyString, PyTuple},
+};
+
+create_exception!(kailua, LuaError, PyException);
+
+/// A Lua state, keeping its globals from one call to the next.
+#[pyclass(unsendable)]
+struct Lua {
+    state: ExeState,
+}
+
+#[pymethods]
+impl Lua {
+    #[new]
+    fn new() -> Self {
+        Lua {
+            state: ExeState::new(),
+        }
+    }
+
+    /// Run `source`, returning what it returns: None for nothing, the
+    /// value for one, and a tuple for several.
+    fn eval<'py>(&mut self, py: Python<'py>, source: &str) -> PyResult<Bound<'py, PyAny>> {
+        let mut values = self.state.eval(source).map_err(lua_error)?;
+        match values.len() {
+            0 => Ok(py.None().into_bound(py)),
+            1 => to_python(py, &values.pop().unwrap()),
+            _ => {
+                let items = values
+                    .iter()
+                    .map(|v| to_python(py, v))
+                    .collect::<PyResult<Vec<_>>>()?;
+                Ok(PyTuple::new(py, items)?.into_any())
+            }
+        }
+    }
+
+    fn get_global<'py>(&self, py: Python<'py>, name: &str) -> PyResult<Bound<'py, PyAny>> {
+        to_python(py, &self.state.get_global(name))
+    }
+
+    fn set_global(&mut self, name: &str, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let v = to_lua(value)?;
+        self.state.set_global(name, v);
+        Ok(())
+    }
+}
+
+fn lua_error(err: anyhow::Error) -> PyErr {
+    LuaError::new_err(format!("{err:#}"))
+}
+
+fn to_python<'py>(py: Python<'py>, v: &Value) -> PyResult<Bound<'py, PyAny>> {
+    let obj = match v {
+        Value::Nil => py.None().into_bound(py),
+        &Value::Boolean(b) => b.into_pyobject(py)?.to_owned().into_any(),
+        &Value::Integer(i) => i.into_pyobject(py)?.into_any(),
+        &Value::Float(f) => f.into_pyobject(py)?.into_any(),
+        Value::Table(t) => table_to_python(py, t, &mut Vec::new())?,
+        Value::Function(_) | Value::NativeClosure(_) => {
+            return Err(PyTypeError::new_err("cannot convert a Lua function"))
+        }
+        s => {
+            let bytes = <&[u8]>::try_from(s).map_err(lua_error)?;
+            match std::str::from_utf8(bytes) {
+                Ok(s) => PyString::new(py, s).into_any(),
+                Err(_) => PyBytes::new(py, bytes).into_any(),
+            }
+        }
+    };
+    Ok(obj)
+}
+
+/// `t` as a dict, failing on tables that contain themselves. `path` holds
+/// the tables being converted.
+fn table_to_python<'py>(
+    py: Python<'py>,
+    t: &Rc<RefCell<Table>>,
+    path: &mut Vec<*const RefCell<Table>>,
+) -> PyResult<Bound<'py, PyAny>> {
+    if path.contains(&Rc::as_ptr(t)) {
+        return Err(PyTypeError::new_err("cannot convert a cyclic table"));
+    }
+    path.push(Rc::as_ptr(t));
+    let t = t.borrow();
+    let dict = PyDict::new(py);
+    let mut convert = |v: &Value| match v {
+        Value::Table(t) => table_to_python(py, t, path),
+        v => to_python(py, v),
+    };
+    for (i, v) in t.array.iter().enumerate() {
+        if *v != Value::Nil {
+            dict.set_item(i + 1, convert(v)?)?;
+        }
+    }
+    for (k, v) in t.map_entries() {
+        dict.set_item(convert(k)?, convert(v)?)?;
+    }
+    path.pop();
+    Ok(dict.into_any())
+}
+
+fn to_lua(obj: &Bound<'_, PyAny>) -> PyResult<Value> {
+    if obj.is_none() {
+        return Ok(Value::Nil);
+    }
+    // before int, which bool is a subclass of
+    if let Ok(b) = obj.cast::<pyo3::types::PyBool>() {
+        return Ok(b.is_true().into());
+    }
+    if let Ok(i) = obj.extract::<LuaInt>() {
+        return Ok(i.into());
+    }
+    if let Ok(f) = obj.extract::<LuaFloat>() {
+        return Ok(f.into());
+    }
+    if let Ok(s) = obj.cast::<PyString>() {
+        return Ok(s.to_str()?.into());
+    }
+    if let Ok(b) = obj.cast::<PyBytes>() {
+        return Ok(b.as_bytes().into());
+    }
+    let mut t = Table::new();
+    if let Ok(dict) = obj.cast::<PyDict>() {
+        for (k, v) in dict.iter() {
+            t.set(to_lua(&k)?, to_lua(&v)?).map_err(lua_error)?;
+        }
+    } else if obj.is_instance_of::<PyList>() || obj.is_instance_of::<PyTuple>() {
+        for (i, v) in obj.try_iter()?.enumerate() {
+            t.set((i as LuaInt + 1).into(), to_lua(&v?)?)
+                .map_err(lua_error)?;
+        }
+    } else {
+        let type_name = obj.get_type().name()?;
+        return Err(PyTypeError::new_err(format!(
+            "cannot convert a {type_name} to a Lua value"
+        )));
+    }
+    Ok(t.into())
+}
+
+#[pymodule]
+#[pyo3(name = "kailua")]
+fn kailua_module(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add_class::<Lua>()?;
+    m.add("LuaError", m.py().get_type::<LuaError>())?;
+    Ok(())
+}
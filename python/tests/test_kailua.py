@@ -0,0 +1,54 @@
+"""Tests of the Python bindings, run after building them with
+`maturin develop`:
+
+    python -m unittest discover tests
+"""
+
+import unittest
+
+import kailua
+
+
+class LuaTest(unittest.TestCase):
+    def setUp(self):
+        self.lua = kailua.Lua()
+
+    def test_eval(self):
+        self.assertIsNone(self.lua.eval("x = 1"))
+        self.assertEqual(self.lua.eval("return x"), 1)
+        self.assertEqual(self.lua.eval("return x, 'a', nil"), (1, "a", None))
+        self.assertEqual(self.lua.eval("return 1.5, true"), (1.5, True))
+
+    def test_globals(self):
+        self.lua.set_global("s", "héllo")
+        self.assertEqual(self.lua.eval("return string.upper(s)"), "HéLLO")
+        self.lua.set_global("b", b"\xff")
+        self.assertEqual(self.lua.get_global("b"), b"\xff")
+        self.assertIsNone(self.lua.get_global("nothing"))
+
+    def test_tables(self):
+        self.lua.set_global("t", {"k": [1, 2, None, 4], 2.0: False})
+        self.assertEqual(self.lua.eval("return t.k"), {1: 1, 2: 2, 4: 4})
+        self.assertEqual(self.lua.get_global("t")[2], False)
+
+    def test_conversion_errors(self):
+        with self.assertRaises(TypeError):
+            self.lua.set_global("x", object())
+        with self.assertRaises(TypeError):
+            self.lua.eval("return print")
+
+    def test_large_integers(self):
+        # become floats, as integer literals out of range do in Lua
+        self.lua.set_global("x", 2**64)
+        self.assertEqual(self.lua.get_global("x"), 2.0**64)
+        self.assertIsInstance(self.lua.get_global("x"), float)
+
+    def test_lua_error(self):
+        with self.assertRaisesRegex(kailua.LuaError, "boom"):
+            self.lua.eval("error('boom')")
+        with self.assertRaises(kailua.LuaError):
+            self.lua.eval("x = = 1")
+
+
+if __name__ == "__main__":
+    unittest.main()
@@ -0,0 +1,164 @@
+//! The arithmetic operators on numbers, and on strings that convert to
+//! them, as in the reference implementation: integers stay integers, but
+//! for `/` and `^`, and wrap around on overflow. Shared by the VM and by the
+//! parser, which folds operators on constants.
+
+use anyhow::bail;
+
+use crate::{
+    numfmt::str2number,
+    value::{LuaFloat, LuaInt, Value},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Idiv,
+    Mod,
+    Pow,
+    // unary minus, whose second operand is ignored
+    Unm,
+}
+
+impl ArithOp {
+    /// Name of the metamethod of the operator.
+    pub fn event(self) -> &'static str {
+        match self {
+            ArithOp::Add => "__add",
+            ArithOp::Sub => "__sub",
+            ArithOp::Mul => "__mul",
+            ArithOp::Div => "__div",
+            ArithOp::Idiv => "__idiv",
+            ArithOp::Mod => "__mod",
+            ArithOp::Pow => "__pow",
+            ArithOp::Unm => "__unm",
+        }
+    }
+}
+
+/// `a op b`, or `None` if an operand is neither a number nor a string
+/// spelling one, for the caller to try metamethods. Integer division and
+/// modulo by zero are errors.
+pub fn arith(op: ArithOp, a: &Value, b: &Value) -> anyhow::Result<Option<Value>> {
+    let (Some(a), Some(b)) = (to_number(a), to_number(b)) else {
+        return Ok(None);
+    };
+    let v = match (a, b) {
+        (Value::Integer(a), Value::Integer(b)) if !matches!(op, ArithOp::Div | ArithOp::Pow) => {
+            Value::Integer(int_arith(op, a, b)?)
+        }
+        (a, b) => Value::Float(float_arith(op, to_float(&a), to_float(&b))),
+    };
+    Ok(Some(v))
+}
+
+/// `v` if it is a number, or the number its string spells.
+pub fn to_number(v: &Value) -> Option<Value> {
+    match v {
+        Value::Integer(_) | Value::Float(_) => Some(v.clone()),
+        v => str2number(<&[u8]>::try_from(v).ok()?),
+    }
+}
+
+fn to_float(v: &Value) -> LuaFloat {
+    match *v {
+        Value::Integer(i) => i as LuaFloat,
+        Value::Float(f) => f,
+        _ => unreachable!("not a number"),
+    }
+}
+
+fn int_arith(op: ArithOp, a: LuaInt, b: LuaInt) -> anyhow::Result<LuaInt> {
+    Ok(match op {
+        ArithOp::Add => a.wrapping_add(b),
+        ArithOp::Sub => a.wrapping_sub(b),
+        ArithOp::Mul => a.wrapping_mul(b),
+        ArithOp::Unm => a.wrapping_neg(),
+        // rounding towards minus infinity, unlike `/` and `%` in Rust
+        ArithOp::Idiv => {
+            if b == 0 {
+                bail!("attempt to divide by zero");
+            }
+            let q = a.wrapping_div(b);
+            if a.wrapping_rem(b) != 0 && (a ^ b) < 0 {
+                q - 1
+            } else {
+                q
+            }
+        }
+        ArithOp::Mod => {
+            if b == 0 {
+                bail!("attempt to perform 'n%0'");
+            }
+            let r = a.wrapping_rem(b);
+            if r != 0 && (r ^ b) < 0 {
+                r + b
+            } else {
+                r
+            }
+        }
+        ArithOp::Div | ArithOp::Pow => unreachable!("float operator"),
+    })
+}
+
+fn float_arith(op: ArithOp, a: LuaFloat, b: LuaFloat) -> LuaFloat {
+    match op {
+        ArithOp::Add => a + b,
+        ArithOp::Sub => a - b,
+        ArithOp::Mul => a * b,
+        ArithOp::Div => a / b,
+        ArithOp::Idiv => (a / b).floor(),
+        ArithOp::Mod => {
+            // the sign of the divisor, as for integers
+            let m = a % b;
+            if if m > 0.0 { b < 0.0 } else { m < 0.0 && b != m } {
+                m + b
+            } else {
+                m
+            }
+        }
+        ArithOp::Pow if b == 2.0 => a * a,
+        ArithOp::Pow => a.powf(b),
+        ArithOp::Unm => -a,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers_and_floats() {
+        let op = |op, a: Value, b: Value| arith(op, &a, &b).unwrap().unwrap();
+        assert_eq!(op(ArithOp::Add, 1.into(), 2.into()), Value::Integer(3));
+        assert_eq!(op(ArithOp::Add, 1.into(), 2.5.into()), Value::Float(3.5));
+        assert_eq!(op(ArithOp::Div, 7.into(), 2.into()), Value::Float(3.5));
+        assert_eq!(op(ArithOp::Pow, 2.into(), 10.into()), Value::Float(1024.0));
+        assert_eq!(
+            op(ArithOp::Add, LuaInt::MAX.into(), 1.into()),
+            LuaInt::MIN.into()
+        );
+        // floor division and modulo round towards minus infinity
+        assert_eq!(op(ArithOp::Idiv, (-7).into(), 2.into()), Value::Integer(-4));
+        assert_eq!(op(ArithOp::Mod, (-7).into(), 2.into()), Value::Integer(1));
+        assert_eq!(op(ArithOp::Mod, 7.into(), (-2).into()), Value::Integer(-1));
+        assert_eq!(op(ArithOp::Mod, (-7.5).into(), 2.into()), Value::Float(0.5));
+        assert_eq!(
+            op(ArithOp::Idiv, LuaInt::MIN.into(), (-1).into()),
+            LuaInt::MIN.into()
+        );
+        // strings spelling numbers are those numbers
+        assert_eq!(
+            op(ArithOp::Mul, "10".into(), "0x10".into()),
+            Value::Integer(160)
+        );
+        assert_eq!(arith(ArithOp::Add, &"ten".into(), &1.into()).unwrap(), None);
+
+        let error = |op| arith(op, &1.into(), &0.into()).unwrap_err().to_string();
+        assert_eq!(error(ArithOp::Idiv), "attempt to divide by zero");
+        assert_eq!(error(ArithOp::Mod), "attempt to perform 'n%0'");
+    }
+}
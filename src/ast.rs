@@ -0,0 +1,903 @@
+//! A syntax tree of Lua source, for tools that read Lua without running it,
+//! such as `kailua ast`. The compiler in [`parse`](crate::parse) builds it
+//! as it reads the tokens, alongside the byte codes, when asked to with
+//! [`ParseProto::load_with_tree`]: the tree covers what the compiler
+//! supports, and a chunk it rejects has no tree.
+
+use std::{
+    fmt::{self, Write},
+    io::Cursor,
+};
+
+use crate::{
+    numfmt::float_to_string,
+    parse::{ParseOptions, ParseProto},
+    value::{LuaFloat, LuaInt},
+};
+
+/// The statements of a chunk, function or control structure.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Block {
+    pub stats: Vec<Stat>,
+}
+
+/// A statement and the line it starts on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stat {
+    pub line: usize,
+    pub kind: StatKind,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StatKind {
+    /// `local names = exps`
+    Local(Vec<String>, Vec<Exp>),
+    /// `targets = exps`, the targets being names and indexes
+    Assign(Vec<Exp>, Vec<Exp>),
+    /// A function or method call, for its side effects.
+    Call(Exp),
+    Do(Block),
+    /// The `if` and `elseif` conditions with their blocks, and the `else`
+    /// block.
+    If(Vec<(Exp, Block)>, Option<Block>),
+    NumericFor {
+        var: String,
+        start: Exp,
+        limit: Exp,
+        step: Option<Exp>,
+        body: Block,
+    },
+    GenericFor {
+        vars: Vec<String>,
+        exps: Vec<Exp>,
+        body: Block,
+    },
+    /// `function name() end`
+    Function(String, FuncBody),
+    LocalFunction(String, FuncBody),
+    Return(Vec<Exp>),
+    Break,
+    Goto(String),
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Exp {
+    Nil,
+    True,
+    False,
+    Dots,
+    Integer(LuaInt),
+    Float(LuaFloat),
+    String(Vec<u8>),
+    Function(FuncBody),
+    Table(Vec<Field>),
+    Binary(BinOp, Box<Exp>, Box<Exp>),
+    Unary(UnOp, Box<Exp>),
+    Name(String),
+    /// `t[k]`, and `t.k` with `k` a string
+    Index(Box<Exp>, Box<Exp>),
+    Call(Box<Exp>, Vec<Exp>),
+    /// `obj:name(args)`
+    Method(Box<Exp>, String, Vec<Exp>),
+    /// An expression in parentheses, which has one value even if it is a
+    /// call or `...`.
+    Paren(Box<Exp>),
+}
+
+/// A field of a table constructor.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    /// `v`, the next item of the list
+    Item(Exp),
+    /// `name = v`
+    Named(String, Exp),
+    /// `[k] = v`
+    Keyed(Exp, Exp),
+}
+
+/// The parameters and body of a function, and the line it starts on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FuncBody {
+    pub line: usize,
+    pub params: Vec<String>,
+    pub vararg: bool,
+    pub body: Block,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinOp {
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Ne,
+    Eq,
+    Concat,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Idiv,
+    Mod,
+    Pow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnOp {
+    Neg,
+}
+
+impl BinOp {
+    /// The operator as written.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinOp::Lt => "<",
+            BinOp::Gt => ">",
+            BinOp::Le => "<=",
+            BinOp::Ge => ">=",
+            BinOp::Ne => "~=",
+            BinOp::Eq => "==",
+            BinOp::Concat => "..",
+            BinOp::Add => "+",
+            BinOp::Sub => "-",
+            BinOp::Mul => "*",
+            BinOp::Div => "/",
+            BinOp::Idiv => "//",
+            BinOp::Mod => "%",
+            BinOp::Pow => "^",
+        }
+    }
+}
+
+impl UnOp {
+    /// The operator as written.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            UnOp::Neg => "-",
+        }
+    }
+}
+
+/// The syntax tree of chunk `src`. A syntax error is a
+/// [`SyntaxError`](crate::lex::SyntaxError), as the compiler raises it.
+pub fn parse(src: &[u8]) -> anyhow::Result<Block> {
+    let input = Cursor::new(src.to_vec());
+    let (_, tree) = ParseProto::load_with_tree(input, ParseOptions::default())?;
+    Ok(tree)
+}
+
+/// The tree of a chunk being compiled, which the compiler adds to as it
+/// reads the tokens. Expressions are pushed once complete, for the
+/// expression or statement they are part of to take them, and blocks once
+/// closed, for their statement or function.
+#[derive(Default)]
+pub(crate) struct TreeBuilder {
+    exps: Vec<Exp>,
+    // the statements of each block being read, innermost last
+    stats: Vec<Vec<Stat>>,
+    blocks: Vec<Block>,
+    // the fields of each table constructor being read, innermost last
+    fields: Vec<Vec<Field>>,
+    // the statement just read, for its block to add with its line
+    stat: Option<StatKind>,
+}
+
+impl TreeBuilder {
+    pub(crate) fn push(&mut self, e: Exp) {
+        self.exps.push(e);
+    }
+
+    pub(crate) fn pop(&mut self) -> Exp {
+        self.exps.pop().unwrap()
+    }
+
+    /// The last `n` expressions, in order.
+    pub(crate) fn pop_n(&mut self, n: usize) -> Vec<Exp> {
+        self.exps.split_off(self.exps.len() - n)
+    }
+
+    /// The function expression pushed last.
+    pub(crate) fn pop_func(&mut self) -> FuncBody {
+        match self.pop() {
+            Exp::Function(func) => func,
+            e => unreachable!("not a function: {e:?}"),
+        }
+    }
+
+    /// `t[k]`, of the last two expressions.
+    pub(crate) fn index(&mut self) {
+        let k = self.pop();
+        let t = self.pop();
+        self.push(Exp::Index(Box::new(t), Box::new(k)));
+    }
+
+    /// `t.key`, of the last expression.
+    pub(crate) fn field(&mut self, key: &str) {
+        let t = self.pop();
+        let k = Exp::String(key.as_bytes().to_vec());
+        self.push(Exp::Index(Box::new(t), Box::new(k)));
+    }
+
+    /// A call of the expression before the last `nargs`.
+    pub(crate) fn call(&mut self, nargs: usize) {
+        let args = self.pop_n(nargs);
+        let f = self.pop();
+        self.push(Exp::Call(Box::new(f), args));
+    }
+
+    /// A call of method `name` of the expression before the last `nargs`.
+    pub(crate) fn method(&mut self, name: &str, nargs: usize) {
+        let args = self.pop_n(nargs);
+        let obj = self.pop();
+        self.push(Exp::Method(Box::new(obj), name.into(), args));
+    }
+
+    pub(crate) fn unary(&mut self, op: UnOp) {
+        let e = self.pop();
+        self.push(Exp::Unary(op, Box::new(e)));
+    }
+
+    pub(crate) fn binary(&mut self, op: BinOp) {
+        let r = self.pop();
+        let l = self.pop();
+        self.push(Exp::Binary(op, Box::new(l), Box::new(r)));
+    }
+
+    pub(crate) fn paren(&mut self) {
+        let e = self.pop();
+        self.push(Exp::Paren(Box::new(e)));
+    }
+
+    /// A function with the block closed last as its body.
+    pub(crate) fn function(&mut self, line: usize, params: Vec<String>, vararg: bool) {
+        let body = self.pop_block();
+        self.push(Exp::Function(FuncBody {
+            line,
+            params,
+            vararg,
+            body,
+        }));
+    }
+
+    pub(crate) fn open_table(&mut self) {
+        self.fields.push(Vec::new());
+    }
+
+    /// The last expression as the next item of the table being read.
+    pub(crate) fn item(&mut self) {
+        let v = self.pop();
+        self.fields.last_mut().unwrap().push(Field::Item(v));
+    }
+
+    pub(crate) fn named(&mut self, name: &str) {
+        let v = self.pop();
+        let field = Field::Named(name.into(), v);
+        self.fields.last_mut().unwrap().push(field);
+    }
+
+    /// `[k] = v`, of the last two expressions.
+    pub(crate) fn keyed(&mut self) {
+        let v = self.pop();
+        let k = self.pop();
+        self.fields.last_mut().unwrap().push(Field::Keyed(k, v));
+    }
+
+    pub(crate) fn close_table(&mut self) {
+        let fields = self.fields.pop().unwrap();
+        self.push(Exp::Table(fields));
+    }
+
+    pub(crate) fn open_block(&mut self) {
+        self.stats.push(Vec::new());
+    }
+
+    pub(crate) fn close_block(&mut self) {
+        let stats = self.stats.pop().unwrap();
+        self.blocks.push(Block { stats });
+    }
+
+    pub(crate) fn pop_block(&mut self) -> Block {
+        self.blocks.pop().unwrap()
+    }
+
+    /// An `if` with `nclauses` conditions and their blocks, and an `else`
+    /// block if `has_else`.
+    pub(crate) fn if_stat(&mut self, nclauses: usize, has_else: bool) {
+        let else_block = has_else.then(|| self.pop_block());
+        let blocks = self.blocks.split_off(self.blocks.len() - nclauses);
+        let conds = self.pop_n(nclauses);
+        let clauses = conds.into_iter().zip(blocks).collect();
+        self.stat(StatKind::If(clauses, else_block));
+    }
+
+    /// `target = value`, of the last two expressions.
+    pub(crate) fn assign(&mut self) {
+        let value = self.pop();
+        let target = self.pop();
+        self.stat(StatKind::Assign(vec![target], vec![value]));
+    }
+
+    /// The statement just read, which [`end_stat`](Self::end_stat) adds.
+    pub(crate) fn stat(&mut self, kind: StatKind) {
+        self.stat = Some(kind);
+    }
+
+    /// Add the statement just read, which starts on `line`, to its block.
+    pub(crate) fn end_stat(&mut self, line: usize) {
+        let kind = self.stat.take().unwrap();
+        self.stats.last_mut().unwrap().push(Stat { line, kind });
+    }
+
+    /// The tree of the whole chunk, its block being the last closed.
+    pub(crate) fn finish(mut self) -> Block {
+        self.pop_block()
+    }
+}
+
+/// A node as it is printed: its kind, its line if it has one, its scalar
+/// attributes and its children, by role.
+struct Node {
+    kind: &'static str,
+    line: Option<usize>,
+    attrs: Vec<(&'static str, Attr)>,
+    children: Vec<(&'static str, Children)>,
+}
+
+enum Attr {
+    Name(String),
+    Names(Vec<String>),
+    /// parameter names, and whether `...` follows them
+    Params(Vec<String>, bool),
+    String(Vec<u8>),
+    Integer(LuaInt),
+    Float(LuaFloat),
+}
+
+enum Children {
+    One(Node),
+    Many(Vec<Node>),
+}
+
+impl Node {
+    fn new(kind: &'static str) -> Self {
+        Self {
+            kind,
+            line: None,
+            attrs: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    fn line(mut self, line: usize) -> Self {
+        self.line = Some(line);
+        self
+    }
+
+    fn attr(mut self, key: &'static str, attr: Attr) -> Self {
+        self.attrs.push((key, attr));
+        self
+    }
+
+    fn one(mut self, role: &'static str, child: Node) -> Self {
+        self.children.push((role, Children::One(child)));
+        self
+    }
+
+    fn many(mut self, role: &'static str, children: Vec<Node>) -> Self {
+        self.children.push((role, Children::Many(children)));
+        self
+    }
+
+    /// One line per node, children indented under it. Statements start
+    /// with their line number, and the children are under their role
+    /// when a node has several kinds of them.
+    fn write_text(&self, out: &mut String, indent: usize) {
+        let _ = write!(out, "{:indent$}", "");
+        if let Some(line) = self.line {
+            let _ = write!(out, "{line}: ");
+        }
+        out.push_str(self.kind);
+        for (_, attr) in &self.attrs {
+            let _ = match attr {
+                Attr::Name(s) => write!(out, " {s}"),
+                Attr::Names(names) => write!(out, " {}", names.join(", ")),
+                Attr::Params(params, vararg) => {
+                    let mut params = params.clone();
+                    if *vararg {
+                        params.push("...".into());
+                    }
+                    write!(out, " ({})", params.join(", "))
+                }
+                Attr::String(s) => write!(out, " {}", quote(s)),
+                Attr::Integer(i) => write!(out, " {i}"),
+                Attr::Float(f) => write!(out, " {}", float_to_string(*f)),
+            };
+        }
+        out.push('\n');
+        let labelled = self.children.len() > 1;
+        for (role, children) in &self.children {
+            let mut indent = indent + 2;
+            if labelled {
+                let _ = writeln!(out, "{:indent$}{role}", "");
+                indent += 2;
+            }
+            match children {
+                Children::One(node) => node.write_text(out, indent),
+                Children::Many(nodes) => {
+                    for node in nodes {
+                        node.write_text(out, indent);
+                    }
+                }
+            }
+        }
+    }
+
+    /// An object with the kind, line and attributes as members, then the
+    /// children, by role. Strings that are not UTF-8 are arrays of bytes.
+    fn write_json(&self, out: &mut String, indent: usize) {
+        let inner = indent + 2;
+        let _ = write!(out, "{{\n{:inner$}\"kind\": {}", "", json_str(self.kind));
+        if let Some(line) = self.line {
+            let _ = write!(out, ",\n{:inner$}\"line\": {line}", "");
+        }
+        for (key, attr) in &self.attrs {
+            let _ = write!(out, ",\n{:inner$}\"{key}\": ", "");
+            let _ = match attr {
+                Attr::Name(s) => write!(out, "{}", json_str(s)),
+                Attr::Names(names) => write!(out, "{}", json_names(names)),
+                Attr::Params(params, vararg) => write!(
+                    out,
+                    "{},\n{:inner$}\"vararg\": {vararg}",
+                    json_names(params),
+                    ""
+                ),
+                Attr::String(s) => match std::str::from_utf8(s) {
+                    Ok(s) => write!(out, "{}", json_str(s)),
+                    Err(_) => write!(out, "{s:?}"),
+                },
+                Attr::Integer(i) => write!(out, "{i}"),
+                // not a number in JSON
+                Attr::Float(f) if !f.is_finite() => write!(out, "{}", json_str(&f.to_string())),
+                Attr::Float(f) => write!(out, "{f:?}"),
+            };
+        }
+        for (role, children) in &self.children {
+            let _ = write!(out, ",\n{:inner$}\"{role}\": ", "");
+            match children {
+                Children::One(node) => node.write_json(out, inner),
+                Children::Many(nodes) if nodes.is_empty() => out.push_str("[]"),
+                Children::Many(nodes) => {
+                    out.push('[');
+                    for (i, node) in nodes.iter().enumerate() {
+                        if i > 0 {
+                            out.push(',');
+                        }
+                        let _ = write!(out, "\n{:1$}", "", inner + 2);
+                        node.write_json(out, inner + 2);
+                    }
+                    let _ = write!(out, "\n{:inner$}]", "");
+                }
+            }
+        }
+        let _ = write!(out, "\n{:indent$}}}", "");
+    }
+}
+
+/// A Lua string literal for `s`.
+fn quote(s: &[u8]) -> String {
+    let mut out = String::from("\"");
+    for &c in s {
+        match c {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\r' => out.push_str("\\r"),
+            b'\t' => out.push_str("\\t"),
+            c if c.is_ascii_graphic() || c == b' ' => out.push(c as char),
+            c => {
+                let _ = write!(out, "\\{c}");
+            }
+        }
+    }
+    out.push('"');
+    out
+}
+
+/// A JSON array of the strings `names`, on one line.
+fn json_names(names: &[String]) -> String {
+    let names: Vec<_> = names.iter().map(|s| json_str(s)).collect();
+    format!("[{}]", names.join(", "))
+}
+
+/// A JSON string literal for `s`.
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+impl Block {
+    fn node(&self) -> Node {
+        Node::new("Block").many("stats", self.stats.iter().map(Stat::node).collect())
+    }
+
+    /// The tree as JSON, for tools: an object per node, with its `kind`.
+    pub fn to_json(&self) -> String {
+        let mut out = String::new();
+        self.node().write_json(&mut out, 0);
+        out.push('\n');
+        out
+    }
+}
+
+/// The tree, a node per line.
+impl fmt::Display for Block {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut out = String::new();
+        self.node().write_text(&mut out, 0);
+        f.write_str(&out)
+    }
+}
+
+fn nodes(exps: &[Exp]) -> Vec<Node> {
+    exps.iter().map(Exp::node).collect()
+}
+
+impl Stat {
+    fn node(&self) -> Node {
+        let node = match &self.kind {
+            StatKind::Local(names, exps) => Node::new("Local")
+                .attr("names", Attr::Names(names.clone()))
+                .many("values", nodes(exps)),
+            StatKind::Assign(targets, exps) => Node::new("Assign")
+                .many("targets", nodes(targets))
+                .many("values", nodes(exps)),
+            StatKind::Call(e) => e.node(),
+            StatKind::Do(body) => Node::new("Do").one("body", body.node()),
+            StatKind::If(clauses, else_block) => {
+                let clauses = clauses
+                    .iter()
+                    .map(|(cond, block)| {
+                        Node::new("Clause")
+                            .one("cond", cond.node())
+                            .one("then", block.node())
+                    })
+                    .collect();
+                let node = Node::new("If").many("clauses", clauses);
+                match else_block {
+                    Some(block) => node.one("else", block.node()),
+                    None => node,
+                }
+            }
+            StatKind::NumericFor {
+                var,
+                start,
+                limit,
+                step,
+                body,
+            } => {
+                let node = Node::new("NumericFor")
+                    .attr("var", Attr::Name(var.clone()))
+                    .one("start", start.node())
+                    .one("limit", limit.node());
+                match step {
+                    Some(step) => node.one("step", step.node()),
+                    None => node,
+                }
+                .one("body", body.node())
+            }
+            StatKind::GenericFor { vars, exps, body } => Node::new("GenericFor")
+                .attr("vars", Attr::Names(vars.clone()))
+                .many("values", nodes(exps))
+                .one("body", body.node()),
+            StatKind::Function(name, func) => {
+                func.node("Function").attr("name", Attr::Name(name.clone()))
+            }
+            StatKind::LocalFunction(name, func) => func
+                .node("LocalFunction")
+                .attr("name", Attr::Name(name.clone())),
+            StatKind::Return(exps) => Node::new("Return").many("values", nodes(exps)),
+            StatKind::Break => Node::new("Break"),
+            StatKind::Goto(name) => Node::new("Goto").attr("label", Attr::Name(name.clone())),
+            StatKind::Label(name) => Node::new("Label").attr("name", Attr::Name(name.clone())),
+        };
+        node.line(self.line)
+    }
+}
+
+impl FuncBody {
+    fn node(&self, kind: &'static str) -> Node {
+        Node::new(kind)
+            .attr("params", Attr::Params(self.params.clone(), self.vararg))
+            .one("body", self.body.node())
+    }
+}
+
+impl Exp {
+    fn node(&self) -> Node {
+        match self {
+            Exp::Nil => Node::new("Nil"),
+            Exp::True => Node::new("True"),
+            Exp::False => Node::new("False"),
+            Exp::Dots => Node::new("Dots"),
+            &Exp::Integer(i) => Node::new("Integer").attr("value", Attr::Integer(i)),
+            &Exp::Float(f) => Node::new("Float").attr("value", Attr::Float(f)),
+            Exp::String(s) => Node::new("String").attr("value", Attr::String(s.clone())),
+            Exp::Function(func) => func.node("FunctionExp").line(func.line),
+            Exp::Table(fields) => {
+                let fields = fields
+                    .iter()
+                    .map(|field| match field {
+                        Field::Item(v) => Node::new("Item").one("value", v.node()),
+                        Field::Named(name, v) => Node::new("Named")
+                            .attr("name", Attr::Name(name.clone()))
+                            .one("value", v.node()),
+                        Field::Keyed(k, v) => Node::new("Keyed")
+                            .one("key", k.node())
+                            .one("value", v.node()),
+                    })
+                    .collect();
+                Node::new("Table").many("fields", fields)
+            }
+            Exp::Binary(op, l, r) => Node::new("Binary")
+                .attr("op", Attr::Name(op.symbol().into()))
+                .many("operands", vec![l.node(), r.node()]),
+            Exp::Unary(op, e) => Node::new("Unary")
+                .attr("op", Attr::Name(op.symbol().into()))
+                .one("operand", e.node()),
+            Exp::Name(name) => Node::new("Name").attr("name", Attr::Name(name.clone())),
+            Exp::Index(t, k) => Node::new("Index")
+                .one("table", t.node())
+                .one("key", k.node()),
+            Exp::Call(f, args) => Node::new("Call")
+                .one("func", f.node())
+                .many("args", nodes(args)),
+            Exp::Method(obj, name, args) => Node::new("Method")
+                .attr("name", Attr::Name(name.clone()))
+                .one("object", obj.node())
+                .many("args", nodes(args)),
+            Exp::Paren(e) => Node::new("Paren").one("exp", e.node()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lex::SyntaxError;
+
+    #[test]
+    fn statements() {
+        let block = parse(b"local a = 'x'\nfunction f(b, ...) return ... end").unwrap();
+        assert_eq!(
+            block.stats[0],
+            Stat {
+                line: 1,
+                kind: StatKind::Local(vec!["a".into()], vec![Exp::String(b"x".to_vec())]),
+            }
+        );
+        let StatKind::Function(name, func) = &block.stats[1].kind else {
+            panic!("{:?}", block.stats[1]);
+        };
+        assert_eq!(name, "f");
+        assert_eq!(
+            (&func.params[..], func.vararg),
+            (&["b".to_string()][..], true)
+        );
+        assert_eq!(func.body.stats[0].kind, StatKind::Return(vec![Exp::Dots]));
+        assert_eq!(block.stats[1].line, 2);
+    }
+
+    #[test]
+    fn priorities() {
+        let exp = |src: &str| {
+            let block = parse(format!("return {src}").as_bytes()).unwrap();
+            let StatKind::Return(exps) = &block.stats[0].kind else {
+                unreachable!()
+            };
+            exps[0].clone()
+        };
+        let bin = |op, l, r| Exp::Binary(op, Box::new(l), Box::new(r));
+        let (a, b, c) = (
+            Exp::Name("a".into()),
+            Exp::Name("b".into()),
+            Exp::Name("c".into()),
+        );
+        assert_eq!(
+            exp("a + b * c"),
+            bin(BinOp::Add, a.clone(), bin(BinOp::Mul, b.clone(), c.clone()))
+        );
+        // `..` and `^` are right associative, `-` left
+        assert_eq!(
+            exp("a .. b .. c"),
+            bin(
+                BinOp::Concat,
+                a.clone(),
+                bin(BinOp::Concat, b.clone(), c.clone())
+            )
+        );
+        assert_eq!(
+            exp("a - b - c"),
+            bin(BinOp::Sub, bin(BinOp::Sub, a.clone(), b.clone()), c.clone())
+        );
+        // unary operators bind tighter than all but `^`
+        assert_eq!(
+            exp("-a ^ b"),
+            Exp::Unary(UnOp::Neg, Box::new(bin(BinOp::Pow, a.clone(), b.clone())))
+        );
+        assert_eq!(
+            exp("-a == b .. c"),
+            bin(
+                BinOp::Eq,
+                Exp::Unary(UnOp::Neg, Box::new(a.clone())),
+                bin(BinOp::Concat, b.clone(), c.clone())
+            )
+        );
+        // constants stay as written, though the compiler folds them
+        assert_eq!(
+            exp("1 + 2"),
+            bin(BinOp::Add, Exp::Integer(1), Exp::Integer(2))
+        );
+        assert_eq!(
+            exp("(a):m(b)[c]"),
+            Exp::Index(
+                Box::new(Exp::Method(
+                    Box::new(Exp::Paren(Box::new(a))),
+                    "m".into(),
+                    vec![b]
+                )),
+                Box::new(c)
+            )
+        );
+    }
+
+    #[test]
+    fn output() {
+        let block = parse(b"print(\"hi\\n\")\nt.n = {1, k = -x}").unwrap();
+        assert_eq!(
+            block.to_string(),
+            "Block\n\
+             \x20 1: Call\n\
+             \x20   func\n\
+             \x20     Name print\n\
+             \x20   args\n\
+             \x20     String \"hi\\n\"\n\
+             \x20 2: Assign\n\
+             \x20   targets\n\
+             \x20     Index\n\
+             \x20       table\n\
+             \x20         Name t\n\
+             \x20       key\n\
+             \x20         String \"n\"\n\
+             \x20   values\n\
+             \x20     Table\n\
+             \x20       Item\n\
+             \x20         Integer 1\n\
+             \x20       Named k\n\
+             \x20         Unary -\n\
+             \x20           Name x\n"
+        );
+
+        let json = parse(b"return 1, 'a'").unwrap().to_json();
+        assert_eq!(
+            json,
+            r#"{
+  "kind": "Block",
+  "stats": [
+    {
+      "kind": "Return",
+      "line": 1,
+      "values": [
+        {
+          "kind": "Integer",
+          "value": 1
+        },
+        {
+          "kind": "String",
+          "value": "a"
+        }
+      ]
+    }
+  ]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn if_clauses() {
+        let block = parse(b"if a then f() elseif b then else return end").unwrap();
+        let call = Exp::Call(Box::new(Exp::Name("f".into())), vec![]);
+        assert_eq!(
+            block.stats[0].kind,
+            StatKind::If(
+                vec![
+                    (
+                        Exp::Name("a".into()),
+                        Block {
+                            stats: vec![Stat {
+                                line: 1,
+                                kind: StatKind::Call(call),
+                            }],
+                        },
+                    ),
+                    (Exp::Name("b".into()), Block::default()),
+                ],
+                Some(Block {
+                    stats: vec![Stat {
+                        line: 1,
+                        kind: StatKind::Return(vec![]),
+                    }],
+                }),
+            )
+        );
+
+        // a clause per condition, each with its block
+        let json = parse(b"if a then elseif b then end").unwrap().to_json();
+        assert_eq!(
+            json,
+            r#"{
+  "kind": "Block",
+  "stats": [
+    {
+      "kind": "If",
+      "line": 1,
+      "clauses": [
+        {
+          "kind": "Clause",
+          "cond": {
+            "kind": "Name",
+            "name": "a"
+          },
+          "then": {
+            "kind": "Block",
+            "stats": []
+          }
+        },
+        {
+          "kind": "Clause",
+          "cond": {
+            "kind": "Name",
+            "name": "b"
+          },
+          "then": {
+            "kind": "Block",
+            "stats": []
+          }
+        }
+      ]
+    }
+  ]
+}
+"#
+        );
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |src: &str| parse(src.as_bytes()).unwrap_err().to_string();
+        // those of the compiler
+        assert_eq!(error("x"), "syntax error near <eof>");
+        assert_eq!(error("f() = 1"), "syntax error near '='");
+        assert_eq!(error("for i do end"), "'=' or 'in' expected near 'do'");
+        assert_eq!(error("do end end"), "'<eof>' expected near 'end'");
+        assert_eq!(error("goto a"), "no visible label 'a' for goto");
+        let err = parse(b"local a = 1\nlocal = 1").unwrap_err();
+        let err = err.downcast::<SyntaxError>().unwrap();
+        assert_eq!((err.span.start.line, err.span.start.column), (2, 7));
+    }
+}
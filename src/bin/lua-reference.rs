@@ -0,0 +1,24 @@
+//! `lua5.4 SCRIPT`, with the reference interpreter built from its C sources
+//! by the `reference` feature, so that the golden and differential tests
+//! can compare kailua against it without Lua installed. Only as much of the
+//! reference command line as they need: the script runs with all the
+//! standard libraries, and an error is reported on stderr with status 1.
+
+use std::{env, path::PathBuf, process::ExitCode};
+
+fn main() -> ExitCode {
+    let Some(script) = env::args_os().nth(1) else {
+        eprintln!("usage: lua-reference SCRIPT");
+        return ExitCode::FAILURE;
+    };
+    // the reference opens `debug` too, which mlua counts as unsafe
+    let lua = unsafe { mlua::Lua::unsafe_new() };
+    // named `@SCRIPT`, so that error positions read as the reference's
+    match lua.load(PathBuf::from(script)).exec() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("lua: {err}");
+            ExitCode::FAILURE
+        }
+    }
+}
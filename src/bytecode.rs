@@ -1,8 +1,20 @@
-#[derive(Debug)]
+use anyhow::bail;
+
+/// Result count of a `Call`, or value count of a `Return`, meaning all the
+/// values up to the top of the stack.
+pub const MULTRET: u8 = u8::MAX;
+
+/// Largest offset of a `Jump` either way, to fit the 24 bits it is packed
+/// into.
+pub const MAX_JUMP: i32 = (1 << 23) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum ByteCode {
     GetGlobal(u8, u8),
     LoadConst(u8, u8),
-    Call(u8, u8),
+    // function register, argument count or MULTRET for all up to the top
+    // of the stack, result count or MULTRET
+    Call(u8, u8, u8),
     LoadNil(u8),
     LoadBool(u8, bool),
     LoadInt(u8, i16),
@@ -10,4 +22,337 @@ pub enum ByteCode {
     SetGlobalConst(u8, u8),
     SetGlobal(u8, u8),
     SetGlobalGlobal(u8, u8),
+    // first register, count or MULTRET
+    Return(u8, u8),
+    // offset from the next instruction
+    Jump(i32),
+    // first register, count: the first register gets the concatenation of
+    // the count registers from it
+    Concat(u8, u8),
+    // base of the loop registers: internal index, limit or iteration
+    // count, step, and the variable. ForPrep skips the loop when it does
+    // not run at all, to the instruction after the ForLoop at that offset
+    // plus one; ForLoop jumps back by its offset while the loop continues
+    ForPrep(u8, u16),
+    ForLoop(u8, u16),
+    // destination, table register, constant key
+    GetField(u8, u8, u8),
+    // base of the loop registers: iterator function, state, control, and
+    // the variables. TForCall calls the function with the state and the
+    // control, keeping the count of results in the variables; TForLoop
+    // copies the first variable to the control and jumps back by its
+    // offset unless it is nil
+    TForCall(u8, u8),
+    TForLoop(u8, u16),
+    // destination, constant global name, constant key: a GetGlobal and
+    // a GetField of the result in one, for `string.len` and the like
+    GetGlobalField(u8, u8, u8),
+    // destination, index of the function among the protos of the chunk
+    Closure(u8, u8),
+    // first register, count or MULTRET: the extra arguments of a vararg
+    // function, padded with nil to the count
+    VarArgs(u8, u8),
+    // register, upvalue: read it into the register, or write the register
+    // to it
+    GetUpval(u8, u8),
+    SetUpval(u8, u8),
+    // first register: close the upvalues open on it and on the registers
+    // above, at the end of the scope of the locals there
+    Close(u8),
+    // destination: an empty table
+    NewTable(u8),
+    // destination, table, key register: `t[k]`, as GetField
+    GetTable(u8, u8, u8),
+    // table, key register or constant key, value register: `t[k] = v`
+    SetTable(u8, u8, u8),
+    SetField(u8, u8, u8),
+    // table, count or MULTRET: set the items 1 to count of the table to
+    // the registers that follow it
+    SetList(u8, u8),
+    // destination, operands: the arithmetic operators on two registers,
+    // on a register and a constant, and addition of a small integer,
+    // which subtraction of one is too
+    Add(u8, u8, u8),
+    Sub(u8, u8, u8),
+    Mul(u8, u8, u8),
+    Div(u8, u8, u8),
+    Idiv(u8, u8, u8),
+    Mod(u8, u8, u8),
+    Pow(u8, u8, u8),
+    AddK(u8, u8, u8),
+    SubK(u8, u8, u8),
+    MulK(u8, u8, u8),
+    DivK(u8, u8, u8),
+    IdivK(u8, u8, u8),
+    ModK(u8, u8, u8),
+    PowK(u8, u8, u8),
+    AddInt(u8, u8, i8),
+    // destination, operand: unary minus
+    Unm(u8, u8),
+    // destination or table, table or small integer key, key or value
+    // register: `t[i]` and `t[i] = v`, as GetField and SetField
+    GetInt(u8, u8, u8),
+    SetInt(u8, u8, u8),
+    // destination, operands: the boolean `a == b`, `a ~= b`, `a < b` or
+    // `a <= b`, `>` and `>=` swapping the operands
+    Eq(u8, u8, u8),
+    Ne(u8, u8, u8),
+    Lt(u8, u8, u8),
+    Le(u8, u8, u8),
+    // destination, object register, constant name: the method `obj.name`
+    // in the destination and the object after it, its first argument, for
+    // `obj:name(args)`
+    GetMethod(u8, u8, u8),
+    // register: skip the next instruction, the jump past the block of an
+    // `if`, if the value is true, that is neither nil nor false
+    Test(u8),
+}
+
+impl ByteCode {
+    /// Name of the opcode, without operands.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ByteCode::GetGlobal(..) => "GetGlobal",
+            ByteCode::LoadConst(..) => "LoadConst",
+            ByteCode::Call(..) => "Call",
+            ByteCode::LoadNil(..) => "LoadNil",
+            ByteCode::LoadBool(..) => "LoadBool",
+            ByteCode::LoadInt(..) => "LoadInt",
+            ByteCode::Move(..) => "Move",
+            ByteCode::SetGlobalConst(..) => "SetGlobalConst",
+            ByteCode::SetGlobal(..) => "SetGlobal",
+            ByteCode::SetGlobalGlobal(..) => "SetGlobalGlobal",
+            ByteCode::Return(..) => "Return",
+            ByteCode::Jump(..) => "Jump",
+            ByteCode::Concat(..) => "Concat",
+            ByteCode::ForPrep(..) => "ForPrep",
+            ByteCode::ForLoop(..) => "ForLoop",
+            ByteCode::GetField(..) => "GetField",
+            ByteCode::TForCall(..) => "TForCall",
+            ByteCode::TForLoop(..) => "TForLoop",
+            ByteCode::GetGlobalField(..) => "GetGlobalField",
+            ByteCode::Closure(..) => "Closure",
+            ByteCode::VarArgs(..) => "VarArgs",
+            ByteCode::GetUpval(..) => "GetUpval",
+            ByteCode::SetUpval(..) => "SetUpval",
+            ByteCode::Close(..) => "Close",
+            ByteCode::NewTable(..) => "NewTable",
+            ByteCode::GetTable(..) => "GetTable",
+            ByteCode::SetTable(..) => "SetTable",
+            ByteCode::SetField(..) => "SetField",
+            ByteCode::SetList(..) => "SetList",
+            ByteCode::Add(..) => "Add",
+            ByteCode::Sub(..) => "Sub",
+            ByteCode::Mul(..) => "Mul",
+            ByteCode::Div(..) => "Div",
+            ByteCode::Idiv(..) => "Idiv",
+            ByteCode::Mod(..) => "Mod",
+            ByteCode::Pow(..) => "Pow",
+            ByteCode::AddK(..) => "AddK",
+            ByteCode::SubK(..) => "SubK",
+            ByteCode::MulK(..) => "MulK",
+            ByteCode::DivK(..) => "DivK",
+            ByteCode::IdivK(..) => "IdivK",
+            ByteCode::ModK(..) => "ModK",
+            ByteCode::PowK(..) => "PowK",
+            ByteCode::AddInt(..) => "AddInt",
+            ByteCode::Unm(..) => "Unm",
+            ByteCode::GetInt(..) => "GetInt",
+            ByteCode::SetInt(..) => "SetInt",
+            ByteCode::Eq(..) => "Eq",
+            ByteCode::Ne(..) => "Ne",
+            ByteCode::Lt(..) => "Lt",
+            ByteCode::Le(..) => "Le",
+            ByteCode::GetMethod(..) => "GetMethod",
+            ByteCode::Test(..) => "Test",
+        }
+    }
+
+    /// Pack into a 32-bit word: the opcode in the low byte, then operands
+    /// A, B and C a byte each, or A and a signed 16-bit sBx or unsigned
+    /// Bx, or a signed 24-bit sJ for jumps.
+    pub fn encode(&self) -> u32 {
+        let abc = |op: u32, a: u8, b: u8, c: u8| {
+            op | (a as u32) << 8 | (b as u32) << 16 | (c as u32) << 24
+        };
+        match *self {
+            ByteCode::GetGlobal(a, b) => abc(0, a, b, 0),
+            ByteCode::LoadConst(a, b) => abc(1, a, b, 0),
+            ByteCode::Call(a, b, c) => abc(2, a, b, c),
+            ByteCode::LoadNil(a) => abc(3, a, 0, 0),
+            ByteCode::LoadBool(a, b) => abc(4, a, b as u8, 0),
+            ByteCode::LoadInt(a, sbx) => 5 | (a as u32) << 8 | (sbx as u16 as u32) << 16,
+            ByteCode::Move(a, b) => abc(6, a, b, 0),
+            ByteCode::SetGlobalConst(a, b) => abc(7, a, b, 0),
+            ByteCode::SetGlobal(a, b) => abc(8, a, b, 0),
+            ByteCode::SetGlobalGlobal(a, b) => abc(9, a, b, 0),
+            ByteCode::Return(a, b) => abc(10, a, b, 0),
+            ByteCode::Jump(sj) => 11 | (sj as u32) << 8,
+            ByteCode::Concat(a, b) => abc(12, a, b, 0),
+            ByteCode::ForPrep(a, bx) => 13 | (a as u32) << 8 | (bx as u32) << 16,
+            ByteCode::ForLoop(a, bx) => 14 | (a as u32) << 8 | (bx as u32) << 16,
+            ByteCode::GetField(a, b, c) => abc(15, a, b, c),
+            ByteCode::TForCall(a, b) => abc(16, a, b, 0),
+            ByteCode::TForLoop(a, bx) => 17 | (a as u32) << 8 | (bx as u32) << 16,
+            ByteCode::GetGlobalField(a, b, c) => abc(18, a, b, c),
+            ByteCode::Closure(a, b) => abc(19, a, b, 0),
+            ByteCode::VarArgs(a, b) => abc(20, a, b, 0),
+            ByteCode::GetUpval(a, b) => abc(21, a, b, 0),
+            ByteCode::SetUpval(a, b) => abc(22, a, b, 0),
+            ByteCode::Close(a) => abc(23, a, 0, 0),
+            ByteCode::NewTable(a) => abc(24, a, 0, 0),
+            ByteCode::GetTable(a, b, c) => abc(25, a, b, c),
+            ByteCode::SetTable(a, b, c) => abc(26, a, b, c),
+            ByteCode::SetField(a, b, c) => abc(27, a, b, c),
+            ByteCode::SetList(a, b) => abc(28, a, b, 0),
+            ByteCode::Add(a, b, c) => abc(29, a, b, c),
+            ByteCode::Sub(a, b, c) => abc(30, a, b, c),
+            ByteCode::Mul(a, b, c) => abc(31, a, b, c),
+            ByteCode::Div(a, b, c) => abc(32, a, b, c),
+            ByteCode::Idiv(a, b, c) => abc(33, a, b, c),
+            ByteCode::Mod(a, b, c) => abc(34, a, b, c),
+            ByteCode::Pow(a, b, c) => abc(35, a, b, c),
+            ByteCode::AddK(a, b, c) => abc(36, a, b, c),
+            ByteCode::SubK(a, b, c) => abc(37, a, b, c),
+            ByteCode::MulK(a, b, c) => abc(38, a, b, c),
+            ByteCode::DivK(a, b, c) => abc(39, a, b, c),
+            ByteCode::IdivK(a, b, c) => abc(40, a, b, c),
+            ByteCode::ModK(a, b, c) => abc(41, a, b, c),
+            ByteCode::PowK(a, b, c) => abc(42, a, b, c),
+            ByteCode::AddInt(a, b, sc) => abc(43, a, b, sc as u8),
+            ByteCode::Unm(a, b) => abc(44, a, b, 0),
+            ByteCode::GetInt(a, b, c) => abc(45, a, b, c),
+            ByteCode::SetInt(a, b, c) => abc(46, a, b, c),
+            ByteCode::Eq(a, b, c) => abc(47, a, b, c),
+            ByteCode::Ne(a, b, c) => abc(48, a, b, c),
+            ByteCode::Lt(a, b, c) => abc(49, a, b, c),
+            ByteCode::Le(a, b, c) => abc(50, a, b, c),
+            ByteCode::GetMethod(a, b, c) => abc(51, a, b, c),
+            ByteCode::Test(a) => abc(52, a, 0, 0),
+        }
+    }
+
+    /// Unpack a word written by [`encode`](Self::encode).
+    pub fn decode(word: u32) -> anyhow::Result<Self> {
+        let a = (word >> 8) as u8;
+        let b = (word >> 16) as u8;
+        let c = (word >> 24) as u8;
+        Ok(match word as u8 {
+            0 => ByteCode::GetGlobal(a, b),
+            1 => ByteCode::LoadConst(a, b),
+            2 => ByteCode::Call(a, b, c),
+            3 => ByteCode::LoadNil(a),
+            4 => ByteCode::LoadBool(a, b != 0),
+            5 => ByteCode::LoadInt(a, (word >> 16) as u16 as i16),
+            6 => ByteCode::Move(a, b),
+            7 => ByteCode::SetGlobalConst(a, b),
+            8 => ByteCode::SetGlobal(a, b),
+            9 => ByteCode::SetGlobalGlobal(a, b),
+            10 => ByteCode::Return(a, b),
+            // arithmetic shift, to extend the sign
+            11 => ByteCode::Jump(word as i32 >> 8),
+            12 => ByteCode::Concat(a, b),
+            13 => ByteCode::ForPrep(a, (word >> 16) as u16),
+            14 => ByteCode::ForLoop(a, (word >> 16) as u16),
+            15 => ByteCode::GetField(a, b, c),
+            16 => ByteCode::TForCall(a, b),
+            17 => ByteCode::TForLoop(a, (word >> 16) as u16),
+            18 => ByteCode::GetGlobalField(a, b, c),
+            19 => ByteCode::Closure(a, b),
+            20 => ByteCode::VarArgs(a, b),
+            21 => ByteCode::GetUpval(a, b),
+            22 => ByteCode::SetUpval(a, b),
+            23 => ByteCode::Close(a),
+            24 => ByteCode::NewTable(a),
+            25 => ByteCode::GetTable(a, b, c),
+            26 => ByteCode::SetTable(a, b, c),
+            27 => ByteCode::SetField(a, b, c),
+            28 => ByteCode::SetList(a, b),
+            29 => ByteCode::Add(a, b, c),
+            30 => ByteCode::Sub(a, b, c),
+            31 => ByteCode::Mul(a, b, c),
+            32 => ByteCode::Div(a, b, c),
+            33 => ByteCode::Idiv(a, b, c),
+            34 => ByteCode::Mod(a, b, c),
+            35 => ByteCode::Pow(a, b, c),
+            36 => ByteCode::AddK(a, b, c),
+            37 => ByteCode::SubK(a, b, c),
+            38 => ByteCode::MulK(a, b, c),
+            39 => ByteCode::DivK(a, b, c),
+            40 => ByteCode::IdivK(a, b, c),
+            41 => ByteCode::ModK(a, b, c),
+            42 => ByteCode::PowK(a, b, c),
+            43 => ByteCode::AddInt(a, b, c as i8),
+            44 => ByteCode::Unm(a, b),
+            45 => ByteCode::GetInt(a, b, c),
+            46 => ByteCode::SetInt(a, b, c),
+            47 => ByteCode::Eq(a, b, c),
+            48 => ByteCode::Ne(a, b, c),
+            49 => ByteCode::Lt(a, b, c),
+            50 => ByteCode::Le(a, b, c),
+            51 => ByteCode::GetMethod(a, b, c),
+            52 => ByteCode::Test(a),
+            op => bail!("invalid opcode {op}"),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_decode() {
+        let codes = [
+            ByteCode::GetGlobal(1, 2),
+            ByteCode::LoadConst(255, 254),
+            ByteCode::Call(3, 4, MULTRET),
+            ByteCode::LoadNil(5),
+            ByteCode::LoadBool(6, true),
+            ByteCode::LoadInt(7, -32768),
+            ByteCode::LoadInt(8, 12345),
+            ByteCode::Move(9, 10),
+            ByteCode::SetGlobalConst(11, 12),
+            ByteCode::SetGlobal(13, 14),
+            ByteCode::SetGlobalGlobal(15, 16),
+            ByteCode::Return(17, 0),
+            ByteCode::Jump(-MAX_JUMP),
+            ByteCode::Jump(MAX_JUMP),
+            ByteCode::Jump(-1),
+            ByteCode::Concat(18, 3),
+            ByteCode::ForPrep(19, 65535),
+            ByteCode::ForLoop(20, 1),
+            ByteCode::GetField(21, 22, 23),
+            ByteCode::TForCall(24, 2),
+            ByteCode::TForLoop(25, 300),
+            ByteCode::GetGlobalField(26, 27, 28),
+            ByteCode::Closure(29, 30),
+            ByteCode::VarArgs(31, MULTRET),
+            ByteCode::GetUpval(32, 33),
+            ByteCode::SetUpval(34, 35),
+            ByteCode::Close(36),
+            ByteCode::NewTable(37),
+            ByteCode::GetTable(38, 39, 40),
+            ByteCode::SetTable(41, 42, 43),
+            ByteCode::SetField(44, 45, 46),
+            ByteCode::SetList(47, MULTRET),
+            ByteCode::Add(48, 49, 50),
+            ByteCode::PowK(51, 52, 53),
+            ByteCode::AddInt(54, 55, -128),
+            ByteCode::Unm(56, 57),
+            ByteCode::GetInt(58, 59, 255),
+            ByteCode::SetInt(60, 61, 62),
+            ByteCode::Eq(63, 64, 65),
+            ByteCode::Ne(66, 67, 68),
+            ByteCode::Lt(69, 70, 71),
+            ByteCode::Le(72, 73, 74),
+            ByteCode::GetMethod(75, 76, 77),
+            ByteCode::Test(78),
+        ];
+        for code in codes {
+            assert_eq!(ByteCode::decode(code.encode()).unwrap(), code);
+        }
+        assert_eq!(ByteCode::Call(1, 2, 3).encode(), 0x0302_0102);
+        assert!(ByteCode::decode(0xff).is_err());
+    }
 }
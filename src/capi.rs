@@ -0,0 +1,505 @@
+//! A subset of the Lua C API, for C and C++ hosts to try kailua in place
+//! of the reference interpreter. The functions have the names and
+//! signatures of those in `lua.h` and `lauxlib.h` that its macros expand
+//! to, so that `lua_pop`, `lua_tostring`, `lua_pcall` and `luaL_dostring`
+//! work as written. Build a library to link against with:
+//!
+//! ```sh
+//! cargo rustc --release --lib --features capi --crate-type cdylib
+//! ```
+//!
+//! The stack of a `lua_State` is its own, apart from that of the VM. Loaded
+//! chunks live on it as functions until called with `lua_pcall`; Lua
+//! functions cannot be pushed from C. Pseudo-indices such as the registry
+//! are not supported.
+//!
+//! # Safety
+//!
+//! As with the reference interpreter: `L` must come from `luaL_newstate`
+//! and not be closed, strings must be NUL-terminated, or `len` bytes long
+//! for `lua_pushlstring`, and a pointer from `lua_tolstring` is only valid
+//! while its value is on the stack.
+#![allow(non_camel_case_types, non_snake_case, clippy::missing_safety_doc)]
+
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_int, c_void, CStr},
+    io::Cursor,
+    ptr,
+    rc::Rc,
+};
+
+use crate::{
+    numfmt,
+    parse::{ParseOptions, ParseProto},
+    value::{LuaFloat, LuaInt, Value, INT_RANGE},
+    vm::{string_chunk_name, ExeState},
+};
+
+pub type lua_Integer = LuaInt;
+pub type lua_Number = LuaFloat;
+pub type lua_KContext = isize;
+pub type lua_KFunction = Option<unsafe extern "C" fn(*mut lua_State, c_int, lua_KContext) -> c_int>;
+pub type lua_Alloc =
+    Option<unsafe extern "C" fn(*mut c_void, *mut c_void, usize, usize) -> *mut c_void>;
+
+pub const LUA_OK: c_int = 0;
+pub const LUA_ERRRUN: c_int = 2;
+pub const LUA_ERRSYNTAX: c_int = 3;
+pub const LUA_MULTRET: c_int = -1;
+
+pub const LUA_TNONE: c_int = -1;
+pub const LUA_TNIL: c_int = 0;
+pub const LUA_TBOOLEAN: c_int = 1;
+pub const LUA_TNUMBER: c_int = 3;
+pub const LUA_TSTRING: c_int = 4;
+pub const LUA_TTABLE: c_int = 5;
+pub const LUA_TFUNCTION: c_int = 6;
+pub const LUA_TUSERDATA: c_int = 7;
+
+/// A state and the stack C sees.
+pub struct lua_State {
+    state: ExeState,
+    stack: Vec<Slot>,
+    // NUL-terminated copies of the strings `lua_tolstring` returned, by
+    // stack position
+    strings: HashMap<usize, Vec<u8>>,
+}
+
+#[derive(Clone)]
+enum Slot {
+    Value(Value),
+    // a chunk from `luaL_loadstring`, not yet called
+    Chunk(Rc<ParseProto>),
+}
+
+impl lua_State {
+    /// The position in the stack of `idx`, counted from 1 at the bottom
+    /// or from -1 at the top.
+    fn position(&self, idx: c_int) -> Option<usize> {
+        let i = if idx > 0 {
+            idx as usize - 1
+        } else {
+            self.stack.len().checked_sub(idx.unsigned_abs() as usize)?
+        };
+        (i < self.stack.len()).then_some(i)
+    }
+
+    fn value(&self, idx: c_int) -> Option<&Value> {
+        match self.stack.get(self.position(idx)?)? {
+            Slot::Value(v) => Some(v),
+            Slot::Chunk(_) => None,
+        }
+    }
+
+    fn push(&mut self, v: Value) {
+        self.stack.push(Slot::Value(v));
+    }
+
+    fn truncate(&mut self, top: usize) {
+        self.stack.truncate(top);
+        self.strings.retain(|&i, _| i < top);
+    }
+
+    /// Call the function below the top `nargs` values, leaving `nresults`
+    /// results, or all if `LUA_MULTRET`, in its place.
+    fn call(&mut self, nargs: usize, nresults: c_int) -> c_int {
+        let Some(func) = self.stack.len().checked_sub(nargs + 1) else {
+            self.push("attempt to call a nil value".into());
+            return LUA_ERRRUN;
+        };
+        let args: Vec<_> = self.stack[func + 1..]
+            .iter()
+            .map(|slot| match slot {
+                Slot::Value(v) => v.clone(),
+                Slot::Chunk(_) => Value::Nil,
+            })
+            .collect();
+        let result = match self.stack[func].clone() {
+            Slot::Chunk(proto) => self.state.execute_results(proto),
+            Slot::Value(f) => self.state.call_results(f, &args),
+        };
+        self.truncate(func);
+        match result {
+            Ok(mut results) => {
+                if nresults != LUA_MULTRET {
+                    results.resize(nresults.max(0) as usize, Value::Nil);
+                }
+                for v in results {
+                    self.push(v);
+                }
+                LUA_OK
+            }
+            Err(err) => {
+                let v = self.state.take_error_value(&err);
+                self.push(v);
+                LUA_ERRRUN
+            }
+        }
+    }
+}
+
+unsafe fn bytes<'a>(s: *const c_char) -> &'a [u8] {
+    CStr::from_ptr(s).to_bytes()
+}
+
+unsafe fn str_arg<'a>(s: *const c_char) -> &'a str {
+    std::str::from_utf8(bytes(s)).unwrap_or_default()
+}
+
+#[no_mangle]
+pub extern "C" fn luaL_newstate() -> *mut lua_State {
+    Box::into_raw(Box::new(lua_State {
+        state: ExeState::new(),
+        stack: Vec::new(),
+        strings: HashMap::new(),
+    }))
+}
+
+/// Like `luaL_newstate`: memory is not allocated through `f`.
+#[no_mangle]
+pub extern "C" fn lua_newstate(_f: lua_Alloc, _ud: *mut c_void) -> *mut lua_State {
+    luaL_newstate()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_close(L: *mut lua_State) {
+    drop(Box::from_raw(L));
+}
+
+/// The standard libraries are open from the start.
+#[no_mangle]
+pub extern "C" fn luaL_openlibs(_L: *mut lua_State) {}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_gettop(L: *mut lua_State) -> c_int {
+    (*L).stack.len() as c_int
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_settop(L: *mut lua_State, idx: c_int) {
+    let L = &mut *L;
+    let top = if idx >= 0 {
+        idx as usize
+    } else {
+        L.stack
+            .len()
+            .saturating_sub(idx.unsigned_abs() as usize - 1)
+    };
+    if top < L.stack.len() {
+        L.truncate(top);
+    } else {
+        L.stack.resize(top, Slot::Value(Value::Nil));
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_absindex(L: *mut lua_State, idx: c_int) -> c_int {
+    if idx > 0 {
+        idx
+    } else {
+        (*L).stack.len() as c_int + idx + 1
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushvalue(L: *mut lua_State, idx: c_int) {
+    let L = &mut *L;
+    let slot = match L.position(idx) {
+        Some(i) => L.stack[i].clone(),
+        None => Slot::Value(Value::Nil),
+    };
+    L.stack.push(slot);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushnil(L: *mut lua_State) {
+    (*L).push(Value::Nil);
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushboolean(L: *mut lua_State, b: c_int) {
+    (*L).push((b != 0).into());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushinteger(L: *mut lua_State, n: lua_Integer) {
+    (*L).push(n.into());
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushnumber(L: *mut lua_State, n: lua_Number) {
+    (*L).push(n.into());
+}
+
+/// Push a copy of `len` bytes at `s`, returning a pointer to it.
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushlstring(
+    L: *mut lua_State,
+    s: *const c_char,
+    len: usize,
+) -> *const c_char {
+    let bytes = if len == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(s.cast(), len)
+    };
+    (*L).push(bytes.into());
+    lua_tolstring(L, -1, ptr::null_mut())
+}
+
+/// Push a copy of NUL-terminated `s`, or nil if `s` is null.
+#[no_mangle]
+pub unsafe extern "C" fn lua_pushstring(L: *mut lua_State, s: *const c_char) -> *const c_char {
+    if s.is_null() {
+        lua_pushnil(L);
+        return ptr::null();
+    }
+    lua_pushlstring(L, s, bytes(s).len())
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_type(L: *mut lua_State, idx: c_int) -> c_int {
+    let L = &*L;
+    let Some(i) = L.position(idx) else {
+        return LUA_TNONE;
+    };
+    match &L.stack[i] {
+        Slot::Chunk(_) => LUA_TFUNCTION,
+        Slot::Value(v) => match v {
+            Value::Nil => LUA_TNIL,
+            Value::Boolean(_) => LUA_TBOOLEAN,
+            Value::Integer(_) | Value::Float(_) => LUA_TNUMBER,
+            Value::Table(_) => LUA_TTABLE,
+            Value::UserData(_) => LUA_TUSERDATA,
+            Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => LUA_TFUNCTION,
+            _ => LUA_TSTRING,
+        },
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn lua_typename(_L: *mut lua_State, tp: c_int) -> *const c_char {
+    let name: &'static CStr = match tp {
+        LUA_TNIL => c"nil",
+        LUA_TBOOLEAN => c"boolean",
+        LUA_TNUMBER => c"number",
+        LUA_TSTRING => c"string",
+        LUA_TTABLE => c"table",
+        LUA_TFUNCTION => c"function",
+        LUA_TUSERDATA => c"userdata",
+        _ => c"no value",
+    };
+    name.as_ptr()
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn lua_toboolean(L: *mut lua_State, idx: c_int) -> c_int {
+    let L = &*L;
+    match L.position(idx).map(|i| &L.stack[i]) {
+        None | Some(Slot::Value(Value::Nil | Value::Boolean(false))) => 0,
+        Some(_) => 1,
+    }
+}
+
+/// The number at `idx`, or a string that converts to one, as an integer
+/// if it has an exact integer value. `isnum`, if not null, tells whether
+/// it did.
+#[no_mangle]
+pub unsafe extern "C" fn lua_tointegerx(
+    L: *mut lua_State,
+    idx: c_int,
+    isnum: *mut c_int,
+) -> lua_Integer {
+    let i = match (*L).value(idx).and_then(to_number) {
+        Some(Value::Integer(i)) => Some(i),
+        Some(Value::Float(f)) if f.fract() == 0.0 && (-INT_RANGE..INT_RANGE).contains(&f) => {
+            Some(f as lua_Integer)
+        }
+        _ => None,
+    };
+    if !isnum.is_null() {
+        *isnum = i.is_some() as c_int;
+    }
+    i.unwrap_or(0)
+}
+
+/// The number at `idx`, or a string that converts to one. `isnum`, if not
+/// null, tells whether it did.
+#[no_mangle]
+pub unsafe extern "C" fn lua_tonumberx(
+    L: *mut lua_State,
+    idx: c_int,
+    isnum: *mut c_int,
+) -> lua_Number {
+    let n = match (*L).value(idx).and_then(to_number) {
+        Some(Value::Integer(i)) => Some(i as lua_Number),
+        Some(Value::Float(f)) => Some(f),
+        _ => None,
+    };
+    if !isnum.is_null() {
+        *isnum = n.is_some() as c_int;
+    }
+    n.unwrap_or(0.0)
+}
+
+fn to_number(v: &Value) -> Option<Value> {
+    match v {
+        Value::Integer(_) | Value::Float(_) => Some(v.clone()),
+        v => numfmt::str2number(<&[u8]>::try_from(v).ok()?),
+    }
+}
+
+/// The string at `idx`, NUL-terminated, its length in `len` if not null.
+/// A number is converted to a string in place, as in the reference. Null
+/// for other values.
+#[no_mangle]
+pub unsafe extern "C" fn lua_tolstring(
+    L: *mut lua_State,
+    idx: c_int,
+    len: *mut usize,
+) -> *const c_char {
+    let L = &mut *L;
+    let Some(i) = L.position(idx) else {
+        return ptr::null();
+    };
+    let s = match &L.stack[i] {
+        Slot::Value(v @ (Value::Integer(_) | Value::Float(_))) => {
+            let s = Value::from(v.to_string());
+            L.stack[i] = Slot::Value(s.clone());
+            s
+        }
+        Slot::Value(v) if <&[u8]>::try_from(v).is_ok() => v.clone(),
+        _ => return ptr::null(),
+    };
+    let mut copy = <&[u8]>::try_from(&s).unwrap().to_vec();
+    if !len.is_null() {
+        *len = copy.len();
+    }
+    copy.push(0);
+    L.strings.insert(i, copy);
+    L.strings[&i].as_ptr().cast()
+}
+
+/// Push the global `name`, returning its type.
+#[no_mangle]
+pub unsafe extern "C" fn lua_getglobal(L: *mut lua_State, name: *const c_char) -> c_int {
+    let v = (*L).state.get_global(str_arg(name));
+    (*L).push(v);
+    lua_type(L, -1)
+}
+
+/// Pop a value and set the global `name` to it.
+#[no_mangle]
+pub unsafe extern "C" fn lua_setglobal(L: *mut lua_State, name: *const c_char) {
+    let L = &mut *L;
+    let v = match L.stack.pop() {
+        Some(Slot::Value(v)) => v,
+        _ => Value::Nil,
+    };
+    L.truncate(L.stack.len());
+    L.state.set_global(str_arg(name), v);
+}
+
+/// Compile `s` and push it as a function, or push the error message.
+#[no_mangle]
+pub unsafe extern "C" fn luaL_loadstring(L: *mut lua_State, s: *const c_char) -> c_int {
+    let L = &mut *L;
+    let source = String::from_utf8_lossy(bytes(s)).into_owned();
+    let options = ParseOptions {
+        chunk_name: string_chunk_name(&source),
+        ..Default::default()
+    };
+    match L.state.load(Cursor::new(source.into_bytes()), options) {
+        Ok(proto) => {
+            L.stack.push(Slot::Chunk(Rc::new(proto)));
+            LUA_OK
+        }
+        Err(err) => {
+            L.push(format!("{err:#}").into());
+            LUA_ERRSYNTAX
+        }
+    }
+}
+
+/// Call the function below the top `nargs` values in protected mode. The
+/// message handler and the continuation are not supported.
+#[no_mangle]
+pub unsafe extern "C" fn lua_pcallk(
+    L: *mut lua_State,
+    nargs: c_int,
+    nresults: c_int,
+    _msgh: c_int,
+    _ctx: lua_KContext,
+    _k: lua_KFunction,
+) -> c_int {
+    (*L).call(nargs.max(0) as usize, nresults)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    unsafe fn string_at(L: *mut lua_State, idx: c_int) -> String {
+        let mut len = 0;
+        let s = lua_tolstring(L, idx, &mut len);
+        assert!(!s.is_null());
+        String::from_utf8(std::slice::from_raw_parts(s.cast(), len).to_vec()).unwrap()
+    }
+
+    #[test]
+    fn dostring() {
+        unsafe {
+            let L = luaL_newstate();
+            luaL_openlibs(L);
+            lua_pushinteger(L, 41);
+            lua_setglobal(L, c"n".as_ptr());
+            assert_eq!(lua_gettop(L), 0);
+
+            // luaL_dostring(L, s) and lua_pcall(L, 0, LUA_MULTRET, 0)
+            let s = c"return n, string.rep('ab', 2), nil";
+            let status = match luaL_loadstring(L, s.as_ptr()) {
+                LUA_OK => lua_pcallk(L, 0, LUA_MULTRET, 0, 0, None),
+                status => status,
+            };
+            assert_eq!(status, LUA_OK);
+            assert_eq!(lua_gettop(L), 3);
+            assert_eq!(lua_tointegerx(L, 1, ptr::null_mut()), 41);
+            assert_eq!(string_at(L, -2), "abab");
+            assert_eq!(lua_type(L, -1), LUA_TNIL);
+            // converted in place
+            assert_eq!(string_at(L, 1), "41");
+            assert_eq!(lua_type(L, 1), LUA_TSTRING);
+            lua_settop(L, -3);
+            assert_eq!(lua_gettop(L), 1);
+
+            assert_eq!(luaL_loadstring(L, c"x = ".as_ptr()), LUA_ERRSYNTAX);
+            assert_eq!(luaL_loadstring(L, c"error('boom')".as_ptr()), LUA_OK);
+            assert_eq!(lua_type(L, -1), LUA_TFUNCTION);
+            assert_eq!(lua_pcallk(L, 0, 0, 0, 0, None), LUA_ERRRUN);
+            assert_eq!(string_at(L, -1), "[string \"error('boom')\"]:1: boom");
+            lua_close(L);
+        }
+    }
+
+    #[test]
+    fn call_global() {
+        unsafe {
+            let L = luaL_newstate();
+            assert_eq!(lua_getglobal(L, c"string".as_ptr()), LUA_TTABLE);
+            assert_eq!(lua_getglobal(L, c"tonumber".as_ptr()), LUA_TFUNCTION);
+            lua_pushstring(L, c"0x10".as_ptr());
+            assert_eq!(lua_pcallk(L, 1, 2, 0, 0, None), LUA_OK);
+            assert_eq!(lua_gettop(L), 3);
+            let mut isnum = 0;
+            assert_eq!(lua_tonumberx(L, 2, &mut isnum), 16.0);
+            assert_eq!(isnum, 1);
+            assert_eq!(lua_type(L, 3), LUA_TNIL);
+            lua_tointegerx(L, 1, &mut isnum);
+            assert_eq!(isnum, 0);
+            assert_eq!(lua_type(L, 4), LUA_TNONE);
+            assert_eq!(CStr::from_ptr(lua_typename(L, lua_type(L, 1))), c"table");
+            lua_close(L);
+        }
+    }
+}
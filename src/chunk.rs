@@ -0,0 +1,246 @@
+//! Binary chunks: compiled protos serialized with bincode, so that embedders
+//! can skip parsing scripts whose source has not changed.
+
+use std::{
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    parse::ParseProto,
+    value::{LuaFloat, LuaInt},
+};
+
+// header fields, in order, as in the reference implementation
+const SIGNATURE: &[u8] = b"\x1bKlu";
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const FORMAT: u8 = 6;
+const LITTLE_ENDIAN: u8 = 0;
+const BIG_ENDIAN: u8 = 1;
+// written in native byte order, to catch mismatches the sizes do not
+const CHECK_INTEGER: LuaInt = 0x5678;
+const CHECK_FLOAT: LuaFloat = 370.5;
+
+pub fn dump(proto: &ParseProto) -> anyhow::Result<Vec<u8>> {
+    let mut data = header();
+    bincode::serialize_into(&mut data, proto)?;
+    Ok(data)
+}
+
+pub fn undump(data: &[u8]) -> anyhow::Result<ParseProto> {
+    let body = check_header(data)?;
+    bincode::deserialize(body).context("invalid binary chunk")
+}
+
+fn header() -> Vec<u8> {
+    let mut h = SIGNATURE.to_vec();
+    h.push(VERSION.len() as u8);
+    h.extend_from_slice(VERSION.as_bytes());
+    h.push(FORMAT);
+    h.push(if cfg!(target_endian = "big") {
+        BIG_ENDIAN
+    } else {
+        LITTLE_ENDIAN
+    });
+    h.push(size_of::<LuaInt>() as u8);
+    h.push(size_of::<LuaFloat>() as u8);
+    h.extend_from_slice(&CHECK_INTEGER.to_ne_bytes());
+    h.extend_from_slice(&CHECK_FLOAT.to_ne_bytes());
+    h
+}
+
+/// Check that `data` starts with a header written by this build, and
+/// return what follows it.
+fn check_header(data: &[u8]) -> anyhow::Result<&[u8]> {
+    let Some(mut rest) = data.strip_prefix(SIGNATURE) else {
+        bail!("not a binary chunk");
+    };
+    let mut take = |n: usize| {
+        if rest.len() < n {
+            bail!("truncated binary chunk");
+        }
+        let (field, tail) = rest.split_at(n);
+        rest = tail;
+        Ok(field)
+    };
+
+    let len = take(1)?[0] as usize;
+    let version = take(len)?;
+    if version != VERSION.as_bytes() {
+        bail!(
+            "version mismatch: binary chunk from kailua {}, this is {VERSION}",
+            String::from_utf8_lossy(version)
+        );
+    }
+    if take(1)?[0] != FORMAT {
+        bail!("format mismatch in binary chunk");
+    }
+    // the rest of the header describes the machine, compare it as written
+    let header = header();
+    let mut expected = &header[SIGNATURE.len() + 1 + VERSION.len() + 1..];
+    let fields = [
+        (1, "endianness"),
+        (1, "integer size"),
+        (1, "float size"),
+        (size_of::<LuaInt>(), "integer format"),
+        (size_of::<LuaFloat>(), "float format"),
+    ];
+    for (n, what) in fields {
+        let (field, tail) = expected.split_at(n);
+        expected = tail;
+        if take(n)? != field {
+            bail!("{what} mismatch in binary chunk");
+        }
+    }
+    Ok(rest)
+}
+
+/// Load the script at `path`, reusing the binary chunk cached in `cache_dir`
+/// if one was compiled from identical source.
+pub fn load_cached(path: &Path, cache_dir: &Path) -> anyhow::Result<ParseProto> {
+    let source = fs::read(path)?;
+    let cache_path = cache_path(&source, cache_dir);
+
+    if let Ok(data) = fs::read(&cache_path) {
+        if let Ok(proto) = undump(&data) {
+            return Ok(proto);
+        }
+    }
+
+    let proto = ParseProto::load(Cursor::new(source))?;
+    fs::create_dir_all(cache_dir)?;
+    fs::write(&cache_path, dump(&proto)?)?;
+    Ok(proto)
+}
+
+fn cache_path(source: &[u8], cache_dir: &Path) -> PathBuf {
+    // FNV-1a, stable across builds unlike std's DefaultHasher
+    let hash = source.iter().fold(0xcbf29ce484222325_u64, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    cache_dir.join(format!("{hash:016x}.kbc"))
+}
+
+/// Serde adapter for proto constants. Binary formats like bincode are not
+/// self-describing, so constants are written as an explicitly tagged enum
+/// instead of through `Value`'s own serde impl.
+pub(crate) mod constants {
+    use std::rc::Rc;
+
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::value::{LuaFloat, LuaInt, Value};
+
+    #[derive(Serialize, Deserialize)]
+    enum Constant {
+        Nil,
+        Boolean(bool),
+        Integer(LuaInt),
+        Float(LuaFloat),
+        String(Vec<u8>),
+    }
+
+    pub fn serialize<S: Serializer>(constants: &[Value], serializer: S) -> Result<S::Ok, S::Error> {
+        let constants = constants
+            .iter()
+            .map(|v| match v {
+                Value::Nil => Ok(Constant::Nil),
+                Value::Boolean(b) => Ok(Constant::Boolean(*b)),
+                Value::Integer(i) => Ok(Constant::Integer(*i)),
+                Value::Float(f) => Ok(Constant::Float(*f)),
+                v => <&[u8]>::try_from(v)
+                    .map(|s| Constant::String(s.to_vec()))
+                    .map_err(serde::ser::Error::custom),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        constants.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Rc<[Value]>, D::Error> {
+        let constants = Vec::<Constant>::deserialize(deserializer)?;
+        Ok(constants
+            .into_iter()
+            .map(|c| match c {
+                Constant::Nil => Value::Nil,
+                Constant::Boolean(b) => b.into(),
+                Constant::Integer(i) => i.into(),
+                Constant::Float(f) => f.into(),
+                Constant::String(s) => s.into(),
+            })
+            .collect())
+    }
+}
+
+/// Serde adapter for byte codes, written as packed 32-bit words.
+pub(crate) mod byte_codes {
+    use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+    use crate::bytecode::ByteCode;
+
+    pub fn serialize<S: Serializer>(codes: &[ByteCode], serializer: S) -> Result<S::Ok, S::Error> {
+        let words: Vec<_> = codes.iter().map(ByteCode::encode).collect();
+        words.serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<ByteCode>, D::Error> {
+        Vec::<u32>::deserialize(deserializer)?
+            .into_iter()
+            .map(|w| ByteCode::decode(w).map_err(de::Error::custom))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn dump_undump() {
+        let proto = ParseProto::load(&b"local a = 1.5 g = \"hello\" print(a)"[..]).unwrap();
+        let proto2 = undump(&dump(&proto).unwrap()).unwrap();
+        assert_eq!(proto2.constants, proto.constants);
+        assert_eq!(
+            format!("{:?}", proto2.byte_codes),
+            format!("{:?}", proto.byte_codes)
+        );
+    }
+
+    #[test]
+    fn header_checks() {
+        let proto = ParseProto::load(&b"print(1)"[..]).unwrap();
+        let data = dump(&proto).unwrap();
+        let error = |data: &[u8]| undump(data).unwrap_err().to_string();
+
+        assert_eq!(error(b"print(1)"), "not a binary chunk");
+        assert_eq!(error(&data[..8]), "truncated binary chunk");
+
+        let old = [
+            SIGNATURE,
+            b"\x050.0.1",
+            &data[SIGNATURE.len() + 1 + VERSION.len()..],
+        ]
+        .concat();
+        assert_eq!(
+            error(&old),
+            format!("version mismatch: binary chunk from kailua 0.0.1, this is {VERSION}")
+        );
+
+        let at = SIGNATURE.len() + 1 + VERSION.len() + 1;
+        let mut swapped = data.clone();
+        swapped[at] ^= 1;
+        assert_eq!(error(&swapped), "endianness mismatch in binary chunk");
+        let mut narrow = data.clone();
+        narrow[at + 1] ^= 12;
+        assert_eq!(error(&narrow), "integer size mismatch in binary chunk");
+        let mut float = data;
+        float[at + 3 + size_of::<LuaInt>()] ^= 0xff;
+        assert_eq!(error(&float), "float format mismatch in binary chunk");
+    }
+}
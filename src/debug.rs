@@ -0,0 +1,177 @@
+//! The `debug` library.
+
+use anyhow::bail;
+
+use crate::{
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+
+/// Build the `debug` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map
+        .insert("traceback".into(), Value::Function(lib_traceback));
+    t.map
+        .insert("getlocal".into(), Value::Function(lib_getlocal));
+    t.map
+        .insert("setlocal".into(), Value::Function(lib_setlocal));
+    t.into()
+}
+
+// debug.traceback([message [, level]])
+fn lib_traceback(state: &mut ExeState) -> anyhow::Result<i32> {
+    let msg = state.arg(1).clone();
+    // a message that is not a string is left alone, as it may be an error
+    // object for the caller of a message handler
+    let msg = match &msg {
+        Value::Nil => String::new(),
+        _ => match String::try_from(&msg) {
+            Ok(s) => s + "\n",
+            Err(_) => {
+                state.push(msg);
+                return Ok(1);
+            }
+        },
+    };
+    let level = match state.arg(2) {
+        &Value::Integer(level) => level.max(0) as usize,
+        _ => 1,
+    };
+    let traceback = state.traceback(level);
+    state.push((msg + &traceback).into());
+    Ok(1)
+}
+
+// debug.getlocal(level, local), or debug.getlocal(f, local) for the names
+// of the parameters of f, which native functions do not have
+fn lib_getlocal(state: &mut ExeState) -> anyhow::Result<i32> {
+    if let Value::LuaClosure(f) = state.arg(1).clone() {
+        let n = check_int(state, 2, "getlocal")?;
+        // the parameters are the first locals
+        let name = match usize::try_from(n) {
+            Ok(n) if (1..=f.proto.nparams as usize).contains(&n) => {
+                f.proto.locvars[n - 1].name.as_str().into()
+            }
+            _ => Value::Nil,
+        };
+        state.push(name);
+        return Ok(1);
+    }
+    if matches!(state.arg(1), Value::Function(_) | Value::NativeClosure(_)) {
+        state.push(Value::Nil);
+        return Ok(1);
+    }
+    let level = check_int(state, 1, "getlocal")?;
+    let n = check_int(state, 2, "getlocal")?;
+    let local = match usize::try_from(level) {
+        Ok(level) => state.local(level, n.max(0) as usize),
+        Err(_) => bail!("level out of range"),
+    };
+    match local {
+        Ok(Some((name, v))) => {
+            state.push(name.as_str().into());
+            state.push(v);
+            Ok(2)
+        }
+        Ok(None) => {
+            state.push(Value::Nil);
+            Ok(1)
+        }
+        Err(_) => bail!("bad argument #1 to 'getlocal' (level out of range)"),
+    }
+}
+
+// debug.setlocal(level, local, value), returning the name of the local
+fn lib_setlocal(state: &mut ExeState) -> anyhow::Result<i32> {
+    let level = check_int(state, 1, "setlocal")?;
+    let n = check_int(state, 2, "setlocal")?;
+    if state.get_top() < 3 {
+        bail!("bad argument #3 to 'setlocal' (value expected)");
+    }
+    let v = state.arg(3).clone();
+    let name = match usize::try_from(level) {
+        Ok(level) => state.set_local(level, n.max(0) as usize, v),
+        Err(_) => bail!("level out of range"),
+    };
+    match name {
+        Ok(name) => state.push(name.map_or(Value::Nil, |name| name.as_str().into())),
+        Err(_) => bail!("bad argument #1 to 'setlocal' (level out of range)"),
+    }
+    Ok(1)
+}
+
+fn check_int(state: &ExeState, i: usize, fname: &str) -> anyhow::Result<LuaInt> {
+    match *state.arg(i) {
+        Value::Integer(n) => Ok(n),
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < LuaInt::MAX as LuaFloat => Ok(f as LuaInt),
+        Value::Float(_) => {
+            bail!("bad argument #{i} to '{fname}' (number has no integer representation)")
+        }
+        _ => bail!(
+            "bad argument #{i} to '{fname}' (number expected, got {})",
+            state.arg_type_name(i)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locals() {
+        let mut state = ExeState::new();
+        let results = state
+            .eval("local a = 1 local b = 'x' return debug.getlocal(1, 2)")
+            .unwrap();
+        assert_eq!(results, ["b".into(), "x".into()]);
+        let results = state
+            .eval("local a = 1 local b = debug.setlocal(1, 1, 5) return a, b, debug.getlocal(1, 3)")
+            .unwrap();
+        assert_eq!(results, [5.into(), "a".into(), Value::Nil]);
+        // the hidden locals of the loop come before its variable
+        let results = state
+            .eval("local a = 1 for i = 1, 2 do n = debug.getlocal(1, 5) end return n")
+            .unwrap();
+        assert_eq!(results, ["i".into()]);
+        // out of scope once the loop is over
+        let results = state
+            .eval("for i = 1, 2 do end return debug.getlocal(1, 1)")
+            .unwrap();
+        assert_eq!(results, [Value::Nil]);
+        // natives have no locals
+        assert_eq!(
+            state.eval("return debug.getlocal(0, 1)").unwrap(),
+            [Value::Nil]
+        );
+        assert_eq!(
+            state.eval("debug.getlocal(2, 1)").unwrap_err().to_string(),
+            "[string \"debug.getlocal(2, 1)\"]:1: bad argument #1 to 'getlocal' (level out of range)"
+        );
+        assert_eq!(
+            state.eval("debug.setlocal(1, 1)").unwrap_err().to_string(),
+            "[string \"debug.setlocal(1, 1)\"]:1: bad argument #3 to 'setlocal' (value expected)"
+        );
+    }
+
+    #[test]
+    fn locals_from_rust() {
+        let mut state = ExeState::new();
+        state.set_global(
+            "double",
+            Value::Function(|state| {
+                let Some((name, Value::Integer(n))) = state.local(1, 1)? else {
+                    bail!("no integer local");
+                };
+                state.set_local(1, 1, (n * 2).into())?;
+                state.push(name.as_str().into());
+                Ok(1)
+            }),
+        );
+        let results = state
+            .eval("local x = 21 local name = double() return x, name")
+            .unwrap();
+        assert_eq!(results, [42.into(), "x".into()]);
+    }
+}
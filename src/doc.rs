@@ -0,0 +1,268 @@
+//! API documentation from `---` comments in Lua sources, for `kailua doc`.
+//!
+//! A block of `---` lines documents the function declared right after it:
+//!
+//! ```lua
+//! --- Add two numbers.
+//! --- @param a number the first
+//! --- @return number
+//! function add(a, b) end
+//! ```
+//!
+//! A block at the top of the file that no declaration follows documents
+//! the module itself.
+
+use std::fmt::Write;
+
+use crate::lex::{tokenize, LosslessToken, Token, TriviaKind};
+
+/// The documentation of a source file.
+#[derive(Debug, Default, PartialEq)]
+pub struct ModuleDoc {
+    pub description: Vec<String>,
+    pub functions: Vec<FunctionDoc>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct FunctionDoc {
+    /// As declared, with the table it is in: `add`, `m.add` or `m:add`.
+    pub name: String,
+    pub params: Vec<String>,
+    pub description: Vec<String>,
+    pub param_docs: Vec<Tagged>,
+    pub returns: Vec<Tagged>,
+}
+
+/// A `@param` or `@return` tag: `@param name [type] text`, or
+/// `@return [type] text` with no name.
+#[derive(Debug, Default, PartialEq)]
+pub struct Tagged {
+    pub name: Option<String>,
+    pub ty: Option<String>,
+    pub text: String,
+}
+
+/// Extract the documentation of `source`.
+pub fn extract(source: &str) -> anyhow::Result<ModuleDoc> {
+    let tokens = tokenize(source.as_bytes())?;
+    let mut doc = ModuleDoc::default();
+    let mut block: Vec<&str> = Vec::new();
+    let mut seen_code = false;
+    for (i, t) in tokens.iter().enumerate() {
+        for trivia in &t.trivia {
+            let text = std::str::from_utf8(trivia.text).unwrap_or_default();
+            match trivia.kind {
+                TriviaKind::Comment if text.starts_with("---") => {
+                    let text = &text[3..];
+                    block.push(text.strip_prefix(' ').unwrap_or(text));
+                }
+                // a blank line ends a block
+                TriviaKind::Whitespace if text.matches('\n').count() < 2 => (),
+                _ => end_block(&mut doc, &mut block, seen_code),
+            }
+        }
+        if t.token == Token::Eos {
+            break;
+        }
+        if !block.is_empty() {
+            if let Some((name, params)) = declaration(&tokens[i..]) {
+                doc.functions.push(function(name, params, &block));
+            }
+            end_block(&mut doc, &mut block, seen_code);
+        }
+        seen_code = true;
+    }
+    Ok(doc)
+}
+
+/// Finish a block of `---` lines. One that documents no function is the
+/// module's, if it comes before any code.
+fn end_block(doc: &mut ModuleDoc, block: &mut Vec<&str>, seen_code: bool) {
+    if !block.is_empty() && !seen_code && doc.description.is_empty() {
+        doc.description = block.iter().map(|s| s.to_string()).collect();
+    }
+    block.clear();
+}
+
+/// The name and parameters of the function declared at the start of
+/// `tokens`, if any: `[local] function name(params)`, or
+/// `name = function(params)`.
+fn declaration(tokens: &[LosslessToken]) -> Option<(String, Vec<String>)> {
+    let mut tokens = tokens.iter().map(|t| &t.token).peekable();
+    if tokens.peek() == Some(&&Token::Local) {
+        tokens.next();
+    }
+    let keyword_first = tokens.peek() == Some(&&Token::Function);
+    if keyword_first {
+        tokens.next();
+    }
+    // name, with the tables it is in
+    let mut name = String::new();
+    loop {
+        let Some(Token::Name(part)) = tokens.next() else {
+            return None;
+        };
+        name += part;
+        match tokens.peek() {
+            Some(Token::Dot) => name.push('.'),
+            Some(Token::Colon) if keyword_first => name.push(':'),
+            _ => break,
+        }
+        tokens.next();
+    }
+    if !keyword_first && (tokens.next()? != &Token::Assign || tokens.next()? != &Token::Function) {
+        return None;
+    }
+    if tokens.next()? != &Token::ParL {
+        return None;
+    }
+    let mut params = Vec::new();
+    loop {
+        match tokens.next()? {
+            Token::Name(p) => params.push(p.clone()),
+            Token::Dots => params.push("...".into()),
+            Token::ParR if params.is_empty() => break,
+            _ => return None,
+        }
+        match tokens.next()? {
+            Token::Comma => (),
+            Token::ParR => break,
+            _ => return None,
+        }
+    }
+    Some((name, params))
+}
+
+fn function(name: String, params: Vec<String>, block: &[&str]) -> FunctionDoc {
+    let mut doc = FunctionDoc {
+        name,
+        params,
+        ..Default::default()
+    };
+    for line in block {
+        if let Some(rest) = line.strip_prefix("@param") {
+            let mut words = rest.split_whitespace();
+            let name = words.next().map(String::from);
+            let (ty, text) = type_and_text(words.collect::<Vec<_>>().join(" "));
+            doc.param_docs.push(Tagged { name, ty, text });
+        } else if let Some(rest) = line.strip_prefix("@return") {
+            let (ty, text) = type_and_text(rest.trim().to_string());
+            doc.returns.push(Tagged {
+                name: None,
+                ty,
+                text,
+            });
+        } else {
+            doc.description.push(line.to_string());
+        }
+    }
+    doc
+}
+
+/// Split a leading type, one of Lua's type names or a `|` union of them,
+/// from the text of a tag.
+fn type_and_text(s: String) -> (Option<String>, String) {
+    const TYPES: &[&str] = &[
+        "nil", "boolean", "number", "integer", "string", "table", "function", "any",
+    ];
+    let (first, rest) = s.split_once(' ').unwrap_or((&s, ""));
+    let is_type = |t: &str| {
+        let t = t.strip_suffix('?').unwrap_or(t);
+        TYPES.contains(&t) || t.ends_with("[]")
+    };
+    if !first.is_empty() && first.split('|').all(is_type) {
+        (Some(first.to_string()), rest.trim().to_string())
+    } else {
+        (None, s)
+    }
+}
+
+impl ModuleDoc {
+    /// Markdown, under a `title` heading.
+    pub fn to_markdown(&self, title: &str) -> String {
+        let mut out = String::new();
+        writeln!(out, "# {title}\n").unwrap();
+        if !self.description.is_empty() {
+            writeln!(out, "{}\n", self.description.join("\n")).unwrap();
+        }
+        for f in &self.functions {
+            writeln!(out, "## `{}({})`\n", f.name, f.params.join(", ")).unwrap();
+            if !f.description.is_empty() {
+                writeln!(out, "{}\n", f.description.join("\n")).unwrap();
+            }
+            if !f.param_docs.is_empty() {
+                writeln!(out, "Parameters:\n").unwrap();
+                for p in &f.param_docs {
+                    writeln!(out, "{}", tagged(p)).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+            if !f.returns.is_empty() {
+                writeln!(out, "Returns:\n").unwrap();
+                for r in &f.returns {
+                    writeln!(out, "{}", tagged(r)).unwrap();
+                }
+                writeln!(out).unwrap();
+            }
+        }
+        out
+    }
+}
+
+fn tagged(t: &Tagged) -> String {
+    let mut line = String::from("-");
+    if let Some(name) = &t.name {
+        line += &format!(" `{name}`");
+    }
+    if let Some(ty) = &t.ty {
+        line += &format!(" *{ty}*");
+    }
+    if !t.text.is_empty() {
+        if t.name.is_some() || t.ty.is_some() {
+            line += ":";
+        }
+        line += &format!(" {}", t.text);
+    }
+    line
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extract_docs() {
+        let src = "#!/usr/bin/env kailua\n\
+            --- Numbers.\n\
+            \n\
+            --- Add two numbers.\n\
+            --- @param a number the first\n\
+            --- @param b\n\
+            --- @return number|nil\n\
+            local function add(a, b) end\n\
+            -- not a doc comment\n\
+            function m.f() end\n\
+            --- Methods too.\n\
+            m.g = function(self, ...) end\n";
+        let doc = extract(src).unwrap();
+        assert_eq!(doc.description, ["Numbers."]);
+        assert_eq!(doc.functions.len(), 2);
+        let add = &doc.functions[0];
+        assert_eq!(add.name, "add");
+        assert_eq!(add.params, ["a", "b"]);
+        assert_eq!(add.description, ["Add two numbers."]);
+        assert_eq!(add.param_docs[0].ty.as_deref(), Some("number"));
+        assert_eq!(add.param_docs[0].text, "the first");
+        assert_eq!(add.returns[0].ty.as_deref(), Some("number|nil"));
+        assert_eq!(doc.functions[1].name, "m.g");
+
+        assert_eq!(
+            doc.to_markdown("lib"),
+            "# lib\n\nNumbers.\n\n\
+             ## `add(a, b)`\n\nAdd two numbers.\n\n\
+             Parameters:\n\n- `a` *number*: the first\n- `b`\n\n\
+             Returns:\n\n- *number|nil*\n\n\
+             ## `m.g(self, ...)`\n\nMethods too.\n\n"
+        );
+    }
+}
@@ -0,0 +1,158 @@
+//! Hooks: a Rust function the VM calls on calls, returns, new lines and
+//! counts of instructions, for debuggers, profilers, coverage and time
+//! limits to be built on. See [`ExeState::set_hook`].
+
+use crate::vm::ExeState;
+
+/// What a hook is called for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookEvent {
+    /// A chunk or a function is called, before it runs.
+    Call,
+    /// A chunk or a function returns normally, after it has run.
+    Return,
+    /// A chunk is about to run an instruction of a new line, or to run
+    /// the same line again after jumping back in a loop.
+    Line(u32),
+    /// A chunk has run [`HookMask::count`] more instructions.
+    Count,
+}
+
+/// The events to call a hook for.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct HookMask {
+    pub call: bool,
+    pub ret: bool,
+    pub line: bool,
+    /// Call the hook every `count` instructions; never if 0.
+    pub count: u32,
+}
+
+pub(crate) type Hook = Box<dyn FnMut(&mut ExeState, HookEvent) -> anyhow::Result<()>>;
+
+/// The hook of a state, taken out while it runs so that it does not
+/// trigger itself.
+#[derive(Default)]
+pub(crate) struct Hooks {
+    pub(crate) hook: Option<Hook>,
+    pub(crate) mask: HookMask,
+    // instructions left before the next count event
+    pub(crate) countdown: u32,
+}
+
+impl std::fmt::Debug for Hooks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("mask", &self.mask)
+            .field("countdown", &self.countdown)
+            .finish_non_exhaustive()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use anyhow::bail;
+
+    use super::*;
+    use crate::value::Value;
+
+    fn record(state: &mut ExeState, mask: HookMask) -> Rc<RefCell<Vec<HookEvent>>> {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        state.set_hook(mask, move |_, event| {
+            seen.borrow_mut().push(event);
+            Ok(())
+        });
+        events
+    }
+
+    #[test]
+    fn events() {
+        let mut state = ExeState::new();
+        let mask = HookMask {
+            call: true,
+            ret: true,
+            line: true,
+            count: 0,
+        };
+        let events = record(&mut state, mask);
+        state
+            .eval("local a = 1\nlocal b = type(a)\nfor i = 1, 2 do\nx = i\nend")
+            .unwrap();
+        use HookEvent::*;
+        let expected = [
+            Call,
+            Line(1),
+            Line(2),
+            Call,
+            Return,
+            Line(3),
+            Line(4),
+            Line(3),
+            Line(4),
+            Line(3),
+            Return,
+        ];
+        assert_eq!(*events.borrow(), expected);
+
+        state.remove_hook();
+        state.eval("x = 1").unwrap();
+        assert_eq!(events.borrow().len(), expected.len());
+    }
+
+    #[test]
+    fn count() {
+        let mut state = ExeState::builder().stats(true).build();
+        let mask = HookMask {
+            count: 1,
+            ..Default::default()
+        };
+        let events = record(&mut state, mask);
+        state.eval("for i = 1, 10 do x = i end").unwrap();
+        let total = state.stats().unwrap().total_instructions();
+        assert_eq!(events.borrow().len() as u64, total);
+    }
+
+    #[test]
+    fn time_limit() {
+        let mut state = ExeState::new();
+        let mask = HookMask {
+            count: 1000,
+            ..Default::default()
+        };
+        let mut budget = 10;
+        state.set_hook(mask, move |_, _| {
+            budget -= 1;
+            if budget == 0 {
+                bail!("too many instructions");
+            }
+            Ok(())
+        });
+        let err = state.eval("::top:: goto top").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"::top:: goto top\"]:1: too many instructions"
+        );
+    }
+
+    #[test]
+    fn locals_in_hook() {
+        let mut state = ExeState::new();
+        let values = Rc::new(RefCell::new(Vec::new()));
+        let seen = values.clone();
+        let mask = HookMask {
+            line: true,
+            ..Default::default()
+        };
+        state.set_hook(mask, move |state, _| {
+            if let Some((_, v)) = state.local(0, 1)? {
+                seen.borrow_mut().push(v);
+            }
+            Ok(())
+        });
+        state.eval("local a = 1\nx = a\nx = a").unwrap();
+        assert_eq!(*values.borrow(), [Value::Integer(1), Value::Integer(1)]);
+    }
+}
@@ -0,0 +1,304 @@
+//! Snapshots of the global state, for hosts that checkpoint scripts between
+//! sessions. Tables are stored once each, so sharing and cycles survive a
+//! round trip, and functions are stored by the names they are reachable
+//! under, globals (`print`) or fields of global tables (`string.len`), to
+//! be looked up again when the image is restored.
+
+use std::{cell::RefCell, collections::HashMap, rc::Rc};
+
+use anyhow::{bail, Context};
+
+use crate::{
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+
+/// The globals of an [`ExeState`], detached from it.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct StateImage {
+    globals: Vec<(String, ImageValue)>,
+    tables: Vec<ImageTable>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+enum ImageValue {
+    Nil,
+    Boolean(bool),
+    Integer(LuaInt),
+    Float(LuaFloat),
+    String(Vec<u8>),
+    // index into `StateImage::tables`
+    Table(usize),
+    // global names first, any of which is enough to restore it
+    Function(Vec<String>),
+    // the table of the globals, `_G`, which is the restored state's own
+    Globals,
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct ImageTable {
+    array: Vec<ImageValue>,
+    map: Vec<(ImageValue, ImageValue)>,
+}
+
+impl StateImage {
+    pub(crate) fn capture(state: &ExeState) -> anyhow::Result<Self> {
+        let mut globals: Vec<_> = state.globals().collect();
+        globals.sort_by(|(a, _), (b, _)| a.cmp(b));
+
+        let mut capture = Capture {
+            globals: state.globals_table().clone(),
+            function_names: function_names(&globals),
+            table_ids: HashMap::new(),
+            tables: Vec::new(),
+        };
+        let globals = globals
+            .into_iter()
+            .map(|(name, v)| {
+                let v = capture
+                    .value(&v)
+                    .with_context(|| format!("cannot snapshot global '{name}'"))?;
+                Ok((name, v))
+            })
+            .collect::<anyhow::Result<_>>()?;
+        Ok(Self {
+            globals,
+            tables: capture.tables,
+        })
+    }
+
+    /// Set the globals in `state` from the image, resolving functions
+    /// against the globals `state` already has. Globals not in the image
+    /// are left alone.
+    pub(crate) fn apply(&self, state: &mut ExeState) -> anyhow::Result<()> {
+        let tables: Vec<_> = self
+            .tables
+            .iter()
+            .map(|_| Value::from(Table::new()))
+            .collect();
+        let restore = Restore {
+            state,
+            tables: &tables,
+        };
+        for (t, image) in tables.iter().zip(&self.tables) {
+            let Value::Table(t) = t else { unreachable!() };
+            let array = image
+                .array
+                .iter()
+                .map(|v| restore.value(v))
+                .collect::<anyhow::Result<_>>()?;
+            let mut table = Table::new();
+            table.array = array;
+            for (k, v) in &image.map {
+                table.set(restore.value(k)?, restore.value(v)?)?;
+            }
+            *t.borrow_mut() = table;
+        }
+        let globals = self
+            .globals
+            .iter()
+            .map(|(name, v)| Ok((name, restore.value(v)?)))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        for (name, v) in globals {
+            state.set_global(name, v);
+        }
+        Ok(())
+    }
+}
+
+/// The names of each function reachable from `globals`, global names
+/// first.
+fn function_names(globals: &[(String, Value)]) -> HashMap<usize, Vec<String>> {
+    let mut names: HashMap<_, Vec<_>> = HashMap::new();
+    for (name, v) in globals {
+        if let Value::Function(f) = v {
+            names.entry(*f as usize).or_default().push(name.to_string());
+        }
+    }
+    for (name, v) in globals {
+        let Value::Table(t) = v else { continue };
+        let t = t.borrow();
+        let mut fields: Vec<_> = t
+            .map
+            .iter()
+            .filter_map(|(k, v)| match (<&str>::try_from(k), v) {
+                (Ok(k), Value::Function(f)) => Some((k, *f as usize)),
+                _ => None,
+            })
+            .collect();
+        fields.sort();
+        for (field, f) in fields {
+            names.entry(f).or_default().push(format!("{name}.{field}"));
+        }
+    }
+    names
+}
+
+struct Capture {
+    globals: Rc<RefCell<Table>>,
+    function_names: HashMap<usize, Vec<String>>,
+    table_ids: HashMap<*const RefCell<Table>, usize>,
+    tables: Vec<ImageTable>,
+}
+
+impl Capture {
+    fn value(&mut self, v: &Value) -> anyhow::Result<ImageValue> {
+        Ok(match v {
+            Value::Nil => ImageValue::Nil,
+            Value::Boolean(b) => ImageValue::Boolean(*b),
+            Value::Integer(i) => ImageValue::Integer(*i),
+            Value::Float(f) => ImageValue::Float(*f),
+            Value::Table(t) if Rc::ptr_eq(t, &self.globals) => ImageValue::Globals,
+            Value::Table(t) => ImageValue::Table(self.table(t)?),
+            Value::Function(f) => match self.function_names.get(&(*f as usize)) {
+                Some(names) => ImageValue::Function(names.clone()),
+                None => bail!("function is not reachable from a global"),
+            },
+            // its upvalues are not reachable at all
+            Value::NativeClosure(_) => bail!("cannot capture a native closure"),
+            // nor the values it captures
+            Value::LuaClosure(_) => bail!("cannot capture a Lua function"),
+            // nor the data of the host program
+            Value::UserData(_) => bail!("cannot capture userdata"),
+            s => ImageValue::String(<&[u8]>::try_from(s)?.to_vec()),
+        })
+    }
+
+    fn table(&mut self, t: &Rc<RefCell<Table>>) -> anyhow::Result<usize> {
+        if let Some(&id) = self.table_ids.get(&Rc::as_ptr(t)) {
+            return Ok(id);
+        }
+        // register before the contents, which may refer back to it
+        let id = self.tables.len();
+        self.table_ids.insert(Rc::as_ptr(t), id);
+        self.tables.push(ImageTable::default());
+
+        let t = t.borrow();
+        let array = t
+            .array
+            .iter()
+            .map(|v| self.value(v))
+            .collect::<anyhow::Result<_>>()?;
+        let map = t
+            .map_entries()
+            .map(|(k, v)| Ok((self.value(k)?, self.value(v)?)))
+            .collect::<anyhow::Result<_>>()?;
+        self.tables[id] = ImageTable { array, map };
+        Ok(id)
+    }
+}
+
+struct Restore<'a> {
+    state: &'a ExeState,
+    tables: &'a [Value],
+}
+
+impl Restore<'_> {
+    fn value(&self, v: &ImageValue) -> anyhow::Result<Value> {
+        Ok(match v {
+            ImageValue::Nil => Value::Nil,
+            ImageValue::Boolean(b) => Value::Boolean(*b),
+            ImageValue::Integer(i) => Value::Integer(*i),
+            ImageValue::Float(f) => Value::Float(*f),
+            ImageValue::String(s) => s.as_slice().into(),
+            ImageValue::Table(id) => self
+                .tables
+                .get(*id)
+                .context("invalid table in image")?
+                .clone(),
+            ImageValue::Function(names) => names
+                .iter()
+                .find_map(|name| self.function(name))
+                .with_context(|| format!("no function '{}' to restore", names[0]))?,
+            ImageValue::Globals => Value::Table(self.state.globals_table().clone()),
+        })
+    }
+
+    fn function(&self, name: &str) -> Option<Value> {
+        let v = match name.split_once('.') {
+            None => self.state.get_global(name),
+            Some((table, field)) => match self.state.get_global(table) {
+                Value::Table(t) => t.borrow().map.get(&Value::from(field)).cloned(),
+                _ => None,
+            }
+            .unwrap_or_default(),
+        };
+        matches!(v, Value::Function(_)).then_some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let mut state = ExeState::new();
+        state
+            .eval("x = 1 s = 'a long string, longer than short ones' p = print")
+            .unwrap();
+        let mut t = Table::new();
+        t.array.push(2.5.into());
+        t.map.insert("f".into(), state.get_global("inspect"));
+        let t = Value::from(t);
+        if let Value::Table(tt) = &t {
+            tt.borrow_mut().map.insert("self".into(), t.clone());
+            tt.borrow_mut()
+                .map
+                .insert("g".into(), state.get_global("_G"));
+        }
+        state.set_global("t", t.clone());
+        let image = state.snapshot().unwrap();
+
+        let mut restored = ExeState::new();
+        restored.restore(&image).unwrap();
+        assert_eq!(restored.get_global("x"), Value::Integer(1));
+        assert_eq!(restored.get_global("s"), state.get_global("s"));
+        assert_eq!(restored.get_global("p"), restored.get_global("print"));
+        let Value::Table(rt) = restored.get_global("t") else {
+            panic!("t is not a table");
+        };
+        let rt2 = rt.borrow();
+        assert_eq!(rt2.array, [Value::Float(2.5)]);
+        assert_eq!(rt2.map[&Value::from("f")], restored.get_global("inspect"));
+        assert!(
+            matches!(&rt2.map[&Value::from("g")], Value::Table(g) if Rc::ptr_eq(g, restored.globals_table()))
+        );
+        assert!(matches!(&rt2.map[&Value::from("self")], Value::Table(s) if Rc::ptr_eq(s, &rt)));
+        drop(rt2);
+
+        // break the cycles so the tables are freed
+        for t in [t, Value::Table(rt)] {
+            if let Value::Table(t) = t {
+                t.borrow_mut().map.clear();
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_function() {
+        fn f(_: &mut ExeState) -> anyhow::Result<i32> {
+            Ok(0)
+        }
+        let mut state = ExeState::new();
+        state.set_global("f", Value::Function(f));
+        let image = state.snapshot().unwrap();
+        assert!(ExeState::new().restore(&image).is_err());
+
+        // reachable only through a nested table
+        let mut inner = Table::new();
+        inner.array.push(Value::Function(f));
+        let mut outer = Table::new();
+        outer.map.insert("inner".into(), inner.into());
+        let mut state = ExeState::new();
+        state.set_global("outer", outer.into());
+        let err = state.snapshot().unwrap_err();
+        assert_eq!(
+            format!("{err:#}"),
+            "cannot snapshot global 'outer': function is not reachable from a global"
+        );
+    }
+}
@@ -0,0 +1,224 @@
+//! Human-readable rendering of values, with the contents of tables spelled
+//! out. Used by the `inspect` library function.
+
+use std::{cell::RefCell, cmp::Ordering, fmt::Write, rc::Rc};
+
+use crate::value::{LuaFloat, Table, Value};
+#[cfg(feature = "vm")]
+use crate::vm::ExeState;
+
+/// How many levels of nested tables are shown by default.
+pub const DEFAULT_DEPTH: usize = 8;
+
+// inspect(value [, depth])
+#[cfg(feature = "vm")]
+pub(crate) fn lib_inspect(state: &mut ExeState) -> anyhow::Result<i32> {
+    let depth = match state.arg(2) {
+        Value::Nil => DEFAULT_DEPTH,
+        Value::Integer(i) if *i >= 0 => *i as usize,
+        Value::Integer(_) => anyhow::bail!("bad argument #2 to 'inspect' (invalid depth)"),
+        _ => anyhow::bail!(
+            "bad argument #2 to 'inspect' (number expected, got {})",
+            state.arg_type_name(2)
+        ),
+    };
+    let s = inspect(state.arg(1), depth);
+    state.push(s.into());
+    Ok(1)
+}
+
+/// Render `v`, showing tables up to `depth` levels deep. Keys are sorted,
+/// tables nested deeper are shown as `{...}` and a table containing itself
+/// as `<cycle>`.
+pub fn inspect(v: &Value, depth: usize) -> String {
+    let mut inspector = Inspector {
+        out: String::new(),
+        path: Vec::new(),
+        depth,
+    };
+    inspector.value(v);
+    inspector.out
+}
+
+struct Inspector {
+    out: String,
+    path: Vec<*const RefCell<Table>>,
+    depth: usize,
+}
+
+impl Inspector {
+    fn value(&mut self, v: &Value) {
+        match v {
+            Value::Table(t) => self.table(t),
+            Value::Float(_) | Value::Integer(_) | Value::Nil | Value::Boolean(_) => {
+                write!(self.out, "{v}").unwrap()
+            }
+            Value::UserData(_) => self.out.push_str("userdata"),
+            #[cfg(feature = "vm")]
+            Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => {
+                self.out.push_str("function")
+            }
+            s => self.string(<&[u8]>::try_from(s).unwrap()),
+        }
+    }
+
+    fn table(&mut self, t: &Rc<RefCell<Table>>) {
+        let ptr = Rc::as_ptr(t);
+        if self.path.contains(&ptr) {
+            self.out.push_str("<cycle>");
+            return;
+        }
+        let t = t.borrow();
+        if t.array.is_empty() && t.map_len() == 0 {
+            self.out.push_str("{}");
+            return;
+        }
+        if self.path.len() >= self.depth {
+            self.out.push_str("{...}");
+            return;
+        }
+        self.path.push(ptr);
+
+        // sort keys so that the output does not depend on hashing
+        let mut entries: Vec<_> = t.map_entries().collect();
+        entries.sort_by(|a, b| compare_keys(a.0, b.0));
+
+        self.out.push('{');
+        for v in &t.array {
+            self.newline();
+            self.value(v);
+            self.out.push(',');
+        }
+        for (k, v) in entries {
+            self.newline();
+            self.key(k);
+            self.out.push_str(" = ");
+            self.value(v);
+            self.out.push(',');
+        }
+        self.out.pop();
+        self.path.pop();
+        self.newline();
+        self.out.push('}');
+    }
+
+    fn key(&mut self, k: &Value) {
+        match <&str>::try_from(k) {
+            Ok(s) if is_name(s) => self.out.push_str(s),
+            _ => {
+                self.out.push('[');
+                self.value(k);
+                self.out.push(']');
+            }
+        }
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.out.push('"');
+        for c in String::from_utf8_lossy(s).chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                c if c.is_control() => write!(self.out, "\\{}", c as u32).unwrap(),
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+
+    fn newline(&mut self) {
+        self.out.push('\n');
+        for _ in 0..self.path.len() {
+            self.out.push_str("  ");
+        }
+    }
+}
+
+fn is_name(s: &str) -> bool {
+    let mut chars = s.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Numbers first in numeric order, then strings, then everything else.
+fn compare_keys(a: &Value, b: &Value) -> Ordering {
+    fn rank(v: &Value) -> u8 {
+        match v {
+            Value::Integer(_) | Value::Float(_) => 0,
+            Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => 1,
+            Value::Boolean(_) => 2,
+            _ => 3,
+        }
+    }
+    fn number(v: &Value) -> LuaFloat {
+        match *v {
+            Value::Integer(i) => i as LuaFloat,
+            Value::Float(f) => f,
+            _ => 0.0,
+        }
+    }
+
+    rank(a).cmp(&rank(b)).then_with(|| match rank(a) {
+        0 => number(a).total_cmp(&number(b)),
+        1 => <&[u8]>::try_from(a)
+            .unwrap()
+            .cmp(<&[u8]>::try_from(b).unwrap()),
+        _ => a.to_string().cmp(&b.to_string()),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scalars() {
+        assert_eq!(inspect(&Value::Nil, DEFAULT_DEPTH), "nil");
+        assert_eq!(inspect(&1.5.into(), DEFAULT_DEPTH), "1.5");
+        assert_eq!(inspect(&"a\"b\n".into(), DEFAULT_DEPTH), r#""a\"b\n""#);
+        assert_eq!(inspect(&Table::new().into(), DEFAULT_DEPTH), "{}");
+    }
+
+    #[test]
+    fn nested() {
+        let mut inner = Table::new();
+        inner.map.insert("c".into(), true.into());
+        let mut t = Table::new();
+        t.array.push(1.into());
+        t.array.push("x".into());
+        t.map.insert("b".into(), inner.into());
+        t.map.insert("a b".into(), 2.into());
+        t.map.insert(10.into(), 3.into());
+        assert_eq!(
+            inspect(&t.into(), DEFAULT_DEPTH),
+            "{\n  1,\n  \"x\",\n  [10] = 3,\n  [\"a b\"] = 2,\n  b = {\n    c = true\n  }\n}"
+        );
+    }
+
+    #[test]
+    fn depth_and_cycle() {
+        let mut inner = Table::new();
+        inner.array.push(1.into());
+        let mut t = Table::new();
+        t.array.push(inner.into());
+        let t = Value::from(t);
+        assert_eq!(inspect(&t, 1), "{\n  {...}\n}");
+
+        if let Value::Table(tt) = &t {
+            tt.borrow_mut().map.insert("self".into(), t.clone());
+        }
+        assert_eq!(
+            inspect(&t, DEFAULT_DEPTH),
+            "{\n  {\n    1\n  },\n  self = <cycle>\n}"
+        );
+        // break the cycle so the table is freed
+        if let Value::Table(tt) = &t {
+            tt.borrow_mut().map.clear();
+        }
+    }
+}
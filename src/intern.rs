@@ -0,0 +1,94 @@
+//! Sharing of equal strings between the constants of different chunks, so
+//! that loading many chunks into one state does not keep a copy of each
+//! string per chunk.
+
+use std::{collections::HashSet, rc::Rc};
+
+use crate::{parse::ParseProto, value::Value};
+
+/// The heap strings seen so far. Short strings are stored inline in their
+/// values, so there is nothing to share for them.
+#[derive(Debug, Default)]
+pub struct Interner {
+    strings: HashSet<Value>,
+    stats: InternStats,
+}
+
+/// How much an [`Interner`] saved.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct InternStats {
+    /// Distinct strings held.
+    pub strings: usize,
+    /// Strings replaced by an equal one held already.
+    pub shared: usize,
+    /// Bytes of the strings replaced.
+    pub bytes_saved: usize,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// `v`, or an equal string interned before.
+    pub fn intern(&mut self, v: Value) -> Value {
+        if !matches!(v, Value::MidStr(_) | Value::LongStr(_)) {
+            return v;
+        }
+        if let Some(shared) = self.strings.get(&v) {
+            self.stats.shared += 1;
+            self.stats.bytes_saved += <&[u8]>::try_from(&v).map_or(0, <[u8]>::len);
+            return shared.clone();
+        }
+        self.strings.insert(v.clone());
+        self.stats.strings += 1;
+        v
+    }
+
+    /// Replace the string constants of `proto`, and of the functions
+    /// defined in it, by the interned ones.
+    pub fn intern_constants(&mut self, proto: &mut ParseProto) {
+        for c in Rc::make_mut(&mut proto.constants) {
+            *c = self.intern(std::mem::take(c));
+        }
+        for p in &mut proto.protos {
+            self.intern_constants(Rc::make_mut(p));
+        }
+    }
+
+    pub fn stats(&self) -> InternStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shared_constants() {
+        let src = b"print('a string too long to be stored inline', 'short')";
+        let mut interner = Interner::new();
+        let protos: Vec<_> = (0..3)
+            .map(|_| {
+                let mut proto = ParseProto::load(&src[..]).unwrap();
+                interner.intern_constants(&mut proto);
+                proto
+            })
+            .collect();
+        let long = |proto: &ParseProto| match proto.constants[1].clone() {
+            Value::MidStr(s) => s,
+            c => panic!("{c:?} is not a heap string"),
+        };
+        assert!(std::rc::Rc::ptr_eq(&long(&protos[0]), &long(&protos[2])));
+        assert_eq!(
+            interner.stats(),
+            InternStats {
+                // "print" and "short" are inline
+                strings: 1,
+                shared: 2,
+                bytes_saved: 2 * 37,
+            }
+        );
+    }
+}
@@ -0,0 +1,498 @@
+use std::{cell::RefCell, fmt::Write, rc::Rc};
+
+use anyhow::bail;
+
+use crate::value::{LuaFloat, LuaInt, Table, Value};
+#[cfg(feature = "vm")]
+use crate::vm::ExeState;
+
+const MAX_DEPTH: usize = 1000;
+
+#[derive(Debug, Default)]
+pub struct EncodeOptions {
+    /// Emit newlines and two-space indentation.
+    pub pretty: bool,
+    /// Values equal to this one are encoded as `null`, in addition to nil.
+    pub null: Option<Value>,
+}
+
+#[derive(Debug, Default)]
+pub struct DecodeOptions {
+    /// Value produced for JSON `null`. Defaults to nil.
+    pub null: Value,
+}
+
+/// Build the `json` library table.
+#[cfg(feature = "vm")]
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("encode".into(), Value::Function(lib_encode));
+    t.map.insert("decode".into(), Value::Function(lib_decode));
+    t.into()
+}
+
+// json.encode(value [, {pretty = bool, null = sentinel}])
+#[cfg(feature = "vm")]
+fn lib_encode(state: &mut ExeState) -> anyhow::Result<i32> {
+    let mut options = EncodeOptions::default();
+    if let Value::Table(t) = state.arg(2) {
+        let t = t.borrow();
+        options.pretty = t.map.get(&Value::from("pretty")).is_some_and(truthy);
+        options.null = t.map.get(&Value::from("null")).cloned();
+    }
+    let s = encode(state.arg(1), &options)?;
+    state.push(s.into());
+    Ok(1)
+}
+
+// json.decode(string [, {null = sentinel}])
+#[cfg(feature = "vm")]
+fn lib_decode(state: &mut ExeState) -> anyhow::Result<i32> {
+    let mut options = DecodeOptions::default();
+    if let Value::Table(t) = state.arg(2) {
+        options.null = t.borrow().get(&"null".into());
+    }
+    let s = <&[u8]>::try_from(state.arg(1))?;
+    let v = decode(s, &options)?;
+    state.push(v);
+    Ok(1)
+}
+
+#[cfg(feature = "vm")]
+fn truthy(v: &Value) -> bool {
+    !matches!(v, Value::Nil | Value::Boolean(false))
+}
+
+pub fn encode(v: &Value, options: &EncodeOptions) -> anyhow::Result<String> {
+    let mut encoder = Encoder {
+        options,
+        out: String::new(),
+        path: Vec::new(),
+    };
+    encoder.value(v)?;
+    Ok(encoder.out)
+}
+
+pub fn decode(s: &[u8], options: &DecodeOptions) -> anyhow::Result<Value> {
+    let mut decoder = Decoder {
+        options,
+        input: s,
+        pos: 0,
+        depth: 0,
+    };
+    let v = decoder.value()?;
+    decoder.skip_spaces();
+    if decoder.pos < s.len() {
+        decoder.error("trailing characters")?;
+    }
+    Ok(v)
+}
+
+struct Encoder<'a> {
+    options: &'a EncodeOptions,
+    out: String,
+    // tables being encoded, to detect cycles
+    path: Vec<*const RefCell<Table>>,
+}
+
+impl Encoder<'_> {
+    fn value(&mut self, v: &Value) -> anyhow::Result<()> {
+        if self.options.null.as_ref() == Some(v) {
+            self.out.push_str("null");
+            return Ok(());
+        }
+        match v {
+            Value::Nil => self.out.push_str("null"),
+            Value::Boolean(b) => write!(self.out, "{b}")?,
+            Value::Integer(i) => write!(self.out, "{i}")?,
+            Value::Float(f) => {
+                if !f.is_finite() {
+                    bail!("cannot encode non-finite number: {f}");
+                }
+                write!(self.out, "{f:?}")?;
+            }
+            Value::Table(t) => self.table(t)?,
+            Value::UserData(_) => bail!("cannot encode userdata"),
+            #[cfg(feature = "vm")]
+            Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => {
+                bail!("cannot encode function")
+            }
+            s => self.string(<&[u8]>::try_from(s)?),
+        }
+        Ok(())
+    }
+
+    fn table(&mut self, t: &Rc<RefCell<Table>>) -> anyhow::Result<()> {
+        let ptr = Rc::as_ptr(t);
+        if self.path.contains(&ptr) {
+            bail!("cannot encode cyclic table");
+        }
+        self.path.push(ptr);
+
+        let t = t.borrow();
+        if t.map_len() == 0 && !t.array.is_empty() {
+            self.out.push('[');
+            for (i, v) in t.array.iter().enumerate() {
+                self.separator(i)?;
+                self.value(v)?;
+            }
+            self.close(t.array.len(), ']')?;
+        } else {
+            // sort keys so that the output does not depend on hashing
+            let mut entries = Vec::with_capacity(t.array.len() + t.map_len());
+            for (i, v) in t.array.iter().enumerate() {
+                entries.push(((i + 1).to_string().into_bytes(), v));
+            }
+            for (k, v) in t.map_entries() {
+                let key = match k {
+                    Value::Integer(i) => i.to_string().into_bytes(),
+                    Value::Float(f) => format!("{f:?}").into_bytes(),
+                    k => match <&[u8]>::try_from(k) {
+                        Ok(s) => s.to_vec(),
+                        Err(_) => bail!("cannot encode table key: {k:?}"),
+                    },
+                };
+                entries.push((key, v));
+            }
+            entries.sort_by(|a, b| a.0.cmp(&b.0));
+
+            self.out.push('{');
+            for (i, (k, v)) in entries.iter().enumerate() {
+                self.separator(i)?;
+                self.string(k);
+                self.out
+                    .push_str(if self.options.pretty { ": " } else { ":" });
+                self.value(v)?;
+            }
+            self.close(entries.len(), '}')?;
+        }
+
+        self.path.pop();
+        Ok(())
+    }
+
+    fn separator(&mut self, i: usize) -> anyhow::Result<()> {
+        if i > 0 {
+            self.out.push(',');
+        }
+        if self.options.pretty {
+            write!(self.out, "\n{:1$}", "", self.path.len() * 2)?;
+        }
+        Ok(())
+    }
+
+    fn close(&mut self, len: usize, c: char) -> anyhow::Result<()> {
+        if self.options.pretty && len > 0 {
+            write!(self.out, "\n{:1$}", "", (self.path.len() - 1) * 2)?;
+        }
+        self.out.push(c);
+        Ok(())
+    }
+
+    fn string(&mut self, s: &[u8]) {
+        self.out.push('"');
+        for c in String::from_utf8_lossy(s).chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                '\u{8}' => self.out.push_str("\\b"),
+                '\u{c}' => self.out.push_str("\\f"),
+                c if c < ' ' => {
+                    let _ = write!(self.out, "\\u{:04x}", c as u32);
+                }
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+struct Decoder<'a> {
+    options: &'a DecodeOptions,
+    input: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Decoder<'_> {
+    fn error<T>(&self, msg: &str) -> anyhow::Result<T> {
+        bail!("invalid JSON at position {}: {msg}", self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, lit: &[u8]) -> anyhow::Result<()> {
+        if self.input[self.pos..].starts_with(lit) {
+            self.pos += lit.len();
+            Ok(())
+        } else {
+            self.error("unexpected character")
+        }
+    }
+
+    fn value(&mut self) -> anyhow::Result<Value> {
+        self.skip_spaces();
+        match self.peek() {
+            Some(b'n') => self.expect(b"null").map(|_| self.options.null.clone()),
+            Some(b't') => self.expect(b"true").map(|_| true.into()),
+            Some(b'f') => self.expect(b"false").map(|_| false.into()),
+            Some(b'"') => self.string().map(Value::from),
+            Some(b'[') => self.nested(Self::array),
+            Some(b'{') => self.nested(Self::object),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => self.error("unexpected character"),
+            None => self.error("unexpected end of input"),
+        }
+    }
+
+    fn nested(&mut self, f: fn(&mut Self) -> anyhow::Result<Value>) -> anyhow::Result<Value> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return self.error("nesting too deep");
+        }
+        let v = f(self)?;
+        self.depth -= 1;
+        Ok(v)
+    }
+
+    fn array(&mut self) -> anyhow::Result<Value> {
+        self.pos += 1; // '['
+        let mut t = Table::new();
+        self.skip_spaces();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(t.into());
+        }
+        loop {
+            t.array.push(self.value()?);
+            self.skip_spaces();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => break,
+                _ => return self.error("expected ',' or ']'"),
+            }
+        }
+        self.pos += 1;
+        Ok(t.into())
+    }
+
+    fn object(&mut self) -> anyhow::Result<Value> {
+        self.pos += 1; // '{'
+        let mut t = Table::new();
+        self.skip_spaces();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(t.into());
+        }
+        loop {
+            self.skip_spaces();
+            if self.peek() != Some(b'"') {
+                return self.error("expected string key");
+            }
+            let key = self.string()?;
+            self.skip_spaces();
+            if self.peek() != Some(b':') {
+                return self.error("expected ':'");
+            }
+            self.pos += 1;
+            let v = self.value()?;
+            if v != Value::Nil {
+                t.map.insert(key.into(), v);
+            }
+            self.skip_spaces();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => break,
+                _ => return self.error("expected ',' or '}'"),
+            }
+        }
+        self.pos += 1;
+        Ok(t.into())
+    }
+
+    fn string(&mut self) -> anyhow::Result<Vec<u8>> {
+        self.pos += 1; // '"'
+        let mut buf = Vec::new();
+        loop {
+            match self.peek() {
+                None => return self.error("unfinished string"),
+                Some(b'"') => break,
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return self.error("invalid escape"),
+                    };
+                    let mut utf8 = [0; 4];
+                    buf.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                }
+                Some(c) if c < b' ' => return self.error("control character in string"),
+                Some(c) => buf.push(c),
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        Ok(buf)
+    }
+
+    // on return, `pos` is at the last hex digit
+    fn unicode_escape(&mut self) -> anyhow::Result<char> {
+        let hi = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&hi) {
+            // surrogate pair
+            self.pos += 1;
+            self.expect(b"\\u")?;
+            self.pos -= 1;
+            let lo = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&lo) {
+                return self.error("invalid surrogate pair");
+            }
+            0x10000 + ((hi - 0xd800) << 10) + (lo - 0xdc00)
+        } else {
+            hi
+        };
+        match char::from_u32(code) {
+            Some(c) => Ok(c),
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn hex4(&mut self) -> anyhow::Result<u32> {
+        let digits = self.input.get(self.pos + 1..self.pos + 5);
+        let code = digits
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .and_then(|d| u32::from_str_radix(d, 16).ok());
+        match code {
+            Some(code) => {
+                self.pos += 4;
+                Ok(code)
+            }
+            None => self.error("invalid unicode escape"),
+        }
+    }
+
+    fn number(&mut self) -> anyhow::Result<Value> {
+        let start = self.pos;
+        let mut is_float = false;
+        while let Some(c) = self.peek() {
+            match c {
+                b'0'..=b'9' | b'-' | b'+' => (),
+                b'.' | b'e' | b'E' => is_float = true,
+                _ => break,
+            }
+            self.pos += 1;
+        }
+        let s = std::str::from_utf8(&self.input[start..self.pos])?;
+        if !is_float {
+            if let Ok(i) = s.parse::<LuaInt>() {
+                return Ok(i.into());
+            }
+        }
+        match s.parse::<LuaFloat>() {
+            Ok(f) => Ok(f.into()),
+            Err(_) => {
+                self.pos = start;
+                self.error("invalid number")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(s: &str) -> String {
+        let v = decode(s.as_bytes(), &DecodeOptions::default()).unwrap();
+        encode(&v, &EncodeOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn encode_scalars() {
+        let options = EncodeOptions::default();
+        assert_eq!(encode(&Value::Nil, &options).unwrap(), "null");
+        assert_eq!(encode(&true.into(), &options).unwrap(), "true");
+        assert_eq!(encode(&12.into(), &options).unwrap(), "12");
+        assert_eq!(encode(&1.5.into(), &options).unwrap(), "1.5");
+        assert_eq!(encode(&"a\"b\n".into(), &options).unwrap(), r#""a\"b\n""#);
+        assert!(encode(&LuaFloat::NAN.into(), &options).is_err());
+    }
+
+    #[test]
+    fn roundtrip_nested() {
+        assert_eq!(
+            roundtrip(r#"[1, 2.5, "x", [true]]"#),
+            r#"[1,2.5,"x",[true]]"#
+        );
+        assert_eq!(
+            roundtrip(r#"{"b": {"c": []}, "a": 1}"#),
+            r#"{"a":1,"b":{"c":{}}}"#
+        );
+        assert_eq!(roundtrip(r#""é😀""#), "\"\u{e9}\u{1f600}\"");
+    }
+
+    #[test]
+    fn pretty() {
+        let v = decode(br#"{"a": [1, 2]}"#, &DecodeOptions::default()).unwrap();
+        let options = EncodeOptions {
+            pretty: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            encode(&v, &options).unwrap(),
+            "{\n  \"a\": [\n    1,\n    2\n  ]\n}"
+        );
+    }
+
+    #[test]
+    fn null_sentinel() {
+        let null = Value::from("NULL");
+        let decode_options = DecodeOptions { null: null.clone() };
+        let v = decode(b"[null, 1]", &decode_options).unwrap();
+        let encode_options = EncodeOptions {
+            null: Some(null),
+            ..Default::default()
+        };
+        assert_eq!(encode(&v, &encode_options).unwrap(), "[null,1]");
+    }
+
+    #[test]
+    fn decode_errors() {
+        let options = DecodeOptions::default();
+        assert!(decode(b"[1, 2", &options).is_err());
+        assert!(decode(b"{1: 2}", &options).is_err());
+        assert!(decode(b"\"abc", &options).is_err());
+        assert!(decode(b"1 2", &options).is_err());
+    }
+
+    #[test]
+    fn cyclic_table() {
+        let v = Value::from(Table::new());
+        if let Value::Table(t) = &v {
+            t.borrow_mut().array.push(v.clone());
+        }
+        assert!(encode(&v, &EncodeOptions::default()).is_err());
+        if let Value::Table(t) = &v {
+            t.borrow_mut().array.clear();
+        }
+    }
+}
@@ -1,14 +1,153 @@
+use anyhow::bail;
 use combine::{
-    attempt, between, choice, eof, from_str, many, many1,
-    parser::{
-        byte::{bytes, digit, letter, spaces},
-        combinator::recognize,
+    attempt, choice, eof,
+    error::{Commit, ParseError, StreamError, UnexpectedParse},
+    look_ahead, many, optional,
+    parser::byte::{bytes, digit, letter},
+    satisfy, skip_many, skip_many1,
+    stream::{
+        easy,
+        position::{self, Positioner, RangePositioner},
+        StreamErrorFor,
     },
-    satisfy, token, Parser, Stream,
+    token, Parser, Stream, StreamOnce,
 };
 
-pub trait ByteStream<'a>: Stream<Token = u8, Range = &'a [u8]> + 'a {}
-impl<'a, T: Stream<Token = u8, Range = &'a [u8]> + 'a> ByteStream<'a> for T {}
+use crate::{
+    numfmt::{float_to_string, str2number},
+    value::{LuaFloat, LuaInt, Value},
+};
+
+pub trait ByteStream<'a>: Stream<Token = u8, Range = &'a [u8], Error: DescribeError> + 'a {}
+impl<'a, T: Stream<Token = u8, Range = &'a [u8], Error: DescribeError> + 'a> ByteStream<'a> for T {}
+
+/// Reduce a stream's parse error to a message for the user.
+pub trait DescribeError {
+    /// Where the error is, if the stream tracks it.
+    fn location(&self) -> Option<Location> {
+        None
+    }
+
+    fn describe(self) -> String;
+}
+
+impl DescribeError for UnexpectedParse {
+    fn describe(self) -> String {
+        "parse failed".into()
+    }
+}
+
+impl DescribeError for easy::Errors<u8, &[u8], Location> {
+    fn location(&self) -> Option<Location> {
+        Some(self.position)
+    }
+
+    fn describe(self) -> String {
+        let mut message = None;
+        let mut unexpected = None;
+        let mut expected = Vec::new();
+        for err in self.errors {
+            match err {
+                easy::Error::Message(info) => message = message.or(Some(describe_info(info))),
+                easy::Error::Unexpected(info) => unexpected = Some(describe_info(info)),
+                easy::Error::Expected(info) => expected.push(describe_info(info)),
+                easy::Error::Other(err) => message = message.or(Some(err.to_string())),
+            }
+        }
+
+        let mut msg = match (message, unexpected) {
+            (Some(msg), _) => msg,
+            (None, Some(unexpected)) => format!("unexpected {unexpected}"),
+            (None, None) => "parse failed".into(),
+        };
+        // a failed choice between all token kinds expects everything
+        if !expected.is_empty() && expected.len() <= 3 {
+            msg += &format!(", expected {}", expected.join(" or "));
+        }
+        format!("{msg} at {}", self.position)
+    }
+}
+
+fn describe_info(info: easy::Info<u8, &[u8]>) -> String {
+    match info {
+        easy::Info::Token(c) => format!("character '{}'", show_byte(c)),
+        easy::Info::Range(r) => format!("'{}'", String::from_utf8_lossy(r)),
+        easy::Info::Owned(s) => s,
+        easy::Info::Static(s) => s.into(),
+    }
+}
+
+fn show_byte(c: u8) -> String {
+    if c.is_ascii_graphic() {
+        (c as char).to_string()
+    } else {
+        format!("<\\{c}>")
+    }
+}
+
+/// Position in the source, tracked as the lexer consumes it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Location {
+    /// Byte offset from the start of the chunk
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Default for Location {
+    fn default() -> Self {
+        Self {
+            offset: 0,
+            line: 1,
+            column: 1,
+        }
+    }
+}
+
+impl std::fmt::Display for Location {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "line {}, column {} (offset {})",
+            self.line, self.column, self.offset
+        )
+    }
+}
+
+impl Positioner<u8> for Location {
+    type Position = Location;
+    type Checkpoint = Location;
+
+    fn position(&self) -> Location {
+        *self
+    }
+
+    fn update(&mut self, token: &u8) {
+        self.offset += 1;
+        if *token == b'\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+    }
+
+    fn checkpoint(&self) -> Location {
+        *self
+    }
+
+    fn reset(&mut self, checkpoint: Location) {
+        *self = checkpoint;
+    }
+}
+
+impl RangePositioner<u8, &[u8]> for Location {
+    fn update_range(&mut self, range: &&[u8]) {
+        for c in range.iter() {
+            self.update(c);
+        }
+    }
+}
 
 #[derive(Debug, PartialEq)]
 pub enum Token {
@@ -72,9 +211,9 @@ pub enum Token {
     Dots,
 
     // constant values
-    Integer(i64),
-    Float(f64),
-    String(String),
+    Integer(LuaInt),
+    Float(LuaFloat),
+    String(Vec<u8>),
 
     // name of variables or table keys
     Name(String),
@@ -83,94 +222,405 @@ pub enum Token {
     Eos,
 }
 
+/// The token as error messages show it, quoted as it is written in the
+/// source, or `<eof>`.
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let text = match self {
+            Token::And => "and",
+            Token::Break => "break",
+            Token::Do => "do",
+            Token::Else => "else",
+            Token::Elseif => "elseif",
+            Token::End => "end",
+            Token::False => "false",
+            Token::For => "for",
+            Token::Function => "function",
+            Token::Goto => "goto",
+            Token::If => "if",
+            Token::In => "in",
+            Token::Local => "local",
+            Token::Nil => "nil",
+            Token::Not => "not",
+            Token::Or => "or",
+            Token::Repeat => "repeat",
+            Token::Return => "return",
+            Token::Then => "then",
+            Token::True => "true",
+            Token::Until => "until",
+            Token::While => "while",
+            Token::Add => "+",
+            Token::Sub => "-",
+            Token::Mul => "*",
+            Token::Div => "/",
+            Token::Mod => "%",
+            Token::Pow => "^",
+            Token::Len => "#",
+            Token::BitAnd => "&",
+            Token::BitXor => "~",
+            Token::BitOr => "|",
+            Token::ShiftL => "<<",
+            Token::ShiftR => ">>",
+            Token::Idiv => "//",
+            Token::Equal => "==",
+            Token::NotEq => "~=",
+            Token::LesEq => "<=",
+            Token::GreEq => ">=",
+            Token::Less => "<",
+            Token::Greater => ">",
+            Token::Assign => "=",
+            Token::ParL => "(",
+            Token::ParR => ")",
+            Token::CurlyL => "{",
+            Token::CurlyR => "}",
+            Token::SqurL => "[",
+            Token::SqurR => "]",
+            Token::DoubColon => "::",
+            Token::SemiColon => ";",
+            Token::Colon => ":",
+            Token::Comma => ",",
+            Token::Dot => ".",
+            Token::Concat => "..",
+            Token::Dots => "...",
+            Token::Integer(i) => return write!(f, "'{i}'"),
+            Token::Float(x) => return write!(f, "'{}'", float_to_string(*x)),
+            Token::String(s) => return write!(f, "'\"{}\"'", String::from_utf8_lossy(s)),
+            Token::Name(name) => name,
+            Token::Eos => return f.write_str("<eof>"),
+        };
+        write!(f, "'{text}'")
+    }
+}
+
+/// A range of the source, from `start` up to but not including `end`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Span {
+    pub start: Location,
+    pub end: Location,
+}
+
+impl std::fmt::Display for Span {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (start, end) = (self.start, self.end);
+        if start.line == end.line {
+            // the last column in the span
+            let last = (end.column - 1).max(start.column);
+            write!(f, "line {}, columns {}-{last}", start.line, start.column)
+        } else {
+            write!(
+                f,
+                "line {}, column {} to line {}, column {}",
+                start.line, start.column, end.line, end.column
+            )
+        }
+    }
+}
+
+/// An error in the source of a chunk, and where it is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    pub message: String,
+    pub span: Span,
+}
+
+impl std::fmt::Display for SyntaxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for SyntaxError {}
+
 pub struct Lex<S> {
     input: Option<S>,
     ahead: Token,
+    ahead_span: Span,
+    // of the last token returned by `next`
+    span: Span,
 }
 
-impl<'a, S: ByteStream<'a>> Lex<S> {
+impl<'a, S: ByteStream<'a> + StreamOnce<Position = Location>> Lex<S> {
     pub fn new(input: S) -> Self {
         Self {
             input: Some(input),
             ahead: Token::Eos,
+            ahead_span: Span::default(),
+            span: Span::default(),
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> anyhow::Result<Token> {
         if self.ahead == Token::Eos {
-            self.do_next()
+            let (t, span) = self.do_next()?;
+            self.span = span;
+            Ok(t)
         } else {
+            self.span = self.ahead_span;
             Ok(std::mem::replace(&mut self.ahead, Token::Eos))
         }
     }
 
     pub fn peek(&mut self) -> anyhow::Result<&Token> {
         if self.ahead == Token::Eos {
-            self.ahead = self.do_next()?;
+            (self.ahead, self.ahead_span) = self.do_next()?;
         }
         Ok(&self.ahead)
     }
 
-    fn do_next(&mut self) -> anyhow::Result<Token> {
+    /// Where the last token returned by [`next`](Self::next) is.
+    pub fn span(&self) -> Span {
+        self.span
+    }
+
+    /// Skip what may precede the first token of a chunk: a UTF-8 byte order
+    /// mark, then a first line starting with `#`, such as
+    /// `#!/usr/bin/env kailua`. Must be called before reading any token.
+    pub fn skip_prefix(&mut self) -> anyhow::Result<()> {
         let input = self.input.take();
-        let (t, rest) = lua_token()
+        // a UTF-16 byte order mark, or an ASCII character encoded in UTF-16
+        let utf16 = choice((
+            attempt(bytes(&b"\xff\xfe"[..])).map(|_| ()),
+            attempt(bytes(&b"\xfe\xff"[..])).map(|_| ()),
+            attempt((satisfy(|c| c != 0), token(0))).map(|_| ()),
+            attempt((token(0), satisfy(|c| c != 0))).map(|_| ()),
+        ));
+        let (is_utf16, rest) = look_ahead(optional(utf16))
             .parse(input.unwrap())
-            .map_err(|_| anyhow::anyhow!("parse failed"))?;
+            .map_err(|err| self.error(err))?;
+        if is_utf16.is_some() {
+            bail!("source is encoded in UTF-16; only UTF-8 is supported");
+        }
+
+        let bom = optional(attempt(bytes(&b"\xef\xbb\xbf"[..])));
+        let shebang = optional((token(b'#'), skip_many(satisfy(|c| c != b'\n'))));
+        let (_, rest) = (bom, shebang).parse(rest).map_err(|err| self.error(err))?;
         self.input = Some(rest);
-        Ok(t)
+        Ok(())
+    }
+
+    fn do_next(&mut self) -> anyhow::Result<(Token, Span)> {
+        let input = self.input.take();
+        let (_, rest) = skip_many(trivia())
+            .parse(input.unwrap())
+            .map_err(|err| self.error(err))?;
+        let start = rest.position();
+        let (t, rest) = token_body().parse(rest).map_err(|err| self.error(err))?;
+        let end = rest.position();
+        self.input = Some(rest);
+        Ok((t, Span { start, end }))
+    }
+
+    /// A lexer error as a [`SyntaxError`], where the stream says it is, or
+    /// else right after the last token.
+    fn error(&self, err: S::Error) -> anyhow::Error {
+        let at = err.location().unwrap_or(self.span.end);
+        SyntaxError {
+            message: err.describe(),
+            span: Span { start: at, end: at },
+        }
+        .into()
     }
 }
 
-fn lua_token<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
+/// Whitespace or a comment, with its exact source text.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Trivia<'a> {
+    pub kind: TriviaKind,
+    pub text: &'a [u8],
+}
+
+/// A token read by [`tokenize`], with the trivia before it.
+#[derive(Debug, PartialEq)]
+pub struct LosslessToken<'a> {
+    pub trivia: Vec<Trivia<'a>>,
+    pub token: Token,
+    /// The source text of the token, e.g. a string literal with its quotes
+    /// and escapes as written.
+    pub text: &'a [u8],
+    pub location: Location,
+}
+
+/// Split `src` into tokens keeping everything in between, so that the
+/// source is the concatenation of the trivia and text of the tokens. The
+/// last token is [`Token::Eos`], holding the trivia at the end.
+pub fn tokenize(src: &[u8]) -> anyhow::Result<Vec<LosslessToken<'_>>> {
+    let describe = |err: easy::Errors<u8, &[u8], Location>| anyhow::anyhow!(err.describe());
+    let mut input = easy::Stream(position::Stream::with_positioner(src, Location::default()));
+    let offset =
+        |input: &easy::Stream<position::Stream<&[u8], Location>>| input.0.positioner.offset;
+    let mut tokens = Vec::new();
+    let mut pending = Vec::new();
+
+    let bom = optional(attempt(bytes(&b"\xef\xbb\xbf"[..])));
+    let shebang = optional((token(b'#'), skip_many(satisfy(|c| c != b'\n'))));
+    let (_, rest) = (bom, shebang).parse(input).map_err(describe)?;
+    if offset(&rest) > 0 {
+        pending.push(Trivia {
+            kind: TriviaKind::Prefix,
+            text: &src[..offset(&rest)],
+        });
+    }
+    input = rest;
+
+    loop {
+        let start = offset(&input);
+        let (kind, rest) = optional(trivia()).parse(input).map_err(describe)?;
+        input = rest;
+        if let Some(kind) = kind {
+            let text = &src[start..offset(&input)];
+            pending.push(Trivia { kind, text });
+            continue;
+        }
+
+        let location = input.0.positioner;
+        let (token, rest) = token_body().parse(input).map_err(describe)?;
+        input = rest;
+        let eos = token == Token::Eos;
+        tokens.push(LosslessToken {
+            trivia: std::mem::take(&mut pending),
+            token,
+            text: &src[location.offset..offset(&input)],
+            location,
+        });
+        if eos {
+            return Ok(tokens);
+        }
+    }
+}
+
+fn token_body<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
 where
     Input: ByteStream<'a>,
 {
-    let name = recognize((letter(), many::<Vec<_>, _, _>(letter().or(digit()))))
-        .map(|v: Vec<u8>| Token::Name(String::from_utf8_lossy(&v).to_string()));
-    let string = between(token(b'"'), token(b'"'), many(satisfy(|c| c != b'"')))
-        .map(|v: Vec<u8>| Token::String(String::from_utf8_lossy(&v).to_string()));
+    // built from its parts rather than `recognize`d, which would rewind over
+    // the whole name and overflow the lookahead buffer of a read stream
+    let name = (
+        letter().or(token(b'_')),
+        many::<Vec<_>, _, _>(letter().or(digit()).or(token(b'_'))),
+    )
+        .map(|(first, mut rest): (u8, Vec<u8>)| {
+            rest.insert(0, first);
+            keyword(&rest)
+                .unwrap_or_else(|| Token::Name(String::from_utf8_lossy(&rest).to_string()))
+        });
     let eos = eof().map(|_| Token::Eos);
-    spaces().with(choice((
-        keywords(),
-        operators(),
-        attempt(float()),
-        integer(),
-        name,
-        string,
-        eos,
-    )))
-}
-
-fn keywords<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
+    choice((numeral(), operators(), name, string(), eos))
+}
+
+/// What may come between tokens: whitespace, or a comment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriviaKind {
+    /// A UTF-8 byte order mark and a first line starting with `#`, as
+    /// skipped by [`Lex::skip_prefix`].
+    Prefix,
+    Whitespace,
+    Comment,
+}
+
+fn trivia<'a, Input>() -> impl Parser<Input, Output = TriviaKind> + 'a
 where
     Input: ByteStream<'a>,
 {
+    let space = skip_many1(satisfy(|c: u8| c.is_ascii_whitespace() || c == b'\x0b'));
     choice((
-        attempt(bytes(&b"and"[..])).map(|_| Token::And),
-        attempt(bytes(&b"break"[..])).map(|_| Token::Break),
-        attempt(bytes(&b"do"[..])).map(|_| Token::Do),
-        attempt(bytes(&b"elseif"[..])).map(|_| Token::Elseif),
-        attempt(bytes(&b"else"[..])).map(|_| Token::Else),
-        attempt(bytes(&b"end"[..])).map(|_| Token::End),
-        attempt(bytes(&b"false"[..])).map(|_| Token::False),
-        attempt(bytes(&b"for"[..])).map(|_| Token::For),
-        attempt(bytes(&b"function"[..])).map(|_| Token::Function),
-        attempt(bytes(&b"goto"[..])).map(|_| Token::Goto),
-        attempt(bytes(&b"if"[..])).map(|_| Token::If),
-        attempt(bytes(&b"in"[..])).map(|_| Token::In),
-        attempt(bytes(&b"local"[..])).map(|_| Token::Local),
-        attempt(bytes(&b"nil"[..])).map(|_| Token::Nil),
-        attempt(bytes(&b"not"[..])).map(|_| Token::Not),
-        attempt(bytes(&b"or"[..])).map(|_| Token::Or),
-        attempt(bytes(&b"repeat"[..])).map(|_| Token::Repeat),
-        attempt(bytes(&b"return"[..])).map(|_| Token::Return),
-        attempt(bytes(&b"then"[..])).map(|_| Token::Then),
-        attempt(bytes(&b"true"[..])).map(|_| Token::True),
-        attempt(bytes(&b"until"[..])).map(|_| Token::Until),
-        attempt(bytes(&b"while"[..])).map(|_| Token::While),
+        space.map(|_| TriviaKind::Whitespace),
+        comment().map(|_| TriviaKind::Comment),
     ))
 }
 
+/// `--` and the rest of the line, or `--` and a long bracket such as
+/// `[==[ ... ]==]`, which may span lines.
+fn comment<'a, Input>() -> impl Parser<Input, Output = ()> + 'a
+where
+    Input: ByteStream<'a>,
+{
+    attempt(bytes(&b"--"[..])).with(combine::parser(|input: &mut Input| {
+        let peek = |input: &mut Input| {
+            let checkpoint = input.checkpoint();
+            let c = input.uncons().ok();
+            input.reset(checkpoint).ok()?;
+            c
+        };
+        // the opening bracket; when it turns out not to be one, what was
+        // read of it is part of a short comment
+        let mut level = None;
+        if peek(input) == Some(b'[') {
+            let _ = input.uncons();
+            let mut n = 0;
+            while peek(input) == Some(b'=') {
+                let _ = input.uncons();
+                n += 1;
+            }
+            if peek(input) == Some(b'[') {
+                let _ = input.uncons();
+                level = Some(n);
+            }
+        }
+        let Some(level) = level else {
+            while peek(input).is_some_and(|c| c != b'\n') {
+                let _ = input.uncons();
+            }
+            return Ok(((), Commit::Commit(())));
+        };
+        // the closing bracket, of the same level
+        loop {
+            match input.uncons().ok() {
+                Some(b']') => {
+                    let mut n = 0;
+                    while peek(input) == Some(b'=') {
+                        let _ = input.uncons();
+                        n += 1;
+                    }
+                    if n == level && peek(input) == Some(b']') {
+                        let _ = input.uncons();
+                        return Ok(((), Commit::Commit(())));
+                    }
+                }
+                Some(_) => (),
+                None => {
+                    let err = StreamErrorFor::<Input>::message_format(
+                        "unfinished long comment near <eof>",
+                    );
+                    let err = Input::Error::from_error(input.position(), err);
+                    return Err(Commit::Commit(err.into()));
+                }
+            }
+        }
+    }))
+}
+
+/// The keyword spelled by `name`, if any. Keywords are recognized only
+/// after reading a whole name, so that e.g. `inspect` is not `in spect`.
+fn keyword(name: &[u8]) -> Option<Token> {
+    let t = match name {
+        b"and" => Token::And,
+        b"break" => Token::Break,
+        b"do" => Token::Do,
+        b"else" => Token::Else,
+        b"elseif" => Token::Elseif,
+        b"end" => Token::End,
+        b"false" => Token::False,
+        b"for" => Token::For,
+        b"function" => Token::Function,
+        b"goto" => Token::Goto,
+        b"if" => Token::If,
+        b"in" => Token::In,
+        b"local" => Token::Local,
+        b"nil" => Token::Nil,
+        b"not" => Token::Not,
+        b"or" => Token::Or,
+        b"repeat" => Token::Repeat,
+        b"return" => Token::Return,
+        b"then" => Token::Then,
+        b"true" => Token::True,
+        b"until" => Token::Until,
+        b"while" => Token::While,
+        _ => return None,
+    };
+    Some(t)
+}
+
 fn operators<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
 where
     Input: ByteStream<'a>,
@@ -216,29 +666,280 @@ where
     ))
 }
 
-fn integer<Input>() -> impl Parser<Input, Output = Token>
+fn string<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
 where
-    Input: Stream<Token = u8>,
+    Input: ByteStream<'a>,
 {
-    from_str(many1::<Vec<_>, _, _>(digit())).map(Token::Integer)
+    satisfy(|c| c == b'"' || c == b'\'').then(|quote| {
+        combine::parser(move |input: &mut Input| {
+            let mut scanner = StringScanner {
+                input,
+                raw: vec![quote],
+            };
+            match scanner.scan(quote) {
+                Ok(s) => Ok((Token::String(s), Commit::Commit(()))),
+                Err(msg) => {
+                    let err = StreamErrorFor::<Input>::message_format(msg);
+                    let err = Input::Error::from_error(scanner.input.position(), err);
+                    Err(Commit::Commit(err.into()))
+                }
+            }
+        })
+    })
+}
+
+/// Scans the body of a string literal, decoding escape sequences. Errors
+/// follow the wording of the reference lexer, quoting the literal read so far.
+struct StringScanner<'s, Input> {
+    input: &'s mut Input,
+    // source text consumed so far, for error messages
+    raw: Vec<u8>,
+}
+
+impl<'a, Input: ByteStream<'a>> StringScanner<'_, Input> {
+    fn peek(&mut self) -> Option<u8> {
+        let checkpoint = self.input.checkpoint();
+        let c = self.input.uncons().ok();
+        self.input.reset(checkpoint).ok()?;
+        c
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let c = self.input.uncons().ok()?;
+        self.raw.push(c);
+        Some(c)
+    }
+
+    fn error<T>(&self, msg: &str, eof: bool) -> Result<T, String> {
+        if eof {
+            Err(format!("{msg} near <eof>"))
+        } else {
+            Err(format!(
+                "{msg} near '{}'",
+                String::from_utf8_lossy(&self.raw)
+            ))
+        }
+    }
+
+    fn scan(&mut self, quote: u8) -> Result<Vec<u8>, String> {
+        let mut buf = Vec::new();
+        loop {
+            match self.next() {
+                None => return self.error("unfinished string", true),
+                Some(b'\n' | b'\r') => return self.error("unfinished string", false),
+                Some(c) if c == quote => return Ok(buf),
+                Some(b'\\') => self.escape(&mut buf)?,
+                Some(c) => buf.push(c),
+            }
+        }
+    }
+
+    fn escape(&mut self, buf: &mut Vec<u8>) -> Result<(), String> {
+        let c = match self.peek() {
+            None => return self.error("unfinished string", true),
+            Some(c) => c,
+        };
+        let byte = match c {
+            b'a' => 0x07,
+            b'b' => 0x08,
+            b'f' => 0x0c,
+            b'n' => b'\n',
+            b'r' => b'\r',
+            b't' => b'\t',
+            b'v' => 0x0b,
+            b'\\' | b'"' | b'\'' => c,
+            b'\n' | b'\r' => {
+                // escaped line break, "\r\n" and "\n\r" count as one
+                self.next();
+                match self.peek() {
+                    Some(d @ (b'\n' | b'\r')) if d != c => {
+                        self.next();
+                    }
+                    _ => (),
+                }
+                buf.push(b'\n');
+                return Ok(());
+            }
+            b'x' => {
+                self.next();
+                let hi = self.hex_digit()?;
+                let lo = self.hex_digit()?;
+                buf.push((hi << 4 | lo) as u8);
+                return Ok(());
+            }
+            b'z' => {
+                self.next();
+                while self.peek().is_some_and(|c| c.is_ascii_whitespace()) {
+                    self.next();
+                }
+                return Ok(());
+            }
+            b'u' => {
+                self.next();
+                return self.utf8_escape(buf);
+            }
+            b'0'..=b'9' => {
+                let mut n = 0;
+                for _ in 0..3 {
+                    match self.peek() {
+                        Some(d @ b'0'..=b'9') => {
+                            self.next();
+                            n = n * 10 + (d - b'0') as u32;
+                        }
+                        _ => break,
+                    }
+                }
+                if n > 255 {
+                    // include the next character, as the reference lexer does
+                    self.next();
+                    return self.error("decimal escape too large", false);
+                }
+                buf.push(n as u8);
+                return Ok(());
+            }
+            _ => {
+                self.next();
+                return self.error("invalid escape sequence", false);
+            }
+        };
+        self.next();
+        buf.push(byte);
+        Ok(())
+    }
+
+    fn hex_digit(&mut self) -> Result<u32, String> {
+        match self.next() {
+            Some(c) if c.is_ascii_hexdigit() => Ok((c as char).to_digit(16).unwrap()),
+            c => self.error("hexadecimal digit expected", c.is_none()),
+        }
+    }
+
+    // \u{XXX}, encoded like the reference implementation: any value below
+    // 2^31, using the original (up to 6 byte) UTF-8 scheme
+    fn utf8_escape(&mut self, buf: &mut Vec<u8>) -> Result<(), String> {
+        match self.next() {
+            Some(b'{') => (),
+            c => return self.error("missing '{' in \\u{xxxx}", c.is_none()),
+        }
+        let mut code = self.hex_digit()?;
+        loop {
+            match self.peek() {
+                Some(c) if c.is_ascii_hexdigit() => {
+                    self.next();
+                    code = code
+                        .checked_mul(16)
+                        .map(|code| code + (c as char).to_digit(16).unwrap())
+                        .filter(|&code| code <= 0x7fff_ffff)
+                        .map_or_else(|| self.error("UTF-8 value too large", false), Ok)?;
+                }
+                Some(b'}') => {
+                    self.next();
+                    break;
+                }
+                c => {
+                    self.next();
+                    return self.error("missing '}' in \\u{xxxx}", c.is_none());
+                }
+            }
+        }
+        utf8_encode(code, buf);
+        Ok(())
+    }
 }
 
-fn float<Input>() -> impl Parser<Input, Output = Token>
+fn utf8_encode(code: u32, buf: &mut Vec<u8>) {
+    if code < 0x80 {
+        buf.push(code as u8);
+        return;
+    }
+    let mut bytes = Vec::new();
+    let mut code = code;
+    // largest value that fits in the first byte
+    let mut first_max = 0x3f;
+    loop {
+        bytes.push(0x80 | (code & 0x3f) as u8);
+        code >>= 6;
+        first_max >>= 1;
+        if code <= first_max {
+            break;
+        }
+    }
+    bytes.push(((!first_max << 1) | code) as u8);
+    buf.extend(bytes.iter().rev());
+}
+
+/// A numeral, read like the reference lexer: greedily, then converted as a
+/// whole, so that e.g. `3x` is a malformed number rather than `3` and `x`.
+fn numeral<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
 where
-    Input: Stream<Token = u8>,
+    Input: ByteStream<'a>,
 {
-    from_str(recognize::<Vec<_>, _, _>((
-        many1::<Vec<_>, _, _>(digit()),
-        token(b'.'),
-        many1::<Vec<_>, _, _>(digit()),
-    )))
-    .map(Token::Float)
+    let start = digit().or(attempt(token(b'.').skip(look_ahead(digit()))));
+    start.then(|first| {
+        combine::parser(move |input: &mut Input| {
+            let mut raw = vec![first];
+            let peek = |input: &mut Input| {
+                let checkpoint = input.checkpoint();
+                let c = input.uncons().ok();
+                input.reset(checkpoint).ok()?;
+                c
+            };
+            let mut exponent = b"Ee";
+            if first == b'0' {
+                if let Some(c @ (b'x' | b'X')) = peek(input) {
+                    let _ = input.uncons();
+                    raw.push(c);
+                    exponent = b"Pp";
+                }
+            }
+            loop {
+                match peek(input) {
+                    Some(c) if exponent.contains(&c) => {
+                        let _ = input.uncons();
+                        raw.push(c);
+                        if let Some(sign @ (b'+' | b'-')) = peek(input) {
+                            let _ = input.uncons();
+                            raw.push(sign);
+                        }
+                    }
+                    Some(c) if c.is_ascii_hexdigit() || c == b'.' => {
+                        let _ = input.uncons();
+                        raw.push(c);
+                    }
+                    _ => break,
+                }
+            }
+            // a letter right after the numeral makes it malformed
+            if let Some(c) = peek(input).filter(|c| c.is_ascii_alphabetic() || *c == b'_') {
+                let _ = input.uncons();
+                raw.push(c);
+            }
+
+            match str2number(&raw) {
+                Some(Value::Integer(i)) => Ok((Token::Integer(i), Commit::Commit(()))),
+                Some(Value::Float(f)) => Ok((Token::Float(f), Commit::Commit(()))),
+                _ => {
+                    let msg = format!("malformed number near '{}'", String::from_utf8_lossy(&raw));
+                    let err = StreamErrorFor::<Input>::message_format(msg);
+                    let err = Input::Error::from_error(input.position(), err);
+                    Err(Commit::Commit(err.into()))
+                }
+            }
+        })
+    })
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    fn lua_token<'a, Input>() -> impl Parser<Input, Output = Token> + 'a
+    where
+        Input: ByteStream<'a>,
+    {
+        skip_many(trivia()).with(token_body())
+    }
+
     #[test]
     fn parse_elseif() {
         let (tok, rest) = lua_token().parse(&b"elseif"[..]).unwrap();
@@ -274,6 +975,165 @@ mod tests {
         assert!(rest.is_empty());
     }
 
+    #[test]
+    fn parse_numerals() {
+        for (src, tok) in [
+            (&b"0xff"[..], Token::Integer(255)),
+            (b".5", Token::Float(0.5)),
+            (b"1e-2", Token::Float(0.01)),
+            (b"0x1p+1", Token::Float(2.0)),
+            (b"9223372036854775808", Token::Float(9223372036854775808.0)),
+        ] {
+            let (t, rest) = lua_token().parse(src).unwrap();
+            assert_eq!(t, tok);
+            assert!(rest.is_empty());
+        }
+        // `..` after a numeral is part of it, as in the reference lexer
+        assert_eq!(lex_error(b"3x"), "malformed number near '3x'");
+        assert_eq!(lex_error(b"1..2"), "malformed number near '1..2'");
+        assert_eq!(lex_error(b"0x"), "malformed number near '0x'");
+    }
+
+    #[test]
+    fn parse_keyword_prefixed_name() {
+        let (tok, rest) = lua_token().parse(&b"inspect"[..]).unwrap();
+        assert_eq!(tok, Token::Name("inspect".into()));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn parse_underscore_name() {
+        let (tok, rest) = lua_token().parse(&b"_assert_eq2"[..]).unwrap();
+        assert_eq!(tok, Token::Name("_assert_eq2".into()));
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn parse_escapes() {
+        let src = br#"'a\tb\65\x41\u{48}\u{7FF}\z
+            c\"\
+'"#;
+        let (tok, rest) = lua_token().parse(&src[..]).unwrap();
+        assert_eq!(tok, Token::String(b"a\tbAAH\xdf\xbfc\"\n".to_vec()));
+        assert!(rest.is_empty());
+    }
+
+    fn lexer(src: &[u8]) -> Lex<impl ByteStream<'_> + StreamOnce<Position = Location>> {
+        Lex::new(position::Stream::with_positioner(src, Location::default()))
+    }
+
+    fn lex_error(src: &[u8]) -> String {
+        let input = easy::Stream(position::Stream::with_positioner(src, Location::default()));
+        let msg = lua_token().parse(input).unwrap_err().describe();
+        // drop the location suffix
+        msg[..msg.rfind(" at line").unwrap()].to_string()
+    }
+
+    #[test]
+    fn escape_errors() {
+        assert_eq!(
+            lex_error(br#""\256""#),
+            r#"decimal escape too large near '"\256"'"#
+        );
+        assert_eq!(
+            lex_error(br#""\xg""#),
+            r#"hexadecimal digit expected near '"\xg'"#
+        );
+        assert_eq!(
+            lex_error(br#""\u{12""#),
+            r#"missing '}' in \u{xxxx} near '"\u{12"'"#
+        );
+        assert_eq!(
+            lex_error(br#""\u12""#),
+            r#"missing '{' in \u{xxxx} near '"\u1'"#
+        );
+        assert_eq!(
+            lex_error(br#""\u{80000000}""#),
+            r#"UTF-8 value too large near '"\u{80000000'"#
+        );
+        assert_eq!(
+            lex_error(br#""\q""#),
+            r#"invalid escape sequence near '"\q'"#
+        );
+        assert_eq!(lex_error(br#""abc"#), "unfinished string near <eof>");
+    }
+
+    #[test]
+    fn unexpected_character() {
+        let input = easy::Stream(position::Stream::with_positioner(
+            &b"print\n  @"[..],
+            Location::default(),
+        ));
+        let (_, rest) = lua_token().parse(input).unwrap();
+        let err = lua_token().parse(rest).unwrap_err().describe();
+        assert_eq!(
+            err,
+            "unexpected character '@' at line 2, column 3 (offset 8)"
+        );
+    }
+
+    #[test]
+    fn skip_prefix() {
+        let mut lex = lexer(b"\xef\xbb\xbf#!/usr/bin/env kailua\nprint");
+        lex.skip_prefix().unwrap();
+        assert_eq!(lex.next().unwrap(), Token::Name("print".into()));
+
+        let mut lex = lexer(b"\xff\xfep\0");
+        assert!(lex.skip_prefix().is_err());
+    }
+
+    #[test]
+    fn skip_comments() {
+        let src = b"-- line\na --[==[ long ]] ]=] ]==] - --[ short\n--[[]]b";
+        let mut lex = lexer(src);
+        assert_eq!(lex.next().unwrap(), Token::Name("a".into()));
+        assert_eq!(lex.next().unwrap(), Token::Sub);
+        assert_eq!(lex.span().to_string(), "line 2, columns 27-27");
+        assert_eq!(lex.next().unwrap(), Token::Name("b".into()));
+        assert_eq!(lex.next().unwrap(), Token::Eos);
+
+        assert_eq!(
+            lex_error(b"--[[ open"),
+            "unfinished long comment near <eof>"
+        );
+    }
+
+    #[test]
+    fn lossless() {
+        let src = b"#!kailua\nprint( 'a\\65' ) -- hi\n--[[ bye ]]\n";
+        let tokens = tokenize(src).unwrap();
+        let kinds: Vec<_> = tokens
+            .iter()
+            .map(|t| (t.trivia.iter().map(|t| t.kind).collect::<Vec<_>>(), t.text))
+            .collect();
+        use TriviaKind::*;
+        assert_eq!(
+            kinds,
+            [
+                (vec![Prefix, Whitespace], &b"print"[..]),
+                (vec![], b"("),
+                (vec![Whitespace], b"'a\\65'"),
+                (vec![Whitespace], b")"),
+                (
+                    vec![Whitespace, Comment, Whitespace, Comment, Whitespace],
+                    b""
+                ),
+            ]
+        );
+        assert_eq!(tokens[2].token, Token::String(b"aA".to_vec()));
+        assert_eq!(tokens[2].location.line, 2);
+        assert_eq!(tokens[4].trivia[1].text, b"-- hi");
+
+        let joined: Vec<u8> = tokens
+            .iter()
+            .flat_map(|t| t.trivia.iter().map(|t| t.text).chain([t.text]))
+            .flatten()
+            .copied()
+            .collect();
+        assert_eq!(joined, src);
+        assert!(tokenize(b"'open").is_err());
+    }
+
     #[test]
     fn parse_sentence() {
         let (t1, rest) = lua_token().parse(&br#"print "hello, world!""#[..]).unwrap();
@@ -0,0 +1,39 @@
+pub mod arith;
+pub mod ast;
+pub mod bytecode;
+#[cfg(feature = "capi")]
+pub mod capi;
+#[cfg(feature = "serde")]
+pub mod chunk;
+#[cfg(feature = "vm")]
+pub mod debug;
+pub mod doc;
+#[cfg(feature = "vm")]
+pub mod hook;
+#[cfg(feature = "vm")]
+pub mod image;
+pub mod inspect;
+pub mod intern;
+pub mod json;
+pub mod lex;
+#[cfg(feature = "vm")]
+pub mod math;
+pub mod numfmt;
+#[cfg(feature = "vm")]
+pub mod os;
+#[cfg(feature = "vm")]
+pub mod package;
+pub mod parse;
+#[cfg(feature = "vm")]
+pub mod sandbox;
+#[cfg(feature = "vm")]
+pub mod stats;
+#[cfg(feature = "vm")]
+pub mod stdio;
+#[cfg(feature = "vm")]
+pub mod string;
+#[cfg(feature = "vm")]
+pub mod table;
+pub mod value;
+#[cfg(feature = "vm")]
+pub mod vm;
@@ -1,26 +1,234 @@
 use std::fs::File;
 use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
-use clap::Parser;
+use anyhow::{anyhow, Context};
+use clap::{Parser, Subcommand};
+use kailua::{
+    ast, doc,
+    lex::SyntaxError,
+    os::Exit,
+    parse,
+    sandbox::SandboxPolicy,
+    value::{AllocCounts, LuaInt},
+    vm,
+};
 
-mod bytecode;
-mod lex;
-mod parse;
-mod value;
-mod vm;
+mod repl;
+mod serve;
+mod test_runner;
 
 #[derive(Parser)]
+#[command(args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// script
-    script: PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Enter interactive mode after running the script
+    #[arg(short)]
+    interactive: bool,
+
+    /// Turn warnings on, as if the script started with `warn("@on")`
+    #[arg(short = 'W')]
+    warnings: bool,
+
+    /// Seed for `math.random`, to make its sequence reproducible
+    #[arg(long)]
+    seed: Option<LuaInt>,
+
+    /// Deny scripts access to files and environment variables
+    #[arg(long)]
+    sandbox: bool,
+
+    /// Print counters of executed instructions and calls to stderr on exit
+    #[arg(long)]
+    stats: bool,
+
+    /// Make reading a global that was never assigned an error
+    #[arg(long)]
+    strict: bool,
+
+    /// Make declaring a local with the name of another local an error,
+    /// instead of a warning shown with -W
+    #[arg(long)]
+    deny_shadowing: bool,
+
+    /// Point runtime errors at the part of the source that failed
+    #[arg(long)]
+    spans: bool,
+
+    /// Print peak stack depth, allocations and global count to stderr on exit
+    #[arg(long)]
+    profile_memory: bool,
+
+    /// scripts to run one after the other in the same state, so that
+    /// globals set by one are seen by the next; interactive mode if none
+    scripts: Vec<PathBuf>,
 }
 
-fn main() -> anyhow::Result<()> {
+#[derive(Subcommand)]
+enum Command {
+    /// Run every `*_test.lua` file under a directory
+    Test {
+        /// directory to search for tests
+        dir: PathBuf,
+    },
+    /// Print Markdown API docs from the `---` comments of Lua sources
+    Doc {
+        /// sources to document, a section each
+        #[arg(required = true)]
+        files: Vec<PathBuf>,
+    },
+    /// Print the syntax tree of a Lua source, an indented node per line,
+    /// as the bytecode dump does for the compiled chunk
+    Ast {
+        /// source to parse
+        file: PathBuf,
+        /// print JSON instead, for tools
+        #[arg(long)]
+        json: bool,
+    },
+    /// Serve a persistent state over stdin and stdout with JSON-RPC, a
+    /// message per line, for other processes to drive
+    Serve,
+}
+
+/// Exits with status 1 when the script raises an error, 2 on a usage error,
+/// or the status the script passes to `os.exit`.
+fn main() -> anyhow::Result<ExitCode> {
     let cli = Cli::parse();
-    let file = File::open(cli.script)?;
 
-    let proto = parse::ParseProto::load(file)?;
-    vm::ExeState::new().execute(&proto)?;
+    match cli.command {
+        Some(Command::Test { dir }) => test_runner::run(&dir),
+        Some(Command::Serve) => exit_code(serve::run(&mut vm::ExeState::new())),
+        Some(Command::Doc { files }) => {
+            for file in &files {
+                let source = std::fs::read_to_string(file)
+                    .with_context(|| format!("cannot open {}", file.display()))?;
+                let title = file.file_stem().unwrap_or_default().to_string_lossy();
+                print!("{}", doc::extract(&source)?.to_markdown(&title));
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        Some(Command::Ast { file, json }) => {
+            let source =
+                std::fs::read(&file).with_context(|| format!("cannot open {}", file.display()))?;
+            let tree =
+                ast::parse(&source).map_err(|err| match err.downcast_ref::<SyntaxError>() {
+                    Some(e) => anyhow!("{}:{}: {e}", file.display(), e.span.start.line),
+                    None => err,
+                })?;
+            if json {
+                print!("{}", tree.to_json());
+            } else {
+                print!("{tree}");
+            }
+            Ok(ExitCode::SUCCESS)
+        }
+        None => {
+            let mut builder = vm::ExeState::builder()
+                .stats(cli.stats)
+                .strict(cli.strict)
+                .warnings(cli.warnings);
+            if cli.sandbox {
+                builder = builder.sandbox(SandboxPolicy::locked());
+            }
+            if let Some(seed) = cli.seed {
+                builder = builder.seed(seed);
+            }
+            builder = builder.interrupt(interrupt_flag()?);
+            let mut state = builder.build();
+            let allocs = AllocCounts::current();
+            let result = run(&cli, &mut state);
+            if let Some(stats) = state.stats() {
+                eprint!("{stats}");
+            }
+            if cli.profile_memory {
+                print_memory_profile(&state, AllocCounts::current().since(allocs));
+            }
+            exit_code(result)
+        }
+    }
+}
 
+/// The status to exit with after `result`, that of `os.exit` if called.
+fn exit_code(result: anyhow::Result<()>) -> anyhow::Result<ExitCode> {
+    match result {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        // truncated to a byte, as the status of C's `exit` is
+        Err(err) => match err.downcast_ref::<Exit>() {
+            Some(&Exit(code)) => Ok(ExitCode::from(code as u8)),
+            None => Err(err),
+        },
+    }
+}
+
+fn run(cli: &Cli, state: &mut vm::ExeState) -> anyhow::Result<()> {
+    let options = parse::ParseOptions {
+        shadowing: if cli.deny_shadowing {
+            parse::Shadowing::Deny
+        } else {
+            parse::Shadowing::Warn
+        },
+        spans: cli.spans,
+        ..Default::default()
+    };
+    // each is compiled only when the ones before it have run, as if it
+    // was loaded by them
+    for script in &cli.scripts {
+        let file =
+            File::open(script).with_context(|| format!("cannot open {}", script.display()))?;
+        let options = parse::ParseOptions {
+            chunk_name: script.display().to_string(),
+            ..options.clone()
+        };
+        let proto = state.load(file, options)?;
+        for warning in &proto.warnings {
+            state.warn(warning);
+        }
+        state
+            .execute(proto)
+            .map_err(|err| with_traceback(state, err))?;
+    }
+    if cli.interactive || cli.scripts.is_empty() {
+        repl::run(state)?;
+    }
     Ok(())
 }
+
+/// Error `err` of a script, followed by the traceback of where it was
+/// raised if nothing caught it, as the reference interpreter shows it.
+fn with_traceback(state: &mut vm::ExeState, err: anyhow::Error) -> anyhow::Error {
+    match state.take_traceback() {
+        Some(traceback) => anyhow!("{err:#}\n{traceback}"),
+        None => err,
+    }
+}
+
+/// A flag set by Ctrl-C, which stops the running script. A second Ctrl-C
+/// before the script has stopped ends the process, as SIGINT would.
+fn interrupt_flag() -> anyhow::Result<Arc<AtomicBool>> {
+    let flag = Arc::new(AtomicBool::new(false));
+    let handler_flag = flag.clone();
+    ctrlc::set_handler(move || {
+        if handler_flag.swap(true, Ordering::Relaxed) {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(flag)
+}
+
+fn print_memory_profile(state: &vm::ExeState, allocs: AllocCounts) {
+    eprintln!("memory profile:");
+    eprintln!("    peak stack depth    {}", state.peak_stack_size());
+    eprintln!("    tables allocated    {}", allocs.tables);
+    eprintln!("    strings allocated   {}", allocs.strings);
+    eprintln!("    globals             {}", state.globals().count());
+    let interned = state.intern_stats();
+    eprintln!(
+        "    shared constants    {} ({} bytes)",
+        interned.shared, interned.bytes_saved
+    );
+}
@@ -0,0 +1,264 @@
+//! The `math` library. Random numbers come from xoshiro256**, seeded the
+//! same way as the reference implementation, so that a given seed always
+//! produces the same sequence.
+
+#[cfg(feature = "float32")]
+use std::f32::consts::PI;
+#[cfg(not(feature = "float32"))]
+use std::f64::consts::PI;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::bail;
+
+use crate::{
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+
+/// Build the `math` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("random".into(), Value::Function(lib_random));
+    t.map
+        .insert("randomseed".into(), Value::Function(lib_randomseed));
+    t.map.insert("maxinteger".into(), LuaInt::MAX.into());
+    t.map.insert("mininteger".into(), LuaInt::MIN.into());
+    t.map.insert("huge".into(), LuaFloat::INFINITY.into());
+    t.map.insert("pi".into(), PI.into());
+    t.into()
+}
+
+// math.random([m [, n]])
+fn lib_random(state: &mut ExeState) -> anyhow::Result<i32> {
+    let (low, up) = match state.get_top() {
+        0 => {
+            let f = state.rng().next_float();
+            state.push(f.into());
+            return Ok(1);
+        }
+        1 => (1, check_int(state, 1, "random")?),
+        2 => (
+            check_int(state, 1, "random")?,
+            check_int(state, 2, "random")?,
+        ),
+        _ => bail!("wrong number of arguments"),
+    };
+    // random(0) gives all bits
+    if state.get_top() == 1 && up == 0 {
+        let n = state.rng().next_u64() as LuaInt;
+        state.push(n.into());
+        return Ok(1);
+    }
+    // the reference implementation blames the first argument either way
+    if low > up {
+        bail!("bad argument #1 to 'random' (interval is empty)");
+    }
+    let n = state.rng().range(low, up);
+    state.push(n.into());
+    Ok(1)
+}
+
+// math.randomseed([x [, y]])
+fn lib_randomseed(state: &mut ExeState) -> anyhow::Result<i32> {
+    let (n1, n2) = if state.get_top() == 0 {
+        (default_seed(), 0)
+    } else {
+        let n1 = check_int(state, 1, "randomseed")?;
+        let n2 = match state.arg(2) {
+            Value::Nil => 0,
+            _ => check_int(state, 2, "randomseed")?,
+        };
+        (n1, n2)
+    };
+    state.rng().seed(n1, n2);
+    // the seed, to repeat the sequence with later
+    state.push(n1.into());
+    state.push(n2.into());
+    Ok(2)
+}
+
+fn check_int(state: &ExeState, i: usize, fname: &str) -> anyhow::Result<LuaInt> {
+    match *state.arg(i) {
+        Value::Integer(n) => Ok(n),
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < LuaInt::MAX as LuaFloat => Ok(f as LuaInt),
+        Value::Float(_) => {
+            bail!("bad argument #{i} to '{fname}' (number has no integer representation)")
+        }
+        _ => bail!(
+            "bad argument #{i} to '{fname}' (number expected, got {})",
+            state.arg_type_name(i)
+        ),
+    }
+}
+
+/// The seed when none is given: one that differs between runs, or 0 with
+/// the `deterministic` feature.
+pub(crate) fn default_seed() -> LuaInt {
+    if cfg!(feature = "deterministic") {
+        return 0;
+    }
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_nanos() as LuaInt)
+}
+
+/// The xoshiro256** generator.
+#[derive(Debug, Clone)]
+pub struct Rng {
+    s: [u64; 4],
+}
+
+impl Rng {
+    pub fn new(seed: LuaInt) -> Self {
+        let mut rng = Self { s: [0; 4] };
+        rng.seed(seed, 0);
+        rng
+    }
+
+    pub fn seed(&mut self, n1: LuaInt, n2: LuaInt) {
+        self.s = [n1 as u64, 0xff, n2 as u64, 0];
+        // discard initial values to "spread" the seed
+        for _ in 0..16 {
+            self.next_u64();
+        }
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let s = &mut self.s;
+        let result = s[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = s[1] << 17;
+        s[2] ^= s[0];
+        s[3] ^= s[1];
+        s[1] ^= s[2];
+        s[0] ^= s[3];
+        s[2] ^= t;
+        s[3] = s[3].rotate_left(45);
+        result
+    }
+
+    /// A float in `[0, 1)` from as many high bits as a float has digits.
+    pub fn next_float(&mut self) -> LuaFloat {
+        let digits = LuaFloat::MANTISSA_DIGITS;
+        (self.next_u64() >> (64 - digits)) as LuaFloat * (0.5 / (1u64 << (digits - 1)) as LuaFloat)
+    }
+
+    /// An integer in `[low, up]`, which must not be empty.
+    pub fn range(&mut self, low: LuaInt, up: LuaInt) -> LuaInt {
+        let n = (up as u64).wrapping_sub(low as u64);
+        let mut ran = self.next_u64();
+        let r = if n & n.wrapping_add(1) == 0 {
+            // n + 1 is a power of 2
+            ran & n
+        } else {
+            // smallest 2^b - 1 not smaller than n, rejecting values above n
+            let lim = u64::MAX >> n.leading_zeros();
+            loop {
+                ran &= lim;
+                if ran <= n {
+                    break ran;
+                }
+                ran = self.next_u64();
+            }
+        };
+        r.wrapping_add(low as u64) as LuaInt
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RuntimeError;
+
+    #[test]
+    fn same_seed_same_sequence() {
+        let mut a = Rng::new(42);
+        let mut b = Rng::new(42);
+        for _ in 0..100 {
+            assert_eq!(a.next_u64(), b.next_u64());
+        }
+        assert_ne!(Rng::new(1).next_u64(), Rng::new(2).next_u64());
+    }
+
+    #[test]
+    fn ranges() {
+        let mut rng = Rng::new(7);
+        for _ in 0..1000 {
+            let f = rng.next_float();
+            assert!((0.0..1.0).contains(&f));
+            assert!((1..=6).contains(&rng.range(1, 6)));
+            assert!((-3..=-2).contains(&rng.range(-3, -2)));
+        }
+        assert_eq!(rng.range(5, 5), 5);
+        rng.range(LuaInt::MIN, LuaInt::MAX);
+    }
+
+    #[test]
+    fn constants() {
+        let mut state = ExeState::new();
+        let results = state
+            .eval("return math.maxinteger, math.mininteger, math.huge, -math.huge, math.pi")
+            .unwrap();
+        assert_eq!(
+            results,
+            [
+                LuaInt::MAX.into(),
+                LuaInt::MIN.into(),
+                LuaFloat::INFINITY.into(),
+                LuaFloat::NEG_INFINITY.into(),
+                PI.into()
+            ]
+        );
+        // wrapping around as integers do
+        let results = state
+            .eval("return math.maxinteger + 1 == math.mininteger")
+            .unwrap();
+        assert_eq!(results, [true.into()]);
+    }
+
+    #[test]
+    fn random_arguments() {
+        let mut state = ExeState::builder().seed(1).build();
+        let results = state
+            .eval("return math.random(), math.random(3), math.random(2, 2), math.random(0)")
+            .unwrap();
+        assert!(matches!(results[0], Value::Float(f) if (0.0..1.0).contains(&f)));
+        assert!(matches!(results[1], Value::Integer(1..=3)));
+        assert_eq!(results[2], Value::Integer(2));
+        assert!(matches!(results[3], Value::Integer(_)));
+
+        // a seed from randomseed repeats the sequence
+        let results = state.eval("return math.randomseed()").unwrap();
+        let Value::Integer(seed) = results[0] else {
+            panic!("{results:?}")
+        };
+        let first = state.eval("return math.random(0)").unwrap();
+        state.set_global("seed", Value::Integer(seed));
+        let src = "math.randomseed(seed) return math.random(0)";
+        assert_eq!(state.eval(src).unwrap(), first);
+
+        let error = |state: &mut ExeState, src| {
+            state
+                .eval(src)
+                .unwrap_err()
+                .downcast::<RuntimeError>()
+                .unwrap()
+                .message
+        };
+        assert_eq!(
+            error(&mut state, "return math.random(3, 1)"),
+            "bad argument #1 to 'random' (interval is empty)"
+        );
+        assert_eq!(
+            error(&mut state, "return math.random(0, 0, 0)"),
+            "wrong number of arguments"
+        );
+        assert_eq!(
+            error(&mut state, "return math.random(1.5)"),
+            "bad argument #1 to 'random' (number has no integer representation)"
+        );
+        assert_eq!(
+            error(&mut state, "return math.random(1, 'x')"),
+            "bad argument #2 to 'random' (number expected, got string)"
+        );
+    }
+}
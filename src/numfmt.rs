@@ -0,0 +1,507 @@
+//! Conversions between numbers and text, in both directions, shared by the
+//! lexer, `tonumber`, `tostring` and `string.format` so that they all agree
+//! with each other and with the reference implementation. Numerals always
+//! use `.` as the decimal point, whatever the locale.
+
+use crate::value::{LuaFloat, LuaInt, Value};
+#[cfg(feature = "vm")]
+use crate::vm::ExeState;
+
+/// The number spelled by `s`, as the reference `lua_stringtonumber`:
+/// surrounding spaces and a sign are allowed, then a decimal or hexadecimal
+/// integer or float. Decimal integers too large for an integer are floats,
+/// hexadecimal ones wrap around.
+pub fn str2number(s: &[u8]) -> Option<Value> {
+    str2int(s)
+        .map(Value::Integer)
+        .or_else(|| str2float(s).map(Value::Float))
+}
+
+/// Like [`str2number`] for an integer in `base`, from 2 to 36, as in
+/// `tonumber(s, base)`.
+pub fn str2int_base(s: &[u8], base: u32) -> Option<LuaInt> {
+    let (neg, digits) = sign(trim(s));
+    if digits.is_empty() {
+        return None;
+    }
+    let mut n: LuaInt = 0;
+    for &c in digits {
+        let d = (c as char).to_digit(base)?;
+        n = n.wrapping_mul(base as LuaInt).wrapping_add(d as LuaInt);
+    }
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+fn str2int(s: &[u8]) -> Option<LuaInt> {
+    let (neg, s) = sign(trim(s));
+    let n = if let Some(hex) = hex_digits(s) {
+        if hex.is_empty() {
+            return None;
+        }
+        let mut n: LuaInt = 0;
+        for &c in hex {
+            let d = (c as char).to_digit(16)?;
+            n = n.wrapping_mul(16).wrapping_add(d as LuaInt);
+        }
+        n
+    } else {
+        if s.is_empty() {
+            return None;
+        }
+        // accumulate negatively, so that the most negative integer fits
+        let mut n: LuaInt = 0;
+        for &c in s {
+            let d = (c as char).to_digit(10)?;
+            n = n.checked_mul(10)?.checked_sub(d as LuaInt)?;
+        }
+        if neg {
+            return Some(n);
+        }
+        n.checked_neg()?
+    };
+    Some(if neg { n.wrapping_neg() } else { n })
+}
+
+fn str2float(s: &[u8]) -> Option<LuaFloat> {
+    // `inf` and `nan` are not numerals
+    if s.iter().any(|&c| c == b'n' || c == b'N') {
+        return None;
+    }
+    let (neg, s) = sign(trim(s));
+    let f = match hex_digits(s) {
+        Some(hex) => parse_hex_float(hex)?,
+        None => {
+            // Rust accepts the same decimal syntax as strtod, but a sign
+            // was taken already
+            if !s.first().is_some_and(|c| c.is_ascii_digit() || *c == b'.') {
+                return None;
+            }
+            std::str::from_utf8(s).ok()?.parse().ok()?
+        }
+    };
+    Some(if neg { -f } else { f })
+}
+
+/// `digits[.digits][p[sign]digits]` in hexadecimal, with the exponent in
+/// decimal as a power of 2. The first 16 significant digits are kept
+/// exactly and any after them only count towards rounding, so that the
+/// result is correctly rounded as by `strtod`, subnormals aside.
+fn parse_hex_float(s: &[u8]) -> Option<LuaFloat> {
+    let (mantissa, exponent) = match s.iter().position(|&c| c == b'p' || c == b'P') {
+        Some(i) => (&s[..i], Some(&s[i + 1..])),
+        None => (s, None),
+    };
+    let (int, frac) = match mantissa.iter().position(|&c| c == b'.') {
+        Some(i) => (&mantissa[..i], &mantissa[i + 1..]),
+        None => (mantissa, &[][..]),
+    };
+    if int.is_empty() && frac.is_empty() {
+        return None;
+    }
+    let mut m: u64 = 0;
+    // power of 2 to scale `m` by
+    let mut e: i64 = 0;
+    let mut sticky = false;
+    for (i, &c) in int.iter().chain(frac).enumerate() {
+        let d = (c as char).to_digit(16)? as u64;
+        if m >> 60 == 0 {
+            m = m << 4 | d;
+            if i >= int.len() {
+                e -= 4;
+            }
+        } else {
+            sticky |= d != 0;
+            if i < int.len() {
+                e += 4;
+            }
+        }
+    }
+    if let Some(exponent) = exponent {
+        let (neg, digits) = sign(exponent);
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            return None;
+        }
+        // huge exponents saturate, to infinity or zero
+        let x = std::str::from_utf8(digits)
+            .ok()?
+            .parse::<i64>()
+            .unwrap_or(i64::MAX)
+            .min(1 << 20);
+        e += if neg { -x } else { x };
+    }
+    // the conversion of 64 bits to the precision of a float rounds to
+    // nearest, and digits lost past them break ties upwards; scaling it in
+    // a double is then exact
+    let f: LuaFloat = (m | sticky as u64) as LuaFloat;
+    Some(ldexp(f as f64, e) as LuaFloat)
+}
+
+/// `f * 2^e`, in steps that do not overflow the exponent of a double.
+fn ldexp(mut f: f64, mut e: i64) -> f64 {
+    let pow2 = |e: i64| f64::from_bits(((e + 1023) as u64) << 52);
+    while e > 1023 {
+        f *= pow2(1023);
+        e -= 1023;
+        if f.is_infinite() {
+            return f;
+        }
+    }
+    while e < -1022 {
+        f *= pow2(-1022);
+        e += 1022;
+        if f == 0.0 {
+            return f;
+        }
+    }
+    f * pow2(e)
+}
+
+/// The digits after a `0x` or `0X` prefix, if `s` has one.
+fn hex_digits(s: &[u8]) -> Option<&[u8]> {
+    s.strip_prefix(b"0x").or_else(|| s.strip_prefix(b"0X"))
+}
+
+fn sign(s: &[u8]) -> (bool, &[u8]) {
+    match s.split_first() {
+        Some((b'-', rest)) => (true, rest),
+        Some((b'+', rest)) => (false, rest),
+        _ => (false, s),
+    }
+}
+
+/// `s` without the spaces around it, as C's `isspace` sees them.
+fn trim(s: &[u8]) -> &[u8] {
+    let is_space = |c: &u8| matches!(c, b' ' | b'\t' | b'\n' | b'\x0b' | b'\x0c' | b'\r');
+    let start = s.iter().position(|c| !is_space(c)).unwrap_or(s.len());
+    let end = s
+        .iter()
+        .rposition(|c| !is_space(c))
+        .map_or(start, |i| i + 1);
+    &s[start..end]
+}
+
+/// Significant digits of floats in `tostring`: `%.14g`, or `%.7g` for
+/// 32-bit floats, as in the reference.
+#[cfg(not(feature = "float32"))]
+const FLOAT_DIGITS: usize = 14;
+#[cfg(feature = "float32")]
+const FLOAT_DIGITS: usize = 7;
+
+/// `f` as `tostring` writes it: `%.14g`, with `.0` added when that looks
+/// like an integer, so that floats stay apart from integers.
+pub fn float_to_string(f: LuaFloat) -> String {
+    let mut s = if f.is_finite() {
+        let abs: LuaFloat = f.abs();
+        fmt_general(abs as f64, FLOAT_DIGITS, false)
+    } else if f.is_nan() {
+        "nan".into()
+    } else {
+        "inf".into()
+    };
+    if s.bytes().all(|c| c.is_ascii_digit()) {
+        s += ".0";
+    }
+    // as glibc writes them, NaNs keep their sign
+    if f.is_sign_negative() {
+        s.insert(0, '-');
+    }
+    s
+}
+
+/// `%f` of a finite `f >= 0`.
+pub fn fmt_fixed(f: f64, precision: usize, alt: bool) -> String {
+    let mut s = format!("{f:.precision$}");
+    if alt && precision == 0 {
+        s.push('.');
+    }
+    s
+}
+
+/// `%e` of a finite `f >= 0`: the exponent has a sign and two digits at
+/// least.
+pub fn fmt_exp(f: f64, precision: usize, alt: bool) -> String {
+    let s = format!("{f:.precision$e}");
+    let (mantissa, exp) = s.split_once('e').unwrap();
+    let exp: i32 = exp.parse().unwrap();
+    let dot = if alt && precision == 0 { "." } else { "" };
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("{mantissa}{dot}e{sign}{:02}", exp.abs())
+}
+
+/// `%g` of a finite `f >= 0`: `%e` for exponents below -4 or from the
+/// precision on, else `%f`, with trailing zeros removed unless `alt`.
+pub fn fmt_general(f: f64, precision: usize, alt: bool) -> String {
+    let p = precision.max(1);
+    // the exponent after rounding to `p` significant digits
+    let e = format!("{f:.*e}", p - 1);
+    let exp: i64 = e.split_once('e').unwrap().1.parse().unwrap();
+    let s = if exp < -4 || exp >= p as i64 {
+        fmt_exp(f, p - 1, alt)
+    } else {
+        fmt_fixed(f, (p as i64 - 1 - exp) as usize, alt)
+    };
+    if alt {
+        return s;
+    }
+    let (mantissa, exp) = match s.find('e') {
+        Some(i) => s.split_at(i),
+        None => (s.as_str(), ""),
+    };
+    let mantissa = match mantissa.contains('.') {
+        true => mantissa.trim_end_matches('0').trim_end_matches('.'),
+        false => mantissa,
+    };
+    format!("{mantissa}{exp}")
+}
+
+/// `%a` of a finite `f >= 0`: `0x1.hhhp+e` for normal numbers, without
+/// trailing zeros unless a precision asks for digits, as glibc writes it.
+pub fn fmt_hex(f: f64, precision: Option<usize>) -> String {
+    if f == 0.0 {
+        let zeros = "0".repeat(precision.unwrap_or(0));
+        let dot = if zeros.is_empty() { "" } else { "." };
+        return format!("0x0{dot}{zeros}p+0");
+    }
+    let bits = f.to_bits();
+    let biased = ((bits >> 52) & 0x7ff) as i64;
+    let mut mantissa = bits & ((1 << 52) - 1);
+    // subnormals have a leading 0 and the exponent of the smallest normal
+    let (mut lead, exp) = if biased == 0 {
+        (0, -1022)
+    } else {
+        (1, biased - 1023)
+    };
+    let mut ndigits = 13;
+    if let Some(p) = precision.filter(|&p| p < 13) {
+        // round half to even at the last digit kept
+        let shift = 4 * (13 - p);
+        let rest = mantissa & ((1 << shift) - 1);
+        let half = 1 << (shift - 1);
+        mantissa >>= shift;
+        if rest > half || (rest == half && mantissa & 1 == 1) {
+            mantissa += 1;
+            if mantissa >> (4 * p) != 0 {
+                mantissa &= (1 << (4 * p)) - 1;
+                lead += 1;
+            }
+        }
+        ndigits = p;
+    }
+    let mut digits = if ndigits == 0 {
+        String::new()
+    } else {
+        format!("{mantissa:0ndigits$x}")
+    };
+    match precision {
+        None => digits.truncate(digits.trim_end_matches('0').len()),
+        Some(p) => digits.extend(std::iter::repeat_n('0', p.saturating_sub(digits.len()))),
+    }
+    let dot = if digits.is_empty() { "" } else { "." };
+    let sign = if exp < 0 { '-' } else { '+' };
+    format!("0x{lead}{dot}{digits}p{sign}{}", exp.abs())
+}
+
+// tonumber(e [, base])
+#[cfg(feature = "vm")]
+pub(crate) fn lib_tonumber(state: &mut ExeState) -> anyhow::Result<i32> {
+    let v = match state.arg(2) {
+        Value::Nil => match state.arg(1) {
+            v @ (Value::Integer(_) | Value::Float(_)) => v.clone(),
+            _ if state.get_top() == 0 => {
+                anyhow::bail!("bad argument #1 to 'tonumber' (value expected)")
+            }
+            v => <&[u8]>::try_from(v)
+                .ok()
+                .and_then(str2number)
+                .unwrap_or_default(),
+        },
+        _ => {
+            // the base first, and no number in place of the string
+            let base = crate::vm::check_int(state, 2, "tonumber")?;
+            let s = match <&[u8]>::try_from(state.arg(1)) {
+                Ok(s) => s,
+                Err(_) => anyhow::bail!(
+                    "bad argument #1 to 'tonumber' (string expected, got {})",
+                    state.arg_type_name(1)
+                ),
+            };
+            if !(2..=36).contains(&base) {
+                anyhow::bail!("bad argument #2 to 'tonumber' (base out of range)");
+            }
+            str2int_base(s, base as u32).map_or(Value::Nil, Value::Integer)
+        }
+    };
+    state.push(v);
+    Ok(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(not(feature = "int32"))]
+    #[test]
+    fn integers() {
+        assert_eq!(str2number(b"10"), Some(Value::Integer(10)));
+        assert_eq!(str2number(b"  -0x10\t\n"), Some(Value::Integer(-16)));
+        assert_eq!(str2number(b"+7"), Some(Value::Integer(7)));
+        assert_eq!(
+            str2number(b"-9223372036854775808"),
+            Some(Value::Integer(i64::MIN))
+        );
+        // too large for an integer: a float, unless hexadecimal
+        assert_eq!(
+            str2number(b"9223372036854775808"),
+            Some(Value::Float(9223372036854775808.0))
+        );
+        assert_eq!(str2number(b"0xffffffffffffffff"), Some(Value::Integer(-1)));
+    }
+
+    #[cfg(not(feature = "float32"))]
+    #[test]
+    fn floats() {
+        assert_eq!(str2number(b"1.5"), Some(Value::Float(1.5)));
+        assert_eq!(str2number(b" .5e1 "), Some(Value::Float(5.0)));
+        assert_eq!(str2number(b"3."), Some(Value::Float(3.0)));
+        assert_eq!(str2number(b"-2E-1"), Some(Value::Float(-0.2)));
+        assert_eq!(str2number(b"0x.8"), Some(Value::Float(0.5)));
+        assert_eq!(str2number(b"0x1p4"), Some(Value::Float(16.0)));
+        assert_eq!(str2number(b"0xA.8P-1"), Some(Value::Float(5.25)));
+        // more digits than a double holds, rounded once
+        assert_eq!(str2number(b"0x1.00000000000008p0"), Some(Value::Float(1.0)));
+        assert_eq!(
+            str2number(b"0x1.000000000000080001p0"),
+            Some(Value::Float(1.0 + f64::EPSILON))
+        );
+        assert_eq!(str2number(b"0x1p-1074"), Some(Value::Float(5e-324)));
+        assert_eq!(str2number(b"0x1p1024"), Some(Value::Float(f64::INFINITY)));
+        assert_eq!(str2number(b"0x1p-99999999999"), Some(Value::Float(0.0)));
+        assert_eq!(
+            str2number(b"0x123456789abcdef0123p-40"),
+            Some(Value::Float(
+                0x123456789abcdef0123u128 as f64 / (1u64 << 40) as f64
+            ))
+        );
+    }
+
+    #[cfg(feature = "int32")]
+    #[test]
+    fn integers_32() {
+        assert_eq!(
+            str2number(b"-2147483648"),
+            Some(Value::Integer(LuaInt::MIN))
+        );
+        assert_eq!(str2number(b"2147483648"), Some(Value::Float(2147483648.0)));
+        assert_eq!(str2number(b"0xffffffff"), Some(Value::Integer(-1)));
+        assert_eq!(str2number(b"0x100000001"), Some(Value::Integer(1)));
+    }
+
+    #[cfg(feature = "float32")]
+    #[test]
+    fn floats_32() {
+        assert_eq!(str2number(b"0.1"), Some(Value::Float(0.1)));
+        assert_eq!(str2number(b"16777217.0"), Some(Value::Float(16777216.0)));
+        assert_eq!(
+            str2number(b"0x1p128"),
+            Some(Value::Float(LuaFloat::INFINITY))
+        );
+        assert_eq!(float_to_string(0.1), "0.1");
+        assert_eq!(float_to_string(1.0 / 3.0), "0.3333333");
+        assert_eq!(float_to_string(1e6), "1000000.0");
+        assert_eq!(float_to_string(16777216.0), "1.677722e+07");
+    }
+
+    #[cfg(not(feature = "float32"))]
+    #[test]
+    fn round_trips() {
+        for f in [
+            0.1,
+            1.0 / 3.0,
+            1e300,
+            5e-324,
+            2.5e-310,
+            123456.789,
+            1e15,
+            2f64.powi(60),
+        ] {
+            let hex = fmt_hex(f, None);
+            assert_eq!(str2number(hex.as_bytes()), Some(Value::Float(f)), "{hex}");
+        }
+    }
+
+    #[cfg(not(feature = "float32"))]
+    #[test]
+    fn float_strings() {
+        let cases = [
+            (1.0, "1.0"),
+            (-0.0, "-0.0"),
+            (0.1, "0.1"),
+            (1e15, "1e+15"),
+            (1e14, "1e+14"),
+            (123456789012.0, "123456789012.0"),
+            (12.345678901234568, "12.345678901235"),
+            (1.0 / 3.0, "0.33333333333333"),
+            (2f64.powi(63), "9.2233720368548e+18"),
+            (1e-5, "1e-05"),
+            (f64::INFINITY, "inf"),
+            (f64::NEG_INFINITY, "-inf"),
+            (f64::NAN, "nan"),
+            (-f64::NAN, "-nan"),
+        ];
+        for (f, expected) in cases {
+            assert_eq!(float_to_string(f), expected);
+        }
+        assert_eq!(fmt_general(100000.0, 6, false), "100000");
+        assert_eq!(fmt_exp(0.000123, 2, false), "1.23e-04");
+        assert_eq!(fmt_hex(1.0, Some(3)), "0x1.000p+0");
+    }
+
+    #[cfg(all(feature = "vm", not(feature = "float32")))]
+    #[test]
+    fn conversions_agree() {
+        let mut state = ExeState::new();
+        state.set_global("x", Value::Float(0.1));
+        let results = state
+            .eval("return tostring(x), string.format('%.14g', x), tonumber(tostring(x)), tostring(10)")
+            .unwrap();
+        assert_eq!(
+            results,
+            vec!["0.1".into(), "0.1".into(), Value::Float(0.1), "10".into()]
+        );
+        assert_eq!(
+            state
+                .eval("return tonumber(string.format('%a', x))")
+                .unwrap(),
+            vec![Value::Float(0.1)]
+        );
+    }
+
+    #[test]
+    fn not_numbers() {
+        for s in [
+            &b""[..],
+            b" ",
+            b"-",
+            b"0x",
+            b"1,5",
+            b"1 2",
+            b"inf",
+            b"nan",
+            b"1e",
+            b"--1",
+            b"0x1p",
+            b"1_000",
+            b"e1",
+        ] {
+            assert_eq!(str2number(s), None, "{}", String::from_utf8_lossy(s));
+        }
+    }
+
+    #[test]
+    fn bases() {
+        assert_eq!(str2int_base(b"ff", 16), Some(255));
+        assert_eq!(str2int_base(b" -Zz ", 36), Some(-1295));
+        assert_eq!(str2int_base(b"102", 2), None);
+        assert_eq!(str2int_base(b"", 10), None);
+    }
+}
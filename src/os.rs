@@ -0,0 +1,343 @@
+//! The `os` library. Time is read through a [`Clock`], which embedders can
+//! replace to make scripts deterministic.
+
+use std::{
+    ffi::c_int,
+    fmt,
+    path::Path,
+    time::{Instant, SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::bail;
+
+use crate::{
+    arith,
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+
+/// Source of the time seen by scripts.
+pub trait Clock: fmt::Debug {
+    /// Seconds since the Unix epoch, for `os.time`.
+    fn time(&self) -> i64;
+    /// Seconds elapsed on a monotonic clock, for `os.clock`.
+    fn clock(&self) -> f64;
+}
+
+/// The real time, with `os.clock` counting from the clock's creation.
+#[derive(Debug)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl SystemClock {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Clock for SystemClock {
+    fn time(&self) -> i64 {
+        match SystemTime::now().duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => -(e.duration().as_secs() as i64),
+        }
+    }
+
+    fn clock(&self) -> f64 {
+        self.start.elapsed().as_secs_f64()
+    }
+}
+
+/// A clock that stands still, for scripts that must run the same way every
+/// time. It is the default with the `deterministic` feature, at the epoch.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct FixedClock {
+    pub time: i64,
+    pub clock: f64,
+}
+
+impl Clock for FixedClock {
+    fn time(&self) -> i64 {
+        self.time
+    }
+
+    fn clock(&self) -> f64 {
+        self.clock
+    }
+}
+
+/// The clock of a state built without one.
+pub(crate) fn default_clock() -> Box<dyn Clock> {
+    if cfg!(feature = "deterministic") {
+        Box::new(FixedClock::default())
+    } else {
+        Box::new(SystemClock::new())
+    }
+}
+
+/// The error `os.exit` raises to end the script with a status. It is not
+/// caught by `pcall`, so it reaches the host, which decides what exiting
+/// means: the command line tool exits the process with it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Exit(pub i32);
+
+impl fmt::Display for Exit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "exit with status {}", self.0)
+    }
+}
+
+impl std::error::Error for Exit {}
+
+/// Build the `os` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("time".into(), Value::Function(lib_time));
+    t.map.insert("clock".into(), Value::Function(lib_clock));
+    t.map.insert("getenv".into(), Value::Function(lib_getenv));
+    t.map.insert("remove".into(), Value::Function(lib_remove));
+    t.map.insert("exit".into(), Value::Function(lib_exit));
+    t.into()
+}
+
+// os.time([table]): now, or the time of a date table
+fn lib_time(state: &mut ExeState) -> anyhow::Result<i32> {
+    let t = match state.arg(1) {
+        Value::Nil => state.clock().time() as LuaInt,
+        t @ Value::Table(_) => date_time(state, &t.clone())?,
+        _ => bail!(
+            "bad argument #1 to 'time' (table expected, got {})",
+            state.arg_type_name(1)
+        ),
+    };
+    state.push(t.into());
+    Ok(1)
+}
+
+/// The time of date table `t`, from its fields `year`, `month` and `day`,
+/// and `hour`, `min` and `sec`, which default to noon. Fields out of range
+/// carry over as with C's `mktime`, so that month 13 is January of the
+/// year after. It is in UTC, there being no time zone to read, and `isdst`
+/// is ignored.
+fn date_time(state: &mut ExeState, t: &Value) -> anyhow::Result<LuaInt> {
+    let year = date_field(state, t, "year", None, 1900)? + 1900;
+    let month = date_field(state, t, "month", None, 1)?;
+    let day = date_field(state, t, "day", None, 0)?;
+    let hour = date_field(state, t, "hour", Some(12), 0)?;
+    let min = date_field(state, t, "min", Some(0), 0)?;
+    let sec = date_field(state, t, "sec", Some(0), 0)?;
+    let days = days_from_civil(year + month.div_euclid(12), month.rem_euclid(12) + 1) + day - 1;
+    let time = ((days * 24 + hour) * 60 + min) * 60 + sec;
+    match LuaInt::try_from(time) {
+        Ok(time) => Ok(time),
+        Err(_) => bail!("time result cannot be represented in this installation"),
+    }
+}
+
+/// Field `key` of date table `t` as an integer, less `delta` as C's
+/// `struct tm` counts it, which must fit a C `int`, or `default` if it is
+/// nil.
+fn date_field(
+    state: &mut ExeState,
+    t: &Value,
+    key: &str,
+    default: Option<i64>,
+    delta: i64,
+) -> anyhow::Result<i64> {
+    let v = state.index(t, &key.into())?;
+    // wide enough for any integer or float with an integer value to check
+    let n = match arith::to_number(&v) {
+        Some(Value::Integer(i)) => Some(i128::from(i)),
+        Some(Value::Float(f)) if f.fract() == 0.0 && f.abs() < i64::MAX as LuaFloat => {
+            Some(f as i128)
+        }
+        _ => None,
+    };
+    match (n, default) {
+        (Some(n), _) => match c_int::try_from(n - i128::from(delta)) {
+            Ok(n) => Ok(n.into()),
+            Err(_) => bail!("field '{key}' is out-of-bound"),
+        },
+        _ if v != Value::Nil => bail!("field '{key}' is not an integer"),
+        (None, Some(default)) => Ok(default),
+        (None, None) => bail!("field '{key}' missing in date table"),
+    }
+}
+
+/// Days from the epoch to the first day of `month`, from 1, of `year`, in
+/// the proleptic Gregorian calendar.
+fn days_from_civil(year: i64, month: i64) -> i64 {
+    // years start in March, for February to end them
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+// os.clock()
+fn lib_clock(state: &mut ExeState) -> anyhow::Result<i32> {
+    let t = state.clock().clock() as LuaFloat;
+    state.push(t.into());
+    Ok(1)
+}
+
+// os.getenv(name): nil if unset or hidden by the sandbox
+fn lib_getenv(state: &mut ExeState) -> anyhow::Result<i32> {
+    let name = check_str(state, 1, "getenv")?;
+    let v = match std::env::var_os(&name) {
+        Some(v) if state.sandbox().env_allowed(&name) => {
+            Value::from(v.to_string_lossy().into_owned())
+        }
+        _ => Value::Nil,
+    };
+    state.push(v);
+    Ok(1)
+}
+
+// os.remove(path): true, or nil and a message on failure
+fn lib_remove(state: &mut ExeState) -> anyhow::Result<i32> {
+    let path = check_str(state, 1, "remove")?;
+    state.sandbox().check_path(Path::new(&path))?;
+    let result = match std::fs::metadata(&path) {
+        Ok(meta) if meta.is_dir() => std::fs::remove_dir(&path),
+        _ => std::fs::remove_file(&path),
+    };
+    match result {
+        Ok(()) => {
+            state.push(true.into());
+            Ok(1)
+        }
+        Err(err) => {
+            state.push(Value::Nil);
+            state.push(format!("{path}: {err}").into());
+            Ok(2)
+        }
+    }
+}
+
+// os.exit([code [, close]]): true for success, false for failure
+fn lib_exit(state: &mut ExeState) -> anyhow::Result<i32> {
+    let code = match *state.arg(1) {
+        Value::Nil | Value::Boolean(true) => 0,
+        Value::Boolean(false) => 1,
+        Value::Integer(i) => i as c_int,
+        Value::Float(f) if f.fract() == 0.0 => f as c_int,
+        _ => bail!(
+            "bad argument #1 to 'exit' (number expected, got {})",
+            state.arg_type_name(1)
+        ),
+    };
+    // there is nothing to close before exiting that dropping the state
+    // does not already
+    if !matches!(state.arg(2), Value::Nil | Value::Boolean(_)) {
+        bail!(
+            "bad argument #2 to 'exit' (boolean expected, got {})",
+            state.arg_type_name(2)
+        );
+    }
+    Err(Exit(code).into())
+}
+
+fn check_str(state: &ExeState, i: usize, fname: &str) -> anyhow::Result<String> {
+    match String::try_from(state.arg(i)) {
+        Ok(s) => Ok(s),
+        Err(_) => bail!(
+            "bad argument #{i} to '{fname}' (string expected, got {})",
+            state.arg_type_name(i)
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RuntimeError;
+
+    #[test]
+    fn injected_clock() {
+        let clock = FixedClock {
+            time: 1_000_000,
+            clock: 1.5,
+        };
+        let state = ExeState::builder().clock(clock).build();
+        assert_eq!(state.clock().time(), 1_000_000);
+        assert_eq!(state.clock().clock(), 1.5);
+
+        let state = ExeState::new();
+        if cfg!(feature = "deterministic") {
+            assert_eq!(state.clock().time(), 0);
+        } else {
+            assert!(state.clock().time() > 1_000_000);
+        }
+    }
+
+    #[test]
+    fn date_table() {
+        let mut state = ExeState::new();
+        let time = |state: &mut ExeState, fields: &str| {
+            let src = format!("return os.time({{{fields}}})");
+            state.eval(&src).map(|results| results[0].clone())
+        };
+        // at noon unless it says
+        let results = time(&mut state, "year = 2000, month = 1, day = 1");
+        assert_eq!(results.unwrap(), Value::Integer(946_728_000));
+        let results = time(&mut state, "year = 1960, month = 1, day = 1, hour = 0");
+        assert_eq!(results.unwrap(), Value::Integer(-315_619_200));
+        let results = time(&mut state, "year = '2000', month = 2.0, day = '3'");
+        assert_eq!(results.unwrap(), Value::Integer(949_579_200));
+        // carried over from fields out of range
+        let results = time(
+            &mut state,
+            "year = 2024, month = 14, day = -3, hour = 25, min = 61, sec = -5",
+        );
+        assert_eq!(results.unwrap(), Value::Integer(1_738_116_055));
+        let results = time(&mut state, "year = 2023, month = 3, day = 0");
+        let last_of_february = time(&mut state, "year = 2023, month = 2, day = 28");
+        assert_eq!(results.unwrap(), last_of_february.unwrap());
+
+        let error = |state: &mut ExeState, fields| {
+            let err = time(state, fields).unwrap_err();
+            err.downcast::<RuntimeError>().unwrap().message
+        };
+        assert_eq!(
+            error(&mut state, "year = 2000"),
+            "field 'month' missing in date table"
+        );
+        assert_eq!(
+            error(&mut state, "year = 2000, month = 1.5, day = 1"),
+            "field 'month' is not an integer"
+        );
+        assert_eq!(
+            error(&mut state, "year = 2 ^ 40, month = 1, day = 1"),
+            "field 'year' is out-of-bound"
+        );
+    }
+
+    #[test]
+    fn exit() {
+        let mut state = ExeState::new();
+        let exit = state.index(&state.get_global("os"), &"exit".into());
+        state.set_global("exit", exit.unwrap());
+        let status = |state: &mut ExeState, src: &str| {
+            let err = state.eval(src).unwrap_err();
+            err.downcast_ref::<Exit>().copied()
+        };
+        assert_eq!(status(&mut state, "exit()"), Some(Exit(0)));
+        assert_eq!(status(&mut state, "exit(false, true)"), Some(Exit(1)));
+        assert_eq!(status(&mut state, "exit(3)"), Some(Exit(3)));
+        // not caught by pcall
+        assert_eq!(status(&mut state, "pcall(exit, 4)"), Some(Exit(4)));
+        assert_eq!(status(&mut state, "exit('x')"), None);
+    }
+}
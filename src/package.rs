@@ -0,0 +1,104 @@
+//! `require` and the `package` library. Hosts register modules by name with
+//! [`ExeState::preload_module`]; nothing is loaded from the filesystem.
+
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::bail;
+
+use crate::{
+    value::{Table, Value},
+    vm::ExeState,
+};
+
+/// Build the `package` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("loaded".into(), Table::new().into());
+    t.map.insert("preload".into(), Table::new().into());
+    t.into()
+}
+
+/// Register `init` in `package.preload[name]`.
+pub(crate) fn preload(state: &mut ExeState, name: &str, init: Value) -> anyhow::Result<()> {
+    field(state, "preload")?
+        .borrow_mut()
+        .map
+        .insert(name.into(), init);
+    Ok(())
+}
+
+// require(name)
+pub(crate) fn lib_require(state: &mut ExeState) -> anyhow::Result<i32> {
+    let name = match String::try_from(state.arg(1)) {
+        Ok(name) => name,
+        Err(_) => bail!(
+            "bad argument #1 to 'require' (string expected, got {})",
+            state.arg_type_name(1)
+        ),
+    };
+    let loaded = field(state, "loaded")?;
+    let cached = loaded.borrow().get(&name.as_str().into());
+    if cached != Value::Nil {
+        state.push(cached);
+        return Ok(1);
+    }
+
+    let init = field(state, "preload")?.borrow().get(&name.as_str().into());
+    if init == Value::Nil {
+        bail!("module '{name}' not found:\n\tno field package.preload['{name}']");
+    }
+    let module = match state.call_first(init, &[name.as_str().into()])? {
+        // a module that returns nothing still counts as loaded
+        Value::Nil => true.into(),
+        module => module,
+    };
+    loaded.borrow_mut().map.insert(name.into(), module.clone());
+    state.push(module);
+    Ok(1)
+}
+
+/// `package.loaded` or `package.preload`.
+fn field(state: &mut ExeState, name: &str) -> anyhow::Result<Rc<RefCell<Table>>> {
+    match state.index(&state.get_global("package"), &name.into()) {
+        Ok(Value::Table(t)) => Ok(t),
+        _ => bail!("'package.{name}' must be a table"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::parse::ParseProto;
+
+    use super::*;
+
+    #[test]
+    fn require_preloaded() {
+        let src = br#"local m = require "greeting" print(m) print(require("greeting"))"#;
+        let proto = ParseProto::load(Cursor::new(src.to_vec())).unwrap();
+
+        let mut state = ExeState::new();
+        state
+            .preload_module("greeting", |state| {
+                let loads = match state.get_global("loads") {
+                    Value::Integer(n) => n,
+                    _ => 0,
+                };
+                state.set_global("loads", (loads + 1).into());
+                state.push("hello".into());
+                Ok(1)
+            })
+            .unwrap();
+        let out = state.with_captured_output(|state| state.execute(proto).unwrap());
+        assert_eq!(out, "hello\nhello\n");
+        assert_eq!(state.get_global("loads"), Value::Integer(1));
+    }
+
+    #[test]
+    fn require_missing() {
+        let proto = ParseProto::load(Cursor::new(br#"require "nope""#.to_vec())).unwrap();
+        let err = ExeState::new().execute(proto).unwrap_err();
+        assert!(err.to_string().starts_with("?:1: module 'nope' not found"));
+    }
+}
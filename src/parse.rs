@@ -1,169 +1,1756 @@
-use std::io::Read;
+use std::{fmt::Write, io::Read, rc::Rc};
 
-use anyhow::{bail, Context, Ok};
-use combine::stream::{buffered, position, read};
+use anyhow::{anyhow, bail, Context, Ok};
+use combine::{
+    stream::{buffered, easy, position, read},
+    StreamOnce,
+};
 
 use crate::{
-    bytecode::ByteCode,
-    lex::{ByteStream, Lex, Token},
+    arith::{arith, ArithOp},
+    ast::{self, BinOp, StatKind, TreeBuilder, UnOp},
+    bytecode::{ByteCode, MAX_JUMP, MULTRET},
+    lex::{ByteStream, Lex, Location, Span, SyntaxError, Token},
     value::Value,
 };
 
+/// Deepest nesting of blocks and expressions, as in the reference
+/// implementation.
+const MAX_DEPTH: usize = 200;
+
 struct ParseProtoBuilder<S> {
     constants: Vec<Value>,
     byte_codes: Vec<ByteCode>,
+    // of each byte code, and of its operands, if asked for
+    spans: Vec<Span>,
+    operand_spans: Vec<[Span; 2]>,
+    lines: Vec<u32>,
     locals: Vec<String>,
+    // of each of `locals`, whether a nested function captures it, and
+    // where it is declared
+    captured: Vec<bool>,
+    declared: Vec<Location>,
+    // debug information of all the locals, those in scope still open
+    locvars: Vec<LocVar>,
+    labels: Vec<Label>,
+    // forward gotos, waiting for their label
+    gotos: Vec<Label>,
+    // the first of `gotos` in the current block
+    first_goto: usize,
+    // the number of byte codes after the last expression in parentheses,
+    // whose call or `...` keeps one value even last in a list
+    single: usize,
+    // the functions defined in this one so far
+    protos: Vec<Rc<ParseProto>>,
+    upvalues: Vec<UpvalDesc>,
+    is_vararg: bool,
+    // the functions this one is nested in, innermost last
+    outer: Vec<Function>,
+    // nesting of blocks and expressions being parsed
+    depth: usize,
+    options: ParseOptions,
+    warnings: Vec<String>,
+    // the syntax tree, if asked for, of the whole chunk: the functions in
+    // it are part of it
+    tree: Option<TreeBuilder>,
     lex: Lex<S>,
 }
 
-impl<'a, S: ByteStream<'a>> ParseProtoBuilder<S> {
-    fn new(input: S) -> Self {
+/// What to do about a local declared with the name of a local already in
+/// scope.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Shadowing {
+    Allow,
+    #[default]
+    Warn,
+    Deny,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct ParseOptions {
+    pub shadowing: Shadowing,
+    /// Record the source span of each instruction, for errors to point at.
+    pub spans: bool,
+    /// Name of the chunk in error positions, such as the path of the
+    /// script; `?` if empty.
+    pub chunk_name: String,
+}
+
+/// A label, or a goto to one: its name, the position of the label or of
+/// the goto's jump, `usize::MAX` for a goto in unreachable code, the
+/// number of locals in scope there, and its line. A goto that leaves the
+/// scope of captured locals has them closed at its label.
+struct Label {
+    name: String,
+    pc: usize,
+    nlocals: usize,
+    line: u32,
+    close: bool,
+}
+
+/// The state of a function being compiled that is its own, put aside
+/// while compiling a function nested in it: the fields of the same names
+/// of [`ParseProtoBuilder`].
+#[derive(Default)]
+struct Function {
+    constants: Vec<Value>,
+    byte_codes: Vec<ByteCode>,
+    spans: Vec<Span>,
+    operand_spans: Vec<[Span; 2]>,
+    lines: Vec<u32>,
+    locals: Vec<String>,
+    captured: Vec<bool>,
+    declared: Vec<Location>,
+    locvars: Vec<LocVar>,
+    labels: Vec<Label>,
+    gotos: Vec<Label>,
+    first_goto: usize,
+    single: usize,
+    protos: Vec<Rc<ParseProto>>,
+    upvalues: Vec<UpvalDesc>,
+    is_vararg: bool,
+}
+
+/// An expression compiled as far as needed to tell where its value is.
+enum Exp {
+    /// In a register: a local, or the free register it was loaded into.
+    Reg(usize),
+    /// A constant, loaded only where it goes, if it is not an operand in
+    /// the instruction.
+    Const(Value),
+}
+
+/// A key of a table, as an operand of the instruction indexing with it.
+enum Key {
+    /// A string, as a constant.
+    Field(u8),
+    /// A small integer.
+    Int(u8),
+    Reg(u8),
+}
+
+/// What [`enter_block`](ParseProtoBuilder::enter_block) saves, to restore
+/// at the end of the block.
+struct Block {
+    nlocals: usize,
+    nlabels: usize,
+    first_goto: usize,
+}
+
+impl<'a, S: ByteStream<'a> + StreamOnce<Position = Location>> ParseProtoBuilder<S> {
+    fn new(input: S, options: ParseOptions) -> Self {
         Self {
             constants: Default::default(),
             byte_codes: Default::default(),
+            spans: Default::default(),
+            operand_spans: Default::default(),
+            lines: Default::default(),
             locals: Default::default(),
+            captured: Default::default(),
+            declared: Default::default(),
+            locvars: Default::default(),
+            labels: Default::default(),
+            gotos: Default::default(),
+            first_goto: 0,
+            single: 0,
+            protos: Default::default(),
+            upvalues: Default::default(),
+            // the main chunk takes the script arguments
+            is_vararg: true,
+            outer: Default::default(),
+            depth: 0,
+            options,
+            warnings: Default::default(),
+            tree: None,
             lex: Lex::new(input),
         }
     }
 
-    fn load(mut self) -> anyhow::Result<ParseProto> {
+    /// The chunk compiled, and its syntax tree if asked for.
+    fn load(mut self) -> anyhow::Result<(ParseProto, Option<ast::Block>)> {
+        // every error is a syntax error, at the last token read if the
+        // lexer did not tell where
+        if let Err(err) = self.chunk() {
+            if err.is::<SyntaxError>() {
+                return Err(err);
+            }
+            return Err(SyntaxError {
+                message: err.to_string(),
+                span: self.lex.span(),
+            }
+            .into());
+        }
+
+        let mut proto = self.proto(0, 0);
+        proto.warnings = std::mem::take(&mut self.warnings);
+        #[cfg(feature = "tracing")]
+        {
+            for warning in &proto.warnings {
+                tracing::warn!("{warning}");
+            }
+            tracing::trace!(
+                byte_codes = proto.byte_codes.len(),
+                constants = proto.constants.len(),
+                "compiled\n{}",
+                proto.disassemble()
+            );
+        }
+        Ok((proto, self.tree.map(TreeBuilder::finish)))
+    }
+
+    /// Add to the syntax tree, if one is asked for.
+    fn record(&mut self, f: impl FnOnce(&mut TreeBuilder)) {
+        if let Some(tree) = &mut self.tree {
+            f(tree);
+        }
+    }
+
+    /// The function compiled so far, as a proto with `nparams` parameters,
+    /// defined at line `line_defined`. Its locals are in scope up to its
+    /// end.
+    fn proto(&mut self, nparams: u8, line_defined: u32) -> ParseProto {
+        self.close_locvars(self.locals.len());
+        let byte_codes = std::mem::take(&mut self.byte_codes);
+        ParseProto {
+            max_stack: max_stack(&byte_codes).max(nparams as usize),
+            constants: std::mem::take(&mut self.constants).into(),
+            byte_codes,
+            spans: std::mem::take(&mut self.spans),
+            operand_spans: std::mem::take(&mut self.operand_spans),
+            lines: std::mem::take(&mut self.lines),
+            locvars: std::mem::take(&mut self.locvars),
+            chunk_name: match self.options.chunk_name.as_str() {
+                "" => "?".into(),
+                name => name.into(),
+            },
+            nparams,
+            is_vararg: self.is_vararg,
+            line_defined,
+            protos: std::mem::take(&mut self.protos),
+            upvalues: std::mem::take(&mut self.upvalues),
+            warnings: Vec::new(),
+        }
+    }
+
+    /// Swap the state of the function being compiled with `f`.
+    fn swap_function(&mut self, f: &mut Function) {
+        std::mem::swap(&mut self.constants, &mut f.constants);
+        std::mem::swap(&mut self.byte_codes, &mut f.byte_codes);
+        std::mem::swap(&mut self.spans, &mut f.spans);
+        std::mem::swap(&mut self.operand_spans, &mut f.operand_spans);
+        std::mem::swap(&mut self.lines, &mut f.lines);
+        std::mem::swap(&mut self.locals, &mut f.locals);
+        std::mem::swap(&mut self.captured, &mut f.captured);
+        std::mem::swap(&mut self.declared, &mut f.declared);
+        std::mem::swap(&mut self.locvars, &mut f.locvars);
+        std::mem::swap(&mut self.labels, &mut f.labels);
+        std::mem::swap(&mut self.gotos, &mut f.gotos);
+        std::mem::swap(&mut self.first_goto, &mut f.first_goto);
+        std::mem::swap(&mut self.single, &mut f.single);
+        std::mem::swap(&mut self.protos, &mut f.protos);
+        std::mem::swap(&mut self.upvalues, &mut f.upvalues);
+        std::mem::swap(&mut self.is_vararg, &mut f.is_vararg);
+    }
+
+    fn chunk(&mut self) -> anyhow::Result<()> {
+        self.lex.skip_prefix()?;
+        self.block()?;
+        match self.lex.next()? {
+            Token::Eos => (),
+            t => bail!("'<eof>' expected near {t}"),
+        }
+        self.check_gotos()?;
+        Ok(())
+    }
+
+    /// Statements up to the end of a block, which is left for the caller to
+    /// check, or up to a `return`, which must end it.
+    fn block(&mut self) -> anyhow::Result<()> {
+        self.enter_level()?;
+        self.record(TreeBuilder::open_block);
+        // the goto or break that the statements follow, up to the next
+        // label: they can never run, and are compiled only to be checked
+        let mut dead: Option<String> = None;
+        let mut warned = false;
         loop {
-            match self.lex.next()? {
+            if block_follow(self.lex.peek()?) {
+                self.record(TreeBuilder::close_block);
+                self.depth -= 1;
+                return Ok(());
+            }
+            let t = self.lex.next()?;
+            let line = self.lex.span().start.line;
+            if t == Token::DoubColon {
+                dead = None;
+            }
+            if let Some(jump) = &dead {
+                if !warned {
+                    let at = line_column(self.lex.span().start);
+                    let msg = format!("code at {at} after {jump} is unreachable");
+                    self.warnings.push(msg);
+                    warned = true;
+                }
+            }
+            let pc = self.byte_codes.len();
+            let ngotos = self.gotos.len();
+            let is_return = t == Token::Return;
+            match t {
                 Token::Name(name) => {
                     if self.lex.peek()? == &Token::Assign {
                         self.assignment(name)?;
                     } else {
-                        self.function_call(name)?;
+                        self.exp_stat(name)?;
                     }
                 }
                 Token::Local => self.local()?,
-                Token::Eos => break,
-                t => bail!("unexpected token: {t:?}"),
+                Token::Function => self.function_stat()?,
+                Token::Goto => {
+                    if let (None, Token::Name(name)) = (&dead, self.lex.peek()?) {
+                        dead = Some(format!("goto '{name}'"));
+                        warned = false;
+                        self.goto()?;
+                        self.record(|tree| tree.end_stat(line));
+                        continue;
+                    }
+                    self.goto()?;
+                }
+                Token::DoubColon => self.label()?,
+                Token::Break => {
+                    let start = self.lex.span().start;
+                    self.goto_label("break".into(), start)?;
+                    self.record(|tree| tree.stat(StatKind::Break));
+                    if dead.is_none() {
+                        dead = Some("break".into());
+                        warned = false;
+                        self.record(|tree| tree.end_stat(line));
+                        continue;
+                    }
+                }
+                Token::Do => self.do_block()?,
+                Token::If => self.if_stat()?,
+                Token::For => self.for_stat()?,
+                Token::Return => self.ret()?,
+                t => bail!("unexpected symbol near {t}"),
+            }
+            self.record(|tree| tree.end_stat(line));
+            if dead.is_some() {
+                self.drop_code(pc, ngotos);
+            }
+            if is_return {
+                self.record(TreeBuilder::close_block);
+                self.depth -= 1;
+                return Ok(());
             }
         }
+    }
 
-        dbg!(&self.constants);
-        eprintln!("byte_codes:");
-        for code in &self.byte_codes {
-            eprintln!("    {code:?}");
+    /// Drop the code from `pc` on, which can never run. Gotos in it are
+    /// still checked against their labels, but have no jump to patch.
+    fn drop_code(&mut self, pc: usize, ngotos: usize) {
+        self.byte_codes.truncate(pc);
+        self.lines.truncate(pc);
+        self.spans.truncate(pc);
+        self.operand_spans.truncate(pc);
+        for v in &mut self.locvars {
+            v.start_pc = v.start_pc.min(pc);
+            if v.end_pc != usize::MAX {
+                v.end_pc = v.end_pc.min(pc);
+            }
+        }
+        for goto in &mut self.gotos[ngotos..] {
+            goto.pc = usize::MAX;
         }
+    }
 
-        Ok(ParseProto {
-            constants: self.constants,
-            byte_codes: self.byte_codes,
-        })
+    /// Go one level deeper into blocks or expressions, which the parser
+    /// follows by recursion, so that hostile input cannot overflow the
+    /// stack. There is no need to come back up after an error, which ends
+    /// the parse.
+    fn enter_level(&mut self) -> anyhow::Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            bail!("chunk has too many syntax levels");
+        }
+        Ok(())
     }
 
-    fn local(&mut self) -> anyhow::Result<()> {
-        let var = if let Token::Name(var) = self.lex.next()? {
-            var
+    /// Open a scope for locals and labels, to be closed by
+    /// [`leave_block`](Self::leave_block).
+    fn enter_block(&mut self) -> Block {
+        let block = Block {
+            nlocals: self.locals.len(),
+            nlabels: self.labels.len(),
+            first_goto: self.first_goto,
+        };
+        self.first_goto = self.gotos.len();
+        block
+    }
+
+    /// Close the scope opened by [`enter_block`](Self::enter_block). Its
+    /// pending gotos are left for the enclosing block, whose locals are
+    /// all they can still see. Locals of the block captured by a function
+    /// have their upvalues closed, for the functions to keep the values
+    /// they had, and each run of the block to have its own.
+    fn leave_block(&mut self, block: Block) -> anyhow::Result<()> {
+        let captured = self.captured[block.nlocals..].contains(&true);
+        self.close_locvars(self.locals.len() - block.nlocals);
+        self.locals.truncate(block.nlocals);
+        self.captured.truncate(block.nlocals);
+        self.declared.truncate(block.nlocals);
+        self.labels.truncate(block.nlabels);
+        for goto in &mut self.gotos[self.first_goto..] {
+            if goto.nlocals > block.nlocals {
+                goto.close |= captured;
+                goto.nlocals = block.nlocals;
+            }
+        }
+        self.first_goto = block.first_goto;
+        if captured {
+            let start = self.lex.span().start;
+            self.emit(ByteCode::Close(reg(block.nlocals)?), start);
+        }
+        Ok(())
+    }
+
+    /// Bring local `name`, declared at `at`, into scope, in the next
+    /// register.
+    fn add_local(&mut self, name: String, at: Location) {
+        self.locvars.push(LocVar {
+            name: name.clone(),
+            start_pc: self.byte_codes.len(),
+            end_pc: usize::MAX,
+        });
+        self.locals.push(name);
+        self.captured.push(false);
+        self.declared.push(at);
+    }
+
+    /// End the scope of the last `n` locals in scope at the next
+    /// instruction.
+    fn close_locvars(&mut self, n: usize) {
+        let pc = self.byte_codes.len();
+        let open = self
+            .locvars
+            .iter_mut()
+            .rev()
+            .filter(|v| v.end_pc == usize::MAX);
+        for v in open.take(n) {
+            v.end_pc = pc;
+        }
+    }
+
+    /// `do block end`, a scope of its own.
+    fn do_block(&mut self) -> anyhow::Result<()> {
+        let block = self.enter_block();
+        self.block()?;
+        match self.lex.next()? {
+            Token::End => (),
+            t => return Err(unexpected(&t, "'end' expected")),
+        }
+        self.record(|tree| {
+            let body = tree.pop_block();
+            tree.stat(StatKind::Do(body));
+        });
+        self.leave_block(block)
+    }
+
+    /// `if exp then block {elseif exp then block} [else block] end`. Each
+    /// condition is tested in a free register, a false one jumping to the
+    /// next, and each block but the last jumps to the end.
+    fn if_stat(&mut self) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        let mut exits = Vec::new();
+        let mut nclauses = 0;
+        let has_else = loop {
+            let dst = self.locals.len();
+            let t = self.lex.next()?;
+            let cond = self.subexp(dst, t, 0)?;
+            let r = self.any_reg(dst, cond, start)?;
+            self.emit(ByteCode::Test(r), start);
+            let skip = self.jump(start);
+            match self.lex.next()? {
+                Token::Then => (),
+                t => return Err(unexpected(&t, "'then' expected")),
+            }
+            self.scoped_block()?;
+            nclauses += 1;
+
+            let t = self.lex.next()?;
+            if matches!(t, Token::Elseif | Token::Else) {
+                exits.push(self.jump(start));
+            }
+            self.patch_jump(skip, self.byte_codes.len())?;
+            match t {
+                Token::Elseif => (),
+                Token::Else => {
+                    self.scoped_block()?;
+                    match self.lex.next()? {
+                        Token::End => break true,
+                        t => return Err(unexpected(&t, "'end' expected")),
+                    }
+                }
+                Token::End => break false,
+                t => return Err(unexpected(&t, "'end' expected")),
+            }
+        };
+        for pc in exits {
+            self.patch_jump(pc, self.byte_codes.len())?;
+        }
+        self.record(|tree| tree.if_stat(nclauses, has_else));
+        Ok(())
+    }
+
+    /// A block with a scope of its own, up to the token ending it.
+    fn scoped_block(&mut self) -> anyhow::Result<()> {
+        let block = self.enter_block();
+        self.block()?;
+        self.leave_block(block)
+    }
+
+    /// A numeric or generic `for`, which the token after the first name
+    /// tells apart.
+    fn for_stat(&mut self) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        let name = match self.lex.next()? {
+            Token::Name(name) => name,
+            t => return Err(unexpected(&t, "expected variable")),
+        };
+        let at = self.lex.span().start;
+        if self.lex.peek()? == &Token::Assign {
+            self.for_num(name, at, start)
         } else {
-            bail!("expected variable");
+            self.for_in(name, at, start)
+        }
+    }
+
+    /// `for name = init, limit [, step] do block end`, in three hidden
+    /// locals for the loop state and one for `name`.
+    fn for_num(&mut self, name: String, at: Location, start: Location) -> anyhow::Result<()> {
+        match self.lex.next()? {
+            Token::Assign => (),
+            t => return Err(unexpected(&t, "expected `=`")),
+        }
+        let outer = self.enter_block();
+        let base = self.locals.len();
+        self.load_exp(base)?;
+        match self.lex.next()? {
+            Token::Comma => (),
+            t => return Err(unexpected(&t, "expected `,`")),
+        }
+        self.load_exp(base + 1)?;
+        let has_step = self.lex.peek()? == &Token::Comma;
+        if has_step {
+            self.lex.next()?;
+            self.load_exp(base + 2)?;
+        } else {
+            self.emit(ByteCode::LoadInt(reg(base + 2)?, 1), start);
+        }
+        match self.lex.next()? {
+            Token::Do => (),
+            t => return Err(unexpected(&t, "expected `do`")),
+        }
+        for _ in 0..3 {
+            self.add_local("(for state)".into(), start);
+        }
+
+        let prep = self.byte_codes.len();
+        self.emit(ByteCode::ForPrep(reg(base)?, 0), start);
+        let body = self.enter_block();
+        self.add_local(name.clone(), at);
+        self.block()?;
+        match self.lex.next()? {
+            Token::End => (),
+            t => return Err(unexpected(&t, "'end' expected")),
+        }
+        self.leave_block(body)?;
+        self.record(|tree| {
+            let body = tree.pop_block();
+            let step = has_step.then(|| tree.pop());
+            let limit = tree.pop();
+            let start = tree.pop();
+            tree.stat(StatKind::NumericFor {
+                var: name,
+                start,
+                limit,
+                step,
+                body,
+            });
+        });
+
+        // the prep skips past the loop, which jumps back to the body
+        let pc = self.byte_codes.len();
+        let offset = u16::try_from(pc - prep).context("control structure too long")?;
+        self.byte_codes[prep] = ByteCode::ForPrep(reg(base)?, offset - 1);
+        self.emit(ByteCode::ForLoop(reg(base)?, offset), start);
+        self.solve_gotos("break", true)?;
+        self.leave_block(outer)
+    }
+
+    /// `for name {, name} in explist do block end`, in three hidden locals
+    /// for the iterator function, its state and the control value, which
+    /// the list is adjusted to, then one for each name.
+    fn for_in(&mut self, name: String, at: Location, start: Location) -> anyhow::Result<()> {
+        let mut names = vec![name];
+        let mut declared = vec![at];
+        while self.lex.peek()? == &Token::Comma {
+            self.lex.next()?;
+            match self.lex.next()? {
+                Token::Name(name) => names.push(name),
+                t => return Err(unexpected(&t, "expected variable")),
+            }
+            declared.push(self.lex.span().start);
+        }
+        match self.lex.next()? {
+            Token::In => (),
+            t => return Err(unexpected(&t, "'=' or 'in' expected")),
+        }
+        let outer = self.enter_block();
+        let base = self.locals.len();
+        let mut n = 0;
+        loop {
+            self.load_exp(base + n)?;
+            n += 1;
+            if self.lex.peek()? != &Token::Comma {
+                break;
+            }
+            self.lex.next()?;
+        }
+        if n < 3 {
+            // a call in last place fills the rest with its results
+            match self.last_call(base + n - 1) {
+                Some(nret) => *nret = (4 - n) as u8,
+                None => {
+                    for i in n..3 {
+                        self.emit(ByteCode::LoadNil(reg(base + i)?), start);
+                    }
+                }
+            }
+        }
+        match self.lex.next()? {
+            Token::Do => (),
+            t => return Err(unexpected(&t, "expected `do`")),
+        }
+        for _ in 0..3 {
+            self.add_local("(for state)".into(), start);
+        }
+
+        // the first call comes before the body, at the end of the loop
+        let prep = self.jump(start);
+        let body = self.enter_block();
+        let nvars = names.len();
+        // the variables come after the three hidden locals
+        reg(base + 3 + nvars)?;
+        for (name, &at) in names.iter().zip(&declared) {
+            self.add_local(name.clone(), at);
+        }
+        self.block()?;
+        match self.lex.next()? {
+            Token::End => (),
+            t => return Err(unexpected(&t, "'end' expected")),
+        }
+        self.leave_block(body)?;
+        self.record(|tree| {
+            let body = tree.pop_block();
+            let exps = tree.pop_n(n);
+            tree.stat(StatKind::GenericFor {
+                vars: names,
+                exps,
+                body,
+            });
+        });
+
+        self.patch_jump(prep, self.byte_codes.len())?;
+        self.emit(ByteCode::TForCall(reg(base)?, nvars as u8), start);
+        let pc = self.byte_codes.len();
+        let offset = u16::try_from(pc - prep).context("control structure too long")?;
+        self.emit(ByteCode::TForLoop(reg(base)?, offset), start);
+        self.solve_gotos("break", true)?;
+        self.leave_block(outer)
+    }
+
+    fn local(&mut self) -> anyhow::Result<()> {
+        if self.lex.peek()? == &Token::Function {
+            return self.local_function();
+        }
+        let var = match self.lex.next()? {
+            Token::Name(var) => var,
+            t => return Err(unexpected(&t, "expected variable")),
         };
-        if self.lex.next()? != Token::Assign {
-            bail!("expected `=`");
+        let span = self.lex.span();
+        match self.lex.next()? {
+            Token::Assign => (),
+            t => return Err(unexpected(&t, "expected `=`")),
         }
+        self.check_shadowing(&var, span)?;
         self.load_exp(self.locals.len())?;
-        self.locals.push(var);
+        self.record(|tree| {
+            let e = tree.pop();
+            tree.stat(StatKind::Local(vec![var.clone()], vec![e]));
+        });
+        self.add_local(var, span.start);
         Ok(())
     }
 
-    fn function_call(&mut self, name: String) -> anyhow::Result<()> {
-        let code = self.load_var(self.locals.len(), name);
-        self.byte_codes.push(code);
+    /// `local function name body`. The local is in scope in the body, for
+    /// the function to call itself.
+    fn local_function(&mut self) -> anyhow::Result<()> {
+        self.lex.next()?;
+        let start = self.lex.span().start;
+        let var = match self.lex.next()? {
+            Token::Name(var) => var,
+            t => return Err(unexpected(&t, "<name> expected")),
+        };
+        let span = self.lex.span();
+        self.check_shadowing(&var, span)?;
+        let dst = self.locals.len();
+        self.add_local(var, span.start);
+        self.function_body(dst, start)?;
+        if let Some(tree) = &mut self.tree {
+            let func = tree.pop_func();
+            tree.stat(StatKind::LocalFunction(self.locals[dst].clone(), func));
+        }
+        Ok(())
+    }
+
+    /// Warn about, or reject, a new local `var` at `span` shadowing one in
+    /// scope, as [`ParseOptions::shadowing`] says.
+    fn check_shadowing(&mut self, var: &String, span: Span) -> anyhow::Result<()> {
+        // `_` is the usual name for values to ignore, so it is reused
+        let Some(i) = self.get_local(var).filter(|_| var != "_") else {
+            return Ok(());
+        };
+        let message = format!(
+            "local '{var}' at {} shadows an earlier local of the same name at {}",
+            line_column(span.start),
+            line_column(self.declared[i]),
+        );
+        match self.options.shadowing {
+            Shadowing::Allow => (),
+            Shadowing::Warn => self.warnings.push(message),
+            Shadowing::Deny => return Err(SyntaxError { message, span }.into()),
+        }
+        Ok(())
+    }
+
+    /// `function name body`, assigning a new function to variable `name`.
+    fn function_stat(&mut self) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        let var = match self.lex.next()? {
+            Token::Name(var) => var,
+            t => return Err(unexpected(&t, "<name> expected")),
+        };
+        let record = |tree: &mut TreeBuilder| {
+            let func = tree.pop_func();
+            tree.stat(StatKind::Function(var.clone(), func));
+        };
+        if let Some(i) = self.get_local(&var) {
+            self.function_body(i, start)?;
+            self.record(record);
+            return Ok(());
+        }
+        let tmp = self.locals.len();
+        self.function_body(tmp, start)?;
+        self.record(record);
+        let code = match self.upvalue(&var)? {
+            Some(u) => ByteCode::SetUpval(reg(tmp)?, u),
+            None => ByteCode::SetGlobal(self.add_const(var.into())?, reg(tmp)?),
+        };
+        self.emit(code, start);
+        Ok(())
+    }
+
+    /// `(params) block end`, the rest of a function definition starting
+    /// at `start`, compiled into a new function in register `dst`. The
+    /// parameters are its first locals, and `...` last makes it take any
+    /// number of arguments.
+    fn function_body(&mut self, dst: usize, start: Location) -> anyhow::Result<()> {
+        self.enter_level()?;
+        let mut outer = Function::default();
+        self.swap_function(&mut outer);
+        self.outer.push(outer);
+
+        match self.lex.next()? {
+            Token::ParL => (),
+            t => return Err(unexpected(&t, "'(' expected")),
+        }
+        if self.lex.peek()? != &Token::ParR {
+            loop {
+                match self.lex.next()? {
+                    Token::Name(name) => self.add_local(name, self.lex.span().start),
+                    Token::Dots => {
+                        self.is_vararg = true;
+                        break;
+                    }
+                    t => return Err(unexpected(&t, "<name> expected")),
+                }
+                if self.lex.peek()? != &Token::Comma {
+                    break;
+                }
+                self.lex.next()?;
+            }
+        }
+        match self.lex.next()? {
+            Token::ParR => (),
+            t => return Err(unexpected(&t, "')' expected")),
+        }
+        let nparams = reg(self.locals.len())?;
+        let params = self.tree.as_ref().map(|_| self.locals.clone());
+        self.block()?;
+        match self.lex.next()? {
+            Token::End => (),
+            t => return Err(unexpected(&t, "'end' expected")),
+        }
+        self.check_gotos()?;
+        let vararg = self.is_vararg;
+        self.record(|tree| tree.function(start.line, params.unwrap(), vararg));
+
+        let proto = self.proto(nparams, start.line as u32);
+        let mut outer = self.outer.pop().unwrap();
+        self.swap_function(&mut outer);
+        self.protos.push(Rc::new(proto));
+        let index =
+            u8::try_from(self.protos.len() - 1).map_err(|_| anyhow!("too many functions"))?;
+        self.emit(ByteCode::Closure(reg(dst)?, index), start);
+        self.depth -= 1;
+        Ok(())
+    }
+
+    /// `return [explist] [';']`, which must end the block.
+    fn ret(&mut self) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        let first = self.locals.len();
+        let mut n = 0;
+        let t = self.lex.peek()?;
+        if !block_follow(t) && t != &Token::SemiColon {
+            loop {
+                self.load_exp(first + n)?;
+                n += 1;
+                if self.lex.peek()? != &Token::Comma {
+                    break;
+                }
+                self.lex.next()?;
+            }
+        }
+        self.record(|tree| {
+            let exps = tree.pop_n(n);
+            tree.stat(StatKind::Return(exps));
+        });
+        let n = match n > 0 && self.set_multret(first + n - 1) {
+            true => MULTRET,
+            false => reg(n)?,
+        };
+        if self.lex.peek()? == &Token::SemiColon {
+            self.lex.next()?;
+        }
+        self.emit(ByteCode::Return(reg(first)?, n), start);
+        Ok(())
+    }
+
+    fn goto(&mut self) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        let name = match self.lex.next()? {
+            Token::Name(name) => name,
+            t => return Err(unexpected(&t, "expected label name")),
+        };
+        self.record(|tree| tree.stat(StatKind::Goto(name.clone())));
+        self.goto_label(name, start)
+    }
+
+    /// A jump to label `name`: back to it if it is in scope, otherwise
+    /// forward, pending until it comes. `break` is a goto to a label the
+    /// loop puts after itself.
+    fn goto_label(&mut self, name: String, start: Location) -> anyhow::Result<()> {
+        match self.labels.iter().find(|l| l.name == name) {
+            Some(label) => {
+                // the locals after the label go out of scope
+                let (target, nlocals) = (label.pc, label.nlocals);
+                if nlocals < self.locals.len() {
+                    self.emit(ByteCode::Close(reg(nlocals)?), start);
+                }
+                self.jump_to(target, start)?;
+            }
+            None => {
+                let pc = self.jump(start);
+                self.gotos.push(Label {
+                    name,
+                    pc,
+                    nlocals: self.locals.len(),
+                    line: start.line as u32,
+                    close: false,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Fail on the first goto still pending at the end of a function,
+    /// since no label can come for it.
+    fn check_gotos(&self) -> anyhow::Result<()> {
+        match self.gotos.first() {
+            Some(goto) if goto.name == "break" => {
+                bail!("break outside a loop at line {}", goto.line)
+            }
+            Some(goto) => bail!("no visible label '{}' for goto", goto.name),
+            None => Ok(()),
+        }
+    }
+
+    /// `::name::`, the target of gotos before and after it.
+    fn label(&mut self) -> anyhow::Result<()> {
+        let name = match self.lex.next()? {
+            Token::Name(name) => name,
+            t => return Err(unexpected(&t, "expected label name")),
+        };
+        match self.lex.next()? {
+            Token::DoubColon => (),
+            t => return Err(unexpected(&t, "expected `::`")),
+        }
+        if self.labels.iter().any(|l| l.name == name) {
+            bail!("label '{name}' already defined");
+        }
+        self.record(|tree| tree.stat(StatKind::Label(name.clone())));
+
+        // as in the reference implementation, a label at the end of a
+        // block is outside the scope of its locals
+        let at_end = block_follow(self.lex.peek()?);
+        let pc = self.byte_codes.len();
+        let line = self.lex.span().start.line as u32;
+        self.solve_gotos(&name, at_end)?;
+        self.labels.push(Label {
+            name,
+            pc,
+            nlocals: self.locals.len(),
+            line,
+            close: false,
+        });
+        Ok(())
+    }
+
+    /// Point the pending gotos of the current block to label `name`, here,
+    /// `at_end` of the block if it is outside the scope of its locals.
+    /// Locals captured in the scopes they leave are closed first.
+    fn solve_gotos(&mut self, name: &str, at_end: bool) -> anyhow::Result<()> {
+        let pc = self.byte_codes.len();
+        let mut close = None;
+        // only gotos in the same block can see it
+        let mut i = self.first_goto;
+        while i < self.gotos.len() {
+            if self.gotos[i].name != name {
+                i += 1;
+                continue;
+            }
+            let goto = self.gotos.remove(i);
+            if !at_end && goto.nlocals < self.locals.len() {
+                bail!(
+                    "<goto {name}> jumps into the scope of local '{}'",
+                    self.locals[goto.nlocals]
+                );
+            }
+            if goto.pc != usize::MAX {
+                self.patch_jump(goto.pc, pc)?;
+            }
+            if goto.close {
+                let level = goto.nlocals.min(self.locals.len());
+                close = Some(close.map_or(level, |c: usize| c.min(level)));
+            }
+        }
+        if let Some(level) = close {
+            let start = self.lex.span().start;
+            self.emit(ByteCode::Close(reg(level)?), start);
+        }
+        Ok(())
+    }
+
+    /// Emit a jump to be patched with [`patch_jump`](Self::patch_jump)
+    /// once its target is known, returning its position.
+    fn jump(&mut self, start: Location) -> usize {
+        self.emit(ByteCode::Jump(0), start);
+        self.byte_codes.len() - 1
+    }
+
+    /// Point the jump at `pc` to `target`.
+    fn patch_jump(&mut self, pc: usize, target: usize) -> anyhow::Result<()> {
+        self.byte_codes[pc] = ByteCode::Jump(jump_offset(pc, target)?);
+        Ok(())
+    }
+
+    /// Emit a jump to `target`, which is already known.
+    fn jump_to(&mut self, target: usize, start: Location) -> anyhow::Result<()> {
+        let pc = self.byte_codes.len();
+        self.emit(ByteCode::Jump(jump_offset(pc, target)?), start);
+        Ok(())
+    }
+
+    /// A statement starting with variable `name` that does not assign it:
+    /// a call, as in `a.b(c)`, or an assignment to a field, as in
+    /// `a.b = v` or `a[k] = v`.
+    fn exp_stat(&mut self, name: String) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        let func = self.locals.len();
+        self.prefix(func, name)?;
+        if self.lex.peek()? != &Token::Assign {
+            // a call last, which keeps none of its results
+            return match self.byte_codes.last_mut() {
+                Some(ByteCode::Call(f, _, nret)) if *f as usize == func => {
+                    *nret = 0;
+                    self.record(|tree| {
+                        let e = tree.pop();
+                        tree.stat(StatKind::Call(e));
+                    });
+                    Ok(())
+                }
+                _ => {
+                    let t = self.lex.next()?;
+                    Err(unexpected(&t, "syntax error"))
+                }
+            };
+        }
+        self.lex.next()?;
+
+        // the field was read last, which becomes the write, with the
+        // value above the table and the key
+        let value = func + 2;
+        let operands = self.operand_spans.last().copied().unwrap_or_default();
+        let code = match self.pop_code() {
+            Some(ByteCode::GetField(t, _, k)) => ByteCode::SetField(t, k, reg(value)?),
+            Some(ByteCode::GetTable(t, _, k)) => ByteCode::SetTable(t, k, reg(value)?),
+            Some(ByteCode::GetInt(t, _, i)) => ByteCode::SetInt(t, i, reg(value)?),
+            Some(ByteCode::GetGlobalField(t, g, k)) => {
+                self.emit(ByteCode::GetGlobal(t, g), start);
+                ByteCode::SetField(t, k, reg(value)?)
+            }
+            _ => bail!("syntax error near '='"),
+        };
+        self.load_exp(value)?;
+        self.emit_operands(code, start, operands);
+        self.record(TreeBuilder::assign);
+        Ok(())
+    }
+
+    /// Load variable `name` and the fields and calls that follow it, as in
+    /// `a.b[c](d)`, into register `dst`. The calls keep one result.
+    fn prefix(&mut self, dst: usize, name: String) -> anyhow::Result<()> {
+        let var = self.lex.span();
+        let start = var.start;
+        self.record(|tree| tree.push(ast::Exp::Name(name.clone())));
+        let mut code = self.load_var(dst, name)?;
+        // the first field of a global is read with the global
+        if let ByteCode::GetGlobal(_, name) = code {
+            if self.lex.peek()? == &Token::Dot {
+                code = ByteCode::GetGlobalField(reg(dst)?, name, self.field()?);
+            }
+        }
+        // a local loaded into its own register is already there
+        if !matches!(code, ByteCode::Move(dst, src) if dst == src) {
+            self.emit_operands(code, start, [var, Span::default()]);
+        }
+        self.suffixes(dst, start)
+    }
+
+    /// The fields, calls and method calls that follow a prefix expression
+    /// in register `dst`, which starts at `start`.
+    fn suffixes(&mut self, dst: usize, start: Location) -> anyhow::Result<()> {
+        loop {
+            // what comes so far, which the suffix indexes or calls
+            let operand = self.span_from(start);
+            let code = match self.lex.peek()? {
+                Token::Dot => ByteCode::GetField(reg(dst)?, reg(dst)?, self.field()?),
+                Token::SqurL => {
+                    self.lex.next()?;
+                    let (t, d) = (reg(dst)?, reg(dst)?);
+                    let key = self.index_key(dst + 1)?;
+                    self.record(TreeBuilder::index);
+                    match key {
+                        Key::Field(k) => ByteCode::GetField(d, t, k),
+                        Key::Int(i) => ByteCode::GetInt(d, t, i),
+                        Key::Reg(k) => ByteCode::GetTable(d, t, k),
+                    }
+                }
+                Token::ParL | Token::String(_) => {
+                    let nargs = self.args(dst, 0, 1, operand)?;
+                    self.record(|tree| tree.call(nargs));
+                    continue;
+                }
+                Token::Colon => {
+                    self.lex.next()?;
+                    let name = match self.lex.next()? {
+                        Token::Name(name) => name,
+                        t => return Err(unexpected(&t, "<name> expected")),
+                    };
+                    let k = self.add_const(name.as_str().into())?;
+                    // the object goes after the method
+                    reg(dst + 1)?;
+                    let code = ByteCode::GetMethod(reg(dst)?, reg(dst)?, k);
+                    self.emit_operands(code, start, [operand, Span::default()]);
+                    if !self.at_call_args()? {
+                        let t = self.lex.next()?;
+                        return Err(unexpected(&t, "function arguments expected"));
+                    }
+                    let method = self.span_from(start);
+                    let nargs = self.args(dst, 1, 1, method)?;
+                    self.record(|tree| tree.method(&name, nargs));
+                    continue;
+                }
+                _ => return Ok(()),
+            };
+            self.emit_operands(code, start, [operand, Span::default()]);
+        }
+    }
+
+    /// `exp]`, the rest of a key in brackets, as an operand of the
+    /// instruction indexing with it: a string or a small integer as is,
+    /// anything else in a register, `dst` if not a local.
+    fn index_key(&mut self, dst: usize) -> anyhow::Result<Key> {
+        let start = self.lex.span().start;
+        let t = self.lex.next()?;
+        let key = match self.subexp(dst, t, 0)? {
+            Exp::Const(Value::Integer(i)) if u8::try_from(i).is_ok() => Key::Int(i as u8),
+            Exp::Const(c @ (Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_))) => {
+                Key::Field(self.add_const(c)?)
+            }
+            e => Key::Reg(self.any_reg(dst, e, start)?),
+        };
         match self.lex.next()? {
+            Token::SqurR => Ok(key),
+            t => Err(unexpected(&t, "']' expected")),
+        }
+    }
+
+    /// `.name`, returning the constant of the name.
+    fn field(&mut self) -> anyhow::Result<u8> {
+        self.lex.next()?;
+        let key = match self.lex.next()? {
+            Token::Name(key) => key,
+            t => return Err(unexpected(&t, "expected field name")),
+        };
+        self.record(|tree| tree.field(&key));
+        self.add_const(key.into())
+    }
+
+    /// Call the function in register `func` with the arguments that
+    /// follow, after the `nself` already above it, the object of a method
+    /// call, keeping `nret` results from `func` on. The call starts with
+    /// the function expression, at `callee`. Returns the number of
+    /// arguments that follow.
+    fn args(&mut self, func: usize, nself: usize, nret: u8, callee: Span) -> anyhow::Result<usize> {
+        let nexp;
+        let narg = match self.lex.next()? {
             Token::ParL => {
-                self.load_exp(self.locals.len() + 1)?;
+                let mut narg = nself;
+                if self.lex.peek()? != &Token::ParR {
+                    loop {
+                        self.load_exp(func + 1 + narg)?;
+                        narg += 1;
+                        if self.lex.peek()? != &Token::Comma {
+                            break;
+                        }
+                        self.lex.next()?;
+                    }
+                }
+                nexp = narg - nself;
 
-                if self.lex.next()? != Token::ParR {
-                    bail!("expected `)`");
+                let narg = match narg > nself && self.set_multret(func + narg) {
+                    true => MULTRET,
+                    false => reg(narg)?,
+                };
+                match self.lex.next()? {
+                    Token::ParR => narg,
+                    t => return Err(unexpected(&t, "expected `)`")),
                 }
             }
             Token::String(s) => {
-                let code = self.load_const(self.locals.len() + 1, s.into());
-                self.byte_codes.push(code);
+                self.record(|tree| tree.push(ast::Exp::String(s.clone())));
+                let code = self.load_const(func + 1 + nself, s.into())?;
+                self.emit(code, self.lex.span().start);
+                nexp = 1;
+                reg(nself + 1)?
+            }
+            t => return Err(unexpected(&t, "expected string")),
+        };
+        let code = ByteCode::Call(reg(func)?, narg, nret);
+        self.emit_operands(code, callee.start, [callee, Span::default()]);
+        Ok(nexp)
+    }
+
+    /// If the last expression was a call to register `func`, or `...`
+    /// into it, make it keep all of its values, as it does in last place of
+    /// a list.
+    fn set_multret(&mut self, func: usize) -> bool {
+        match self.last_call(func) {
+            Some(nret) => {
+                *nret = MULTRET;
+                true
             }
-            _ => bail!("expected string"),
+            None => false,
         }
-        self.byte_codes
-            .push(ByteCode::Call(self.locals.len() as u8, 1));
-        Ok(())
+    }
+
+    /// The count of results of the last expression, if it was a call to
+    /// register `func`, or `...` into it, not in parentheses.
+    fn last_call(&mut self, func: usize) -> Option<&mut u8> {
+        if self.byte_codes.len() == self.single {
+            return None;
+        }
+        match self.byte_codes.last_mut() {
+            Some(ByteCode::Call(f, _, nret) | ByteCode::VarArgs(f, nret))
+                if *f as usize == func =>
+            {
+                Some(nret)
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the next token starts the arguments of a call.
+    fn at_call_args(&mut self) -> anyhow::Result<bool> {
+        Ok(matches!(self.lex.peek()?, Token::ParL | Token::String(_)))
+    }
+
+    /// Whether the next token starts a method call, `:name(args)`.
+    fn at_method(&mut self) -> anyhow::Result<bool> {
+        Ok(self.lex.peek()? == &Token::Colon)
+    }
+
+    /// Whether the next token starts a field, `.name` or `[exp]`.
+    fn at_index(&mut self) -> anyhow::Result<bool> {
+        Ok(matches!(self.lex.peek()?, Token::Dot | Token::SqurL))
     }
 
     fn assignment(&mut self, var: String) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
         self.lex.next()?;
+        self.record(|tree| tree.push(ast::Exp::Name(var.clone())));
 
         if let Some(i) = self.get_local(&var) {
             // local variable
             self.load_exp(i)?;
+        } else if let Some(u) = self.upvalue(&var)? {
+            // local variable of an enclosing function
+            let tmp = self.locals.len();
+            self.load_exp(tmp)?;
+            self.emit(ByteCode::SetUpval(reg(tmp)?, u), start);
         } else {
-            // global variable
-            let dst = self.add_const(var.into()) as u8;
-            let code = match self.lex.next()? {
-                Token::Nil => ByteCode::SetGlobalConst(dst, self.add_const(Value::Nil) as u8),
-                Token::True => ByteCode::SetGlobalConst(dst, self.add_const(true.into()) as u8),
-                Token::False => ByteCode::SetGlobalConst(dst, self.add_const(false.into()) as u8),
-                Token::Integer(i) => ByteCode::SetGlobalConst(dst, self.add_const(i.into()) as u8),
-                Token::Float(f) => ByteCode::SetGlobalConst(dst, self.add_const(f.into()) as u8),
-                Token::String(s) => ByteCode::SetGlobalConst(dst, self.add_const(s.into()) as u8),
-                // from variable
-                Token::Name(var) => {
-                    if let Some(i) = self.get_local(&var) {
-                        // local variable
-                        ByteCode::SetGlobal(dst, i as u8)
-                    } else {
-                        ByteCode::SetGlobalGlobal(dst, self.add_const(var.into()) as u8)
+            // global variable, from a constant, a local, another global, or
+            // anything else through a free register
+            let dst = self.add_const(var.into())?;
+            let tmp = self.locals.len();
+            let t = self.lex.next()?;
+            let code = match self.subexp(tmp, t, 0)? {
+                Exp::Const(c) => ByteCode::SetGlobalConst(dst, self.add_const(c)?),
+                Exp::Reg(r) if r != tmp => ByteCode::SetGlobal(dst, reg(r)?),
+                Exp::Reg(_) => match self.byte_codes.last() {
+                    Some(&ByteCode::GetGlobal(d, k)) if d as usize == tmp => {
+                        self.pop_code();
+                        ByteCode::SetGlobalGlobal(dst, k)
                     }
-                }
-                _ => bail!("invalid argument"),
+                    _ => ByteCode::SetGlobal(dst, reg(tmp)?),
+                },
             };
-            self.byte_codes.push(code);
+            self.emit(code, start);
         }
+        self.record(TreeBuilder::assign);
         Ok(())
     }
 
-    fn add_const(&mut self, c: Value) -> usize {
-        self.constants
+    /// Take back the last instruction, to replace it.
+    fn pop_code(&mut self) -> Option<ByteCode> {
+        self.lines.pop();
+        self.spans.pop();
+        self.operand_spans.pop();
+        self.byte_codes.pop()
+    }
+
+    /// Add `code`, compiled from the source from `start` up to the last
+    /// token read.
+    fn emit(&mut self, code: ByteCode, start: Location) {
+        self.emit_operands(code, start, Default::default());
+    }
+
+    /// Add `code` as [`emit`](Self::emit) does, with the spans of the
+    /// operands it may fail on, an empty span for one it has not.
+    fn emit_operands(&mut self, code: ByteCode, start: Location, operands: [Span; 2]) {
+        self.byte_codes.push(code);
+        self.lines.push(start.line as u32);
+        if self.options.spans {
+            self.spans.push(self.span_from(start));
+            self.operand_spans.push(operands);
+        }
+    }
+
+    /// The source from `start` up to the last token read.
+    fn span_from(&self, start: Location) -> Span {
+        Span {
+            start,
+            end: self.lex.span().end,
+        }
+    }
+
+    /// Index of constant `c`, added if it is new.
+    fn add_const(&mut self, c: Value) -> anyhow::Result<u8> {
+        let i = self
+            .constants
             .iter()
             .position(|v| v == &c)
             .unwrap_or_else(|| {
                 self.constants.push(c);
                 self.constants.len() - 1
-            })
+            });
+        u8::try_from(i).map_err(|_| anyhow!("too many constants"))
     }
 
-    fn load_const(&mut self, dst: usize, c: Value) -> ByteCode {
-        ByteCode::LoadConst(dst as u8, self.add_const(c) as u8)
+    fn load_const(&mut self, dst: usize, c: Value) -> anyhow::Result<ByteCode> {
+        Ok(ByteCode::LoadConst(reg(dst)?, self.add_const(c)?))
     }
 
     fn load_exp(&mut self, dst: usize) -> anyhow::Result<()> {
-        let code = match self.lex.next()? {
-            Token::Nil => ByteCode::LoadNil(dst as u8),
-            Token::True => ByteCode::LoadBool(dst as u8, true),
-            Token::False => ByteCode::LoadBool(dst as u8, false),
-            Token::Integer(i) => {
-                if let Result::Ok(ii) = i16::try_from(i) {
-                    ByteCode::LoadInt(dst as u8, ii)
-                } else {
-                    self.load_const(dst, i.into())
-                }
-            }
-            Token::Float(f) => self.load_const(dst, f.into()),
-            Token::String(s) => self.load_const(dst, s.into()),
-            Token::Name(var) => self.load_var(dst, var),
-            _ => bail!("invalid argument"),
+        let t = self.lex.next()?;
+        self.exp(dst, t)
+    }
+
+    /// Load the expression starting with token `t` into register `dst`.
+    fn exp(&mut self, dst: usize, t: Token) -> anyhow::Result<()> {
+        let start = self.lex.span().start;
+        // a local being assigned gets only the result, since the operands,
+        // or the arguments of a call, may still read it
+        let e = self.subexp(dst.max(self.locals.len()), t, 0)?;
+        self.discharge(dst, e, start)
+    }
+
+    /// The expression starting with token `t`, up to the first binary
+    /// operator of a priority at most `limit`, compiled into free register
+    /// `dst` unless it is a local or a constant.
+    fn subexp(&mut self, dst: usize, t: Token, limit: u8) -> anyhow::Result<Exp> {
+        let start = self.lex.span().start;
+        self.enter_level()?;
+        let mut e = match t {
+            Token::Sub => {
+                let t = self.lex.next()?;
+                let operand = self.lex.span().start;
+                let e = self.subexp(dst, t, UNARY_PRIORITY)?;
+                self.record(|tree| tree.unary(UnOp::Neg));
+                let operand = self.span_from(operand);
+                self.unary_minus(dst, e, start, operand)?
+            }
+            t => self.simple(dst, t)?,
         };
-        self.byte_codes.push(code);
+        while let Some((left, right)) = binary_priority(self.lex.peek()?) {
+            if left <= limit {
+                break;
+            }
+            let l = self.span_from(start);
+            let t = self.lex.next()?;
+            let bin = binary_op(&t);
+            let op = match t {
+                Token::Concat => {
+                    e = self.concat(dst, e, right, start)?;
+                    self.record(|tree| tree.binary(bin));
+                    continue;
+                }
+                t @ (Token::Equal
+                | Token::NotEq
+                | Token::Less
+                | Token::LesEq
+                | Token::Greater
+                | Token::GreEq) => {
+                    e = self.compare(t, dst, e, right, start)?;
+                    self.record(|tree| tree.binary(bin));
+                    continue;
+                }
+                Token::Add => ArithOp::Add,
+                Token::Sub => ArithOp::Sub,
+                Token::Mul => ArithOp::Mul,
+                Token::Div => ArithOp::Div,
+                Token::Idiv => ArithOp::Idiv,
+                Token::Mod => ArithOp::Mod,
+                _ => ArithOp::Pow,
+            };
+            // the left operand stays where the right one cannot overwrite
+            // it, and a number is kept for folding, or for the constant
+            // forms, leaving `dst` for it if neither applies
+            let rdst = match e {
+                Exp::Reg(r) if r != dst => dst,
+                Exp::Const(Value::Integer(_) | Value::Float(_)) | Exp::Reg(_) => dst + 1,
+                Exp::Const(c) => {
+                    self.discharge(dst, Exp::Const(c), start)?;
+                    e = Exp::Reg(dst);
+                    dst + 1
+                }
+            };
+            let t = self.lex.next()?;
+            let rstart = self.lex.span().start;
+            let r = self.subexp(rdst, t, right)?;
+            e = self.arith(op, dst, e, r, [l, self.span_from(rstart)])?;
+            self.record(|tree| tree.binary(bin));
+        }
+        self.depth -= 1;
+        Ok(e)
+    }
+
+    /// A single operand, starting with token `t`: a constant, a local, or
+    /// anything else loaded into register `dst`.
+    fn simple(&mut self, dst: usize, t: Token) -> anyhow::Result<Exp> {
+        let start = self.lex.span().start;
+        self.record(|tree| {
+            if let Some(e) = leaf(&t) {
+                tree.push(e);
+            }
+        });
+        match t {
+            Token::Nil => return Ok(Exp::Const(Value::Nil)),
+            Token::True => return Ok(Exp::Const(true.into())),
+            Token::False => return Ok(Exp::Const(false.into())),
+            Token::Integer(i) => return Ok(Exp::Const(i.into())),
+            Token::Float(f) => return Ok(Exp::Const(f.into())),
+            Token::String(s) => return Ok(Exp::Const(s.into())),
+            Token::Dots => {
+                if !self.is_vararg {
+                    bail!("cannot use '...' outside a vararg function");
+                }
+                self.emit(ByteCode::VarArgs(reg(dst)?, 1), start);
+            }
+            Token::Function => self.function_body(dst, start)?,
+            Token::CurlyL => self.table(dst, start)?,
+            Token::ParL => {
+                let t = self.lex.next()?;
+                let e = self.subexp(dst, t, 0)?;
+                match self.lex.next()? {
+                    Token::ParR => (),
+                    t => return Err(unexpected(&t, "')' expected")),
+                }
+                self.record(TreeBuilder::paren);
+                if self.at_index()? || self.at_call_args()? || self.at_method()? {
+                    self.discharge(dst, e, start)?;
+                    self.suffixes(dst, start)?;
+                    return Ok(Exp::Reg(dst));
+                }
+                // a call or `...` in parentheses has one value, even last
+                self.single = self.byte_codes.len();
+                return Ok(e);
+            }
+            Token::Name(var) => {
+                // a local alone is already in its register
+                if !self.at_index()? && !self.at_call_args()? && !self.at_method()? {
+                    if let Some(i) = self.get_local(&var) {
+                        self.record(|tree| tree.push(ast::Exp::Name(var)));
+                        return Ok(Exp::Reg(i));
+                    }
+                }
+                self.prefix(dst, var)?;
+            }
+            t => return Err(unexpected(&t, "invalid argument")),
+        }
+        Ok(Exp::Reg(dst))
+    }
+
+    /// Put the value of `e` in register `dst`.
+    fn discharge(&mut self, dst: usize, e: Exp, start: Location) -> anyhow::Result<()> {
+        let code = match e {
+            Exp::Reg(r) if r == dst => return Ok(()),
+            // a free register the last instruction may have written
+            Exp::Reg(r) if r >= self.locals.len() => {
+                self.move_result(reg(dst)?, reg(r)?, start);
+                return Ok(());
+            }
+            Exp::Reg(r) => ByteCode::Move(reg(dst)?, reg(r)?),
+            Exp::Const(Value::Nil) => ByteCode::LoadNil(reg(dst)?),
+            Exp::Const(Value::Boolean(b)) => ByteCode::LoadBool(reg(dst)?, b),
+            Exp::Const(Value::Integer(i)) if i16::try_from(i).is_ok() => {
+                ByteCode::LoadInt(reg(dst)?, i as i16)
+            }
+            Exp::Const(c) => self.load_const(dst, c)?,
+        };
+        self.emit(code, start);
         Ok(())
     }
 
-    fn load_var(&mut self, dst: usize, name: String) -> ByteCode {
-        if let Some(i) = self.get_local(&name) {
-            ByteCode::Move(dst as u8, i as u8)
+    /// The register of `e`, loaded into `dst` if it is a constant.
+    fn any_reg(&mut self, dst: usize, e: Exp, start: Location) -> anyhow::Result<u8> {
+        match e {
+            Exp::Reg(r) => reg(r),
+            e => {
+                self.discharge(dst, e, start)?;
+                reg(dst)
+            }
+        }
+    }
+
+    /// `-e`, folded if `e` is a number, with `e` at `operand`.
+    fn unary_minus(
+        &mut self,
+        dst: usize,
+        e: Exp,
+        start: Location,
+        operand: Span,
+    ) -> anyhow::Result<Exp> {
+        if let Exp::Const(c) = &e {
+            if let Some(v) = fold(ArithOp::Unm, c, c) {
+                return Ok(Exp::Const(v));
+            }
+        }
+        let src = self.any_reg(dst, e, start)?;
+        let code = ByteCode::Unm(reg(dst)?, src);
+        self.emit_operands(code, start, [operand, Span::default()]);
+        Ok(Exp::Reg(dst))
+    }
+
+    /// `l op r` into register `dst`: folded if both are numbers, or with
+    /// a number `r` in the instruction, as a small integer to add or as a
+    /// constant. The operands are never swapped, as a metamethod gets them
+    /// in order; `operands` are where they are.
+    fn arith(
+        &mut self,
+        op: ArithOp,
+        dst: usize,
+        l: Exp,
+        r: Exp,
+        operands: [Span; 2],
+    ) -> anyhow::Result<Exp> {
+        let start = operands[0].start;
+        if let (Exp::Const(a), Exp::Const(b)) = (&l, &r) {
+            if let Some(v) = fold(op, a, b) {
+                return Ok(Exp::Const(v));
+            }
+        }
+        let a = self.any_reg(dst, l, start)?;
+        let d = reg(dst)?;
+        let code = match (op, &r) {
+            (ArithOp::Add, &Exp::Const(Value::Integer(i))) if i8::try_from(i).is_ok() => {
+                ByteCode::AddInt(d, a, i as i8)
+            }
+            (op, Exp::Const(c @ (Value::Integer(_) | Value::Float(_)))) => {
+                let k = self.add_const(c.clone())?;
+                match op {
+                    ArithOp::Add => ByteCode::AddK(d, a, k),
+                    ArithOp::Sub => ByteCode::SubK(d, a, k),
+                    ArithOp::Mul => ByteCode::MulK(d, a, k),
+                    ArithOp::Div => ByteCode::DivK(d, a, k),
+                    ArithOp::Idiv => ByteCode::IdivK(d, a, k),
+                    ArithOp::Mod => ByteCode::ModK(d, a, k),
+                    _ => ByteCode::PowK(d, a, k),
+                }
+            }
+            _ => {
+                // above `a`, which is a local or `dst`
+                let b = self.any_reg(dst + 1, r, start)?;
+                match op {
+                    ArithOp::Add => ByteCode::Add(d, a, b),
+                    ArithOp::Sub => ByteCode::Sub(d, a, b),
+                    ArithOp::Mul => ByteCode::Mul(d, a, b),
+                    ArithOp::Div => ByteCode::Div(d, a, b),
+                    ArithOp::Idiv => ByteCode::Idiv(d, a, b),
+                    ArithOp::Mod => ByteCode::Mod(d, a, b),
+                    _ => ByteCode::Pow(d, a, b),
+                }
+            }
+        };
+        self.emit_operands(code, start, operands);
+        Ok(Exp::Reg(dst))
+    }
+
+    /// `l op r` for comparison operator `op`, with `r` up to an operator
+    /// of priority at most `limit`, into register `dst`. Both operands go
+    /// in registers, and `>` and `>=` are `<` and `<=` with the registers
+    /// swapped, though still evaluated in order.
+    fn compare(
+        &mut self,
+        op: Token,
+        dst: usize,
+        l: Exp,
+        limit: u8,
+        start: Location,
+    ) -> anyhow::Result<Exp> {
+        let a = self.any_reg(dst, l, start)?;
+        let rdst = if a as usize == dst { dst + 1 } else { dst };
+        let t = self.lex.next()?;
+        let r = self.subexp(rdst, t, limit)?;
+        let b = self.any_reg(rdst, r, start)?;
+        let d = reg(dst)?;
+        let code = match op {
+            Token::Equal => ByteCode::Eq(d, a, b),
+            Token::NotEq => ByteCode::Ne(d, a, b),
+            Token::Less => ByteCode::Lt(d, a, b),
+            Token::LesEq => ByteCode::Le(d, a, b),
+            Token::Greater => ByteCode::Lt(d, b, a),
+            _ => ByteCode::Le(d, b, a),
+        };
+        self.emit(code, start);
+        Ok(Exp::Reg(dst))
+    }
+
+    /// `l .. r`, with `r` up to an operator of priority at most `limit`,
+    /// into register `dst`. The operands of `..` go in consecutive
+    /// registers.
+    fn concat(&mut self, dst: usize, l: Exp, limit: u8, start: Location) -> anyhow::Result<Exp> {
+        self.discharge(dst, l, start)?;
+        let t = self.lex.next()?;
+        let rstart = self.lex.span().start;
+        let r = self.subexp(dst + 1, t, limit)?;
+        self.discharge(dst + 1, r, rstart)?;
+        // `..` is right associative, which one instruction for the whole
+        // chain leaves to the VM
+        match self.byte_codes.last_mut() {
+            Some(ByteCode::Concat(first, n)) if *first as usize == dst + 1 => {
+                *first -= 1;
+                *n += 1;
+                // which starts with the left operand
+                *self.lines.last_mut().unwrap() = start.line as u32;
+                if let Some(span) = self.spans.last_mut() {
+                    span.start = start;
+                }
+            }
+            _ => self.emit(ByteCode::Concat(reg(dst)?, 2), start),
+        }
+        Ok(Exp::Reg(dst))
+    }
+
+    /// Move the result of the last instruction from free register `src`
+    /// to `dst`, by having the instruction write `dst` itself when it can.
+    fn move_result(&mut self, dst: u8, src: u8, start: Location) {
+        let code = match self.byte_codes.last_mut() {
+            Some(
+                ByteCode::GetGlobal(d, _)
+                | ByteCode::LoadConst(d, _)
+                | ByteCode::LoadNil(d)
+                | ByteCode::LoadBool(d, _)
+                | ByteCode::LoadInt(d, _)
+                | ByteCode::Move(d, _)
+                | ByteCode::GetField(d, _, _)
+                | ByteCode::GetGlobalField(d, _, _)
+                | ByteCode::Closure(d, _)
+                | ByteCode::GetUpval(d, _)
+                | ByteCode::GetTable(d, _, _)
+                | ByteCode::GetInt(d, _, _)
+                | ByteCode::Add(d, _, _)
+                | ByteCode::Sub(d, _, _)
+                | ByteCode::Mul(d, _, _)
+                | ByteCode::Div(d, _, _)
+                | ByteCode::Idiv(d, _, _)
+                | ByteCode::Mod(d, _, _)
+                | ByteCode::Pow(d, _, _)
+                | ByteCode::AddK(d, _, _)
+                | ByteCode::SubK(d, _, _)
+                | ByteCode::MulK(d, _, _)
+                | ByteCode::DivK(d, _, _)
+                | ByteCode::IdivK(d, _, _)
+                | ByteCode::ModK(d, _, _)
+                | ByteCode::PowK(d, _, _)
+                | ByteCode::AddInt(d, _, _)
+                | ByteCode::Unm(d, _)
+                | ByteCode::Eq(d, _, _)
+                | ByteCode::Ne(d, _, _)
+                | ByteCode::Lt(d, _, _)
+                | ByteCode::Le(d, _, _),
+            ) if *d == src => d,
+            _ => {
+                self.emit(ByteCode::Move(dst, src), start);
+                return;
+            }
+        };
+        *code = dst;
+    }
+
+    /// `{ [field {sep field} [sep]] }`, a new table in register `dst`.
+    /// Fields without keys are items of the list, set together at the
+    /// end from the registers after `dst`, those with keys as they come.
+    fn table(&mut self, dst: usize, start: Location) -> anyhow::Result<()> {
+        self.emit(ByteCode::NewTable(reg(dst)?), start);
+        self.record(TreeBuilder::open_table);
+        let mut n = 0;
+        while self.lex.peek()? != &Token::CurlyR {
+            let item = dst + 1 + n;
+            match self.lex.next()? {
+                Token::SqurL => {
+                    let key = self.index_key(item)?;
+                    match self.lex.next()? {
+                        Token::Assign => (),
+                        t => return Err(unexpected(&t, "'=' expected")),
+                    }
+                    self.load_exp(item + 1)?;
+                    self.record(TreeBuilder::keyed);
+                    let (t, v) = (reg(dst)?, reg(item + 1)?);
+                    let code = match key {
+                        Key::Field(k) => ByteCode::SetField(t, k, v),
+                        Key::Int(i) => ByteCode::SetInt(t, i, v),
+                        Key::Reg(k) => ByteCode::SetTable(t, k, v),
+                    };
+                    self.emit(code, start);
+                }
+                Token::Name(key) if self.lex.peek()? == &Token::Assign => {
+                    self.lex.next()?;
+                    let k = self.add_const(key.as_str().into())?;
+                    self.load_exp(item)?;
+                    self.record(|tree| tree.named(&key));
+                    self.emit(ByteCode::SetField(reg(dst)?, k, reg(item)?), start);
+                }
+                t => {
+                    self.exp(item, t)?;
+                    self.record(TreeBuilder::item);
+                    n += 1;
+                }
+            }
+            match self.lex.peek()? {
+                Token::Comma | Token::SemiColon => {
+                    self.lex.next()?;
+                }
+                Token::CurlyR => (),
+                t => return Err(unexpected(t, "'}' expected")),
+            }
+        }
+        self.lex.next()?;
+        self.record(TreeBuilder::close_table);
+        if n > 0 {
+            // a call or `...` last fills the list with all its values
+            let count = match self.set_multret(dst + n) {
+                true => MULTRET,
+                false => reg(n)?,
+            };
+            self.emit(ByteCode::SetList(reg(dst)?, count), start);
+        }
+        Ok(())
+    }
+
+    fn load_var(&mut self, dst: usize, name: String) -> anyhow::Result<ByteCode> {
+        Ok(if let Some(i) = self.get_local(&name) {
+            ByteCode::Move(reg(dst)?, reg(i)?)
+        } else if let Some(u) = self.upvalue(&name)? {
+            ByteCode::GetUpval(reg(dst)?, u)
         } else {
-            let ic = self.add_const(name.into());
-            ByteCode::GetGlobal(dst as u8, ic as u8)
+            ByteCode::GetGlobal(reg(dst)?, self.add_const(name.into())?)
+        })
+    }
+
+    /// Index of upvalue `name` of the function being compiled: a local of
+    /// an enclosing function, captured by every function from there in.
+    /// It is added if new. `None` if no enclosing function has such a
+    /// local, for a global.
+    fn upvalue(&mut self, name: &str) -> anyhow::Result<Option<u8>> {
+        if let Some(i) = self.upvalues.iter().position(|u| u.name == name) {
+            return Ok(Some(i as u8));
         }
+        // the innermost enclosing function with the local, or with an
+        // upvalue for it already
+        let found = self.outer.iter().enumerate().rev().find_map(|(level, f)| {
+            match f.locals.iter().rposition(|v| v == name) {
+                Some(r) => Some((level, true, r)),
+                None => f
+                    .upvalues
+                    .iter()
+                    .position(|u| u.name == name)
+                    .map(|i| (level, false, i)),
+            }
+        });
+        let Some((level, mut in_stack, mut index)) = found else {
+            return Ok(None);
+        };
+        if in_stack {
+            self.outer[level].captured[index] = true;
+        }
+        for f in &mut self.outer[level + 1..] {
+            f.upvalues.push(UpvalDesc::new(name, in_stack, index)?);
+            (in_stack, index) = (false, f.upvalues.len() - 1);
+        }
+        self.upvalues.push(UpvalDesc::new(name, in_stack, index)?);
+        Ok(Some((self.upvalues.len() - 1) as u8))
     }
 
     fn get_local(&mut self, name: &String) -> Option<usize> {
@@ -171,24 +1758,738 @@ impl<'a, S: ByteStream<'a>> ParseProtoBuilder<S> {
     }
 }
 
-#[derive(Debug)]
+/// `line:column` of `at`, for messages.
+fn line_column(at: Location) -> String {
+    format!("{}:{}", at.line, at.column)
+}
+
+/// Number of registers the byte codes use, including the arguments and
+/// results of calls.
+fn max_stack(byte_codes: &[ByteCode]) -> usize {
+    byte_codes
+        .iter()
+        .map(|code| match *code {
+            ByteCode::GetGlobal(dst, _)
+            | ByteCode::LoadConst(dst, _)
+            | ByteCode::LoadNil(dst)
+            | ByteCode::LoadBool(dst, _)
+            | ByteCode::LoadInt(dst, _)
+            | ByteCode::Move(dst, _)
+            | ByteCode::GetField(dst, _, _)
+            | ByteCode::GetGlobalField(dst, _, _)
+            | ByteCode::Closure(dst, _)
+            | ByteCode::GetUpval(dst, _)
+            | ByteCode::NewTable(dst)
+            | ByteCode::GetTable(dst, _, _)
+            | ByteCode::GetInt(dst, _, _)
+            | ByteCode::Add(dst, _, _)
+            | ByteCode::Sub(dst, _, _)
+            | ByteCode::Mul(dst, _, _)
+            | ByteCode::Div(dst, _, _)
+            | ByteCode::Idiv(dst, _, _)
+            | ByteCode::Mod(dst, _, _)
+            | ByteCode::Pow(dst, _, _)
+            | ByteCode::AddK(dst, _, _)
+            | ByteCode::SubK(dst, _, _)
+            | ByteCode::MulK(dst, _, _)
+            | ByteCode::DivK(dst, _, _)
+            | ByteCode::IdivK(dst, _, _)
+            | ByteCode::ModK(dst, _, _)
+            | ByteCode::PowK(dst, _, _)
+            | ByteCode::AddInt(dst, _, _)
+            | ByteCode::Unm(dst, _)
+            | ByteCode::Eq(dst, _, _)
+            | ByteCode::Ne(dst, _, _)
+            | ByteCode::Lt(dst, _, _)
+            | ByteCode::Le(dst, _, _) => dst as usize + 1,
+            ByteCode::Concat(first, n) => first as usize + n as usize,
+            ByteCode::GetMethod(dst, _, _) => dst as usize + 2,
+            ByteCode::ForPrep(base, _) | ByteCode::ForLoop(base, _) => base as usize + 4,
+            // the function and its two arguments are copied above the
+            // control, where the results go
+            ByteCode::TForCall(base, nvars) => base as usize + 3 + nvars.max(3) as usize,
+            ByteCode::TForLoop(base, _) => base as usize + 4,
+            ByteCode::Call(func, narg, nret) => {
+                let narg = if narg == MULTRET { 0 } else { narg };
+                let nret = if nret == MULTRET { 0 } else { nret };
+                func as usize + 1 + narg.max(nret) as usize
+            }
+            ByteCode::Return(first, n) | ByteCode::VarArgs(first, n) if n != MULTRET => {
+                first as usize + n as usize
+            }
+            _ => 0,
+        })
+        .max()
+        .unwrap_or(0)
+}
+
+/// Register `r`, or a count of registers, as an operand. The largest
+/// byte is left for `MULTRET`.
+fn reg(r: usize) -> anyhow::Result<u8> {
+    match u8::try_from(r) {
+        Result::Ok(r) if r != MULTRET => Ok(r),
+        _ => bail!("function or expression needs too many registers"),
+    }
+}
+
+/// Offset of a jump at `pc` to `target`, relative to the next instruction.
+fn jump_offset(pc: usize, target: usize) -> anyhow::Result<i32> {
+    let offset = target as i64 - (pc as i64 + 1);
+    if offset.abs() > MAX_JUMP as i64 {
+        bail!("control structure too long");
+    }
+    Ok(offset as i32)
+}
+
+/// Whether token `t` ends a block.
+fn block_follow(t: &Token) -> bool {
+    matches!(t, Token::Eos | Token::End | Token::Else | Token::Elseif)
+}
+
+/// Priority of unary operators, between those of the binary ones.
+const UNARY_PRIORITY: u8 = 12;
+
+/// Left and right priorities of binary operator `t`, as in the reference
+/// implementation: the higher binds tighter, and a right one lower than
+/// the left makes the operator right associative.
+fn binary_priority(t: &Token) -> Option<(u8, u8)> {
+    Some(match t {
+        Token::Equal
+        | Token::NotEq
+        | Token::Less
+        | Token::LesEq
+        | Token::Greater
+        | Token::GreEq => (3, 3),
+        Token::Concat => (9, 8),
+        Token::Add | Token::Sub => (10, 10),
+        Token::Mul | Token::Div | Token::Idiv | Token::Mod => (11, 11),
+        Token::Pow => (14, 13),
+        _ => return None,
+    })
+}
+
+/// The operator of the syntax tree for binary operator `t`.
+fn binary_op(t: &Token) -> BinOp {
+    match t {
+        Token::Less => BinOp::Lt,
+        Token::Greater => BinOp::Gt,
+        Token::LesEq => BinOp::Le,
+        Token::GreEq => BinOp::Ge,
+        Token::NotEq => BinOp::Ne,
+        Token::Equal => BinOp::Eq,
+        Token::Concat => BinOp::Concat,
+        Token::Add => BinOp::Add,
+        Token::Sub => BinOp::Sub,
+        Token::Mul => BinOp::Mul,
+        Token::Div => BinOp::Div,
+        Token::Idiv => BinOp::Idiv,
+        Token::Mod => BinOp::Mod,
+        _ => BinOp::Pow,
+    }
+}
+
+/// The expression of the syntax tree for token `t`, if it is one by
+/// itself: a constant or `...`.
+fn leaf(t: &Token) -> Option<ast::Exp> {
+    Some(match t {
+        Token::Nil => ast::Exp::Nil,
+        Token::True => ast::Exp::True,
+        Token::False => ast::Exp::False,
+        &Token::Integer(i) => ast::Exp::Integer(i),
+        &Token::Float(f) => ast::Exp::Float(f),
+        Token::String(s) => ast::Exp::String(s.clone()),
+        Token::Dots => ast::Exp::Dots,
+        _ => return None,
+    })
+}
+
+/// `a op b` for constants, if both are numbers and the result is one the
+/// reference folds too: not an error, nor a NaN or a zero float, whose
+/// sign the constant table would lose.
+fn fold(op: ArithOp, a: &Value, b: &Value) -> Option<Value> {
+    if !matches!(a, Value::Integer(_) | Value::Float(_))
+        || !matches!(b, Value::Integer(_) | Value::Float(_))
+    {
+        return None;
+    }
+    match arith(op, a, b).ok()?? {
+        Value::Float(f) if f.is_nan() || f == 0.0 => None,
+        v => Some(v),
+    }
+}
+
+/// Error for an unexpected token, which it names. Errors at the end of
+/// the input say `near <eof>`, which interactive mode takes as a sign that
+/// the chunk is incomplete.
+fn unexpected(t: &Token, msg: &str) -> anyhow::Error {
+    anyhow::anyhow!("{msg} near {t}")
+}
+
+/// A local variable, for debug information: its name and the instructions
+/// it is in scope for, from `start_pc` up to but not including `end_pc`.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct LocVar {
+    pub name: String,
+    pub start_pc: usize,
+    pub end_pc: usize,
+}
+
+impl LocVar {
+    pub fn in_scope(&self, pc: usize) -> bool {
+        self.start_pc <= pc && pc < self.end_pc
+    }
+}
+
+/// A variable of an enclosing function that a function uses: the local in
+/// register `index` of the function defining it if `in_stack`, otherwise
+/// upvalue `index` of that function.
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UpvalDesc {
+    pub name: String,
+    pub in_stack: bool,
+    pub index: u8,
+}
+
+impl UpvalDesc {
+    fn new(name: &str, in_stack: bool, index: usize) -> anyhow::Result<Self> {
+        Ok(Self {
+            name: name.into(),
+            in_stack,
+            index: u8::try_from(index).map_err(|_| anyhow!("too many upvalues"))?,
+        })
+    }
+}
+
+/// A compiled chunk. Cloning it shares the constants, which do not change
+/// once compiled.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct ParseProto {
-    pub constants: Vec<Value>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::chunk::constants"))]
+    pub constants: Rc<[Value]>,
+    #[cfg_attr(feature = "serde", serde(with = "crate::chunk::byte_codes"))]
     pub byte_codes: Vec<ByteCode>,
+    /// Number of fixed parameters.
+    pub nparams: u8,
+    /// Whether it takes extra arguments as `...`.
+    pub is_vararg: bool,
+    /// Line of the `function` keyword defining it, 0 for a main chunk.
+    pub line_defined: u32,
+    /// The functions defined in it, which `Closure` instructions create
+    /// by index.
+    pub protos: Vec<Rc<ParseProto>>,
+    /// The variables of enclosing functions it uses, by index.
+    pub upvalues: Vec<UpvalDesc>,
+    /// Number of registers it needs.
+    pub max_stack: usize,
+    /// Source span of each byte code, when compiled with
+    /// [`ParseOptions::spans`]; empty otherwise. Not kept in compiled
+    /// chunks.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub spans: Vec<Span>,
+    /// Source spans of the operands each byte code may fail on, such as
+    /// the value it indexes or calls, or either side of an arithmetic
+    /// operator, for its errors to point at the one they are about; an
+    /// empty span for an operand it has not. Kept as `spans` is.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub operand_spans: Vec<[Span; 2]>,
+    /// Source line of each byte code.
+    pub lines: Vec<u32>,
+    /// Name of the chunk, for error positions.
+    pub chunk_name: String,
+    /// Locals in order of declaration. Those in scope at an instruction
+    /// are in registers from 0, in this order.
+    pub locvars: Vec<LocVar>,
+    /// Problems found while compiling that did not stop it.
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub warnings: Vec<String>,
 }
 
 impl ParseProto {
     pub fn load(input: impl Read + 'static) -> anyhow::Result<Self> {
-        let input = buffered::Stream::new(position::Stream::new(read::Stream::new(input)), 10);
-        let builder = ParseProtoBuilder::new(input);
+        Self::load_with(input, ParseOptions::default())
+    }
+
+    pub fn load_with(input: impl Read + 'static, options: ParseOptions) -> anyhow::Result<Self> {
+        Self::compile(input, options, false).map(|(proto, _)| proto)
+    }
+
+    /// Like [`load_with`](Self::load_with), also building the syntax tree
+    /// of the chunk, as [`ast::parse`] does.
+    pub fn load_with_tree(
+        input: impl Read + 'static,
+        options: ParseOptions,
+    ) -> anyhow::Result<(Self, ast::Block)> {
+        let (proto, tree) = Self::compile(input, options, true)?;
+        Ok((proto, tree.unwrap()))
+    }
+
+    fn compile(
+        input: impl Read + 'static,
+        options: ParseOptions,
+        tree: bool,
+    ) -> anyhow::Result<(Self, Option<ast::Block>)> {
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("compile", chunk = %options.chunk_name).entered();
+        let input = easy::Stream(buffered::Stream::new(
+            position::Stream::with_positioner(read::Stream::new(input), Location::default()),
+            10,
+        ));
+        let mut builder = ParseProtoBuilder::new(input, options);
+        if tree {
+            builder.tree = Some(TreeBuilder::default());
+        }
 
         builder.load()
     }
 
-    pub fn get_global(&self, index: usize) -> anyhow::Result<&str> {
+    /// Like [`load_with`](Self::load_with), for tools that show compile
+    /// errors: the error says where in the source it is.
+    pub fn try_compile(
+        input: impl Read + 'static,
+        options: ParseOptions,
+    ) -> Result<Self, SyntaxError> {
+        Self::load_with(input, options).map_err(|err| match err.downcast::<SyntaxError>() {
+            Result::Ok(err) => err,
+            // not raised by the parser, which reports syntax errors only
+            Err(err) => SyntaxError {
+                message: err.to_string(),
+                span: Span::default(),
+            },
+        })
+    }
+
+    /// Human-readable listing of the constants and byte codes, with the
+    /// constants referenced by each instruction resolved in a comment,
+    /// followed by the listings of the functions defined in it.
+    pub fn disassemble(&self) -> String {
+        let mut out = String::new();
+        let vararg = if self.is_vararg { "+" } else { "" };
+        writeln!(
+            out,
+            "{}{vararg} params, {} slots",
+            self.nparams, self.max_stack
+        )
+        .unwrap();
+        writeln!(out, "constants: {}", self.constants.len()).unwrap();
+        for (i, c) in self.constants.iter().enumerate() {
+            writeln!(out, "    {i:<4}{}", Self::show_const(c)).unwrap();
+        }
+        // main chunks have none
+        if !self.upvalues.is_empty() {
+            writeln!(out, "upvalues: {}", self.upvalues.len()).unwrap();
+            for (i, u) in self.upvalues.iter().enumerate() {
+                let from = if u.in_stack { "local" } else { "upvalue" };
+                writeln!(out, "    {i:<4}{:<8}{from} {}", u.name, u.index).unwrap();
+            }
+        }
+        writeln!(out, "byte_codes: {}", self.byte_codes.len()).unwrap();
+        for (pc, code) in self.byte_codes.iter().enumerate() {
+            let line = format!("    {pc:<4}{code:?}");
+            if let ByteCode::Jump(sj) = *code {
+                writeln!(out, "{line:<32}; to {}", pc as i64 + 1 + sj as i64).unwrap();
+                continue;
+            }
+            if let ByteCode::GetUpval(_, u) | ByteCode::SetUpval(_, u) = *code {
+                let name = self.upvalues.get(u as usize).map_or("?", |u| &u.name);
+                writeln!(out, "{line:<32}; {name}").unwrap();
+                continue;
+            }
+            let consts: &[u8] = match *code {
+                ByteCode::GetGlobal(_, k)
+                | ByteCode::LoadConst(_, k)
+                | ByteCode::GetField(_, _, k)
+                | ByteCode::GetMethod(_, _, k)
+                | ByteCode::SetField(_, k, _)
+                | ByteCode::AddK(_, _, k)
+                | ByteCode::SubK(_, _, k)
+                | ByteCode::MulK(_, _, k)
+                | ByteCode::DivK(_, _, k)
+                | ByteCode::IdivK(_, _, k)
+                | ByteCode::ModK(_, _, k)
+                | ByteCode::PowK(_, _, k) => &[k],
+                ByteCode::SetGlobal(g, _) => &[g],
+                ByteCode::SetGlobalConst(g, k)
+                | ByteCode::SetGlobalGlobal(g, k)
+                | ByteCode::GetGlobalField(_, g, k) => &[g, k],
+                _ => &[],
+            };
+            if consts.is_empty() {
+                writeln!(out, "{line}").unwrap();
+            } else {
+                let consts: Vec<_> = consts
+                    .iter()
+                    .map(|&k| match self.constants.get(k as usize) {
+                        Some(c) => Self::show_const(c),
+                        None => "?".into(),
+                    })
+                    .collect();
+                writeln!(out, "{line:<32}; {}", consts.join(" ")).unwrap();
+            }
+        }
+        for (i, proto) in self.protos.iter().enumerate() {
+            writeln!(out, "\nfunction {i}, line {}:", proto.line_defined).unwrap();
+            out += &proto.disassemble();
+        }
+        out
+    }
+
+    fn show_const(c: &Value) -> String {
+        match <&str>::try_from(c) {
+            Result::Ok(s) => format!("{s:?}"),
+            Err(_) => format!("{c:?}"),
+        }
+    }
+
+    pub fn constant(&self, index: usize) -> anyhow::Result<&Value> {
         self.constants
             .get(index)
-            .context("constant index out of bounds")?
-            .try_into()
+            .context("constant index out of bounds")
+    }
+
+    pub fn get_global(&self, index: usize) -> anyhow::Result<&str> {
+        self.constant(index)?.try_into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::File, io::Cursor};
+
+    use super::*;
+
+    #[test]
+    fn disassemble_test_lua() {
+        insta::glob!("../test_lua", "*.lua", |path| {
+            let proto = ParseProto::load(File::open(path).unwrap()).unwrap();
+            insta::assert_snapshot!(proto.disassemble());
+        });
+    }
+
+    #[test]
+    fn metadata() {
+        let proto = ParseProto::load(&b"local a = 1 print(a, 'x') return f()"[..]).unwrap();
+        assert_eq!((proto.nparams, proto.is_vararg), (0, true));
+        // `a`, then `print` and its two arguments
+        assert_eq!(proto.max_stack, 4);
+    }
+
+    #[test]
+    fn functions() {
+        let proto = ParseProto::load(&b"local function f(a, b, ...)\nend\nreturn f"[..]).unwrap();
+        let f = &proto.protos[0];
+        assert_eq!((f.nparams, f.is_vararg, f.line_defined), (2, true, 1));
+        assert_eq!(proto.byte_codes[0], ByteCode::Closure(0, 0));
+
+        let error = |src: &'static str| ParseProto::load(src.as_bytes()).unwrap_err().to_string();
+        assert_eq!(
+            error("function f() return ... end"),
+            "cannot use '...' outside a vararg function"
+        );
+        assert_eq!(error("function f(a, 1) end"), "<name> expected near '1'");
+    }
+
+    #[test]
+    fn upvalues() {
+        let src = b"local a = 1 local b = 2 function f() return function() return b, a end end";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        // the middle function captures for the inner one in the order it asks
+        let f = &proto.protos[0];
+        fn desc(u: &UpvalDesc) -> (&str, bool, u8) {
+            (&u.name, u.in_stack, u.index)
+        }
+        let f_upvalues: Vec<_> = f.upvalues.iter().map(desc).collect();
+        assert_eq!(f_upvalues, [("b", true, 1), ("a", true, 0)]);
+        let inner: Vec<_> = f.protos[0].upvalues.iter().map(desc).collect();
+        assert_eq!(inner, [("b", false, 0), ("a", false, 1)]);
+        assert_eq!(f.protos[0].byte_codes[0], ByteCode::GetUpval(0, 0));
+    }
+
+    #[test]
+    fn closes() {
+        let codes = |src: &'static str| ParseProto::load(src.as_bytes()).unwrap().byte_codes;
+        // only scopes with captured locals close them
+        assert!(!codes("do local a = 1 end").contains(&ByteCode::Close(0)));
+        let captured = codes("do local a = 1 f = function() return a end end");
+        assert_eq!(captured.last(), Some(&ByteCode::Close(0)));
+        // a goto back out of the scope of locals closes them
+        let back = codes("local a = 1 ::top:: local b = 2 goto top");
+        assert_eq!(
+            back[back.len() - 2..],
+            [ByteCode::Close(1), ByteCode::Jump(-3)]
+        );
+
+        let error = |src: &'static str| ParseProto::load(src.as_bytes()).unwrap_err().to_string();
+        assert_eq!(error("print(1)\nbreak"), "break outside a loop at line 2");
+        assert_eq!(
+            error("for i = 1, 2 do function f() break end end"),
+            "break outside a loop at line 1"
+        );
+    }
+
+    #[test]
+    fn clone_shares_constants() {
+        let proto = ParseProto::load(&b"print('hello')"[..]).unwrap();
+        let copy = proto.clone();
+        assert!(Rc::ptr_eq(&proto.constants, &copy.constants));
+    }
+
+    #[test]
+    fn shadowing() {
+        let src = b"local a = 1 local _ = 2 local _ = 3 local a = a";
+        let load = |shadowing| {
+            ParseProto::load_with(
+                &src[..],
+                ParseOptions {
+                    shadowing,
+                    ..Default::default()
+                },
+            )
+        };
+
+        assert!(load(Shadowing::Allow).unwrap().warnings.is_empty());
+        assert_eq!(
+            load(Shadowing::Warn).unwrap().warnings,
+            ["local 'a' at 1:43 shadows an earlier local of the same name at 1:7"]
+        );
+        let err = load(Shadowing::Deny).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "local 'a' at 1:43 shadows an earlier local of the same name at 1:7"
+        );
+        let err = err.downcast::<SyntaxError>().unwrap();
+        assert_eq!(err.span.to_string(), "line 1, columns 43-43");
+
+        // parameters and loop variables are locals too
+        let src = b"function f(x)\n  for i = x, 2 do\n    local i = x\n    local x = i\n  end\nend";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        assert_eq!(
+            proto.warnings,
+            [
+                "local 'i' at 3:11 shadows an earlier local of the same name at 2:7",
+                "local 'x' at 4:11 shadows an earlier local of the same name at 1:12",
+            ]
+        );
+    }
+
+    #[test]
+    fn spans() {
+        let src = b"local a = x\nprint(a.b, 'c')";
+        let options = ParseOptions {
+            spans: true,
+            ..Default::default()
+        };
+        let proto = ParseProto::load_with(&src[..], options).unwrap();
+        let spans: Vec<_> = proto.spans.iter().map(Span::to_string).collect();
+        assert_eq!(
+            spans,
+            [
+                "line 1, columns 11-11", // x
+                "line 2, columns 1-5",   // print
+                "line 2, columns 7-7",   // a
+                "line 2, columns 7-9",   // a.b
+                "line 2, columns 12-14", // 'c'
+                "line 2, columns 1-15",  // the call
+            ]
+        );
+        assert!(ParseProto::load(&src[..]).unwrap().spans.is_empty());
+    }
+
+    #[test]
+    fn no_redundant_moves() {
+        let src = b"local a = 1 local b = x a = a a = b.c a = string.len a = a .. b";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        assert_eq!(
+            proto.byte_codes,
+            [
+                ByteCode::LoadInt(0, 1),
+                ByteCode::GetGlobal(1, 0),
+                ByteCode::Move(2, 1),
+                ByteCode::GetField(0, 2, 1),
+                ByteCode::GetGlobalField(0, 2, 3),
+                ByteCode::Move(2, 0),
+                ByteCode::Move(3, 1),
+                ByteCode::Concat(2, 2),
+                ByteCode::Move(0, 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn method_calls() {
+        let src = b"local s = 'a' s:rep(2) return ('b'):rep(s:len())";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        assert_eq!(
+            proto.byte_codes,
+            [
+                ByteCode::LoadConst(0, 0),
+                ByteCode::Move(1, 0),
+                ByteCode::GetMethod(1, 1, 1),
+                ByteCode::LoadInt(3, 2),
+                ByteCode::Call(1, 2, 0),
+                ByteCode::LoadConst(1, 2),
+                ByteCode::GetMethod(1, 1, 1),
+                ByteCode::Move(3, 0),
+                ByteCode::GetMethod(3, 3, 3),
+                ByteCode::Call(3, 1, MULTRET),
+                ByteCode::Call(1, MULTRET, MULTRET),
+                ByteCode::Return(1, MULTRET),
+            ]
+        );
+
+        let error = |src: &'static str| ParseProto::load(src.as_bytes()).unwrap_err().to_string();
+        assert_eq!(error("s:len"), "function arguments expected near <eof>");
+        assert_eq!(error("s:1()"), "<name> expected near '1'");
+    }
+
+    #[test]
+    fn if_stat() {
+        let src = b"local a = 1 if a then x = 1 elseif a == 2 then x = 2 else x = 3 end";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        assert_eq!(
+            proto.byte_codes,
+            [
+                ByteCode::LoadInt(0, 1),
+                ByteCode::Test(0),
+                ByteCode::Jump(2),
+                ByteCode::SetGlobalConst(0, 1),
+                ByteCode::Jump(7),
+                ByteCode::LoadInt(1, 2),
+                ByteCode::Eq(1, 0, 1),
+                ByteCode::Test(1),
+                ByteCode::Jump(2),
+                ByteCode::SetGlobalConst(0, 2),
+                ByteCode::Jump(1),
+                ByteCode::SetGlobalConst(0, 3),
+            ]
+        );
+
+        let error = |src: &'static str| ParseProto::load(src.as_bytes()).unwrap_err().to_string();
+        assert_eq!(error("if a end"), "'then' expected near 'end'");
+        assert_eq!(
+            error("if a then else else end"),
+            "'end' expected near 'else'"
+        );
+    }
+
+    #[test]
+    fn unreachable_code() {
+        let src = b"goto a print(1) for i = 1, 2 do goto a end ::a:: print(2)";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        assert_eq!(proto.byte_codes.len(), 4);
+        assert_eq!(proto.byte_codes[0], ByteCode::Jump(0));
+        assert_eq!(
+            proto.warnings,
+            ["code at 1:8 after goto 'a' is unreachable"]
+        );
+
+        let src = b"goto a print(1) goto b ::a::";
+        let err = ParseProto::load(&src[..]).unwrap_err();
+        assert_eq!(err.to_string(), "no visible label 'b' for goto");
+
+        // as after a break, up to the end of its block
+        let src = b"for i = 1, 2 do break print(i) end print(0)";
+        let proto = ParseProto::load(&src[..]).unwrap();
+        assert_eq!(
+            proto.byte_codes,
+            [
+                ByteCode::LoadInt(0, 1),
+                ByteCode::LoadInt(1, 2),
+                ByteCode::LoadInt(2, 1),
+                ByteCode::ForPrep(0, 1),
+                // the break, and no call of print in the loop
+                ByteCode::Jump(1),
+                ByteCode::ForLoop(0, 2),
+                ByteCode::GetGlobal(0, 0),
+                ByteCode::LoadInt(1, 0),
+                ByteCode::Call(0, 1, 0),
+            ]
+        );
+        assert_eq!(proto.warnings, ["code at 1:23 after break is unreachable"]);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |src: &'static str| {
+            let err = ParseProto::try_compile(src.as_bytes(), ParseOptions::default()).unwrap_err();
+            (err.message, err.span.to_string())
+        };
+        assert_eq!(
+            error("print(1)\nlocal x = = 2"),
+            (
+                "invalid argument near '='".into(),
+                "line 2, columns 11-11".into()
+            )
+        );
+        // tokens as they are written
+        assert_eq!(error("x = 1 2.5").0, "unexpected symbol near '2.5'");
+        assert_eq!(error("x = 1 end").0, "'<eof>' expected near 'end'");
+        assert_eq!(error("local 'a'").0, "expected variable near '\"a\"'");
+        assert_eq!(error("print 'a\nb'").1, "line 2, columns 1-1");
+        // only calls and assignments are statements
+        assert_eq!(error("f() = 1").0, "syntax error near '='");
+        assert_eq!(error("t.x").0, "syntax error near <eof>");
+        assert!(ParseProto::try_compile(&b"print(1)"[..], ParseOptions::default()).is_ok());
+    }
+
+    #[test]
+    fn operand_limits() {
+        let error = |src: String| ParseProto::load(Cursor::new(src)).unwrap_err().to_string();
+
+        let mut src: String = (0..300).map(|i| format!("g{i} = 's{i}'\n")).collect();
+        src += "print(g299)";
+        assert!(error(src).ends_with("too many constants"));
+
+        let args = vec!["1"; 300].join(", ");
+        assert!(error(format!("print({args})"))
+            .ends_with("function or expression needs too many registers"));
+    }
+
+    #[test]
+    fn nesting() {
+        let nested = |open: &str, close: &str, n| {
+            let src = format!("{}{}", open.repeat(n), close.repeat(n));
+            ParseProto::load(Cursor::new(src.into_bytes())).map(|_| ())
+        };
+        assert!(nested("print(", ")", 100).is_ok());
+        // four registers a loop
+        assert!(nested("for i = 1, 2 do ", "end ", 60).is_ok());
+        let err = nested("print(", ")", 100_000).unwrap_err();
+        assert_eq!(err.to_string(), "chunk has too many syntax levels");
+        // loops run out of registers first
+        let err = nested("for i = 1, 2 do ", "end ", 100_000).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "function or expression needs too many registers"
+        );
+    }
+
+    #[test]
+    fn goto_errors() {
+        let error = |src: &'static str| ParseProto::load(src.as_bytes()).unwrap_err().to_string();
+        assert_eq!(error("goto nowhere"), "no visible label 'nowhere' for goto");
+        assert_eq!(error("::a:: ::a::"), "label 'a' already defined");
+        assert_eq!(
+            error("goto l local x = 1 ::l:: print(x)"),
+            "<goto l> jumps into the scope of local 'x'"
+        );
+        // out of scope at the end of the chunk, or of a block
+        assert!(ParseProto::load(&b"goto l local x = 1 ::l::"[..]).is_ok());
+        assert!(ParseProto::load(&b"for i = 1, 2 do goto c local x = 1 ::c:: end"[..]).is_ok());
+        // labels in a block are not visible outside it
+        assert_eq!(
+            error("for i = 1, 2 do ::a:: end goto a"),
+            "no visible label 'a' for goto"
+        );
+        assert_eq!(
+            error("goto a for i = 1, 2 do ::a:: end"),
+            "no visible label 'a' for goto"
+        );
+        assert_eq!(
+            error("::a:: for i = 1, 2 do ::a:: end"),
+            "label 'a' already defined"
+        );
     }
 }
@@ -0,0 +1,239 @@
+//! Interactive mode: read chunks with line editing and run them in a shared
+//! state. A chunk that ends too early is continued on the next line.
+
+use std::{io::Cursor, path::PathBuf};
+
+use kailua::{
+    inspect::{inspect, DEFAULT_DEPTH},
+    os::Exit,
+    parse::{ParseOptions, ParseProto},
+    value::Value,
+    vm::ExeState,
+};
+use rustyline::{
+    completion::Completer, error::ReadlineError, highlight::Highlighter, hint::Hinter,
+    history::DefaultHistory, validate::Validator, Context, Editor, Helper,
+};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = ">> ";
+
+pub fn run(state: &mut ExeState) -> anyhow::Result<()> {
+    println!("kailua {}", env!("CARGO_PKG_VERSION"));
+
+    let mut editor = Editor::<ReplHelper, DefaultHistory>::new()?;
+    editor.set_helper(Some(ReplHelper::new(state)));
+    let history = history_path();
+    if let Some(path) = &history {
+        // missing on first use
+        let _ = editor.load_history(path);
+    }
+
+    let mut chunk = String::new();
+    let mut result = Ok(());
+    loop {
+        let prompt = if chunk.is_empty() {
+            PROMPT
+        } else {
+            CONTINUATION_PROMPT
+        };
+        match editor.readline(prompt) {
+            Ok(line) => {
+                chunk.push_str(&line);
+                chunk.push('\n');
+            }
+            Err(ReadlineError::Interrupted) => {
+                // cancel the current chunk, but keep running
+                chunk.clear();
+                continue;
+            }
+            Err(ReadlineError::Eof) => break,
+            Err(err) => return Err(err.into()),
+        }
+
+        match eval(state, &chunk) {
+            Err(err) if is_incomplete(&err) => continue,
+            // `os.exit`, for the caller to carry out
+            Err(err) if err.is::<Exit>() => {
+                result = Err(err);
+                break;
+            }
+            Err(err) => {
+                eprintln!("{err:#}");
+                if let Some(traceback) = state.take_traceback() {
+                    eprintln!("{traceback}");
+                }
+            }
+            Ok(()) => (),
+        }
+        editor.add_history_entry(chunk.trim_end())?;
+        chunk.clear();
+        editor.set_helper(Some(ReplHelper::new(state)));
+    }
+
+    if let Some(path) = &history {
+        editor.save_history(path)?;
+    }
+    result
+}
+
+/// Run a chunk, printing the values it returns. As in the reference REPL,
+/// a chunk that is an expression list is run as `return <chunk>`, so that
+/// typing `1, x` shows both values.
+fn eval(state: &mut ExeState, chunk: &str) -> anyhow::Result<()> {
+    let proto = load_chunk(state, chunk)?;
+    let results = state.execute_results(proto)?;
+    if !results.is_empty() {
+        let shown: Vec<_> = results.iter().map(|v| inspect(v, DEFAULT_DEPTH)).collect();
+        println!("{}", shown.join("\t"));
+    }
+    Ok(())
+}
+
+/// Compile a chunk as an expression list to return, or else as statements.
+/// An expression list cut short, such as `1 +`, is reported as such, to be
+/// continued, though the statements fail from their start.
+fn load_chunk(state: &mut ExeState, chunk: &str) -> anyhow::Result<ParseProto> {
+    match load(state, &format!("return {chunk}")) {
+        Ok(proto) => Ok(proto),
+        Err(exp_err) => match load(state, chunk) {
+            Ok(proto) => Ok(proto),
+            Err(_) if is_incomplete(&exp_err) => Err(exp_err),
+            Err(err) => Err(err),
+        },
+    }
+}
+
+fn load(state: &mut ExeState, chunk: &str) -> anyhow::Result<ParseProto> {
+    let options = ParseOptions {
+        chunk_name: "stdin".into(),
+        ..Default::default()
+    };
+    state.load(Cursor::new(chunk.as_bytes().to_vec()), options)
+}
+
+/// Whether a chunk failed only because it ended too early.
+fn is_incomplete(err: &anyhow::Error) -> bool {
+    err.to_string().contains("near <eof>")
+}
+
+fn history_path() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".kailua_history"))
+}
+
+const KEYWORDS: &[&str] = &[
+    "and", "break", "do", "else", "elseif", "end", "false", "for", "function", "goto", "if", "in",
+    "local", "nil", "not", "or", "repeat", "return", "then", "true", "until", "while",
+];
+
+/// Completes keywords and global names, and `a.b.<Tab>` with the string
+/// keys of the table at `a.b`.
+struct ReplHelper {
+    // tables are shared, so fields are completed from their live contents
+    globals: Vec<(String, Value)>,
+}
+
+impl ReplHelper {
+    fn new(state: &ExeState) -> Self {
+        let globals = state
+            .globals()
+            .map(|(name, v)| (name.to_string(), v.clone()))
+            .collect();
+        Self { globals }
+    }
+
+    fn names(&self, path: Option<&str>) -> Vec<String> {
+        let Some(path) = path else {
+            let globals = self.globals.iter().map(|(name, _)| name.clone());
+            return globals
+                .chain(KEYWORDS.iter().map(|k| k.to_string()))
+                .collect();
+        };
+
+        let mut segments = path.split('.');
+        let first = segments.next().unwrap_or_default();
+        let mut v = match self.globals.iter().find(|(name, _)| name == first) {
+            Some((_, v)) => v.clone(),
+            None => return Vec::new(),
+        };
+        for segment in segments {
+            let field = match &v {
+                Value::Table(t) => Some(t.borrow().get(&segment.into())),
+                _ => None,
+            };
+            match field {
+                Some(field) => v = field,
+                None => return Vec::new(),
+            }
+        }
+
+        match &v {
+            Value::Table(t) => t
+                .borrow()
+                .map_entries()
+                .filter_map(|(k, _)| <&str>::try_from(k).ok().map(String::from))
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+impl Completer for ReplHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        let start = line[..pos]
+            .rfind(|c: char| !(c.is_alphanumeric() || c == '_' || c == '.'))
+            .map_or(0, |i| i + 1);
+        let word = &line[start..pos];
+        let (path, prefix) = match word.rsplit_once('.') {
+            Some((path, prefix)) => (Some(path), prefix),
+            None => (None, word),
+        };
+
+        let mut candidates: Vec<_> = self
+            .names(path)
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+        candidates.sort();
+        candidates.dedup();
+        Ok((pos - prefix.len(), candidates))
+    }
+}
+
+impl Hinter for ReplHelper {
+    type Hint = String;
+}
+
+impl Highlighter for ReplHelper {}
+
+impl Validator for ReplHelper {}
+
+impl Helper for ReplHelper {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn incomplete_chunks() {
+        let mut state = ExeState::new();
+        let incomplete = |state: &mut ExeState, chunk| match load_chunk(state, chunk) {
+            Ok(_) => panic!("{chunk} compiled"),
+            Err(err) => is_incomplete(&err),
+        };
+        assert!(incomplete(&mut state, "1 +\n"));
+        assert!(incomplete(&mut state, "for i = 1, 2 do\n"));
+        assert!(incomplete(&mut state, "print(1,\n"));
+        assert!(!incomplete(&mut state, "x = = 1\n"));
+        assert!(!incomplete(&mut state, "1 + )\n"));
+        assert!(load_chunk(&mut state, "1 +\n2\n").is_ok());
+        assert!(load_chunk(&mut state, "x = 1\n").is_ok());
+    }
+}
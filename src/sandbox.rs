@@ -0,0 +1,108 @@
+//! Limits on what scripts may reach outside the interpreter, enforced by
+//! the `os` and `io` libraries.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::bail;
+
+#[derive(Debug, Clone)]
+pub struct SandboxPolicy {
+    /// Directories scripts may access, including everything below them.
+    /// `None` allows every path.
+    pub allowed_paths: Option<Vec<PathBuf>>,
+    /// Environment variables scripts may read. `None` allows all of them.
+    pub allowed_env: Option<Vec<String>>,
+    /// Whether scripts may use the network.
+    pub network: bool,
+}
+
+impl SandboxPolicy {
+    /// No restrictions, the default.
+    pub fn unrestricted() -> Self {
+        Self {
+            allowed_paths: None,
+            allowed_env: None,
+            network: true,
+        }
+    }
+
+    /// Nothing outside the interpreter is reachable; widen it by adding
+    /// paths and variables.
+    pub fn locked() -> Self {
+        Self {
+            allowed_paths: Some(Vec::new()),
+            allowed_env: Some(Vec::new()),
+            network: false,
+        }
+    }
+
+    /// Fail unless `path` is inside one of the allowed directories.
+    pub fn check_path(&self, path: &Path) -> anyhow::Result<()> {
+        let Some(allowed) = &self.allowed_paths else {
+            return Ok(());
+        };
+        let path = resolve(path);
+        if !allowed.iter().any(|dir| path.starts_with(resolve(dir))) {
+            bail!("{}: access denied by sandbox", path.display());
+        }
+        Ok(())
+    }
+
+    /// Whether environment variable `name` may be read.
+    pub fn env_allowed(&self, name: &str) -> bool {
+        self.allowed_env
+            .as_ref()
+            .is_none_or(|allowed| allowed.iter().any(|n| n == name))
+    }
+}
+
+impl Default for SandboxPolicy {
+    fn default() -> Self {
+        Self::unrestricted()
+    }
+}
+
+/// Absolute form of `path` with symlinks and `..` resolved, so that
+/// `allowed/../secret` does not pass as inside `allowed`. A path that does
+/// not exist yet is resolved through its parent.
+fn resolve(path: &Path) -> PathBuf {
+    if let Ok(path) = path.canonicalize() {
+        return path;
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) if !parent.as_os_str().is_empty() => resolve(parent).join(name),
+        _ => std::env::current_dir().unwrap_or_default().join(path),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn paths() {
+        let dir = std::env::temp_dir().join("kailua-sandbox-test");
+        std::fs::create_dir_all(dir.join("inner")).unwrap();
+
+        assert!(SandboxPolicy::unrestricted()
+            .check_path(Path::new("/"))
+            .is_ok());
+        assert!(SandboxPolicy::locked().check_path(&dir).is_err());
+
+        let mut policy = SandboxPolicy::locked();
+        policy.allowed_paths = Some(vec![dir.join("inner")]);
+        assert!(policy.check_path(&dir.join("inner/new.txt")).is_ok());
+        assert!(policy.check_path(&dir.join("inner/../secret")).is_err());
+        assert!(policy.check_path(&dir).is_err());
+    }
+
+    #[test]
+    fn env() {
+        assert!(SandboxPolicy::unrestricted().env_allowed("HOME"));
+        let mut policy = SandboxPolicy::locked();
+        assert!(!policy.env_allowed("HOME"));
+        policy.allowed_env = Some(vec!["LANG".into()]);
+        assert!(policy.env_allowed("LANG"));
+        assert!(!policy.env_allowed("HOME"));
+    }
+}
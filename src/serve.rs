@@ -0,0 +1,194 @@
+//! `kailua serve`: a persistent state driven over stdin and stdout with
+//! JSON-RPC 2.0, a request and its response a line each.
+//!
+//! Methods:
+//!
+//! - `compile {source, name?}`: compile a chunk without running it,
+//!   returning `{chunk}`, a number to run it by later.
+//! - `eval {source}` or `eval {chunk}`: run a chunk, returning
+//!   `{values, output}`: what it returned and what it printed.
+//! - `call {function, args?}`: call the function at a path of globals,
+//!   such as `"string.upper"`, with `args`, returning `{values, output}`.
+//!
+//! Every request gets a response, with the `id` of the request, null if
+//! it had none. Lua errors are responses with error code -32000 and the
+//! message of the error.
+
+use std::{
+    io::{self, BufRead, Cursor, Write},
+    rc::Rc,
+};
+
+use kailua::{
+    json::{self, DecodeOptions, EncodeOptions},
+    os::Exit,
+    parse::{ParseOptions, ParseProto},
+    value::{Table, Value},
+    vm::ExeState,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+const LUA_ERROR: i64 = -32000;
+
+/// An error response: its code and message.
+struct RpcError(i64, String);
+
+impl RpcError {
+    fn params(msg: &str) -> Self {
+        RpcError(INVALID_PARAMS, msg.into())
+    }
+}
+
+pub fn run(state: &mut ExeState) -> anyhow::Result<()> {
+    let mut server = Server {
+        state,
+        chunks: Vec::new(),
+        exit: None,
+    };
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let (id, result) = match json::decode(line.as_bytes(), &DecodeOptions::default()) {
+            Ok(Value::Table(request)) => {
+                let request = request.borrow().clone();
+                (request.get(&"id".into()), server.handle(&request))
+            }
+            Ok(_) => (
+                Value::Nil,
+                Err(RpcError(INVALID_REQUEST, "request is not an object".into())),
+            ),
+            Err(err) => (Value::Nil, Err(RpcError(PARSE_ERROR, format!("{err:#}")))),
+        };
+        let response = match result {
+            Ok(result) => format!("\"result\":{result}"),
+            Err(RpcError(code, msg)) => format!(
+                "\"error\":{{\"code\":{code},\"message\":{}}}",
+                encode(&msg.into())?
+            ),
+        };
+        writeln!(
+            stdout,
+            "{{\"jsonrpc\":\"2.0\",\"id\":{},{response}}}",
+            encode(&id)?
+        )?;
+        stdout.flush()?;
+        // `os.exit`, for the caller to carry out once the response is out
+        if let Some(exit) = server.exit {
+            return Err(exit);
+        }
+    }
+    Ok(())
+}
+
+struct Server<'a> {
+    state: &'a mut ExeState,
+    // compiled by `compile`, numbered from 1
+    chunks: Vec<Rc<ParseProto>>,
+    exit: Option<anyhow::Error>,
+}
+
+impl Server<'_> {
+    /// The result of `request`, as JSON text.
+    fn handle(&mut self, request: &Table) -> Result<String, RpcError> {
+        let params = match request.get(&"params".into()) {
+            Value::Table(t) => t.borrow().clone(),
+            Value::Nil => Table::new(),
+            _ => return Err(RpcError::params("params must be an object")),
+        };
+        let method = request.get(&"method".into());
+        match <&str>::try_from(&method) {
+            Ok("compile") => {
+                let source = string_param(&params, "source")?;
+                let mut options = ParseOptions::default();
+                if params.get(&"name".into()) != Value::Nil {
+                    options.chunk_name = string_param(&params, "name")?;
+                }
+                let proto = self
+                    .state
+                    .load(Cursor::new(source.into_bytes()), options)
+                    .map_err(lua_error)?;
+                self.chunks.push(proto.into());
+                Ok(format!("{{\"chunk\":{}}}", self.chunks.len()))
+            }
+            Ok("eval") => match params.get(&"chunk".into()) {
+                Value::Nil => {
+                    let source = string_param(&params, "source")?;
+                    self.run_captured(|state, _| state.eval(&source))
+                }
+                Value::Integer(i) if i >= 1 && i as usize <= self.chunks.len() => self
+                    .run_captured(|state, chunks| {
+                        state.execute_results(chunks[i as usize - 1].clone())
+                    }),
+                _ => Err(RpcError::params("no such chunk")),
+            },
+            Ok("call") => {
+                let path = string_param(&params, "function")?;
+                let args = match params.get(&"args".into()) {
+                    Value::Table(t) => t.borrow().array.clone(),
+                    Value::Nil => Vec::new(),
+                    _ => return Err(RpcError::params("args must be an array")),
+                };
+                let mut names = path.split('.');
+                let mut f = self.state.get_global(names.next().unwrap_or_default());
+                for name in names {
+                    f = self.state.index(&f, &name.into()).map_err(lua_error)?;
+                }
+                self.run_captured(|state, _| state.call_results(f, &args))
+            }
+            Ok(name) => Err(RpcError(
+                METHOD_NOT_FOUND,
+                format!("method '{name}' not found"),
+            )),
+            Err(_) => Err(RpcError(INVALID_REQUEST, "method must be a string".into())),
+        }
+    }
+
+    /// Run `f`, capturing what it prints, returning `{values, output}`.
+    fn run_captured(
+        &mut self,
+        f: impl FnOnce(&mut ExeState, &[Rc<ParseProto>]) -> anyhow::Result<Vec<Value>>,
+    ) -> Result<String, RpcError> {
+        let mut result = None;
+        let chunks = &self.chunks;
+        let output = self.state.with_captured_output(|state| {
+            result = Some(f(state, chunks));
+        });
+        let values = match result.unwrap() {
+            Ok(values) => values,
+            Err(err) if err.is::<Exit>() => {
+                self.exit = Some(err);
+                Vec::new()
+            }
+            Err(err) => return Err(lua_error(err)),
+        };
+        let values = values
+            .iter()
+            .map(encode)
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map_err(lua_error)?;
+        let output = encode(&output.into()).map_err(lua_error)?;
+        Ok(format!(
+            "{{\"values\":[{}],\"output\":{output}}}",
+            values.join(",")
+        ))
+    }
+}
+
+fn string_param(params: &Table, name: &str) -> Result<String, RpcError> {
+    String::try_from(&params.get(&name.into()))
+        .map_err(|_| RpcError::params(&format!("{name} must be a string")))
+}
+
+fn encode(v: &Value) -> anyhow::Result<String> {
+    json::encode(v, &EncodeOptions::default())
+}
+
+fn lua_error(err: anyhow::Error) -> RpcError {
+    RpcError(LUA_ERROR, format!("{err:#}"))
+}
@@ -0,0 +1,35 @@
+//! Opt-in counters of the work done by the VM, to guide optimization of
+//! both scripts and the VM itself.
+
+use std::{collections::BTreeMap, fmt};
+
+use crate::bytecode::ByteCode;
+
+#[derive(Debug, Default, Clone)]
+pub struct Stats {
+    /// Executed instructions by opcode name.
+    pub instructions: BTreeMap<&'static str, u64>,
+    /// Function calls, including calls made by native functions.
+    pub calls: u64,
+}
+
+impl Stats {
+    pub(crate) fn instruction(&mut self, code: &ByteCode) {
+        *self.instructions.entry(code.name()).or_default() += 1;
+    }
+
+    /// Total number of executed instructions.
+    pub fn total_instructions(&self) -> u64 {
+        self.instructions.values().sum()
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.total_instructions())?;
+        for (name, count) in &self.instructions {
+            writeln!(f, "    {name:<16}{count}")?;
+        }
+        writeln!(f, "calls: {}", self.calls)
+    }
+}
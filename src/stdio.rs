@@ -0,0 +1,36 @@
+//! The `io` library, writing to the output configured on [`ExeState`].
+
+use std::io::Write;
+
+use anyhow::bail;
+
+use crate::{
+    value::{Table, Value},
+    vm::ExeState,
+};
+
+/// Build the `io` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("write".into(), Value::Function(lib_write));
+    t.into()
+}
+
+// io.write(...)
+fn lib_write(state: &mut ExeState) -> anyhow::Result<i32> {
+    let mut buf = Vec::new();
+    for i in 1..=state.get_top() {
+        match state.arg(i) {
+            v @ (Value::Integer(_) | Value::Float(_)) => write!(buf, "{v}")?,
+            v => match <&[u8]>::try_from(v) {
+                Ok(s) => buf.extend_from_slice(s),
+                Err(_) => bail!(
+                    "bad argument #{i} to 'write' (string expected, got {})",
+                    v.type_name()
+                ),
+            },
+        }
+    }
+    state.output().write_all(&buf)?;
+    Ok(0)
+}
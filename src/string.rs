@@ -0,0 +1,496 @@
+//! The `string` library, which is also the `__index` of the metatable
+//! shared by all strings, so that `s:len()` means `string.len(s)`.
+
+use std::borrow::Cow;
+
+use anyhow::bail;
+
+use crate::{
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+
+mod format;
+mod pattern;
+
+/// Build the `string` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("len".into(), Value::Function(lib_len));
+    t.map.insert("sub".into(), Value::Function(lib_sub));
+    t.map.insert("upper".into(), Value::Function(lib_upper));
+    t.map.insert("lower".into(), Value::Function(lib_lower));
+    t.map.insert("rep".into(), Value::Function(lib_rep));
+    t.map.insert("reverse".into(), Value::Function(lib_reverse));
+    t.map.insert("format".into(), Value::Function(lib_format));
+    t.map.insert("gsub".into(), Value::Function(lib_gsub));
+    t.map.insert("gmatch".into(), Value::Function(lib_gmatch));
+    t.into()
+}
+
+/// Build the metatable shared by all strings, indexing into `lib`.
+pub fn metatable(lib: Value) -> Value {
+    let mut t = Table::new();
+    t.map.insert("__index".into(), lib);
+    t.into()
+}
+
+// string.len(s)
+fn lib_len(state: &mut ExeState) -> anyhow::Result<i32> {
+    let len = check_str(state, 1, "len")?.len();
+    state.push((len as LuaInt).into());
+    Ok(1)
+}
+
+// string.sub(s, i [, j])
+fn lib_sub(state: &mut ExeState) -> anyhow::Result<i32> {
+    let s = check_str(state, 1, "sub")?;
+    let i = check_int(state, 2, "sub")?;
+    let j = opt_int(state, 3, "sub", -1)?;
+    let s = sub(&s, i, j).to_vec();
+    state.push(s.into());
+    Ok(1)
+}
+
+// string.upper(s)
+fn lib_upper(state: &mut ExeState) -> anyhow::Result<i32> {
+    let s = check_str(state, 1, "upper")?.to_ascii_uppercase();
+    state.push(s.into());
+    Ok(1)
+}
+
+// string.lower(s)
+fn lib_lower(state: &mut ExeState) -> anyhow::Result<i32> {
+    let s = check_str(state, 1, "lower")?.to_ascii_lowercase();
+    state.push(s.into());
+    Ok(1)
+}
+
+// string.rep(s, n [, sep])
+fn lib_rep(state: &mut ExeState) -> anyhow::Result<i32> {
+    let s = check_str(state, 1, "rep")?;
+    let n = check_int(state, 2, "rep")?;
+    let sep = match state.arg(3) {
+        Value::Nil => Cow::Borrowed(&[][..]),
+        _ => check_str(state, 3, "rep")?,
+    };
+    // check the size first, so that a huge count fails without trying
+    // to allocate
+    let n = n.max(0) as u64;
+    let len = (s.len() as u64)
+        .checked_mul(n)
+        .zip((sep.len() as u64).checked_mul(n.saturating_sub(1)))
+        .and_then(|(strings, seps)| strings.checked_add(seps))
+        .filter(|&len| len <= state.max_string_size() as u64);
+    let Some(len) = len else {
+        bail!("resulting string too large");
+    };
+    let mut out = Vec::with_capacity(len as usize);
+    for k in 0..n {
+        if k > 0 {
+            out.extend_from_slice(&sep);
+        }
+        out.extend_from_slice(&s);
+    }
+    drop((s, sep));
+    state.push(out.into());
+    Ok(1)
+}
+
+// string.reverse(s)
+fn lib_reverse(state: &mut ExeState) -> anyhow::Result<i32> {
+    let mut s = check_str(state, 1, "reverse")?.to_vec();
+    s.reverse();
+    state.push(s.into());
+    Ok(1)
+}
+
+// string.format(fmt, ...)
+fn lib_format(state: &mut ExeState) -> anyhow::Result<i32> {
+    let s = format::format(state)?;
+    state.push(s.into());
+    Ok(1)
+}
+
+// string.gsub(s, pattern, repl [, n])
+fn lib_gsub(state: &mut ExeState) -> anyhow::Result<i32> {
+    let src = check_str(state, 1, "gsub")?.to_vec();
+    let pat = check_str(state, 2, "gsub")?.to_vec();
+    let repl = state.arg(3).clone();
+    if !matches!(
+        repl,
+        Value::Integer(_)
+            | Value::Float(_)
+            | Value::Table(_)
+            | Value::Function(_)
+            | Value::NativeClosure(_)
+            | Value::LuaClosure(_)
+    ) && <&[u8]>::try_from(&repl).is_err()
+    {
+        bail!(
+            "bad argument #3 to 'gsub' (string/function/table expected, got {})",
+            state.arg_type_name(3)
+        );
+    }
+    let max = opt_int(state, 4, "gsub", src.len() as LuaInt + 1)?;
+    let (anchor, pat) = match pat.strip_prefix(b"^") {
+        Some(pat) => (true, pat),
+        None => (false, &pat[..]),
+    };
+
+    let mut ms = pattern::MatchState::new(&src, pat);
+    let mut out = Vec::new();
+    let mut s = 0;
+    let mut last_match = None;
+    let mut n = 0;
+    while n < max {
+        ms.reset();
+        match ms.do_match(s, 0)? {
+            // an empty match right after the last one would repeat it
+            Some(e) if Some(e) != last_match => {
+                n += 1;
+                add_value(state, &ms, &mut out, s, e, &repl)?;
+                s = e;
+                last_match = Some(e);
+            }
+            _ if s < src.len() => {
+                out.push(src[s]);
+                s += 1;
+            }
+            _ => break,
+        }
+        if anchor {
+            break;
+        }
+    }
+    out.extend_from_slice(&src[s..]);
+    state.push(out.into());
+    state.push(Value::Integer(n));
+    Ok(2)
+}
+
+// string.gmatch(s, pattern [, init])
+fn lib_gmatch(state: &mut ExeState) -> anyhow::Result<i32> {
+    let len = check_str(state, 1, "gmatch")?.len() as LuaInt;
+    check_str(state, 2, "gmatch")?;
+    let init = match opt_int(state, 3, "gmatch", 1)? {
+        i if i > 0 => i.min(len + 1),
+        i if i < -len => 1,
+        0 => 1,
+        i => len + i + 1,
+    };
+    // the subject, the pattern, where to look next and the end of the
+    // last match, which an empty match must not repeat
+    let upvalues = vec![
+        state.arg(1).clone(),
+        state.arg(2).clone(),
+        Value::Integer(init - 1),
+        Value::Nil,
+    ];
+    state.push(Value::native_closure(gmatch_next, upvalues));
+    Ok(1)
+}
+
+/// The iterator returned by `string.gmatch`: the captures of the next
+/// match, or nothing after the last one.
+fn gmatch_next(state: &mut ExeState) -> anyhow::Result<i32> {
+    let (src, pat) = (state.upvalue(1), state.upvalue(2));
+    let (src, pat) = (<&[u8]>::try_from(&src)?, <&[u8]>::try_from(&pat)?);
+    let Value::Integer(start) = state.upvalue(3) else {
+        unreachable!("set by gmatch");
+    };
+    let last_match = match state.upvalue(4) {
+        Value::Integer(e) => Some(e as usize),
+        _ => None,
+    };
+    let mut ms = pattern::MatchState::new(src, pat);
+    for s in start as usize..=src.len() {
+        ms.reset();
+        match ms.do_match(s, 0)? {
+            Some(e) if Some(e) != last_match => {
+                state.set_upvalue(3, Value::Integer(e as LuaInt));
+                state.set_upvalue(4, Value::Integer(e as LuaInt));
+                let captures = ms.captures(s, e)?;
+                let n = captures.len();
+                for c in captures {
+                    state.push(c);
+                }
+                return Ok(n as i32);
+            }
+            _ => (),
+        }
+    }
+    Ok(0)
+}
+
+/// Append the replacement by `repl` of the match from `s` to `e`.
+fn add_value(
+    state: &mut ExeState,
+    ms: &pattern::MatchState,
+    out: &mut Vec<u8>,
+    s: usize,
+    e: usize,
+    repl: &Value,
+) -> anyhow::Result<()> {
+    let v = match repl {
+        Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => {
+            state.call_first(repl.clone(), &ms.captures(s, e)?)?
+        }
+        Value::Table(_) => state.index(repl, &ms.capture(0, s, e)?)?,
+        _ => {
+            let repl = match <&[u8]>::try_from(repl) {
+                Ok(r) => r.to_vec(),
+                Err(_) => repl.to_string().into_bytes(),
+            };
+            return add_string(ms, out, &repl, s, e);
+        }
+    };
+    match v {
+        // keep the original text
+        Value::Nil | Value::Boolean(false) => out.extend_from_slice(&ms.src()[s..e]),
+        Value::Integer(_) | Value::Float(_) => out.extend_from_slice(v.to_string().as_bytes()),
+        _ => match <&[u8]>::try_from(&v) {
+            Ok(r) => out.extend_from_slice(r),
+            Err(_) => bail!("invalid replacement value (a {})", v.type_name()),
+        },
+    }
+    Ok(())
+}
+
+/// Append `repl` with `%0` to `%9` replaced by the captures, and `%%` by
+/// `%`.
+fn add_string(
+    ms: &pattern::MatchState,
+    out: &mut Vec<u8>,
+    repl: &[u8],
+    s: usize,
+    e: usize,
+) -> anyhow::Result<()> {
+    let mut bytes = repl.iter();
+    while let Some(&c) = bytes.next() {
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        match bytes.next() {
+            Some(b'%') => out.push(b'%'),
+            Some(b'0') => out.extend_from_slice(&ms.src()[s..e]),
+            Some(&d) if d.is_ascii_digit() => {
+                let v = ms.capture((d - b'1') as usize, s, e)?;
+                match <&[u8]>::try_from(&v) {
+                    Ok(c) => out.extend_from_slice(c),
+                    Err(_) => out.extend_from_slice(v.to_string().as_bytes()),
+                }
+            }
+            _ => bail!("invalid use of '%' in replacement string"),
+        }
+    }
+    Ok(())
+}
+
+/// Argument `i` as a string, converting a number as `tostring` does.
+fn check_str<'s>(state: &'s ExeState, i: usize, fname: &str) -> anyhow::Result<Cow<'s, [u8]>> {
+    match state.arg(i) {
+        v @ (Value::Integer(_) | Value::Float(_)) => Ok(Cow::Owned(v.to_string().into_bytes())),
+        v => match <&[u8]>::try_from(v) {
+            Ok(s) => Ok(Cow::Borrowed(s)),
+            Err(_) => bail!(
+                "bad argument #{i} to '{fname}' (string expected, got {})",
+                state.arg_type_name(i)
+            ),
+        },
+    }
+}
+
+/// Argument `i` as an integer, converting a string as `tonumber` does.
+fn check_int(state: &ExeState, i: usize, fname: &str) -> anyhow::Result<LuaInt> {
+    match format::to_number(state.arg(i)) {
+        Some(Value::Integer(n)) => Ok(n),
+        Some(Value::Float(f)) if f.fract() == 0.0 && f.abs() < LuaInt::MAX as LuaFloat => {
+            Ok(f as LuaInt)
+        }
+        Some(_) => {
+            bail!("bad argument #{i} to '{fname}' (number has no integer representation)")
+        }
+        None => bail!(
+            "bad argument #{i} to '{fname}' (number expected, got {})",
+            state.arg_type_name(i)
+        ),
+    }
+}
+
+fn opt_int(state: &ExeState, i: usize, fname: &str, default: LuaInt) -> anyhow::Result<LuaInt> {
+    match state.arg(i) {
+        Value::Nil => Ok(default),
+        _ => check_int(state, i, fname),
+    }
+}
+
+/// Bytes `i` to `j` of `s`, both inclusive and 1-based, counting from the
+/// end when negative.
+fn sub(s: &[u8], i: LuaInt, j: LuaInt) -> &[u8] {
+    let len = s.len() as LuaInt;
+    let start = match i {
+        i if i < 0 => (len + i + 1).max(1),
+        0 => 1,
+        i => i,
+    };
+    let end = if j < 0 { len + j + 1 } else { j.min(len) };
+    if start > end {
+        &[]
+    } else {
+        &s[start as usize - 1..end as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RuntimeError;
+
+    #[test]
+    fn sub_indices() {
+        let s = b"hello";
+        assert_eq!(sub(s, 2, 4), b"ell");
+        assert_eq!(sub(s, 0, -1), b"hello");
+        assert_eq!(sub(s, -3, -1), b"llo");
+        assert_eq!(sub(s, -10, 2), b"he");
+        assert_eq!(sub(s, 4, 100), b"lo");
+        assert_eq!(sub(s, 4, 2), b"");
+        assert_eq!(sub(s, 1, -10), b"");
+    }
+
+    #[test]
+    fn gsub() {
+        let mut state = ExeState::new();
+        let mut vars = Table::new();
+        vars.map.insert("name".into(), "world".into());
+        vars.map.insert("n".into(), Value::Integer(3));
+        state.set_global("vars", vars.into());
+        let cases = [
+            ("'hello world', 'o', '0'", "hell0 w0rld", 2),
+            ("'hello world', '(%w+) (%w+)', '%2 %1'", "world hello", 1),
+            ("'abc', '', '-'", "-a-b-c-", 4),
+            ("'abc', '%w', '%0%0', 2", "aabbc", 2),
+            ("'hello world', '%w+', string.upper", "HELLO WORLD", 2),
+            (
+                "'$name has $n, $none', '%$(%w+)', vars",
+                "world has 3, $none",
+                3,
+            ),
+            ("'x = 1', '()', '%1'", "1x2 3=4 516", 6),
+            ("'50%', '%%', '%%%%'", "50%%", 1),
+            ("'aaa', '^a', 'b'", "baa", 1),
+        ];
+        for (args, expected, n) in cases {
+            let results = state
+                .eval(&format!("return string.gsub({args})"))
+                .unwrap_or_else(|e| panic!("{args}: {e}"));
+            assert_eq!(results, [expected.into(), Value::Integer(n)], "{args}");
+        }
+        let error = |state: &mut ExeState, args| {
+            let src = format!("return string.gsub({args})");
+            state
+                .eval(&src)
+                .unwrap_err()
+                .downcast::<RuntimeError>()
+                .unwrap()
+                .message
+        };
+        assert_eq!(
+            error(&mut state, "'x', 'x', '%2'"),
+            "invalid capture index %2"
+        );
+        assert_eq!(
+            error(&mut state, "'x', 'x', '%'"),
+            "invalid use of '%' in replacement string"
+        );
+        assert_eq!(
+            error(&mut state, "'x', 'x', vars, vars"),
+            "bad argument #4 to 'gsub' (number expected, got table)"
+        );
+        assert_eq!(
+            error(&mut state, "'x', 'x'"),
+            "bad argument #3 to 'gsub' (string/function/table expected, got no value)"
+        );
+        assert_eq!(
+            error(&mut state, "'x', 'x', table.pack"),
+            "invalid replacement value (a table)"
+        );
+    }
+
+    #[test]
+    fn rep_size() {
+        let mut state = ExeState::builder().max_string_size(10).build();
+        let results = state
+            .eval("return string.rep('ab', 3, ', '), string.rep('x', 0, 'y')")
+            .unwrap();
+        assert_eq!(results, ["ab, ab, ab".into(), "".into()]);
+        assert_eq!(
+            state.eval("return string.rep('ab', 5)").unwrap()[0],
+            "ababababab".into()
+        );
+        let error = |state: &mut ExeState, src: &str| {
+            state
+                .eval(src)
+                .unwrap_err()
+                .downcast::<RuntimeError>()
+                .unwrap()
+                .message
+        };
+        assert_eq!(
+            error(&mut state, "return string.rep('ab', 4, '--')"),
+            "resulting string too large"
+        );
+        // would overflow a 64-bit length, and is refused without trying
+        let mut state = ExeState::new();
+        assert_eq!(
+            error(
+                &mut state,
+                &format!("return string.rep('abc', {}, ',')", LuaInt::MAX)
+            ),
+            "resulting string too large"
+        );
+        #[cfg(not(feature = "int32"))]
+        assert_eq!(
+            error(&mut state, "return string.rep('x', 1000000000000)"),
+            "resulting string too large"
+        );
+    }
+
+    #[test]
+    fn argument_conversions() {
+        let mut state = ExeState::new();
+        let results = state
+            .eval("return string.upper(1.5), string.rep(10, '2'), string.sub(12345, '0x2', 3.0)")
+            .unwrap();
+        assert_eq!(results, ["1.5".into(), "1010".into(), "23".into()]);
+        let error = |state: &mut ExeState, src: &str| {
+            state
+                .eval(src)
+                .unwrap_err()
+                .downcast::<RuntimeError>()
+                .unwrap()
+                .message
+        };
+        assert_eq!(
+            error(&mut state, "string.sub('abc', ' 3.5 ')"),
+            "bad argument #2 to 'sub' (number has no integer representation)"
+        );
+        // the string is checked first
+        assert_eq!(
+            error(&mut state, "string.rep()"),
+            "bad argument #1 to 'rep' (string expected, got no value)"
+        );
+    }
+
+    #[test]
+    fn method_lookup() {
+        let mut state = ExeState::new();
+        let len = state.index(&"abc".into(), &"len".into()).unwrap();
+        assert!(matches!(len, Value::Function(_)));
+        let missing = state.index(&"abc".into(), &"nope".into()).unwrap();
+        assert_eq!(missing, Value::Nil);
+        assert!(state.index(&1.into(), &"len".into()).is_err());
+    }
+}
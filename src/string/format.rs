@@ -0,0 +1,467 @@
+//! `string.format`: the conversions of C's `printf`, which the reference
+//! implementation passes on to it, and `%q`. Output is bytes, so that
+//! strings with any bytes, zeros included, go through unchanged.
+
+use std::io::Write;
+
+use anyhow::bail;
+
+use crate::{
+    numfmt::{fmt_exp, fmt_fixed, fmt_general, fmt_hex, str2number},
+    value::{LuaFloat, LuaInt, LuaUnsigned, Value},
+    vm::ExeState,
+};
+
+/// A conversion specification: `%[flags][width][.precision]conversion`.
+#[derive(Debug, Default)]
+struct Spec {
+    left: bool,
+    plus: bool,
+    space: bool,
+    alt: bool,
+    zero: bool,
+    width: usize,
+    precision: Option<usize>,
+    conversion: u8,
+}
+
+/// The output of `string.format` for the arguments of `state`.
+pub(super) fn format(state: &ExeState) -> anyhow::Result<Vec<u8>> {
+    let fmt = super::check_str(state, 1, "format")?;
+    let mut out = Vec::with_capacity(fmt.len());
+    let mut arg = 1;
+    let mut i = 0;
+    while i < fmt.len() {
+        let c = fmt[i];
+        i += 1;
+        if c != b'%' {
+            out.push(c);
+            continue;
+        }
+        if fmt.get(i) == Some(&b'%') {
+            out.push(b'%');
+            i += 1;
+            continue;
+        }
+        let (spec, len) = parse_spec(&fmt[i..])?;
+        i += len;
+        arg += 1;
+        if arg > state.get_top() {
+            bail!("bad argument #{arg} to 'format' (no value)");
+        }
+        let v = state.arg(arg);
+        match spec.conversion {
+            b'c' => {
+                let c = check_int(v, arg)? as u8;
+                pad(&mut out, &spec, b"", &[c], false);
+            }
+            b'd' | b'i' => {
+                let n = check_int(v, arg)?;
+                let digits = int_digits(n.unsigned_abs().to_string(), spec.precision);
+                pad(&mut out, &spec, sign(n < 0, &spec), digits.as_bytes(), true);
+            }
+            b'u' => {
+                let digits = int_digits(
+                    (check_int(v, arg)? as LuaUnsigned).to_string(),
+                    spec.precision,
+                );
+                pad(&mut out, &spec, b"", digits.as_bytes(), true);
+            }
+            b'o' | b'x' | b'X' => {
+                let n = check_int(v, arg)? as LuaUnsigned;
+                let digits = match spec.conversion {
+                    b'o' => format!("{n:o}"),
+                    b'x' => format!("{n:x}"),
+                    _ => format!("{n:X}"),
+                };
+                let mut digits = int_digits(digits, spec.precision);
+                let prefix: &[u8] = match spec.conversion {
+                    b'o' if spec.alt && !digits.starts_with('0') => {
+                        digits.insert(0, '0');
+                        b""
+                    }
+                    b'x' if spec.alt && n != 0 => b"0x",
+                    b'X' if spec.alt && n != 0 => b"0X",
+                    _ => b"",
+                };
+                pad(&mut out, &spec, prefix, digits.as_bytes(), true);
+            }
+            b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => {
+                // in a double, as the reference formats floats of any width
+                let f = check_float(v, arg)? as f64;
+                let upper = spec.conversion.is_ascii_uppercase();
+                let body = if f.is_finite() {
+                    match spec.conversion.to_ascii_lowercase() {
+                        b'a' => fmt_hex(f.abs(), spec.precision),
+                        b'e' => fmt_exp(f.abs(), spec.precision.unwrap_or(6), spec.alt),
+                        b'f' => fmt_fixed(f.abs(), spec.precision.unwrap_or(6), spec.alt),
+                        _ => fmt_general(f.abs(), spec.precision.unwrap_or(6), spec.alt),
+                    }
+                } else if f.is_nan() {
+                    "nan".into()
+                } else {
+                    "inf".into()
+                };
+                let body = if upper {
+                    body.to_ascii_uppercase()
+                } else {
+                    body
+                };
+                let (prefix, digits) = match body.strip_prefix("0x").or(body.strip_prefix("0X")) {
+                    Some(digits) => (&body[..2], digits),
+                    None => ("", body.as_str()),
+                };
+                let sign = sign(f.is_sign_negative() && !f.is_nan(), &spec);
+                let prefix = [sign, prefix.as_bytes()].concat();
+                pad(&mut out, &spec, &prefix, digits.as_bytes(), f.is_finite());
+            }
+            b'p' => {
+                let p = match v {
+                    Value::Table(t) => format!("{:p}", std::rc::Rc::as_ptr(t)),
+                    _ => "(null)".into(),
+                };
+                pad(&mut out, &spec, b"", p.as_bytes(), false);
+            }
+            b'q' => {
+                if len > 1 {
+                    bail!("specifier '%q' cannot have modifiers");
+                }
+                quoted(&mut out, v)?;
+            }
+            b's' => {
+                let s = match <&[u8]>::try_from(v) {
+                    Ok(s) => s.to_vec(),
+                    Err(_) => v.to_string().into_bytes(),
+                };
+                if len == 1 {
+                    // the whole string, whatever it holds
+                    out.extend_from_slice(&s);
+                    continue;
+                }
+                if s.contains(&0) {
+                    bail!("bad argument #{arg} to 'format' (string contains zeros)");
+                }
+                let s = &s[..spec.precision.unwrap_or(s.len()).min(s.len())];
+                pad(&mut out, &spec, b"", s, false);
+            }
+            _ => unreachable!("checked by parse_spec"),
+        }
+    }
+    Ok(out)
+}
+
+/// The specification at the start of `s`, after a `%`, and its length.
+/// As in the reference implementation, each conversion takes only the
+/// flags that make sense for it, and width and precision have at most two
+/// digits.
+fn parse_spec(s: &[u8]) -> anyhow::Result<(Spec, usize)> {
+    let mut spec = Spec::default();
+    let mut i = 0;
+    while let Some(&c) = s.get(i) {
+        match c {
+            b'-' => spec.left = true,
+            b'+' => spec.plus = true,
+            b' ' => spec.space = true,
+            b'#' => spec.alt = true,
+            b'0' => spec.zero = true,
+            _ => break,
+        }
+        i += 1;
+    }
+    let digits = |i: &mut usize| {
+        let start = *i;
+        while *i < s.len() && *i - start < 2 && s[*i].is_ascii_digit() {
+            *i += 1;
+        }
+        std::str::from_utf8(&s[start..*i]).unwrap().parse().ok()
+    };
+    spec.width = digits(&mut i).unwrap_or(0);
+    if s.get(i) == Some(&b'.') {
+        i += 1;
+        spec.precision = Some(digits(&mut i).unwrap_or(0));
+    }
+    let invalid = |i: usize| {
+        let end = (i + 1).min(s.len());
+        anyhow::anyhow!(
+            "invalid conversion '%{}' to 'format'",
+            String::from_utf8_lossy(&s[..end])
+        )
+    };
+    let Some(&conversion) = s.get(i) else {
+        return Err(invalid(i));
+    };
+    spec.conversion = conversion;
+    let (flags, precision): (&[u8], bool) = match conversion {
+        b'c' | b'p' => (b"-", false),
+        b's' => (b"-", true),
+        b'd' | b'i' => (b"-+ 0", true),
+        b'u' => (b"-0", true),
+        b'o' | b'x' | b'X' => (b"-#0", true),
+        b'a' | b'A' | b'e' | b'E' | b'f' | b'F' | b'g' | b'G' => (b"-+ #0", true),
+        b'q' => (b"", false),
+        _ => return Err(invalid(i)),
+    };
+    let used = [
+        (spec.left, b'-'),
+        (spec.plus, b'+'),
+        (spec.space, b' '),
+        (spec.alt, b'#'),
+        (spec.zero, b'0'),
+    ];
+    if used.iter().any(|&(on, f)| on && !flags.contains(&f))
+        || (spec.precision.is_some() && !precision)
+    {
+        return Err(invalid(i));
+    }
+    Ok((spec, i + 1))
+}
+
+/// The sign to put before a number.
+fn sign(negative: bool, spec: &Spec) -> &'static [u8] {
+    if negative {
+        b"-"
+    } else if spec.plus {
+        b"+"
+    } else if spec.space {
+        b" "
+    } else {
+        b""
+    }
+}
+
+/// `digits` with at least `precision` of them, as C pads integers.
+fn int_digits(digits: String, precision: Option<usize>) -> String {
+    match precision {
+        // a zero precision prints nothing for zero
+        Some(0) if digits == "0" => String::new(),
+        Some(p) if p > digits.len() => format!("{}{digits}", "0".repeat(p - digits.len())),
+        _ => digits,
+    }
+}
+
+/// Write `prefix` and `body` padded to the width of `spec`: with spaces,
+/// on the left unless `-`, or with zeros after the prefix for `0` when
+/// `zeros` allows it, as it does for numbers but not for `inf` and `nan`.
+fn pad(out: &mut Vec<u8>, spec: &Spec, prefix: &[u8], body: &[u8], zeros: bool) {
+    let fill = spec.width.saturating_sub(prefix.len() + body.len());
+    // C ignores `0` for integers given a precision
+    let int = matches!(spec.conversion, b'd' | b'i' | b'u' | b'o' | b'x' | b'X');
+    if spec.left {
+        out.extend_from_slice(prefix);
+        out.extend_from_slice(body);
+        out.extend(std::iter::repeat_n(b' ', fill));
+    } else if spec.zero && zeros && !(int && spec.precision.is_some()) {
+        out.extend_from_slice(prefix);
+        out.extend(std::iter::repeat_n(b'0', fill));
+        out.extend_from_slice(body);
+    } else {
+        out.extend(std::iter::repeat_n(b' ', fill));
+        out.extend_from_slice(prefix);
+        out.extend_from_slice(body);
+    }
+}
+
+/// Write `v` as a Lua literal that reads back as an equal value.
+fn quoted(out: &mut Vec<u8>, v: &Value) -> anyhow::Result<()> {
+    match v {
+        Value::Nil | Value::Boolean(_) => write!(out, "{v}")?,
+        // the most negative integer has no decimal literal
+        Value::Integer(LuaInt::MIN) => write!(out, "{:#x}", LuaInt::MIN as LuaUnsigned)?,
+        Value::Integer(i) => write!(out, "{i}")?,
+        Value::Float(f) if f.is_nan() => out.extend_from_slice(b"(0/0)"),
+        Value::Float(f) if f.is_infinite() => {
+            out.extend_from_slice(if *f > 0.0 { b"1e9999" } else { b"-1e9999" })
+        }
+        &Value::Float(f) => {
+            if f.is_sign_negative() {
+                out.push(b'-');
+            }
+            let abs: LuaFloat = f.abs();
+            out.extend_from_slice(fmt_hex(abs as f64, None).as_bytes());
+        }
+        _ => match <&[u8]>::try_from(v) {
+            Ok(s) => quoted_str(out, s),
+            Err(_) => bail!("bad argument #2 to 'format' (value has no literal form)"),
+        },
+    }
+    Ok(())
+}
+
+fn quoted_str(out: &mut Vec<u8>, s: &[u8]) {
+    out.push(b'"');
+    for (i, &c) in s.iter().enumerate() {
+        match c {
+            b'"' | b'\\' | b'\n' => out.extend_from_slice(&[b'\\', c]),
+            // control characters by their code, in three digits when a
+            // digit follows
+            0..=31 | 127 => {
+                if s.get(i + 1).is_some_and(u8::is_ascii_digit) {
+                    write!(out, "\\{c:03}").unwrap();
+                } else {
+                    write!(out, "\\{c}").unwrap();
+                }
+            }
+            _ => out.push(c),
+        }
+    }
+    out.push(b'"');
+}
+
+/// Argument `arg` as an integer, from a number or a string that converts
+/// to one.
+fn check_int(v: &Value, arg: usize) -> anyhow::Result<LuaInt> {
+    match to_number(v) {
+        Some(Value::Integer(n)) => Ok(n),
+        Some(Value::Float(f)) if f.fract() == 0.0 && f.abs() < LuaInt::MAX as LuaFloat => {
+            Ok(f as LuaInt)
+        }
+        Some(_) => {
+            bail!("bad argument #{arg} to 'format' (number has no integer representation)")
+        }
+        None => bail!(
+            "bad argument #{arg} to 'format' (number expected, got {})",
+            v.type_name()
+        ),
+    }
+}
+
+fn check_float(v: &Value, arg: usize) -> anyhow::Result<LuaFloat> {
+    match to_number(v) {
+        Some(Value::Integer(n)) => Ok(n as LuaFloat),
+        Some(Value::Float(f)) => Ok(f),
+        _ => bail!(
+            "bad argument #{arg} to 'format' (number expected, got {})",
+            v.type_name()
+        ),
+    }
+}
+
+/// `v` as a number, converting a string as the reference does for
+/// arithmetic.
+pub(super) fn to_number(v: &Value) -> Option<Value> {
+    match v {
+        Value::Integer(_) | Value::Float(_) => Some(v.clone()),
+        _ => str2number(<&[u8]>::try_from(v).ok()?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RuntimeError;
+
+    fn format(args: &str) -> anyhow::Result<Vec<u8>> {
+        let mut state = ExeState::new();
+        // there is no arithmetic to make these with
+        state.set_global("inf", Value::Float(LuaFloat::INFINITY));
+        state.set_global("nan", Value::Float(LuaFloat::NAN));
+        state.set_global("mininteger", Value::Integer(LuaInt::MIN));
+        // the message alone, without the position of the call
+        let results = state
+            .eval(&format!("return string.format({args})"))
+            .map_err(|err| anyhow::anyhow!(err.downcast::<RuntimeError>().unwrap().message))?;
+        Ok(<&[u8]>::try_from(&results[0]).unwrap().to_vec())
+    }
+
+    #[cfg(not(any(feature = "int32", feature = "float32")))]
+    #[test]
+    fn conversions() {
+        let cases = [
+            ("'%5d|%-5d|%05d|%+d|% d'", "   42|42   |00042|+42| 42"),
+            ("'%.3d|%x|%#X|%#o|%c'", "007|ff|0XFF|010|A"),
+            ("'%d %u'", "-7 18446744073709551609"),
+            (
+                "'%f|%.2f|%10.3f|%-8.1f|'",
+                "0.500000|3.14|     3.142|2.0     |",
+            ),
+            ("'%e|%.0e|%#.0e'", "1.500000e+00|2e+03|2.e+03"),
+            ("'%g|%g|%g|%#g'", "0.5|1e+20|1e-05|0.500000"),
+            ("'%g|%.3g|%G'", "100000|1.23e+05|1E+100"),
+            ("'%a|%A|%.1a'", "0x1.8p+1|0X1P-1|0x1.0p+0"),
+            ("'%f|%5.1f|%E'", "inf|  nan|-INF"),
+            ("'%5s|%-5s|%.2s|%s'", "   ab|ab   |ab|1.5"),
+            ("'%%|%s'", "%|nil"),
+        ];
+        let args = [
+            "42, 42, 42, 42, 42",
+            "7, 255, 255, 8, 65",
+            "'-7', '-7'",
+            "0.5, 3.14159, 3.14159, 2",
+            "1.5, 2000, 2000",
+            "0.5, 1e20, 0.00001, 0.5",
+            "100000, 123456, 1e100",
+            "3, 0.5, 1.03",
+            "inf, nan, '-1e999'",
+            "'ab', 'ab', 'abc', 1.5",
+            "nil",
+        ];
+        for ((fmt, expected), args) in cases.iter().zip(args) {
+            let out = format(&format!("{fmt}, {args}")).unwrap_or_else(|e| panic!("{fmt}: {e}"));
+            assert_eq!(String::from_utf8_lossy(&out), *expected, "{fmt}");
+        }
+    }
+
+    #[cfg(feature = "int32")]
+    #[test]
+    fn conversions_32() {
+        assert_eq!(
+            format("'%d %u %x %q', '-7', '-7', '-1', mininteger").unwrap(),
+            b"-7 4294967289 ffffffff 0x80000000"
+        );
+    }
+
+    #[test]
+    fn binary_safe() {
+        assert_eq!(format(r"'a\0%sb\255', 'x\0y'").unwrap(), b"a\0x\0yb\xff");
+        assert_eq!(
+            format(r"'%5s', 'x\0y'").unwrap_err().to_string(),
+            "bad argument #2 to 'format' (string contains zeros)"
+        );
+    }
+
+    #[test]
+    fn quoted() {
+        assert_eq!(
+            format(r#"'%q', 'a"b\\c\nd\0e\0001\r\127'"#).unwrap(),
+            b"\"a\\\"b\\\\c\\\nd\\0e\\0001\\13\\127\""
+        );
+        assert_eq!(
+            format("'%q %q %q %q', 1, 0.5, nil, true").unwrap(),
+            b"1 0x1p-1 nil true"
+        );
+        #[cfg(not(feature = "int32"))]
+        assert_eq!(
+            format("'%q %q %q', inf, mininteger, nan").unwrap(),
+            b"1e9999 0x8000000000000000 (0/0)"
+        );
+        // the literal reads back as the same string
+        let s = r#"'x\0\1\0012\n\\"\r'"#;
+        let src = format(&format!("'return %q', {s}")).unwrap();
+        let mut state = ExeState::new();
+        let back = state.eval(std::str::from_utf8(&src).unwrap()).unwrap();
+        assert_eq!(back, state.eval(&format!("return {s}")).unwrap());
+    }
+
+    #[test]
+    fn invalid() {
+        let error = |args| format(args).unwrap_err().to_string();
+        assert_eq!(error("'%y', 1"), "invalid conversion '%y' to 'format'");
+        assert_eq!(error("'%123d', 1"), "invalid conversion '%123' to 'format'");
+        assert_eq!(error("'%#d', 1"), "invalid conversion '%#d' to 'format'");
+        assert_eq!(error("'%.3c', 1"), "invalid conversion '%.3c' to 'format'");
+        assert_eq!(error("'%5q', 1"), "specifier '%q' cannot have modifiers");
+        assert_eq!(error("'%d'"), "bad argument #2 to 'format' (no value)");
+        assert_eq!(
+            error("'%d', 1.5"),
+            "bad argument #2 to 'format' (number has no integer representation)"
+        );
+        assert_eq!(
+            error("'%d', 'x'"),
+            "bad argument #2 to 'format' (number expected, got string)"
+        );
+        assert_eq!(
+            error("'%q', string"),
+            "bad argument #2 to 'format' (value has no literal form)"
+        );
+    }
+}
@@ -0,0 +1,429 @@
+//! Lua patterns, matched as the reference `lstrlib.c` does: by recursive
+//! backtracking over the bytes of the subject, with at most 32 captures.
+
+use anyhow::bail;
+
+use crate::value::{LuaInt, Value};
+
+const MAXCCALLS: usize = 200;
+const MAXCAPTURES: usize = 32;
+const CAP_UNFINISHED: isize = -1;
+const CAP_POSITION: isize = -2;
+
+pub(super) struct MatchState<'a> {
+    src: &'a [u8],
+    pat: &'a [u8],
+    level: usize,
+    /// Start and length of each capture, or one of `CAP_UNFINISHED` and
+    /// `CAP_POSITION` for the length.
+    capture: [(usize, isize); MAXCAPTURES],
+    matchdepth: usize,
+}
+
+impl<'a> MatchState<'a> {
+    pub fn new(src: &'a [u8], pat: &'a [u8]) -> Self {
+        Self {
+            src,
+            pat,
+            level: 0,
+            capture: [(0, 0); MAXCAPTURES],
+            matchdepth: MAXCCALLS,
+        }
+    }
+
+    /// The subject.
+    pub fn src(&self) -> &'a [u8] {
+        self.src
+    }
+
+    /// Forget the captures of the last match, before trying another.
+    pub fn reset(&mut self) {
+        self.level = 0;
+        self.matchdepth = MAXCCALLS;
+    }
+
+    /// The end of a match of the pattern from byte `p` on, starting at
+    /// byte `s` of the subject.
+    pub fn do_match(&mut self, s: usize, p: usize) -> anyhow::Result<Option<usize>> {
+        if self.matchdepth == 0 {
+            bail!("pattern too complex");
+        }
+        self.matchdepth -= 1;
+        let result = self.match_here(s, p);
+        self.matchdepth += 1;
+        result
+    }
+
+    fn match_here(&mut self, mut s: usize, mut p: usize) -> anyhow::Result<Option<usize>> {
+        loop {
+            let Some(&pc) = self.pat.get(p) else {
+                return Ok(Some(s));
+            };
+            match pc {
+                b'(' => {
+                    return if self.pat.get(p + 1) == Some(&b')') {
+                        self.start_capture(s, p + 2, CAP_POSITION)
+                    } else {
+                        self.start_capture(s, p + 1, CAP_UNFINISHED)
+                    };
+                }
+                b')' => return self.end_capture(s, p + 1),
+                b'$' if p + 1 == self.pat.len() => {
+                    return Ok((s == self.src.len()).then_some(s));
+                }
+                b'%' if self.pat.get(p + 1) == Some(&b'b') => {
+                    match self.match_balance(s, p + 2)? {
+                        Some(e) => {
+                            s = e;
+                            p += 4;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                b'%' if self.pat.get(p + 1) == Some(&b'f') => {
+                    p += 2;
+                    if self.pat.get(p) != Some(&b'[') {
+                        bail!("missing '[' after '%f' in pattern");
+                    }
+                    let ep = self.class_end(p)?;
+                    let previous = if s == 0 { 0 } else { self.src[s - 1] };
+                    let current = self.src.get(s).copied().unwrap_or(0);
+                    if !self.match_bracket_class(previous, p, ep - 1)
+                        && self.match_bracket_class(current, p, ep - 1)
+                    {
+                        p = ep;
+                        continue;
+                    }
+                    return Ok(None);
+                }
+                b'%' if self.pat.get(p + 1).is_some_and(u8::is_ascii_digit) => {
+                    match self.match_capture(s, self.pat[p + 1])? {
+                        Some(e) => {
+                            s = e;
+                            p += 2;
+                            continue;
+                        }
+                        None => return Ok(None),
+                    }
+                }
+                _ => {
+                    let ep = self.class_end(p)?;
+                    let suffix = self.pat.get(ep).copied();
+                    if !self.single_match(s, p, ep) {
+                        if matches!(suffix, Some(b'*' | b'?' | b'-')) {
+                            // accept empty
+                            p = ep + 1;
+                            continue;
+                        }
+                        return Ok(None);
+                    }
+                    match suffix {
+                        Some(b'?') => match self.do_match(s + 1, ep + 1)? {
+                            Some(e) => return Ok(Some(e)),
+                            None => {
+                                p = ep + 1;
+                                continue;
+                            }
+                        },
+                        Some(b'+') => return self.max_expand(s + 1, p, ep),
+                        Some(b'*') => return self.max_expand(s, p, ep),
+                        Some(b'-') => return self.min_expand(s, p, ep),
+                        _ => {
+                            s += 1;
+                            p = ep;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The end of the single character class starting at `p`.
+    fn class_end(&self, mut p: usize) -> anyhow::Result<usize> {
+        let c = self.pat[p];
+        p += 1;
+        match c {
+            b'%' => {
+                if p >= self.pat.len() {
+                    bail!("malformed pattern (ends with '%')");
+                }
+                Ok(p + 1)
+            }
+            b'[' => {
+                if self.pat.get(p) == Some(&b'^') {
+                    p += 1;
+                }
+                // look for a ']', the first one closing nothing
+                loop {
+                    if p >= self.pat.len() {
+                        bail!("malformed pattern (missing ']')");
+                    }
+                    let c = self.pat[p];
+                    p += 1;
+                    // skip escapes, such as '%]'
+                    if c == b'%' && p < self.pat.len() {
+                        p += 1;
+                    }
+                    if self.pat.get(p) == Some(&b']') {
+                        return Ok(p + 1);
+                    }
+                }
+            }
+            _ => Ok(p),
+        }
+    }
+
+    /// Whether byte `s` of the subject is in the class from `p` to `ep`.
+    fn single_match(&self, s: usize, p: usize, ep: usize) -> bool {
+        let Some(&c) = self.src.get(s) else {
+            return false;
+        };
+        match self.pat[p] {
+            b'.' => true,
+            b'%' => match_class(c, self.pat[p + 1]),
+            b'[' => self.match_bracket_class(c, p, ep - 1),
+            pc => pc == c,
+        }
+    }
+
+    /// Whether `c` is in the set from the `[` at `p` to the `]` at `ec`.
+    fn match_bracket_class(&self, c: u8, mut p: usize, ec: usize) -> bool {
+        let mut found = true;
+        if self.pat[p + 1] == b'^' {
+            found = false;
+            p += 1;
+        }
+        p += 1;
+        while p < ec {
+            if self.pat[p] == b'%' {
+                p += 1;
+                if match_class(c, self.pat[p]) {
+                    return found;
+                }
+            } else if self.pat[p + 1] == b'-' && p + 2 < ec {
+                if self.pat[p] <= c && c <= self.pat[p + 2] {
+                    return found;
+                }
+                p += 2;
+            } else if self.pat[p] == c {
+                return found;
+            }
+            p += 1;
+        }
+        !found
+    }
+
+    fn match_balance(&self, s: usize, p: usize) -> anyhow::Result<Option<usize>> {
+        if p + 1 >= self.pat.len() {
+            bail!("malformed pattern (missing arguments to '%b')");
+        }
+        let (open, close) = (self.pat[p], self.pat[p + 1]);
+        if self.src.get(s) != Some(&open) {
+            return Ok(None);
+        }
+        let mut depth = 1;
+        for (i, &c) in self.src.iter().enumerate().skip(s + 1) {
+            if c == close {
+                depth -= 1;
+                if depth == 0 {
+                    return Ok(Some(i + 1));
+                }
+            } else if c == open {
+                depth += 1;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Match as many repetitions of the class as possible, then give them
+    /// back one by one until the rest of the pattern matches.
+    fn max_expand(&mut self, s: usize, p: usize, ep: usize) -> anyhow::Result<Option<usize>> {
+        let mut i = 0;
+        while self.single_match(s + i, p, ep) {
+            i += 1;
+        }
+        loop {
+            if let Some(e) = self.do_match(s + i, ep + 1)? {
+                return Ok(Some(e));
+            }
+            if i == 0 {
+                return Ok(None);
+            }
+            i -= 1;
+        }
+    }
+
+    /// Match as few repetitions of the class as the rest of the pattern
+    /// allows.
+    fn min_expand(&mut self, mut s: usize, p: usize, ep: usize) -> anyhow::Result<Option<usize>> {
+        loop {
+            if let Some(e) = self.do_match(s, ep + 1)? {
+                return Ok(Some(e));
+            }
+            if !self.single_match(s, p, ep) {
+                return Ok(None);
+            }
+            s += 1;
+        }
+    }
+
+    fn start_capture(&mut self, s: usize, p: usize, what: isize) -> anyhow::Result<Option<usize>> {
+        if self.level >= MAXCAPTURES {
+            bail!("too many captures");
+        }
+        self.capture[self.level] = (s, what);
+        self.level += 1;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.level -= 1;
+        }
+        Ok(result)
+    }
+
+    fn end_capture(&mut self, s: usize, p: usize) -> anyhow::Result<Option<usize>> {
+        let Some(l) = (0..self.level)
+            .rev()
+            .find(|&l| self.capture[l].1 == CAP_UNFINISHED)
+        else {
+            bail!("invalid pattern capture");
+        };
+        self.capture[l].1 = (s - self.capture[l].0) as isize;
+        let result = self.do_match(s, p)?;
+        if result.is_none() {
+            self.capture[l].1 = CAP_UNFINISHED;
+        }
+        Ok(result)
+    }
+
+    /// Match a repetition of the capture numbered by digit `l`.
+    fn match_capture(&self, s: usize, l: u8) -> anyhow::Result<Option<usize>> {
+        let l = (l as usize).wrapping_sub(b'1' as usize);
+        if l >= self.level || self.capture[l].1 == CAP_UNFINISHED {
+            bail!("invalid capture index %{} in pattern", l.wrapping_add(1));
+        }
+        let (start, len) = self.capture[l];
+        let captured = &self.src[start..start + len as usize];
+        Ok(self.src[s..]
+            .starts_with(captured)
+            .then(|| s + captured.len()))
+    }
+
+    /// Capture `i` of the match from `s` to `e`, or the whole match if the
+    /// pattern has no captures and `i` is 0.
+    pub fn capture(&self, i: usize, s: usize, e: usize) -> anyhow::Result<Value> {
+        if i >= self.level {
+            if i != 0 {
+                bail!("invalid capture index %{}", i + 1);
+            }
+            return Ok(self.src[s..e].into());
+        }
+        match self.capture[i] {
+            (_, CAP_UNFINISHED) => bail!("unfinished capture"),
+            (start, CAP_POSITION) => Ok(Value::Integer(start as LuaInt + 1)),
+            (start, len) => Ok(self.src[start..start + len as usize].into()),
+        }
+    }
+
+    /// All the captures of the match from `s` to `e`, or the whole match if
+    /// the pattern has none.
+    pub fn captures(&self, s: usize, e: usize) -> anyhow::Result<Vec<Value>> {
+        (0..self.level.max(1))
+            .map(|i| self.capture(i, s, e))
+            .collect()
+    }
+}
+
+/// Whether `c` is in the class of `%` followed by `cl`: a letter naming a
+/// class, its complement in upper case, or else `cl` itself.
+fn match_class(c: u8, cl: u8) -> bool {
+    let found = match cl.to_ascii_lowercase() {
+        b'a' => c.is_ascii_alphabetic(),
+        b'c' => c.is_ascii_control(),
+        b'd' => c.is_ascii_digit(),
+        b'g' => c.is_ascii_graphic(),
+        b'l' => c.is_ascii_lowercase(),
+        b'p' => c.is_ascii_punctuation(),
+        // C's isspace includes the vertical tab
+        b's' => c.is_ascii_whitespace() || c == b'\x0b',
+        b'u' => c.is_ascii_uppercase(),
+        b'w' => c.is_ascii_alphanumeric(),
+        b'x' => c.is_ascii_hexdigit(),
+        _ => return cl == c,
+    };
+    found == cl.is_ascii_lowercase()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The first match of `pat` in `src`, and its captures.
+    fn find(src: &str, pat: &str) -> anyhow::Result<Option<(usize, usize, Vec<Value>)>> {
+        let (anchor, pat) = match pat.strip_prefix('^') {
+            Some(pat) => (true, pat),
+            None => (false, pat),
+        };
+        let mut ms = MatchState::new(src.as_bytes(), pat.as_bytes());
+        for s in 0..=src.len() {
+            ms.reset();
+            if let Some(e) = ms.do_match(s, 0)? {
+                return Ok(Some((s + 1, e, ms.captures(s, e)?)));
+            }
+            if anchor {
+                break;
+            }
+        }
+        Ok(None)
+    }
+
+    #[test]
+    fn matches() {
+        let cases = [
+            ("hello world", "o w", Some((5, 7))),
+            ("hello world", "%a+", Some((1, 5))),
+            ("  x = 10", "%w+%s*=%s*%d+", Some((3, 8))),
+            ("aaab", "a-b", Some((1, 4))),
+            ("aaab", "^b", None),
+            ("aaab", "b$", Some((4, 4))),
+            ("f(a(b)c)d", "%b()", Some((2, 8))),
+            ("THE (quick) fox", "%f[%a]%a+", Some((1, 3))),
+            ("x [y] z", "[%[%]]", Some((3, 3))),
+            ("a-b", "[a-]+", Some((1, 2))),
+            ("abc", "[^a]", Some((2, 2))),
+            ("", "x*", Some((1, 0))),
+        ];
+        for (src, pat, expected) in cases {
+            let found = find(src, pat).unwrap().map(|(s, e, _)| (s, e));
+            assert_eq!(found, expected, "{pat:?} in {src:?}");
+        }
+    }
+
+    #[test]
+    fn captures() {
+        let (_, _, caps) = find("key = value", "(%w+)%s*=%s*(%w+)").unwrap().unwrap();
+        assert_eq!(caps, ["key".into(), "value".into()]);
+        let (_, _, caps) = find("abc", "()b()").unwrap().unwrap();
+        assert_eq!(caps, [Value::Integer(2), Value::Integer(3)]);
+        let (_, _, caps) = find("say 'hi' now", "(['\"])(.-)%1").unwrap().unwrap();
+        assert_eq!(caps, ["'".into(), "hi".into()]);
+    }
+
+    #[test]
+    fn malformed() {
+        let error = |pat| find("abc", pat).unwrap_err().to_string();
+        assert_eq!(error("%"), "malformed pattern (ends with '%')");
+        assert_eq!(error("[a"), "malformed pattern (missing ']')");
+        assert_eq!(error("%b"), "malformed pattern (missing arguments to '%b')");
+        assert_eq!(error("%f"), "missing '[' after '%f' in pattern");
+        assert_eq!(error("(a"), "unfinished capture");
+        assert_eq!(error("a)"), "invalid pattern capture");
+        assert_eq!(error("%1"), "invalid capture index %1 in pattern");
+        assert_eq!(error(&"(".repeat(33)), "too many captures");
+        let deep = "a?".repeat(300);
+        assert_eq!(
+            find(&"a".repeat(300), &deep).unwrap_err().to_string(),
+            "pattern too complex"
+        );
+    }
+}
@@ -0,0 +1,473 @@
+//! The `table` library.
+
+use std::{cell::RefCell, rc::Rc};
+
+use anyhow::bail;
+
+use crate::{
+    value::{LuaFloat, LuaInt, Table, Value},
+    vm::ExeState,
+};
+
+/// Build the `table` library table.
+pub fn lib() -> Value {
+    let mut t = Table::new();
+    t.map.insert("pack".into(), Value::Function(lib_pack));
+    t.map.insert("sort".into(), Value::Function(lib_sort));
+    t.map.insert("concat".into(), Value::Function(lib_concat));
+    t.map.insert("rehash".into(), Value::Function(lib_rehash));
+    t.into()
+}
+
+// table.pack(...)
+fn lib_pack(state: &mut ExeState) -> anyhow::Result<i32> {
+    let n = state.get_top();
+    let mut t = Table::new();
+    for i in 1..=n {
+        t.set(Value::Integer(i as LuaInt), state.arg(i).clone())?;
+    }
+    // the count, which the table alone cannot tell when there are nils
+    t.set("n".into(), Value::Integer(n as LuaInt))?;
+    state.push(t.into());
+    Ok(1)
+}
+
+// table.concat(list [, sep [, i [, j]]])
+fn lib_concat(state: &mut ExeState) -> anyhow::Result<i32> {
+    let Value::Table(t) = state.arg(1).clone() else {
+        bail!(
+            "bad argument #1 to 'concat' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    let sep = match state.arg(2) {
+        Value::Nil => Vec::new(),
+        v @ (Value::Integer(_) | Value::Float(_)) => v.to_string().into_bytes(),
+        v => match <&[u8]>::try_from(v) {
+            Ok(sep) => sep.to_vec(),
+            Err(_) => bail!(
+                "bad argument #2 to 'concat' (string expected, got {})",
+                state.arg_type_name(2)
+            ),
+        },
+    };
+    let i = opt_int(state, 3, "concat", 1)?;
+    let j = opt_int(state, 4, "concat", t.borrow().array.len() as LuaInt)?;
+
+    // check every element and the total length before allocating
+    let t = t.borrow();
+    let mut pieces = Vec::new();
+    let mut len = 0;
+    for k in i..=j {
+        let piece = match t.get(&Value::Integer(k)) {
+            // numbers are short enough to be stored inline
+            v @ (Value::Integer(_) | Value::Float(_)) => Value::from(v.to_string()),
+            v if <&[u8]>::try_from(&v).is_ok() => v,
+            _ => bail!("invalid value (at index {k}) in table for 'concat'"),
+        };
+        len += <&[u8]>::try_from(&piece)?.len() + if k < j { sep.len() } else { 0 };
+        if len > state.max_string_size() {
+            bail!("resulting string too large");
+        }
+        pieces.push(piece);
+    }
+    drop(t);
+    let mut out = Vec::with_capacity(len);
+    for (n, piece) in pieces.iter().enumerate() {
+        if n > 0 {
+            out.extend_from_slice(&sep);
+        }
+        out.extend_from_slice(<&[u8]>::try_from(piece)?);
+    }
+    state.push(out.into());
+    Ok(1)
+}
+
+fn opt_int(state: &ExeState, i: usize, fname: &str, default: LuaInt) -> anyhow::Result<LuaInt> {
+    match *state.arg(i) {
+        Value::Nil => Ok(default),
+        Value::Integer(n) => Ok(n),
+        Value::Float(f) if f.fract() == 0.0 && f.abs() < LuaInt::MAX as LuaFloat => Ok(f as LuaInt),
+        Value::Float(_) => {
+            bail!("bad argument #{i} to '{fname}' (number has no integer representation)")
+        }
+        _ => bail!(
+            "bad argument #{i} to '{fname}' (number expected, got {})",
+            state.arg_type_name(i)
+        ),
+    }
+}
+
+// table.rehash(t): resize the array and map parts of `t` to what its keys
+// need now, to release the memory of keys removed since it last grew
+fn lib_rehash(state: &mut ExeState) -> anyhow::Result<i32> {
+    let Value::Table(t) = state.arg(1).clone() else {
+        bail!(
+            "bad argument #1 to 'rehash' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    t.borrow_mut().rehash();
+    Ok(0)
+}
+
+// table.sort(list [, comp])
+fn lib_sort(state: &mut ExeState) -> anyhow::Result<i32> {
+    let Value::Table(t) = state.arg(1).clone() else {
+        bail!(
+            "bad argument #1 to 'sort' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    let comp = match state.arg(2) {
+        Value::Nil => None,
+        f @ (Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_)) => {
+            Some(f.clone())
+        }
+        _ => bail!(
+            "bad argument #2 to 'sort' (function expected, got {})",
+            state.arg_type_name(2)
+        ),
+    };
+
+    let lt = |state: &mut ExeState, a: &Value, b: &Value| match &comp {
+        None => a.less_than(b),
+        Some(f) => {
+            let r = state.call_first(f.clone(), &[a.clone(), b.clone()])?;
+            Ok(!matches!(r, Value::Nil | Value::Boolean(false)))
+        }
+    };
+    if state.stable_sort() {
+        // sort a copy, so that the comparator may look at the table
+        let mut list = t.borrow().array.clone();
+        merge_sort(&mut list, &mut |a, b| lt(state, a, b))?;
+        let mut t = t.borrow_mut();
+        for (i, v) in list.into_iter().enumerate() {
+            t.set(Value::Integer(i as LuaInt + 1), v)?;
+        }
+    } else {
+        let n = t.borrow().array.len() as LuaInt;
+        let mut sort = QuickSort {
+            t: &t,
+            lt: &mut |a: &Value, b: &Value| lt(state, a, b),
+        };
+        sort.sort(1, n, 0)?;
+    }
+    Ok(0)
+}
+
+/// The reference quicksort, in place: elements are read and written one
+/// at a time, never borrowing the table while the comparator runs, which
+/// may look at it or even change it. A comparator that is not a strict
+/// order is caught when a partition runs past its bounds.
+struct QuickSort<'a, F> {
+    t: &'a Rc<RefCell<Table>>,
+    lt: &'a mut F,
+}
+
+impl<F: FnMut(&Value, &Value) -> anyhow::Result<bool>> QuickSort<'_, F> {
+    fn get(&self, i: LuaInt) -> Value {
+        self.t.borrow().get(&Value::Integer(i))
+    }
+
+    fn set(&self, i: LuaInt, v: Value) -> anyhow::Result<()> {
+        self.t.borrow_mut().set(Value::Integer(i), v)
+    }
+
+    /// Swap elements `i` and `j`, whose values are `vi` and `vj`.
+    fn swap(&self, i: LuaInt, vi: Value, j: LuaInt, vj: Value) -> anyhow::Result<()> {
+        self.set(i, vj)?;
+        self.set(j, vi)
+    }
+
+    /// Sort elements `lo` to `up`. `rnd` picks pivots in large intervals,
+    /// which is 0 for the middle element until the partitions turn out
+    /// unbalanced.
+    fn sort(&mut self, mut lo: LuaInt, mut up: LuaInt, mut rnd: u32) -> anyhow::Result<()> {
+        while lo < up {
+            // sort the first, middle and last elements
+            let (a, b) = (self.get(lo), self.get(up));
+            if (self.lt)(&b, &a)? {
+                self.swap(lo, a, up, b)?;
+            }
+            if up - lo == 1 {
+                break;
+            }
+            let mut p = if up - lo < 100 || rnd == 0 {
+                (lo + up) / 2
+            } else {
+                let r4 = (up - lo) / 4;
+                (rnd as LuaInt).rem_euclid(r4 * 2) + lo + r4
+            };
+            let (a, b) = (self.get(p), self.get(lo));
+            if (self.lt)(&a, &b)? {
+                self.swap(p, a, lo, b)?;
+            } else {
+                let c = self.get(up);
+                if (self.lt)(&c, &a)? {
+                    self.swap(p, a, up, c)?;
+                }
+            }
+            if up - lo == 2 {
+                break;
+            }
+            // the pivot waits next to the end
+            let (pivot, b) = (self.get(p), self.get(up - 1));
+            self.swap(p, pivot.clone(), up - 1, b)?;
+            p = self.partition(lo, up, &pivot)?;
+
+            // recurse into the smaller side, loop on the larger one
+            let n;
+            if p - lo < up - p {
+                self.sort(lo, p - 1, rnd)?;
+                n = p - lo;
+                lo = p + 1;
+            } else {
+                self.sort(p + 1, up, rnd)?;
+                n = up - p;
+                up = p - 1;
+            }
+            if (up - lo) / 128 > n {
+                rnd = scramble(lo, up);
+            }
+        }
+        Ok(())
+    }
+
+    /// Move the elements from `lo` to `up` less than `pivot`, which is at
+    /// `up - 1`, before those greater, returning where the pivot goes.
+    fn partition(&mut self, lo: LuaInt, up: LuaInt, pivot: &Value) -> anyhow::Result<LuaInt> {
+        let (mut i, mut j) = (lo, up - 1);
+        loop {
+            i += 1;
+            let mut a = self.get(i);
+            while (self.lt)(&a, pivot)? {
+                if i == up - 1 {
+                    bail!("invalid order function for sorting");
+                }
+                i += 1;
+                a = self.get(i);
+            }
+            j -= 1;
+            let mut b = self.get(j);
+            while (self.lt)(pivot, &b)? {
+                if j < i {
+                    bail!("invalid order function for sorting");
+                }
+                j -= 1;
+                b = self.get(j);
+            }
+            if j < i {
+                let p = self.get(up - 1);
+                self.swap(up - 1, p, i, a)?;
+                return Ok(i);
+            }
+            self.swap(i, a, j, b)?;
+        }
+    }
+}
+
+/// A pivot offset for a badly partitioned interval. The reference
+/// implementation mixes in the clock; this stays the same between runs.
+fn scramble(lo: LuaInt, up: LuaInt) -> u32 {
+    let h = (lo as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ up as u64;
+    (h >> 32) as u32
+}
+
+/// Sort `list` by `lt`, stopping at its first error. The standard sorts
+/// cannot report errors from the comparison, and may panic when it is not
+/// a total order, as a Lua comparator is free not to be.
+fn merge_sort(
+    list: &mut [Value],
+    lt: &mut impl FnMut(&Value, &Value) -> anyhow::Result<bool>,
+) -> anyhow::Result<()> {
+    if list.len() < 2 {
+        return Ok(());
+    }
+    let mid = list.len() / 2;
+    merge_sort(&mut list[..mid], lt)?;
+    merge_sort(&mut list[mid..], lt)?;
+
+    let mut merged = Vec::with_capacity(list.len());
+    let (mut i, mut j) = (0, mid);
+    while i < mid && j < list.len() {
+        // take from the right only when strictly less, to keep equal
+        // elements in order
+        if lt(&list[j], &list[i])? {
+            merged.push(std::mem::take(&mut list[j]));
+            j += 1;
+        } else {
+            merged.push(std::mem::take(&mut list[i]));
+            i += 1;
+        }
+    }
+    merged.extend(list[i..mid].iter_mut().map(std::mem::take));
+    merged.extend(list[j..].iter_mut().map(std::mem::take));
+    for (slot, v) in list.iter_mut().zip(merged) {
+        *slot = v;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::vm::RuntimeError;
+
+    fn sort(state: &mut ExeState, values: Vec<Value>) -> anyhow::Result<Vec<Value>> {
+        let mut t = Table::new();
+        t.array = values;
+        let t = Value::from(t);
+        let sort = state.index(&state.get_global("table"), &"sort".into())?;
+        state.call(sort, std::slice::from_ref(&t))?;
+        let Value::Table(t) = t else { unreachable!() };
+        let array = t.borrow().array.clone();
+        Ok(array)
+    }
+
+    #[test]
+    fn default_order() {
+        let mut state = ExeState::new();
+        let sorted = sort(
+            &mut state,
+            vec!["b".into(), "B".into(), "a".into(), "".into()],
+        );
+        assert_eq!(
+            sorted.unwrap(),
+            ["".into(), "B".into(), "a".into(), "b".into()]
+        );
+        let sorted = sort(&mut state, vec![3.into(), 1.5.into(), (-1).into()]);
+        assert_eq!(sorted.unwrap(), [(-1).into(), 1.5.into(), 3.into()]);
+
+        let err = sort(&mut state, vec![1.into(), "x".into()]).unwrap_err();
+        assert!(format!("{err:#}").contains("attempt to compare"));
+    }
+
+    #[test]
+    fn concat() {
+        let mut state = ExeState::new();
+        // the count of pack is in the hash part, which concat ignores
+        state.eval("t = table.pack('a', 'b', 3, 4.5)").unwrap();
+        let cases = [
+            ("t", "ab34.5"),
+            ("t, ', '", "a, b, 3, 4.5"),
+            ("t, '-', 2", "b-3-4.5"),
+            ("t, '-', 2, 3", "b-3"),
+            ("t, 0, 1, 2", "a0b"),
+            ("t, '-', 3, 2", ""),
+            ("t, '-', 4, 4", "4.5"),
+        ];
+        for (args, expected) in cases {
+            let results = state.eval(&format!("return table.concat({args})")).unwrap();
+            assert_eq!(results, [expected.into()], "{args}");
+        }
+        let error = |state: &mut ExeState, args| {
+            let src = format!("return table.concat({args})");
+            state
+                .eval(&src)
+                .unwrap_err()
+                .downcast::<RuntimeError>()
+                .unwrap()
+                .message
+        };
+        assert_eq!(
+            error(&mut state, "t, '', 1, 5"),
+            "invalid value (at index 5) in table for 'concat'"
+        );
+        assert_eq!(
+            error(&mut state, "table.pack(1, table, 2)"),
+            "invalid value (at index 2) in table for 'concat'"
+        );
+        assert_eq!(
+            error(&mut state, "'x'"),
+            "bad argument #1 to 'concat' (table expected, got string)"
+        );
+        assert_eq!(
+            error(&mut state, "t, t"),
+            "bad argument #2 to 'concat' (string expected, got table)"
+        );
+        let mut state = ExeState::builder().max_string_size(5).build();
+        state.eval("t = table.pack('ab', 'cd')").unwrap();
+        assert_eq!(error(&mut state, "t, '--'"), "resulting string too large");
+    }
+
+    #[test]
+    fn large_sort() {
+        let mut state = ExeState::new();
+        // enough to choose random pivots, and shuffled
+        let values: Vec<Value> = (0..1000).map(|i| Value::Integer(i * 7919 % 1000)).collect();
+        let sorted = sort(&mut state, values).unwrap();
+        let expected: Vec<Value> = (0..1000).map(Value::Integer).collect();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn invalid_order() {
+        // `<=` instead of `<` is not a strict order
+        fn less_equal(state: &mut ExeState) -> anyhow::Result<i32> {
+            let lt = state.arg(2).less_than(state.arg(1))?;
+            state.push((!lt).into());
+            Ok(1)
+        }
+        fn always(state: &mut ExeState) -> anyhow::Result<i32> {
+            state.push(true.into());
+            Ok(1)
+        }
+        let mut state = ExeState::new();
+        for comp in [less_equal as fn(&mut ExeState) -> _, always] {
+            let mut t = Table::new();
+            t.array = vec![Value::Integer(5); 50];
+            state.set_global("t", t.into());
+            state.set_global("comp", Value::Function(comp));
+            let err = state.eval("table.sort(t, comp)").unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "[string \"table.sort(t, comp)\"]:1: invalid order function for sorting"
+            );
+        }
+    }
+
+    #[test]
+    fn stable() {
+        // compare the first byte only
+        fn first(state: &mut ExeState) -> anyhow::Result<i32> {
+            let (a, b) = (state.arg(1), state.arg(2));
+            let key = |v: &Value| <&[u8]>::try_from(v).unwrap()[0];
+            let lt = key(a) < key(b);
+            state.push(lt.into());
+            Ok(1)
+        }
+        let mut state = ExeState::builder().stable_sort(true).build();
+        state.set_global("first", Value::Function(first));
+        let values: Vec<Value> = (0..200)
+            .map(|i| format!("{}{i}", (b'a' + i as u8 % 3) as char).into())
+            .collect();
+        let mut t = Table::new();
+        t.array = values.clone();
+        state.set_global("t", t.into());
+        state.eval("table.sort(t, first)").unwrap();
+        let Value::Table(t) = state.get_global("t") else {
+            unreachable!()
+        };
+        let mut expected = values;
+        expected.sort_by_key(|v| <&[u8]>::try_from(v).unwrap()[0]);
+        assert_eq!(t.borrow().array, expected);
+    }
+
+    #[test]
+    fn rehash() {
+        let mut state = ExeState::new();
+        let mut t = Table::new();
+        t.array = vec![Value::Nil, Value::Nil, Value::Nil, 4.into()];
+        state.set_global("t", t.into());
+        state.eval("table.rehash(t)").unwrap();
+        let Value::Table(t) = state.get_global("t") else {
+            unreachable!()
+        };
+        assert!(t.borrow().array.is_empty());
+        assert_eq!(t.borrow().get(&4.into()), 4.into());
+
+        let err = state.eval("table.rehash(1)").unwrap_err();
+        assert!(format!("{err:#}").contains("bad argument #1 to 'rehash'"));
+    }
+}
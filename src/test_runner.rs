@@ -0,0 +1,82 @@
+//! `kailua test`: run Lua test files, each in a fresh state.
+
+use std::{
+    fs::{self, File},
+    path::{Path, PathBuf},
+    process::ExitCode,
+};
+
+use anyhow::bail;
+use kailua::{parse::ParseProto, value::Value, vm::ExeState};
+
+pub fn run(dir: &Path) -> anyhow::Result<ExitCode> {
+    let mut files = Vec::new();
+    find_tests(dir, &mut files)?;
+    files.sort();
+
+    let mut failed = 0;
+    for path in &files {
+        match run_file(path) {
+            Ok(()) => println!("{} ... ok", path.display()),
+            Err(err) => {
+                println!("{} ... FAILED\n    {err:#}", path.display());
+                failed += 1;
+            }
+        }
+    }
+
+    let status = if failed == 0 { "ok" } else { "FAILED" };
+    println!(
+        "\ntest result: {status}. {} passed; {failed} failed",
+        files.len() - failed
+    );
+    Ok(if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    })
+}
+
+fn find_tests(dir: &Path, files: &mut Vec<PathBuf>) -> anyhow::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            find_tests(&path, files)?;
+        } else if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.ends_with("_test.lua"))
+        {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+fn run_file(path: &Path) -> anyhow::Result<()> {
+    let proto = ParseProto::load(File::open(path)?)?;
+    let mut state = ExeState::new();
+    state.set_global("assert_eq", Value::Function(lib_assert_eq));
+    state.set_global("assert_error", Value::Function(lib_assert_error));
+    state.execute(proto)
+}
+
+// assert_eq(actual, expected [, message])
+fn lib_assert_eq(state: &mut ExeState) -> anyhow::Result<i32> {
+    let (actual, expected) = (state.arg(1), state.arg(2));
+    if actual != expected {
+        match state.arg(3) {
+            Value::Nil => bail!("assertion failed: expected {expected:?}, got {actual:?}"),
+            msg => bail!("assertion failed: {msg}"),
+        }
+    }
+    Ok(0)
+}
+
+// assert_error(f, ...): call `f` with the remaining arguments and expect it to fail
+fn lib_assert_error(state: &mut ExeState) -> anyhow::Result<i32> {
+    if state.call_arg(1).is_ok() {
+        bail!("assertion failed: expected an error");
+    }
+    Ok(0)
+}
@@ -1,29 +1,648 @@
-use std::{cell::RefCell, collections::HashMap, hash::Hash, rc::Rc};
+use std::{
+    any::Any,
+    cell::{Cell, Ref, RefCell, RefMut},
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    rc::{Rc, Weak},
+};
 
 use anyhow::bail;
 
-use crate::vm::ExeState;
+use crate::numfmt::float_to_string;
+
+#[cfg(feature = "vm")]
+use crate::{parse::ParseProto, vm::ExeState};
+
+#[cfg(feature = "serde")]
+mod serde_impl;
 
 const SHORT_STR_MAX: usize = 14;
 const MID_STR_MAX: usize = 48 - 1;
 
-#[derive(Clone, PartialEq)]
+/// The integers of Lua: 64 bits, or 32 with the `int32` feature, as with
+/// `LUA_32BITS` in the reference, for targets without fast 64-bit
+/// arithmetic.
+#[cfg(not(feature = "int32"))]
+pub type LuaInt = i64;
+#[cfg(feature = "int32")]
+pub type LuaInt = i32;
+
+/// `LuaInt` reinterpreted as unsigned, for counts and hexadecimal.
+#[cfg(not(feature = "int32"))]
+pub type LuaUnsigned = u64;
+#[cfg(feature = "int32")]
+pub type LuaUnsigned = u32;
+
+/// The floats of Lua: 64 bits, or 32 with the `float32` feature.
+#[cfg(not(feature = "float32"))]
+pub type LuaFloat = f64;
+#[cfg(feature = "float32")]
+pub type LuaFloat = f32;
+
+/// The map part of a table. It keeps its keys in the order they were
+/// inserted, so that traversals do not depend on hashes, some of which are
+/// addresses, and `next` finds the key after another by its index.
+pub type TableMap = indexmap::IndexMap<Value, Value>;
+
+/// A table: the keys from 1 to `array.len()` in the array part, and the
+/// rest in the map part. A key cleared in the map part stays there as nil
+/// until the next rehash, so that a traversal can go on from it.
+#[derive(Debug, Clone)]
 pub struct Table {
     pub array: Vec<Value>,
-    pub map: HashMap<Value, Value>,
+    pub map: TableMap,
+    /// Nil, or the table of the metamethods of this one.
+    pub metatable: Value,
+    // nil entries of the map
+    dead: usize,
+}
+
+/// The representations of strings, by length: inline up to
+/// `SHORT_STR_MAX` bytes, in a fixed-size heap buffer up to `MID_STR_MAX`,
+/// and in a vector beyond.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StrRepr {
+    Short,
+    Mid,
+    Long,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub enum Value {
+    #[default]
     Nil,
     Boolean(bool),
-    Integer(i64),
-    Float(f64),
+    Integer(LuaInt),
+    Float(LuaFloat),
     ShortStr(u8, [u8; SHORT_STR_MAX]),
-    MidStr(Rc<(u8, [u8; MID_STR_MAX])>),
-    LongStr(Rc<Vec<u8>>),
+    // heap strings carry the hash of their bytes, computed once, so that
+    // table lookups do not rehash them
+    MidStr(Rc<(u8, [u8; MID_STR_MAX], u64)>),
+    LongStr(Rc<(Vec<u8>, u64)>),
     Table(Rc<RefCell<Table>>),
-    Function(fn(&mut ExeState) -> i32),
+    UserData(Rc<UserData>),
+    #[cfg(feature = "vm")]
+    Function(fn(&mut ExeState) -> anyhow::Result<i32>),
+    #[cfg(feature = "vm")]
+    NativeClosure(Rc<NativeClosure>),
+    #[cfg(feature = "vm")]
+    LuaClosure(Rc<LuaClosure>),
+}
+
+/// A value of the host program, opaque to Lua but for what the
+/// metamethods of its metatable do with it: arithmetic, comparisons,
+/// indexing, `tostring`. The metatable is given when it is created and
+/// cannot be changed from Lua, as with the reference `setmetatable`.
+///
+/// The data is dropped with the last reference to it, unless the userdata
+/// was created by a state, with `ExeState::userdata`, and the metatable
+/// had a `__gc` metamethod then: the data lives on in a new userdata,
+/// queued for that state to pass to `__gc` before dropping it for good,
+/// as the reference finalizers do.
+pub struct UserData {
+    data: RefCell<Box<dyn Any>>,
+    pub metatable: Value,
+    // the queue of the state to finalize it, if any and still there
+    finalize: Weak<FinalizeQueue>,
+}
+
+/// The userdata of a state dropped with a `__gc` metamethod, waiting for
+/// the state to call it.
+pub(crate) type FinalizeQueue = RefCell<Vec<Value>>;
+
+impl Drop for UserData {
+    fn drop(&mut self) {
+        let Some(queue) = self.finalize.upgrade() else {
+            return;
+        };
+        let v = Value::UserData(Rc::new(UserData {
+            data: RefCell::new(std::mem::replace(self.data.get_mut(), Box::new(()))),
+            metatable: std::mem::take(&mut self.metatable),
+            finalize: Weak::new(),
+        }));
+        queue.borrow_mut().push(v);
+    }
+}
+
+impl UserData {
+    /// The data, if it is a `T`.
+    pub fn borrow<T: Any>(&self) -> Option<Ref<'_, T>> {
+        Ref::filter_map(self.data.borrow(), |d| d.downcast_ref()).ok()
+    }
+
+    /// The data, mutably, if it is a `T`.
+    pub fn borrow_mut<T: Any>(&self) -> Option<RefMut<'_, T>> {
+        RefMut::filter_map(self.data.borrow_mut(), |d| d.downcast_mut()).ok()
+    }
+}
+
+impl Value {
+    /// A new [`UserData`] holding `data`, with `metatable`, nil or a table.
+    /// Its `__gc` metamethod is never called.
+    pub fn userdata(data: impl Any, metatable: Value) -> Value {
+        Self::finalized_userdata(data, metatable, Weak::new())
+    }
+
+    /// A new [`UserData`] to be queued in `finalize` when dropped.
+    pub(crate) fn finalized_userdata(
+        data: impl Any,
+        metatable: Value,
+        finalize: Weak<FinalizeQueue>,
+    ) -> Value {
+        Value::UserData(Rc::new(UserData {
+            data: RefCell::new(Box::new(data)),
+            metatable,
+            finalize,
+        }))
+    }
+}
+
+/// A native function with values of its own, which it reads and writes
+/// through [`ExeState::upvalue`] and [`ExeState::set_upvalue`] while it
+/// runs, as the reference C closures do.
+#[cfg(feature = "vm")]
+pub struct NativeClosure {
+    pub func: fn(&mut ExeState) -> anyhow::Result<i32>,
+    pub upvalues: RefCell<Vec<Value>>,
+}
+
+/// A function defined in Lua, created each time its definition runs, with
+/// the variables of enclosing functions it uses.
+#[cfg(feature = "vm")]
+pub struct LuaClosure {
+    pub proto: Rc<ParseProto>,
+    pub upvalues: Vec<Rc<RefCell<Upvalue>>>,
+    // the globals its calls have read or written, kept from one to the next
+    pub(crate) cache: Rc<GlobalCache>,
+}
+
+#[cfg(feature = "vm")]
+impl LuaClosure {
+    pub fn new(proto: Rc<ParseProto>, upvalues: Vec<Rc<RefCell<Upvalue>>>) -> Self {
+        Self {
+            proto,
+            upvalues,
+            cache: Rc::default(),
+        }
+    }
+}
+
+/// The values of the globals a Lua function has read or written, by the
+/// constant naming them, each valid while the globals epoch of the state
+/// it was cached in lasts. A closure run by another state starts over.
+#[cfg(feature = "vm")]
+#[derive(Debug, Default)]
+pub(crate) struct GlobalCache {
+    // the state the values are from, 0 for none
+    state: Cell<usize>,
+    entries: RefCell<Vec<Option<(u64, Value)>>>,
+}
+
+#[cfg(feature = "vm")]
+impl GlobalCache {
+    /// Make the cache that of state `state`, with room for `n` constants.
+    pub(crate) fn claim(&self, state: usize, n: usize) {
+        let mut entries = self.entries.borrow_mut();
+        if self.state.replace(state) != state {
+            entries.clear();
+        }
+        if entries.len() < n {
+            entries.resize(n, None);
+        }
+    }
+
+    /// The value of the global named by constant `k`, if cached at `epoch`.
+    pub(crate) fn get(&self, k: u8, epoch: u64) -> Option<Value> {
+        match self.entries.borrow().get(k as usize) {
+            Some(Some((e, v))) if *e == epoch => Some(v.clone()),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn set(&self, k: u8, epoch: u64, v: Value) {
+        if let Some(entry) = self.entries.borrow_mut().get_mut(k as usize) {
+            *entry = Some((epoch, v));
+        }
+    }
+}
+
+/// A local captured by closures, which all share it: open, at its stack
+/// index, while the function defining it runs, then closed over with its
+/// last value.
+#[cfg(feature = "vm")]
+#[derive(Debug)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[cfg(feature = "vm")]
+impl Value {
+    /// A new [`NativeClosure`] of `func`, with `upvalues`.
+    pub fn native_closure(
+        func: fn(&mut ExeState) -> anyhow::Result<i32>,
+        upvalues: Vec<Value>,
+    ) -> Value {
+        Value::NativeClosure(Rc::new(NativeClosure {
+            func,
+            upvalues: RefCell::new(upvalues),
+        }))
+    }
+}
+
+impl Table {
+    pub fn new() -> Self {
+        Self {
+            array: Vec::new(),
+            map: TableMap::new(),
+            metatable: Value::Nil,
+            dead: 0,
+        }
+    }
+
+    /// The entries of the map part that are not nil.
+    pub fn map_entries(&self) -> impl Iterator<Item = (&Value, &Value)> {
+        self.map.iter().filter(|(_, v)| **v != Value::Nil)
+    }
+
+    /// The number of entries of the map part that are not nil.
+    pub fn map_len(&self) -> usize {
+        self.map.len() - self.dead
+    }
+
+    /// `t[key]`, nil if absent.
+    pub fn get(&self, key: &Value) -> Value {
+        match normalize_key(key) {
+            Value::Integer(i) if i >= 1 && (i as usize) <= self.array.len() => {
+                self.array[i as usize - 1].clone()
+            }
+            k => self.map.get(&k).cloned().unwrap_or_default(),
+        }
+    }
+
+    /// `t[key] = value`, with the reference rules for keys: nil and NaN are
+    /// errors, and floats with an integer value are the same key as that
+    /// integer. Setting a key to nil removes it.
+    pub fn set(&mut self, key: Value, value: Value) -> anyhow::Result<()> {
+        let key = match key {
+            Value::Nil => bail!("table index is nil"),
+            Value::Float(f) if f.is_nan() => bail!("table index is NaN"),
+            k => normalize_key(&k),
+        };
+        match key {
+            Value::Integer(i) if i >= 1 && (i as usize) <= self.array.len() => {
+                let i = i as usize - 1;
+                self.array[i] = value;
+                // keep the array part free of trailing nils
+                if i == self.array.len() - 1 {
+                    while self.array.last() == Some(&Value::Nil) {
+                        self.array.pop();
+                    }
+                }
+            }
+            Value::Integer(i) if i >= 1 && i as usize == self.array.len() + 1 => {
+                if value == Value::Nil {
+                    self.clear(&key);
+                    return Ok(());
+                }
+                if self.remove(&key) == Some(Value::Nil) {
+                    self.dead -= 1;
+                }
+                self.array.push(value);
+                self.migrate();
+            }
+            k if value == Value::Nil => self.clear(&k),
+            k => {
+                // like the reference, which rehashes when the hash part has
+                // no free slot, and shrink the map if most of it was removed
+                let full = self.map.len() == self.map.capacity();
+                match self.map.insert(k, value) {
+                    Some(Value::Nil) => self.dead = self.dead.saturating_sub(1),
+                    Some(_) => (),
+                    None if full || self.map.capacity() > 4 * self.map_len() + 8 => self.rehash(),
+                    None => (),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Clear `key` of the map part, leaving it there as nil.
+    fn clear(&mut self, key: &Value) {
+        if let Some(v) = self.map.get_mut(key) {
+            if *v != Value::Nil {
+                *v = Value::Nil;
+                self.dead += 1;
+            }
+        }
+    }
+
+    /// Remove `key` from the map part.
+    fn remove(&mut self, key: &Value) -> Option<Value> {
+        // the last key takes its place, which is as deterministic
+        self.map.swap_remove(key)
+    }
+
+    /// Move the keys that follow the array part from the map to it.
+    fn migrate(&mut self) {
+        loop {
+            let next = Value::Integer(self.array.len() as LuaInt + 1);
+            match self.map.get(&next) {
+                Some(v) if *v != Value::Nil => {
+                    let v = self.remove(&next).unwrap();
+                    self.array.push(v);
+                }
+                _ => break,
+            }
+        }
+    }
+
+    /// Resize the array part to the size the reference `rehash` picks: the
+    /// largest power of two `n` such that more than half of the keys from
+    /// 1 to `n` are set. Keys move between the map and the array to fit,
+    /// so a key far past the others stays in the map, however large, and
+    /// an array left mostly empty by removals gives its keys back to the
+    /// map. Cleared keys leave the map, and both parts release the memory
+    /// they no longer need, which may end a traversal of the table.
+    pub fn rehash(&mut self) {
+        self.map.retain(|_, v| *v != Value::Nil);
+        self.dead = 0;
+
+        // keys in (2^(b-1), 2^b] for each b, and all of them
+        let mut nums = [0usize; 65];
+        let mut total = 0;
+        let array_keys = (1..).zip(&self.array).filter(|(_, v)| **v != Value::Nil);
+        let map_keys = self.map.keys().filter_map(|k| match *k {
+            Value::Integer(i) if i >= 1 => Some(i as u64),
+            _ => None,
+        });
+        for key in array_keys.map(|(i, _)| i).chain(map_keys) {
+            nums[(u64::BITS - (key - 1).leading_zeros()) as usize] += 1;
+            total += 1;
+        }
+
+        let mut size = 0;
+        let mut count = 0;
+        for (b, &n) in nums.iter().enumerate().take(63) {
+            let limit = 1usize << b;
+            if total <= limit / 2 {
+                break;
+            }
+            count += n;
+            if count > limit / 2 {
+                size = limit;
+            }
+        }
+        if size > self.array.len() {
+            for i in self.array.len() + 1..=size {
+                let v = self
+                    .remove(&Value::Integer(i as LuaInt))
+                    .unwrap_or_default();
+                self.array.push(v);
+            }
+        } else {
+            for (i, v) in (size + 1..).zip(self.array.drain(size..)) {
+                if v != Value::Nil {
+                    self.map.insert(Value::Integer(i as LuaInt), v);
+                }
+            }
+        }
+        while self.array.last() == Some(&Value::Nil) {
+            self.array.pop();
+        }
+        self.migrate();
+        // leave room to grow, so that the next insertion does not rehash
+        self.array.shrink_to(2 * self.array.len());
+        self.map.shrink_to(2 * self.map.len());
+    }
+
+    /// The entry after `key`, or the first one if `key` is nil, as `next`
+    /// walks the table: the array part in order, then the map part in
+    /// insertion order. None after the last entry.
+    ///
+    /// As in the reference, a traversal may assign to or clear any key,
+    /// the current one included, but adding keys may make it miss or
+    /// repeat some, or fail with "invalid key to 'next'" when a rehash
+    /// dropped the current key.
+    pub fn next(&self, key: &Value) -> anyhow::Result<Option<(Value, Value)>> {
+        let start = match normalize_key(key) {
+            Value::Nil => 0,
+            Value::Integer(i) if i >= 1 && (i as usize) <= self.array.len() => i as usize,
+            k if self.map.contains_key(&k) => {
+                let i = self.map.get_index_of(&k).unwrap();
+                let entry = self.map[i + 1..].iter().find(|(_, v)| **v != Value::Nil);
+                return Ok(entry.map(|(k, v)| (k.clone(), v.clone())));
+            }
+            // a key of the array part since cleared, and all those after it
+            Value::Integer(i) if i >= 1 && (i as usize) <= self.array.capacity() => {
+                self.array.len()
+            }
+            _ => bail!("invalid key to 'next'"),
+        };
+        let entry = self.array[start..]
+            .iter()
+            .enumerate()
+            .find(|(_, v)| **v != Value::Nil)
+            .map(|(i, v)| (Value::Integer((start + i + 1) as LuaInt), v.clone()))
+            .or_else(|| {
+                self.map_entries()
+                    .next()
+                    .map(|(k, v)| (k.clone(), v.clone()))
+            });
+        Ok(entry)
+    }
+}
+
+impl PartialEq for Table {
+    fn eq(&self, other: &Self) -> bool {
+        self.array == other.array
+            && self.map_len() == other.map_len()
+            && self.map_entries().all(|(k, v)| other.map.get(k) == Some(v))
+    }
+}
+
+/// A float key with an integer value as that integer.
+fn normalize_key(key: &Value) -> Value {
+    match *key {
+        Value::Float(f) if f.fract() == 0.0 && (-INT_RANGE..INT_RANGE).contains(&f) => {
+            Value::Integer(f as LuaInt)
+        }
+        ref k => k.clone(),
+    }
+}
+
+impl Value {
+    /// Name of the type of the value, as returned by `type()` and shown in
+    /// error messages, in the wording of the reference implementation.
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::Nil => "nil",
+            Value::Boolean(_) => "boolean",
+            Value::Integer(_) | Value::Float(_) => "number",
+            Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => "string",
+            Value::Table(_) => "table",
+            Value::UserData(_) => "userdata",
+            #[cfg(feature = "vm")]
+            Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => "function",
+        }
+    }
+
+    /// How the value is stored if it is a string, `None` otherwise.
+    pub fn str_repr(&self) -> Option<StrRepr> {
+        match self {
+            Value::ShortStr(..) => Some(StrRepr::Short),
+            Value::MidStr(_) => Some(StrRepr::Mid),
+            Value::LongStr(_) => Some(StrRepr::Long),
+            _ => None,
+        }
+    }
+
+    /// The value with a string in the representation its length calls
+    /// for, and its hash recomputed. Strings are compared and hashed by
+    /// representation first, so two equal strings are only equal values if
+    /// both are normalized. The `From` conversions always give normalized
+    /// strings; this is for strings built some other way.
+    pub fn normalize(self) -> Value {
+        match <&[u8]>::try_from(&self) {
+            Ok(s) => Value::from(s),
+            Err(_) => self,
+        }
+    }
+
+    /// `self < other`: numbers by their mathematical values, even between
+    /// integers and floats, and strings byte by byte whatever the locale.
+    /// Anything else cannot be compared.
+    pub fn less_than(&self, other: &Value) -> anyhow::Result<bool> {
+        Ok(match (self, other) {
+            (&Value::Integer(l), &Value::Integer(r)) => l < r,
+            (&Value::Float(l), &Value::Float(r)) => l < r,
+            (&Value::Integer(i), &Value::Float(f)) => int_lt_float(i, f),
+            (&Value::Float(f), &Value::Integer(i)) => float_lt_int(f, i),
+            _ => compare_strs(self, other)?.is_lt(),
+        })
+    }
+
+    /// `self <= other`, with the rules of [`less_than`](Self::less_than).
+    pub fn less_equal(&self, other: &Value) -> anyhow::Result<bool> {
+        Ok(match (self, other) {
+            (&Value::Integer(l), &Value::Integer(r)) => l <= r,
+            (&Value::Float(l), &Value::Float(r)) => l <= r,
+            (&Value::Integer(i), &Value::Float(f)) => int_le_float(i, f),
+            (&Value::Float(f), &Value::Integer(i)) => float_le_int(f, i),
+            _ => compare_strs(self, other)?.is_le(),
+        })
+    }
+}
+
+fn compare_strs(l: &Value, r: &Value) -> anyhow::Result<std::cmp::Ordering> {
+    match (<&[u8]>::try_from(l), <&[u8]>::try_from(r)) {
+        (Ok(l), Ok(r)) => Ok(l.cmp(r)),
+        _ => Err(compare_error(l, r)),
+    }
+}
+
+/// The error of comparing `l` with `r`, which cannot be.
+pub(crate) fn compare_error(l: &Value, r: &Value) -> anyhow::Error {
+    if l.type_name() == r.type_name() {
+        anyhow::anyhow!("attempt to compare two {} values", l.type_name())
+    } else {
+        anyhow::anyhow!(
+            "attempt to compare {} with {}",
+            l.type_name(),
+            r.type_name()
+        )
+    }
+}
+
+// Mixed comparisons round the float to an integer in the direction that
+// keeps the result exact, since not every integer is a float. Floats
+// outside the integer range are above or below every integer, and NaN
+// compares false with anything.
+// `INT_RANGE` is 2^63, or 2^31 with 32-bit integers: integers are from
+// `-INT_RANGE` included to `INT_RANGE` excluded, which are floats.
+pub(crate) const INT_RANGE: LuaFloat = -(LuaInt::MIN as LuaFloat);
+
+fn int_lt_float(i: LuaInt, f: LuaFloat) -> bool {
+    if f >= INT_RANGE {
+        true
+    } else if f >= -INT_RANGE {
+        i < f.ceil() as LuaInt
+    } else {
+        false
+    }
+}
+
+fn int_le_float(i: LuaInt, f: LuaFloat) -> bool {
+    if f >= INT_RANGE {
+        true
+    } else if f >= -INT_RANGE {
+        i <= f.floor() as LuaInt
+    } else {
+        false
+    }
+}
+
+fn float_lt_int(f: LuaFloat, i: LuaInt) -> bool {
+    if f.is_nan() || f >= INT_RANGE {
+        false
+    } else if f >= -INT_RANGE {
+        (f.floor() as LuaInt) < i
+    } else {
+        true
+    }
+}
+
+fn float_le_int(f: LuaFloat, i: LuaInt) -> bool {
+    if f.is_nan() || f >= INT_RANGE {
+        false
+    } else if f >= -INT_RANGE {
+        f.ceil() as LuaInt <= i
+    } else {
+        true
+    }
+}
+
+impl Default for Table {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Numbers of tables and heap-allocated strings created so far on the
+/// current thread. Short strings are stored inline and not counted.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct AllocCounts {
+    pub tables: u64,
+    pub strings: u64,
+}
+
+thread_local! {
+    static ALLOC_COUNTS: Cell<AllocCounts> = const {
+        Cell::new(AllocCounts {
+            tables: 0,
+            strings: 0,
+        })
+    };
+}
+
+impl AllocCounts {
+    pub fn current() -> Self {
+        ALLOC_COUNTS.get()
+    }
+
+    /// Allocations made since `earlier`.
+    pub fn since(self, earlier: Self) -> Self {
+        Self {
+            tables: self.tables - earlier.tables,
+            strings: self.strings - earlier.strings,
+        }
+    }
+}
+
+fn count_alloc(f: impl FnOnce(&mut AllocCounts)) {
+    let mut counts = ALLOC_COUNTS.get();
+    f(&mut counts);
+    ALLOC_COUNTS.set(counts);
 }
 
 fn vec_to_short_mid_str(v: &[u8]) -> Option<Value> {
@@ -35,15 +654,25 @@ fn vec_to_short_mid_str(v: &[u8]) -> Option<Value> {
     } else if len <= MID_STR_MAX {
         let mut buf = [0; MID_STR_MAX];
         buf[..len].copy_from_slice(v);
-        Some(Value::MidStr(Rc::new((len as u8, buf))))
+        count_alloc(|c| c.strings += 1);
+        Some(Value::MidStr(Rc::new((len as u8, buf, str_hash(v)))))
     } else {
         None
     }
 }
 
+fn str_hash(s: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    s.hash(&mut hasher);
+    hasher.finish()
+}
+
 impl From<&[u8]> for Value {
     fn from(v: &[u8]) -> Self {
-        vec_to_short_mid_str(v).unwrap_or_else(|| Value::LongStr(Rc::new(v.to_vec())))
+        vec_to_short_mid_str(v).unwrap_or_else(|| {
+            count_alloc(|c| c.strings += 1);
+            Value::LongStr(Rc::new((v.to_vec(), str_hash(v))))
+        })
     }
 }
 
@@ -55,7 +684,11 @@ impl From<&str> for Value {
 
 impl From<Vec<u8>> for Value {
     fn from(v: Vec<u8>) -> Self {
-        vec_to_short_mid_str(&v).unwrap_or_else(|| Value::LongStr(Rc::new(v)))
+        vec_to_short_mid_str(&v).unwrap_or_else(|| {
+            count_alloc(|c| c.strings += 1);
+            let hash = str_hash(&v);
+            Value::LongStr(Rc::new((v, hash)))
+        })
     }
 }
 
@@ -72,7 +705,7 @@ impl<'a> TryFrom<&'a Value> for &'a [u8] {
         match value {
             Value::ShortStr(len, buf) => Ok(&buf[..*len as usize]),
             Value::MidStr(s) => Ok(&s.1[..s.0 as usize]),
-            Value::LongStr(s) => Ok(s),
+            Value::LongStr(s) => Ok(&s.0),
             _ => bail!("not a string"),
         }
     }
@@ -100,31 +733,42 @@ impl From<bool> for Value {
     }
 }
 
-impl From<i64> for Value {
-    fn from(value: i64) -> Self {
+impl From<LuaInt> for Value {
+    fn from(value: LuaInt) -> Self {
         Self::Integer(value)
     }
 }
 
-impl From<f64> for Value {
-    fn from(value: f64) -> Self {
+impl From<LuaFloat> for Value {
+    fn from(value: LuaFloat) -> Self {
         Self::Float(value)
     }
 }
 
+impl From<Table> for Value {
+    fn from(value: Table) -> Self {
+        count_alloc(|c| c.tables += 1);
+        Self::Table(Rc::new(RefCell::new(value)))
+    }
+}
+
 impl std::fmt::Debug for Value {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Nil => write!(f, "nil"),
             Self::Boolean(b) => write!(f, "{b}"),
             Self::Integer(i) => write!(f, "{i}"),
-            Self::Float(n) => write!(f, "{n:?}"),
+            Self::Float(n) => f.write_str(&float_to_string(*n)),
             Self::Table(t) => {
                 let t = t.borrow();
-                write!(f, "table:{}:{}", t.array.len(), t.map.len())
+                write!(f, "table:{}:{}", t.array.len(), t.map_len())
             }
-            Self::Function(_) => write!(f, "function"),
-            s => write!(f, "{}", <&str>::try_from(s).unwrap()),
+            Self::UserData(_) => write!(f, "userdata"),
+            #[cfg(feature = "vm")]
+            Self::Function(_) | Self::NativeClosure(_) | Self::LuaClosure(_) => {
+                write!(f, "function")
+            }
+            s => write!(f, "{}", String::from_utf8_lossy(s.try_into().unwrap())),
         }
     }
 }
@@ -135,10 +779,14 @@ impl std::fmt::Display for Value {
             Self::Nil => write!(f, "nil"),
             Self::Boolean(b) => write!(f, "{b}"),
             Self::Integer(i) => write!(f, "{i}"),
-            Self::Float(n) => write!(f, "{n:?}"),
+            Self::Float(n) => f.write_str(&float_to_string(*n)),
             Self::Table(t) => write!(f, "table: {:?}", Rc::as_ptr(t)),
-            Self::Function(_) => write!(f, "function"),
-            s => write!(f, "{}", <&str>::try_from(s).unwrap()),
+            Self::UserData(u) => write!(f, "userdata: {:?}", Rc::as_ptr(u)),
+            #[cfg(feature = "vm")]
+            Self::Function(_) | Self::NativeClosure(_) | Self::LuaClosure(_) => {
+                write!(f, "function")
+            }
+            s => write!(f, "{}", String::from_utf8_lossy(s.try_into().unwrap())),
         }
     }
 }
@@ -153,10 +801,20 @@ impl PartialEq for Value {
             (&Self::ShortStr(ll, sl), &Self::ShortStr(lr, sr)) => {
                 sl[..ll as usize] == sr[..lr as usize]
             }
-            (Self::MidStr(l), Self::MidStr(r)) => l.1[..l.0 as usize] == r.1[..r.0 as usize],
-            (Self::LongStr(l), Self::LongStr(r)) => *l == *r,
-            (Self::Table(l), Self::Table(r)) => l == r,
-            (Self::Function(l), Self::Function(r)) => std::ptr::eq(l, r),
+            (Self::MidStr(l), Self::MidStr(r)) => {
+                l.2 == r.2 && l.1[..l.0 as usize] == r.1[..r.0 as usize]
+            }
+            (Self::LongStr(l), Self::LongStr(r)) => l.1 == r.1 && l.0 == r.0,
+            // by identity, as in the reference; comparing contents would
+            // borrow tables that may be borrowed mutably, or recurse forever
+            (Self::Table(l), Self::Table(r)) => Rc::ptr_eq(l, r),
+            (Self::UserData(l), Self::UserData(r)) => Rc::ptr_eq(l, r),
+            #[cfg(feature = "vm")]
+            (Self::Function(l), Self::Function(r)) => *l as usize == *r as usize,
+            #[cfg(feature = "vm")]
+            (Self::NativeClosure(l), Self::NativeClosure(r)) => Rc::ptr_eq(l, r),
+            #[cfg(feature = "vm")]
+            (Self::LuaClosure(l), Self::LuaClosure(r)) => Rc::ptr_eq(l, r),
             _ => false,
         }
     }
@@ -170,12 +828,278 @@ impl Hash for Value {
             Value::Nil => (),
             Value::Boolean(b) => b.hash(state),
             Value::Integer(i) => i.hash(state),
-            Value::Float(f) => f.to_bits().hash(state),
+            // -0.0 and 0.0 are equal, so they must hash alike
+            Value::Float(f) => (f + 0.0).to_bits().hash(state),
             Value::ShortStr(len, buf) => buf[..*len as usize].hash(state),
-            Value::MidStr(s) => s.1[..s.0 as usize].hash(state),
-            Value::LongStr(s) => s.hash(state),
+            Value::MidStr(s) => state.write_u64(s.2),
+            Value::LongStr(s) => state.write_u64(s.1),
             Value::Table(t) => Rc::as_ptr(t).hash(state),
+            Value::UserData(u) => Rc::as_ptr(u).hash(state),
+            #[cfg(feature = "vm")]
             Value::Function(f) => (*f as *const usize).hash(state),
+            #[cfg(feature = "vm")]
+            Value::NativeClosure(c) => Rc::as_ptr(c).hash(state),
+            #[cfg(feature = "vm")]
+            Value::LuaClosure(c) => Rc::as_ptr(c).hash(state),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn table_keys() {
+        let mut t = Table::new();
+        assert_eq!(
+            t.set(Value::Nil, 1.into()).unwrap_err().to_string(),
+            "table index is nil"
+        );
+        assert_eq!(
+            t.set(LuaFloat::NAN.into(), 1.into())
+                .unwrap_err()
+                .to_string(),
+            "table index is NaN"
+        );
+
+        // float keys with integer values are integer keys
+        t.set(2.0.into(), "b".into()).unwrap();
+        assert_eq!(t.get(&2.into()), "b".into());
+        assert!(t.array.is_empty());
+        // filling the gap moves the rest to the array
+        t.set(1.into(), "a".into()).unwrap();
+        assert_eq!(t.array, ["a".into(), "b".into()]);
+        assert!(t.map.is_empty());
+
+        t.set(2.into(), Value::Nil).unwrap();
+        assert_eq!(t.array, ["a".into()]);
+        t.set("k".into(), true.into()).unwrap();
+        t.set("k".into(), Value::Nil).unwrap();
+        // cleared, but kept for a traversal to go on from
+        assert_eq!(t.map_len(), 0);
+        assert_eq!(t.map.len(), 1);
+        assert_eq!(t.get(&"k".into()), Value::Nil);
+    }
+
+    #[test]
+    fn string_representations() {
+        for (len, repr) in [
+            (0, StrRepr::Short),
+            (SHORT_STR_MAX, StrRepr::Short),
+            (SHORT_STR_MAX + 1, StrRepr::Mid),
+            (MID_STR_MAX, StrRepr::Mid),
+            (MID_STR_MAX + 1, StrRepr::Long),
+        ] {
+            let s = vec![b'x'; len];
+            assert_eq!(Value::from(s.as_slice()).str_repr(), Some(repr));
+            assert_eq!(Value::from(s).str_repr(), Some(repr));
+        }
+        assert_eq!(Value::Integer(1).str_repr(), None);
+
+        // a short string stored as a long one is not equal to itself
+        // until normalized
+        let odd = Value::LongStr(Rc::new((b"ab".to_vec(), str_hash(b"ab"))));
+        assert_ne!(odd, "ab".into());
+        let normal = odd.normalize();
+        assert_eq!(normal.str_repr(), Some(StrRepr::Short));
+        assert_eq!(normal, "ab".into());
+        assert_eq!(Value::Integer(1).normalize(), Value::Integer(1));
+    }
+
+    #[test]
+    fn array_sizes() {
+        // a lone large key does not grow the array
+        let mut t = Table::new();
+        t.set(1_000_000.into(), 1.into()).unwrap();
+        assert!(t.array.is_empty());
+        assert_eq!(t.get(&1_000_000.into()), 1.into());
+
+        // keys filling more than half of 1..=n move to the array, even
+        // with 1 missing
+        let mut t = Table::new();
+        for i in 2..=40 {
+            t.set(i.into(), i.into()).unwrap();
+        }
+        assert_eq!(t.array.len(), 40);
+        assert_eq!(t.array[0], Value::Nil);
+        assert!(t.map.is_empty());
+        for i in 2..=40 {
+            assert_eq!(t.get(&i.into()), i.into());
+        }
+        let mut n = 0;
+        let mut k = Value::Nil;
+        while let Some((key, _)) = t.next(&k).unwrap() {
+            n += 1;
+            k = key;
+        }
+        assert_eq!(n, 39);
+
+        // sparse keys stay in the map
+        let mut t = Table::new();
+        for i in 0..64 {
+            t.set((i * 10 + 5).into(), true.into()).unwrap();
+        }
+        assert!(t.array.is_empty());
+    }
+
+    #[test]
+    fn shrinking() {
+        // a map emptied by removals shrinks when keys are added again
+        let mut t = Table::new();
+        for i in 0..1000 {
+            t.set(format!("k{i}").into(), true.into()).unwrap();
+        }
+        for i in 0..1000 {
+            t.set(format!("k{i}").into(), Value::Nil).unwrap();
+        }
+        t.set("k".into(), true.into()).unwrap();
+        assert!(t.map.capacity() < 16);
+        assert_eq!(t.get(&"k".into()), true.into());
+
+        // an array with few keys left gives them to the map
+        let mut t = Table::new();
+        for i in 1..=1000 {
+            t.set(i.into(), i.into()).unwrap();
         }
+        for i in 1..1000 {
+            if i % 100 != 0 {
+                t.set(i.into(), Value::Nil).unwrap();
+            }
+        }
+        assert_eq!(t.array.len(), 1000);
+        t.rehash();
+        assert!(t.array.is_empty() && t.array.capacity() == 0);
+        assert_eq!(t.map.len(), 10);
+        for i in 1..=1000 {
+            let expected = if i % 100 == 0 { i.into() } else { Value::Nil };
+            assert_eq!(t.get(&i.into()), expected);
+        }
+    }
+
+    /// All the entries `next` visits from the start, clearing each one
+    /// after visiting it if `clear`.
+    fn traverse(t: &mut Table, clear: bool) -> anyhow::Result<Vec<Value>> {
+        let mut keys = Vec::new();
+        let mut k = Value::Nil;
+        while let Some((key, _)) = t.next(&k)? {
+            if clear {
+                t.set(key.clone(), Value::Nil)?;
+            }
+            keys.push(key.clone());
+            k = key;
+        }
+        Ok(keys)
+    }
+
+    #[test]
+    fn large_traversal() {
+        // each step finds the next key by index, not by a walk from the
+        // start, which would take minutes here
+        let mut t = Table::new();
+        for i in 0..200_000 {
+            t.set(format!("k{i}").into(), i.into()).unwrap();
+        }
+        let keys = traverse(&mut t, false).unwrap();
+        assert_eq!(keys.len(), 200_000);
+        assert_eq!(keys[0], "k0".into());
+        assert_eq!(keys[199_999], "k199999".into());
+    }
+
+    #[test]
+    fn clearing_while_traversing() {
+        let mut t = Table::new();
+        for i in 1..=20 {
+            t.set(i.into(), true.into()).unwrap();
+            t.set(format!("k{i}").into(), true.into()).unwrap();
+            t.set((i * 1000).into(), true.into()).unwrap();
+        }
+        let mut keys = traverse(&mut t, true).unwrap();
+        assert_eq!(keys.len(), 60);
+        keys.sort_by_key(|k| k.to_string());
+        keys.dedup();
+        assert_eq!(keys.len(), 60);
+        assert!(t.array.is_empty() && t.map_len() == 0);
+        assert_eq!(t, Table::new());
+
+        // assigning to existing keys does not disturb the traversal
+        for i in 1..=20 {
+            t.set(format!("k{i}").into(), 1.into()).unwrap();
+        }
+        let mut k = Value::Nil;
+        let mut n = 0;
+        while let Some((key, _)) = t.next(&k).unwrap() {
+            t.set(key.clone(), 2.into()).unwrap();
+            n += 1;
+            k = key;
+        }
+        assert_eq!(n, 20);
+        assert!(t.map_entries().all(|(_, v)| *v == 2.into()));
+    }
+
+    #[test]
+    fn adding_while_traversing() {
+        let mut t = Table::new();
+        for i in 0..7 {
+            t.set(format!("k{i}").into(), true.into()).unwrap();
+        }
+        let (k, _) = t.next(&Value::Nil).unwrap().unwrap();
+        t.set(k.clone(), Value::Nil).unwrap();
+        // enough new keys to rehash, which drops the cleared one
+        for i in 0..100 {
+            t.set(format!("n{i}").into(), true.into()).unwrap();
+        }
+        assert_eq!(t.next(&k).unwrap_err().to_string(), "invalid key to 'next'");
+        assert_eq!(
+            t.next(&"absent".into()).unwrap_err().to_string(),
+            "invalid key to 'next'"
+        );
+    }
+
+    #[test]
+    fn comparisons() {
+        let lt = |l: Value, r: Value| l.less_than(&r).unwrap();
+        let le = |l: Value, r: Value| l.less_equal(&r).unwrap();
+
+        // strings compare by bytes, not by locale
+        assert!(lt("B".into(), "a".into()));
+        assert!(lt("a".into(), "ab".into()));
+        assert!(lt("".into(), "\0".into()));
+        assert!(lt("\x7f".into(), "\u{e9}".into()));
+        assert!(le("ab".into(), "ab".into()));
+        assert!(!lt("ab".into(), "ab".into()));
+
+        // integers and floats compare exactly
+        assert!(lt(1.into(), 1.5.into()));
+        assert!(le(1.into(), 1.0.into()));
+        assert!(!lt(1.0.into(), 1.into()));
+        assert!(lt(LuaInt::MAX.into(), INT_RANGE.into()));
+        assert!(!le(INT_RANGE.into(), LuaInt::MAX.into()));
+        assert!(lt((-INT_RANGE * 2.0).into(), LuaInt::MIN.into()));
+        assert!(le((-INT_RANGE).into(), LuaInt::MIN.into()));
+        // past the precision of floats, if integers go that far
+        if let Some(p) = (1 as LuaInt).checked_shl(LuaFloat::MANTISSA_DIGITS) {
+            assert!(!lt((p + 1).into(), (p as LuaFloat).into()));
+        }
+        assert!(!lt(LuaFloat::NAN.into(), 1.into()));
+        assert!(!le(1.into(), LuaFloat::NAN.into()));
+
+        let err = |l: Value, r: Value| l.less_than(&r).unwrap_err().to_string();
+        assert_eq!(
+            err(1.into(), "2".into()),
+            "attempt to compare number with string"
+        );
+        assert_eq!(
+            err("2".into(), 1.into()),
+            "attempt to compare string with number"
+        );
+        assert_eq!(
+            err(true.into(), false.into()),
+            "attempt to compare two boolean values"
+        );
+        assert_eq!(
+            err(Value::Nil, 1.into()),
+            "attempt to compare nil with number"
+        );
     }
 }
@@ -0,0 +1,197 @@
+//! Serde support for [`Value`].
+//!
+//! Tables holding only a sequence serialize as sequences, other tables as
+//! maps. Functions cannot be represented outside the interpreter, so
+//! serializing one (or a cyclic table) is an error.
+
+use std::{cell::RefCell, fmt, rc::Rc};
+
+use serde::{
+    de::{self, MapAccess, SeqAccess, Visitor},
+    ser::{self, SerializeMap, SerializeSeq},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use super::{LuaFloat, LuaInt, Table, Value};
+
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let path = RefCell::new(Vec::new());
+        Tracked {
+            value: self,
+            path: &path,
+        }
+        .serialize(serializer)
+    }
+}
+
+/// A value together with the tables enclosing it, to detect cycles.
+struct Tracked<'a> {
+    value: &'a Value,
+    path: &'a RefCell<Vec<*const RefCell<Table>>>,
+}
+
+impl Tracked<'_> {
+    fn with<'b>(&'b self, value: &'b Value) -> Tracked<'b> {
+        Tracked {
+            value,
+            path: self.path,
+        }
+    }
+}
+
+impl Serialize for Tracked<'_> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self.value {
+            Value::Nil => serializer.serialize_unit(),
+            Value::Boolean(b) => serializer.serialize_bool(*b),
+            Value::Integer(i) => i.serialize(serializer),
+            Value::Float(f) => f.serialize(serializer),
+            Value::Table(t) => {
+                let ptr = Rc::as_ptr(t);
+                if self.path.borrow().contains(&ptr) {
+                    return Err(ser::Error::custom("cannot serialize cyclic table"));
+                }
+                self.path.borrow_mut().push(ptr);
+                let result = self.table(&t.borrow(), serializer);
+                self.path.borrow_mut().pop();
+                result
+            }
+            Value::UserData(_) => Err(ser::Error::custom("cannot serialize userdata")),
+            #[cfg(feature = "vm")]
+            Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => {
+                Err(ser::Error::custom("cannot serialize function"))
+            }
+            s => {
+                let bytes = <&[u8]>::try_from(s).map_err(ser::Error::custom)?;
+                match std::str::from_utf8(bytes) {
+                    Ok(s) => serializer.serialize_str(s),
+                    Err(_) => serializer.serialize_bytes(bytes),
+                }
+            }
+        }
+    }
+}
+
+impl Tracked<'_> {
+    fn table<S: Serializer>(&self, t: &Table, serializer: S) -> Result<S::Ok, S::Error> {
+        if t.map_len() == 0 && !t.array.is_empty() {
+            let mut seq = serializer.serialize_seq(Some(t.array.len()))?;
+            for v in &t.array {
+                seq.serialize_element(&self.with(v))?;
+            }
+            seq.end()
+        } else {
+            let mut map = serializer.serialize_map(Some(t.array.len() + t.map_len()))?;
+            for (i, v) in t.array.iter().enumerate() {
+                map.serialize_entry(&(i as i64 + 1), &self.with(v))?;
+            }
+            for (k, v) in t.map_entries() {
+                map.serialize_entry(&self.with(k), &self.with(v))?;
+            }
+            map.end()
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Value {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(ValueVisitor)
+    }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+    type Value = Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a Lua value")
+    }
+
+    fn visit_unit<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_none<E>(self) -> Result<Value, E> {
+        Ok(Value::Nil)
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<Value, D::Error> {
+        Value::deserialize(deserializer)
+    }
+
+    fn visit_bool<E>(self, v: bool) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Value, E> {
+        Ok(LuaInt::try_from(v).map_or(Value::Float(v as LuaFloat), Value::Integer))
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Value, E> {
+        Ok(LuaInt::try_from(v).map_or(Value::Float(v as LuaFloat), Value::Integer))
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Value, E> {
+        Ok(Value::Float(v as LuaFloat))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_string<E>(self, v: String) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_bytes<E>(self, v: &[u8]) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Value, E> {
+        Ok(v.into())
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Value, A::Error> {
+        let mut t = Table::new();
+        while let Some(v) = seq.next_element()? {
+            t.array.push(v);
+        }
+        Ok(t.into())
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Value, A::Error> {
+        let mut t = Table::new();
+        while let Some((k, v)) = map.next_entry::<Value, Value>()? {
+            if k == Value::Nil {
+                return Err(de::Error::custom("table index is nil"));
+            }
+            if v != Value::Nil {
+                t.map.insert(k, v);
+            }
+        }
+        Ok(t.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_roundtrip() {
+        let json: serde_json::Value =
+            serde_json::from_str(r#"{"a": [1, 2.5, "x", true], "b": {"c": "d"}}"#).unwrap();
+        let v: Value = serde_json::from_value(json.clone()).unwrap();
+        assert_eq!(serde_json::to_value(&v).unwrap(), json);
+    }
+
+    #[test]
+    #[cfg(feature = "vm")]
+    fn function_is_error() {
+        let mut t = Table::new();
+        t.array.push(Value::Function(|_| Ok(0)));
+        assert!(serde_json::to_string(&Value::from(t)).is_err());
+    }
+}
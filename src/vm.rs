@@ -1,90 +1,4368 @@
-use std::collections::HashMap;
+use std::{
+    any::Any,
+    cell::RefCell,
+    collections::HashSet,
+    io::{self, Cursor, Read, Write},
+    panic::{self, AssertUnwindSafe},
+    rc::{Rc, Weak},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
-use anyhow::bail;
+use anyhow::{anyhow, bail};
 
-use crate::{bytecode::ByteCode, parse::ParseProto, value::Value};
+use crate::{
+    arith::{self, ArithOp},
+    bytecode::{ByteCode, MULTRET},
+    debug,
+    hook::{HookEvent, HookMask, Hooks},
+    image::StateImage,
+    inspect,
+    intern::{InternStats, Interner},
+    json,
+    lex::{Span, SyntaxError},
+    math::{self, Rng},
+    numfmt,
+    os::{self, Clock},
+    package,
+    parse::{ParseOptions, ParseProto},
+    sandbox::SandboxPolicy,
+    stats::Stats,
+    stdio, string, table,
+    value::{
+        compare_error, FinalizeQueue, GlobalCache, LuaClosure, LuaFloat, LuaInt, LuaUnsigned,
+        Table, Upvalue, Value, INT_RANGE,
+    },
+};
 
+/// Levels of calls at most that recurse in Rust, whose stack would run out
+/// long before the Lua one: native functions, and the Lua functions they
+/// call, metamethods included. Lua functions calling each other do not.
+const MAX_C_CALLS: usize = 200;
+
+// identifies each state, for the globals closures cache, see `GlobalCache`
+static NEXT_STATE_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Tables an `__index` or `__newindex` lookup goes through at most.
+const MAX_META_CHAIN: usize = 2000;
+
+/// Stack slots allocated up front by default.
+pub const DEFAULT_STACK_SIZE: usize = 256;
+/// Default limit on the number of stack slots, as in the reference
+/// implementation.
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1_000_000;
+/// Default limit on the levels of calls, about as deep as the reference
+/// implementation goes with its default stack limit.
+pub const DEFAULT_MAX_CALLS: usize = 200_000;
+/// Default limit on the length of the strings built by natives such as
+/// `string.rep`, the largest a 32-bit reference implementation allows.
+pub const DEFAULT_MAX_STRING_SIZE: usize = i32::MAX as usize;
+
+/// A Lua state: globals, a stack and the libraries, running chunks.
+///
+/// # Deterministic execution
+///
+/// Built with the `deterministic` feature, a state runs a script the same
+/// way every time and on every platform, for replays and lockstep
+/// simulations: `math.random` is seeded with 0 unless given a seed, and
+/// `os.time` and `os.clock` read a [`FixedClock`](os::FixedClock) unless
+/// given a clock. Tables are traversed in the order their keys were added
+/// with or without it.
+///
+/// Floats follow IEEE 754 with rounding to nearest, which Rust guarantees
+/// on every target with hardware floats, and numbers are parsed and
+/// formatted by kailua itself, so they do not depend on the C library.
+/// What remains up to the embedder: `tostring` of a table shows its
+/// address, and files and environment variables, which the
+/// [`sandbox`](ExeStateBuilder::sandbox) can deny.
 #[derive(Debug)]
 pub struct ExeState {
-    globals: HashMap<String, Value>,
+    // the global environment, also reachable from scripts as `_G`
+    globals: Rc<RefCell<Table>>,
+    // bumped whenever the globals may have changed behind the back of the
+    // running chunks, which then drop the globals they have cached
+    globals_epoch: u64,
+    id: usize,
     stack: Vec<Value>,
+    max_stack_size: usize,
+    max_calls: usize,
+    // levels of calls recursing in Rust, see `MAX_C_CALLS`
+    c_calls: usize,
+    max_string_size: usize,
+    stable_sort: bool,
     func_index: usize,
+    // stack index of register 0 of the running chunk
+    base: usize,
+    stats: Option<Stats>,
+    // in strict mode, names of the globals that have been assigned or allowed
+    declared: Option<HashSet<Value>>,
+    warnings: Warnings,
+    catch_panics: bool,
+    rng: Rng,
+    clock: Box<dyn Clock>,
+    output: Output,
+    sandbox: SandboxPolicy,
+    // largest number of stack slots used so far
+    peak_stack_size: usize,
+    // metatable shared by all strings
+    string_meta: Value,
+    // set from outside, typically a signal handler, to stop the script
+    interrupt: Option<Arc<AtomicBool>>,
+    // shares the string constants of the chunks loaded by `load`
+    interner: Interner,
+    // the value of the last error raised with one, see `ErrorObject`
+    error_object: Value,
+    // of the last error nothing caught, taken where it was raised
+    uncaught_traceback: Option<String>,
+    // the userdata created by `userdata` dropped since the finalizers last
+    // ran
+    finalize: Rc<FinalizeQueue>,
+    // levels of calls, innermost last
+    frames: Vec<Frame>,
+    // the upvalues still in the registers of running functions, by stack
+    // index
+    open_upvalues: Vec<(usize, Rc<RefCell<Upvalue>>)>,
+    // protected calls in progress, innermost last
+    protected: Vec<Protected>,
+    hooks: Hooks,
+    // the chunk run by `step`, if any
+    stepping: Option<Stepping>,
 }
 
-impl ExeState {
-    pub fn new() -> Self {
-        let mut globals = HashMap::new();
-        globals.insert("print".into(), Value::Function(lib_print));
+/// A chunk run one instruction at a time, see [`ExeState::step`].
+#[derive(Debug)]
+struct Stepping {
+    proto: Rc<ParseProto>,
+    next: usize,
+    prev: Option<usize>,
+    cache: Rc<GlobalCache>,
+    // stack index of its registers, where its results go
+    base: usize,
+}
+
+/// Where a chunk run with [`ExeState::step`] is.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Step {
+    /// Stopped before instruction `pc`, of line `line`.
+    Paused { pc: usize, line: u32 },
+    /// Returned these values, which is the last step.
+    Done(Vec<Value>),
+}
+
+/// A protected call: by `pcall`, or by `xpcall` with its message handler
+/// and, once it has been called on the error, its result.
+#[derive(Debug)]
+enum Protected {
+    Plain,
+    Handler {
+        handler: Value,
+        result: Option<Value>,
+    },
+}
+
+/// A level of calls, for `error` to tell where a caller is.
+#[derive(Debug)]
+enum Frame {
+    /// A chunk, or a Lua function, at its running instruction and the line
+    /// of it, with its registers from stack index `base`.
+    Chunk {
+        proto: Rc<ParseProto>,
+        // the function called, nil for a main chunk
+        func: Value,
+        base: usize,
+        line: u32,
+        pc: usize,
+        // the extra arguments, for `...`, in as many slots below `base`
+        nvarargs: usize,
+        // stack index its results go to, that of the function called or
+        // `base` for a main chunk, and how many the caller wants, if not all
+        ret: usize,
+        nret: Option<usize>,
+        cache: Rc<GlobalCache>,
+    },
+    /// A native function, the value called.
+    Native(Value),
+}
 
+/// What the run loop does after an instruction.
+enum Flow {
+    /// Run the next instruction of the same function.
+    Next,
+    /// Run the Lua function the instruction has entered, from its start.
+    Enter,
+    /// Go back to the caller of the function that returned this many
+    /// results.
+    Return(usize),
+}
+
+/// An error raised with a value, such as a table, for `pcall` to return
+/// as it is. Errors must be `Send` and values are not, so the value waits
+/// in the state, and this carries its description for when nothing
+/// catches it.
+#[derive(Debug)]
+pub struct ErrorObject(String);
+
+impl std::fmt::Display for ErrorObject {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ErrorObject {}
+
+/// An error raised running a chunk, with the position of the instruction
+/// raising it, or calling the native function that did, if there is one.
+/// Its value for `pcall` is its message after the position, as
+/// `chunk:line: message`; shown, it has the source span of the instruction
+/// or of the operand it is about instead of the line, as
+/// `chunk:line:first-last: message`, if the chunk was compiled with spans.
+#[derive(Debug)]
+pub struct RuntimeError {
+    /// Chunk name and line.
+    pub position: Option<(String, u32)>,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl RuntimeError {
+    /// An error with `message` as it is, such as one that says where it
+    /// was raised already.
+    pub fn new(message: impl Into<String>) -> Self {
         Self {
-            globals,
-            stack: Vec::new(),
+            position: None,
+            message: message.into(),
+            span: None,
+        }
+    }
+
+    /// Its message, after its position if it has one.
+    pub fn value(&self) -> String {
+        match &self.position {
+            Some((chunk, line)) => format!("{chunk}:{line}: {}", self.message),
+            None => self.message.clone(),
+        }
+    }
+}
+
+impl std::fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (Some((chunk, _)), Some(Span { start, end })) = (&self.position, self.span) else {
+            return f.write_str(&self.value());
+        };
+        write!(f, "{chunk}:{}:{}-", start.line, start.column)?;
+        if end.line != start.line {
+            write!(f, "{}:", end.line)?;
+        }
+        // the last column in the span
+        let last = end.column.saturating_sub(1);
+        let last = if end.line == start.line {
+            last.max(start.column)
+        } else {
+            last
+        };
+        write!(f, "{last}: {}", self.message)
+    }
+}
+
+impl std::error::Error for RuntimeError {}
+
+/// An error of the running instruction about one of its operands, for its
+/// span to point at.
+#[derive(Debug)]
+struct OperandError {
+    message: String,
+    operand: Operand,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Operand {
+    /// The first or the second operand of the instruction, as compiled.
+    Nth(usize),
+    /// The value in a register, as the instruction that set it compiled.
+    Register(u8),
+}
+
+impl std::fmt::Display for OperandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for OperandError {}
+
+/// State of the warning system: whether `warn` emits anything, and where.
+struct Warnings {
+    enabled: bool,
+    handler: Box<dyn FnMut(&str)>,
+}
+
+/// Where `print` and `io.write` send their output.
+struct Output(Box<dyn Write>);
+
+impl std::fmt::Debug for Output {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Output")
+    }
+}
+
+/// A writer appending to a buffer that stays readable through its clones.
+#[derive(Clone, Default)]
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for Warnings {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Warnings")
+            .field("enabled", &self.enabled)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Configures an [`ExeState`] before creating it.
+#[derive(Debug)]
+pub struct ExeStateBuilder {
+    stack_size: usize,
+    max_stack_size: usize,
+    max_calls: usize,
+    max_string_size: usize,
+    stable_sort: bool,
+    stats: bool,
+    strict: bool,
+    allowed_globals: Vec<String>,
+    warnings: bool,
+    catch_panics: bool,
+    seed: Option<LuaInt>,
+    clock: Option<Box<dyn Clock>>,
+    output: Option<Output>,
+    sandbox: SandboxPolicy,
+    interrupt: Option<Arc<AtomicBool>>,
+}
+
+impl ExeStateBuilder {
+    /// Number of stack slots to allocate up front.
+    pub fn stack_size(mut self, size: usize) -> Self {
+        self.stack_size = size;
+        self
+    }
+
+    /// Largest number of stack slots; using more is a "stack overflow" error.
+    pub fn max_stack_size(mut self, size: usize) -> Self {
+        self.max_stack_size = size;
+        self
+    }
+
+    /// Most levels of calls, of Lua and native functions alike; a deeper
+    /// call is a "stack overflow" error. Natives, and the Lua functions they
+    /// call, are limited to 200 levels whatever this is, as they recurse in
+    /// Rust.
+    pub fn max_calls(mut self, n: usize) -> Self {
+        self.max_calls = n;
+        self
+    }
+
+    /// Longest string natives such as `string.rep` may build; a longer
+    /// result is a "resulting string too large" error, raised before any
+    /// memory is allocated for it.
+    pub fn max_string_size(mut self, size: usize) -> Self {
+        self.max_string_size = size;
+        self
+    }
+
+    /// Make `table.sort` a merge sort, which keeps equal elements in
+    /// their order, instead of the quicksort of the reference
+    /// implementation. It sorts a copy of the array part, written back at
+    /// the end, and needs as much memory again.
+    pub fn stable_sort(mut self, enable: bool) -> Self {
+        self.stable_sort = enable;
+        self
+    }
+
+    /// Count executed instructions and calls; see [`ExeState::stats`].
+    pub fn stats(mut self, enable: bool) -> Self {
+        self.stats = enable;
+        self
+    }
+
+    /// Make reading a global that was never assigned an error, to catch
+    /// typos in names.
+    pub fn strict(mut self, enable: bool) -> Self {
+        self.strict = enable;
+        self
+    }
+
+    /// Allow reading global `name` in strict mode before it is assigned.
+    pub fn allow_global(mut self, name: &str) -> Self {
+        self.allowed_globals.push(name.into());
+        self
+    }
+
+    /// Emit warnings from the start, as if `warn("@on")` had been called.
+    pub fn warnings(mut self, enable: bool) -> Self {
+        self.warnings = enable;
+        self
+    }
+
+    /// Turn panics in native functions into errors, which is the default.
+    /// Disable to get the panic's backtrace when debugging a native.
+    pub fn catch_panics(mut self, enable: bool) -> Self {
+        self.catch_panics = enable;
+        self
+    }
+
+    /// Seed `math.random`, making its sequence the same on every run.
+    /// Without one, the seed differs between runs, unless built with the
+    /// `deterministic` feature, where it is 0.
+    pub fn seed(mut self, seed: LuaInt) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Read `os.time` and `os.clock` from `clock` instead of the system,
+    /// or instead of a [`FixedClock`](os::FixedClock) with the
+    /// `deterministic` feature.
+    pub fn clock(mut self, clock: impl Clock + 'static) -> Self {
+        self.clock = Some(Box::new(clock));
+        self
+    }
+
+    /// Send the output of `print` and `io.write` to `out` instead of
+    /// stdout.
+    pub fn output(mut self, out: impl Write + 'static) -> Self {
+        self.output = Some(Output(Box::new(out)));
+        self
+    }
+
+    /// Restrict the files and environment variables scripts can reach.
+    pub fn sandbox(mut self, policy: SandboxPolicy) -> Self {
+        self.sandbox = policy;
+        self
+    }
+
+    /// Stop the running script with an "interrupted!" error when `flag`
+    /// is set, as a SIGINT handler does. The flag is cleared when the error
+    /// is raised, which `pcall` can catch like any other.
+    pub fn interrupt(mut self, flag: Arc<AtomicBool>) -> Self {
+        self.interrupt = Some(flag);
+        self
+    }
+
+    pub fn build(self) -> ExeState {
+        let mut state = ExeState {
+            globals: Rc::new(RefCell::new(Table::new())),
+            globals_epoch: 0,
+            id: NEXT_STATE_ID.fetch_add(1, Ordering::Relaxed),
+            stack: Vec::with_capacity(self.stack_size.min(self.max_stack_size)),
+            max_stack_size: self.max_stack_size,
+            max_calls: self.max_calls,
+            c_calls: 0,
+            max_string_size: self.max_string_size,
+            stable_sort: self.stable_sort,
             func_index: 0,
+            base: 0,
+            stats: self.stats.then(Stats::default),
+            declared: self.strict.then(HashSet::new),
+            warnings: Warnings {
+                enabled: self.warnings,
+                handler: Box::new(|msg| eprintln!("Lua warning: {msg}")),
+            },
+            catch_panics: self.catch_panics,
+            rng: Rng::new(self.seed.unwrap_or_else(math::default_seed)),
+            clock: self.clock.unwrap_or_else(os::default_clock),
+            output: self
+                .output
+                .unwrap_or_else(|| Output(Box::new(io::stdout()))),
+            sandbox: self.sandbox,
+            peak_stack_size: 0,
+            string_meta: Value::Nil,
+            interrupt: self.interrupt,
+            interner: Interner::new(),
+            error_object: Value::Nil,
+            uncaught_traceback: None,
+            finalize: Rc::default(),
+            frames: Vec::new(),
+            open_upvalues: Vec::new(),
+            protected: Vec::new(),
+            hooks: Hooks::default(),
+            stepping: None,
+        };
+        let string = string::lib();
+        state.string_meta = string::metatable(string.clone());
+        state.set_global("string", string);
+        state.set_global("math", math::lib());
+        state.set_global("table", table::lib());
+        state.set_global("os", os::lib());
+        state.set_global("debug", debug::lib());
+        state.set_global("io", stdio::lib());
+        state.set_global("package", package::lib());
+        state.set_global("require", Value::Function(package::lib_require));
+        state.set_global("print", Value::Function(lib_print));
+        state.set_global("json", json::lib());
+        state.set_global("inspect", Value::Function(inspect::lib_inspect));
+        state.set_global("tonumber", Value::Function(numfmt::lib_tonumber));
+        state.set_global("tostring", Value::Function(lib_tostring));
+        state.set_global("type", Value::Function(lib_type));
+        state.set_global("select", Value::Function(lib_select));
+        state.set_global("next", Value::Function(lib_next));
+        state.set_global("pairs", Value::Function(lib_pairs));
+        state.set_global("rawget", Value::Function(lib_rawget));
+        state.set_global("rawset", Value::Function(lib_rawset));
+        state.set_global("getmetatable", Value::Function(lib_getmetatable));
+        state.set_global("setmetatable", Value::Function(lib_setmetatable));
+        state.set_global("assert", Value::Function(lib_assert));
+        state.set_global("error", Value::Function(lib_error));
+        state.set_global("pcall", Value::Function(lib_pcall));
+        state.set_global("xpcall", Value::Function(lib_xpcall));
+        state.set_global("warn", Value::Function(lib_warn));
+        state.set_global("load", Value::Function(lib_load));
+        state.set_global("loadstring", Value::Function(lib_loadstring));
+        state.set_global("loadfile", Value::Function(lib_loadfile));
+        state.set_global("_G", Value::Table(state.globals.clone()));
+        for name in &self.allowed_globals {
+            state.declare_global(name.as_str().into());
+        }
+        state
+    }
+}
+
+impl Default for ExeStateBuilder {
+    fn default() -> Self {
+        Self {
+            stack_size: DEFAULT_STACK_SIZE,
+            max_stack_size: DEFAULT_MAX_STACK_SIZE,
+            max_calls: DEFAULT_MAX_CALLS,
+            max_string_size: DEFAULT_MAX_STRING_SIZE,
+            stable_sort: false,
+            stats: false,
+            strict: false,
+            allowed_globals: Vec::new(),
+            warnings: false,
+            catch_panics: true,
+            seed: None,
+            clock: None,
+            output: None,
+            sandbox: SandboxPolicy::default(),
+            interrupt: None,
+        }
+    }
+}
+
+impl ExeState {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> ExeStateBuilder {
+        ExeStateBuilder::default()
+    }
+
+    /// Run chunk `proto`, which can be shared with other states or with
+    /// the chunks running it already: a native function called from a
+    /// chunk can run another, on top of it.
+    pub fn execute(&mut self, proto: impl Into<Rc<ParseProto>>) -> anyhow::Result<()> {
+        self.execute_results(proto).map(|_| ())
+    }
+
+    /// Compile `input` into a chunk to run in this state. Its string
+    /// constants are shared with those of the chunks loaded before. A
+    /// syntax error says where it is as runtime errors do, `chunk:line:`.
+    pub fn load(
+        &mut self,
+        input: impl Read + 'static,
+        options: ParseOptions,
+    ) -> anyhow::Result<ParseProto> {
+        let chunk_name = match options.chunk_name.as_str() {
+            "" => "?".to_string(),
+            name => name.to_string(),
+        };
+        let mut proto = ParseProto::load_with(input, options).map_err(|err| {
+            match err.downcast::<SyntaxError>() {
+                Ok(e) => SyntaxError {
+                    message: format!("{chunk_name}:{}: {}", e.span.start.line, e.message),
+                    span: e.span,
+                }
+                .into(),
+                Err(err) => err,
+            }
+        })?;
+        self.interner.intern_constants(&mut proto);
+        Ok(proto)
+    }
+
+    /// Compile and run `source` against this state, returning the values of
+    /// its `return` statement, if any.
+    pub fn eval(&mut self, source: &str) -> anyhow::Result<Vec<Value>> {
+        let options = ParseOptions {
+            chunk_name: string_chunk_name(source),
+            ..Default::default()
+        };
+        let proto = self.load(Cursor::new(source.as_bytes().to_vec()), options)?;
+        self.execute_results(proto)
+    }
+
+    /// Like [`execute`](Self::execute), returning the values of the
+    /// chunk's `return` statement.
+    pub fn execute_results(
+        &mut self,
+        proto: impl Into<Rc<ParseProto>>,
+    ) -> anyhow::Result<Vec<Value>> {
+        let proto = proto.into();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!("execute", chunk = %proto.chunk_name).entered();
+        let base = self.stack.len();
+        let depth = self.frames.len();
+        if depth == 0 {
+            self.uncaught_traceback = None;
+        }
+        let results = match self.enter_chunk(&proto, base) {
+            Ok(()) => self.run(depth).map(|_| self.stack.split_off(base)),
+            Err(err) => Err(self.unwind(err, depth)),
+        };
+        self.run_finalizers();
+        #[cfg(feature = "tracing")]
+        if let Err(err) = &results {
+            tracing::debug!("error: {err:#}");
+        }
+        results
+    }
+
+    /// Start running `proto` one instruction at a time, each with a call
+    /// to [`step`](Self::step), in place of any chunk being stepped.
+    pub fn start(&mut self, proto: impl Into<Rc<ParseProto>>) -> anyhow::Result<()> {
+        let proto = proto.into();
+        if self.stepping.take().is_some() {
+            self.pop_chunk();
+        }
+        let base = self.stack.len();
+        let depth = self.frames.len();
+        if let Err(err) = self.enter_chunk(&proto, base) {
+            return Err(self.unwind(err, depth));
+        }
+        let (_, cache, _) = self.running();
+        self.stepping = Some(Stepping {
+            proto,
+            next: 0,
+            prev: None,
+            cache,
+            base,
+        });
+        Ok(())
+    }
+
+    /// Run exactly one instruction of the chunk given to
+    /// [`start`](Self::start), a call running to its end. An error ends
+    /// the chunk, as returning does.
+    pub fn step(&mut self) -> anyhow::Result<Step> {
+        let Some(mut stepping) = self.stepping.take() else {
+            bail!("no chunk to step");
+        };
+        let depth = self.frames.len() - 1;
+        // the globals may have been changed between the steps
+        self.globals_epoch += 1;
+        let mut flow = self.instruction(
+            &stepping.proto,
+            &mut stepping.next,
+            &mut stepping.prev,
+            &stepping.cache,
+        );
+        if let Ok(Flow::Enter) = flow {
+            flow = self.run(self.frames.len() - 1).map(|_| Flow::Next);
+        }
+        let len = stepping.proto.byte_codes.len();
+        match flow {
+            Ok(Flow::Next) if stepping.next < len => {
+                let step = Step::Paused {
+                    pc: stepping.next,
+                    line: stepping
+                        .proto
+                        .lines
+                        .get(stepping.next)
+                        .copied()
+                        .unwrap_or(0),
+                };
+                self.stepping = Some(stepping);
+                Ok(step)
+            }
+            // past its end, a chunk returns nothing
+            Ok(Flow::Next | Flow::Enter) => match self.return_from(self.stack.len(), 0) {
+                Ok(_) => Ok(Step::Done(Vec::new())),
+                Err(err) => Err(self.unwind(err, depth)),
+            },
+            Ok(Flow::Return(_)) => Ok(Step::Done(self.stack.split_off(stepping.base))),
+            Err(err) => Err(self.unwind(err, depth)),
+        }
+    }
+
+    /// Enter main chunk `proto`, with its registers from stack index
+    /// `base`, above anything already on the stack, such as the arguments
+    /// of the native function running it.
+    fn enter_chunk(&mut self, proto: &Rc<ParseProto>, base: usize) -> anyhow::Result<()> {
+        let cache = Rc::new(GlobalCache::default());
+        cache.claim(self.id, proto.constants.len());
+        self.base = base;
+        self.frames.push(Frame::Chunk {
+            proto: proto.clone(),
+            func: Value::Nil,
+            base,
+            line: 0,
+            pc: 0,
+            nvarargs: 0,
+            ret: base,
+            nret: None,
+            cache,
+        });
+        self.hook_event(HookEvent::Call)?;
+        // room for all the registers, so that writing them does not
+        // reallocate the stack
+        self.grow_stack(self.base + proto.max_stack)
+    }
+
+    /// Enter a call to Lua function `f` at stack index `func` with the
+    /// `narg` values above it as arguments, which become its first
+    /// registers, as many as it has parameters: the missing ones are nil,
+    /// and the extra ones are its `...` if it takes them, dropped
+    /// otherwise. The call leaves `nret` results from `func` on when it
+    /// returns, or all of them.
+    fn enter_lua(
+        &mut self,
+        f: &Rc<LuaClosure>,
+        func: usize,
+        narg: usize,
+        nret: Option<usize>,
+    ) -> anyhow::Result<()> {
+        self.begin_call(func, narg)?;
+        let proto = &f.proto;
+        let nparams = proto.nparams as usize;
+        let nvarargs = match proto.is_vararg {
+            true => narg.saturating_sub(nparams),
+            false => 0,
+        };
+        let base = match nvarargs {
+            0 => func + 1,
+            _ => func + 1 + narg,
+        };
+        self.grow_stack(base + proto.max_stack.max(nparams))?;
+        if nvarargs > 0 {
+            // the extra arguments stay where they are, below the registers,
+            // and the others move above them
+            for i in func + 1..func + 1 + nparams {
+                let v = std::mem::take(&mut self.stack[i]);
+                self.stack.push(v);
+            }
+        } else {
+            self.stack.truncate(base + nparams);
+            self.stack.resize(base + nparams, Value::Nil);
+        }
+        f.cache.claim(self.id, proto.constants.len());
+        self.base = base;
+        self.frames.push(Frame::Chunk {
+            proto: proto.clone(),
+            func: Value::LuaClosure(f.clone()),
+            base,
+            line: 0,
+            pc: 0,
+            nvarargs,
+            ret: func,
+            nret,
+            cache: f.cache.clone(),
+        });
+        self.hook_event(HookEvent::Call)
+    }
+
+    /// Return from the running Lua function with the `n` values from stack
+    /// index `first`, popping its frame: they move where the caller wants
+    /// them, as many as it wants, which is the number returned.
+    fn return_from(&mut self, first: usize, n: usize) -> anyhow::Result<usize> {
+        self.hook_event(HookEvent::Return)?;
+        let Some(&Frame::Chunk {
+            base, ret, nret, ..
+        }) = self.frames.last()
+        else {
+            bail!("no function to return from");
+        };
+        let nret = nret.unwrap_or(n);
+        // registers never written read as nil
+        if self.stack.len() < first + n {
+            self.grow_stack(first + n)?;
+            self.stack.resize(first + n, Value::Nil);
+        }
+        self.grow_stack(ret + nret)?;
+        self.frames.pop();
+        self.close_upvalues(base);
+        for i in 0..n.min(nret) {
+            self.stack.swap(ret + i, first + i);
+        }
+        self.stack.truncate(ret + n.min(nret));
+        self.stack.resize(ret + nret, Value::Nil);
+        self.restore_base();
+        Ok(nret)
+    }
+
+    /// Pop the frames above `depth` after error `err`, the message handler
+    /// of an `xpcall` seeing it first, and trace it to the instruction
+    /// raising it, in the innermost Lua function.
+    fn unwind(&mut self, mut err: anyhow::Error, depth: usize) -> anyhow::Error {
+        if self.frames.len() > depth {
+            self.record_traceback(&err);
+        }
+        while self.frames.len() > depth {
+            err = self.handle_error(err);
+            self.pop_chunk();
+        }
+        err
+    }
+
+    /// Pop the frame of the running chunk with its registers, going back
+    /// to those of the chunk below it, if any.
+    fn pop_chunk(&mut self) {
+        if let Some(Frame::Chunk { base, ret, .. }) = self.frames.pop() {
+            self.close_upvalues(base);
+            self.stack.truncate(ret);
+        }
+        self.restore_base();
+    }
+
+    /// Go back to the registers of the innermost Lua function.
+    fn restore_base(&mut self) {
+        self.base = self
+            .frames
+            .iter()
+            .rev()
+            .find_map(|frame| match frame {
+                Frame::Chunk { base, .. } => Some(*base),
+                Frame::Native(_) => None,
+            })
+            .unwrap_or(0);
+    }
+
+    /// The function of the running Lua function, its cache of globals and
+    /// its running instruction.
+    fn running(&self) -> (Rc<ParseProto>, Rc<GlobalCache>, usize) {
+        match self.frames.last() {
+            Some(Frame::Chunk {
+                proto, cache, pc, ..
+            }) => (proto.clone(), cache.clone(), *pc),
+            _ => unreachable!("no Lua function running"),
         }
     }
 
-    pub fn execute(&mut self, proto: &ParseProto) -> anyhow::Result<()> {
-        for code in &proto.byte_codes {
-            match *code {
-                ByteCode::GetGlobal(dst, name) => {
-                    let name = &proto.constants[name as usize];
-                    let key = <&str>::try_from(name)?;
-                    let v = self.globals.get(key).unwrap_or(&Value::Nil).clone();
-                    self.set_stack(dst, v);
+    /// Run the Lua function entered last, and the Lua functions it calls,
+    /// in a loop rather than recursing, until it returns the number of
+    /// results returned. An error pops the frames down to `depth`.
+    fn run(&mut self, depth: usize) -> anyhow::Result<usize> {
+        let (mut proto, mut cache, _) = self.running();
+        let mut next = 0;
+        // the instruction run before, for the line hook to tell a new line
+        let mut prev = None;
+        loop {
+            match self.instruction(&proto, &mut next, &mut prev, &cache) {
+                Ok(Flow::Next) => (),
+                Ok(Flow::Enter) => {
+                    (proto, cache, _) = self.running();
+                    next = 0;
+                    prev = None;
                 }
-                ByteCode::LoadConst(dst, c) => {
-                    let v = proto.constants[c as usize].clone();
-                    self.set_stack(dst, v);
+                Ok(Flow::Return(n)) if self.frames.len() <= depth => return Ok(n),
+                Ok(Flow::Return(_)) => {
+                    // the caller goes on after its call
+                    let pc;
+                    (proto, cache, pc) = self.running();
+                    next = pc + 1;
+                    prev = Some(pc);
                 }
-                ByteCode::Call(func, _) => {
-                    self.func_index = func as usize;
-                    let func = &self.stack[self.func_index];
-                    if let Value::Function(f) = func {
-                        f(self);
-                    } else {
-                        bail!("invalid function: {func:?}");
+                Err(err) => {
+                    let err = self.locate(err, self.frames.len() - 1);
+                    return Err(self.unwind(err, depth));
+                }
+            }
+        }
+    }
+
+    /// Error `err` of the running instruction of the Lua function of frame
+    /// `i`, or of a native function it called, at the position of the
+    /// instruction, unless it was raised with one or with a value. Its span
+    /// is that of the operand it is about, if it says and has one.
+    fn locate(&self, err: anyhow::Error, i: usize) -> anyhow::Error {
+        if err.is::<RuntimeError>() || err.is::<ErrorObject>() || err.is::<os::Exit>() {
+            return err;
+        }
+        let Some(Frame::Chunk {
+            proto, line, pc, ..
+        }) = self.frames.get(i)
+        else {
+            return err;
+        };
+        let operand = match err.downcast_ref::<OperandError>() {
+            Some(OperandError {
+                operand: Operand::Nth(n),
+                ..
+            }) => proto.operand_spans.get(*pc).map(|spans| spans[*n]),
+            Some(OperandError {
+                operand: Operand::Register(r),
+                ..
+            }) => setter(proto, *pc, *r).and_then(|(i, _)| proto.spans.get(i).copied()),
+            None => None,
+        };
+        // an operand with no span of its own has the one of the instruction
+        let span = operand
+            .filter(|span| *span != Span::default())
+            .or_else(|| proto.spans.get(*pc).copied());
+        RuntimeError {
+            position: Some((proto.chunk_name.clone(), *line)),
+            message: format!("{err:#}"),
+            span,
+        }
+        .into()
+    }
+
+    /// Run instruction `next` of `proto`, moving `next` on to the one to
+    /// run after it, and `prev` to it.
+    fn instruction(
+        &mut self,
+        proto: &ParseProto,
+        next: &mut usize,
+        prev: &mut Option<usize>,
+        cache: &GlobalCache,
+    ) -> anyhow::Result<Flow> {
+        let pc = *next;
+        let Some(code) = proto.byte_codes.get(pc) else {
+            // past its end, a function returns nothing
+            return self.return_from(self.stack.len(), 0).map(Flow::Return);
+        };
+        if let Some(Frame::Chunk { line, pc, .. }) = self.frames.last_mut() {
+            *pc = *next;
+            if let Some(&l) = proto.lines.get(*next) {
+                *line = l;
+            }
+        }
+        *next += 1;
+        if self.hooks.mask.line || self.hooks.mask.count != 0 {
+            self.instruction_hooks(proto, pc, *prev)?;
+        }
+        *prev = Some(pc);
+        if let Some(stats) = &mut self.stats {
+            stats.instruction(code);
+        }
+        if let Some(flag) = &self.interrupt {
+            if flag.load(Ordering::Relaxed) {
+                flag.store(false, Ordering::Relaxed);
+                return Err(RuntimeError::new("interrupted!").into());
+            }
+        }
+        match *code {
+            ByteCode::GetGlobal(dst, name) => {
+                let v = self.read_global(proto, cache, name)?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::LoadConst(dst, c) => {
+                let v = proto.constant(c as usize)?.clone();
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::Call(func, narg, nret) => {
+                let at = self.base + func as usize;
+                // arguments up to the top, left by a call in last place
+                let narg = if narg == MULTRET {
+                    self.stack.len().saturating_sub(at + 1)
+                } else {
+                    narg as usize
+                };
+                // registers never written read as nil
+                let top = at + 1 + narg;
+                if self.stack.len() < top {
+                    self.grow_stack(top)?;
+                    self.stack.resize(top, Value::Nil);
+                }
+                let f = &self.stack[at];
+                if !matches!(
+                    f,
+                    Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_)
+                ) {
+                    let name = match register_name(proto, pc, func) {
+                        Some(name) => format!(" ({name})"),
+                        None => String::new(),
+                    };
+                    return Err(OperandError {
+                        message: format!("attempt to call a {} value{name}", f.type_name()),
+                        operand: Operand::Nth(0),
                     }
+                    .into());
+                }
+                let nret = (nret != MULTRET).then_some(nret as usize);
+                return self.enter_or_call(at, narg, nret);
+            }
+            ByteCode::LoadNil(dst) => self.set_stack(dst, Value::Nil)?,
+            ByteCode::LoadBool(dst, c) => self.set_stack(dst, c.into())?,
+            ByteCode::LoadInt(dst, c) => self.set_stack(dst, (c as LuaInt).into())?,
+            ByteCode::Move(dst, src) => self.set_stack(dst, self.register(src))?,
+            ByteCode::SetGlobalConst(dst, src) => {
+                let v = proto.constant(src as usize)?.clone();
+                self.write_global(proto, cache, dst, v)?;
+            }
+            ByteCode::SetGlobal(dst, src) => {
+                self.write_global(proto, cache, dst, self.register(src))?;
+            }
+            ByteCode::SetGlobalGlobal(dst, src) => {
+                let v = self.read_global(proto, cache, src)?;
+                self.write_global(proto, cache, dst, v)?;
+            }
+            ByteCode::Return(first, n) => {
+                let first = self.base + first as usize;
+                let n = if n == MULTRET {
+                    self.stack.len().saturating_sub(first)
+                } else {
+                    n as usize
+                };
+                return self.return_from(first, n).map(Flow::Return);
+            }
+            ByteCode::Jump(offset) => *next = next.wrapping_add_signed(offset as isize),
+            ByteCode::Test(a) => {
+                if !matches!(self.register(a), Value::Nil | Value::Boolean(false)) {
+                    *next += 1;
+                }
+            }
+            ByteCode::Concat(first, n) => {
+                let v = self.concat(proto, pc, first, n)?;
+                self.set_stack(first, v)?;
+            }
+            ByteCode::GetGlobalField(dst, name, k) => {
+                let t = self.read_global(proto, cache, name)?;
+                if !self.can_index(&t, "__index") {
+                    bail!(
+                        "attempt to index a {} value (global '{}')",
+                        t.type_name(),
+                        proto.get_global(name as usize)?
+                    );
+                }
+                let v = self.index(&t, proto.constant(k as usize)?)?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::GetField(dst, src, k) => {
+                let t = self.register(src);
+                if !self.can_index(&t, "__index") {
+                    return Err(index_error(proto, pc, src, &t));
+                }
+                let v = self.index(&t, proto.constant(k as usize)?)?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::GetMethod(dst, src, k) => {
+                let t = self.register(src);
+                if !self.can_index(&t, "__index") {
+                    return Err(index_error(proto, pc, src, &t));
+                }
+                let f = self.index(&t, proto.constant(k as usize)?)?;
+                self.set_stack(dst.saturating_add(1), t)?;
+                self.set_stack(dst, f)?;
+            }
+            ByteCode::GetTable(dst, src, k) => {
+                let t = self.register(src);
+                if !self.can_index(&t, "__index") {
+                    return Err(index_error(proto, pc, src, &t));
+                }
+                let v = self.index(&t, &self.register(k))?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::SetTable(dst, k, src) | ByteCode::SetField(dst, k, src) => {
+                let t = self.register(dst);
+                if !self.can_index(&t, "__newindex") {
+                    return Err(index_error(proto, pc, dst, &t));
+                }
+                let k = match *code {
+                    ByteCode::SetField(..) => proto.constant(k as usize)?.clone(),
+                    _ => self.register(k),
+                };
+                self.set_index(&t, k, self.register(src))?;
+            }
+            ByteCode::NewTable(dst) => self.set_stack(dst, Table::new().into())?,
+            ByteCode::GetInt(dst, src, i) => {
+                let t = self.register(src);
+                if !self.can_index(&t, "__index") {
+                    return Err(index_error(proto, pc, src, &t));
                 }
-                ByteCode::LoadNil(dst) => self.set_stack(dst, Value::Nil),
-                ByteCode::LoadBool(dst, c) => self.set_stack(dst, c.into()),
-                ByteCode::LoadInt(dst, c) => self.set_stack(dst, (c as i64).into()),
-                ByteCode::Move(dst, src) => self.set_stack(dst, self.stack[src as usize].clone()),
-                ByteCode::SetGlobalConst(dst, src) => {
-                    let var = proto.get_global(dst as usize)?.to_owned();
-                    self.globals
-                        .insert(var, proto.constants[src as usize].clone());
+                let v = self.index(&t, &Value::Integer(i as LuaInt))?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::SetInt(dst, i, src) => {
+                let t = self.register(dst);
+                if !self.can_index(&t, "__newindex") {
+                    return Err(index_error(proto, pc, dst, &t));
+                }
+                self.set_index(&t, Value::Integer(i as LuaInt), self.register(src))?;
+            }
+            ByteCode::Add(dst, a, b)
+            | ByteCode::Sub(dst, a, b)
+            | ByteCode::Mul(dst, a, b)
+            | ByteCode::Div(dst, a, b)
+            | ByteCode::Idiv(dst, a, b)
+            | ByteCode::Mod(dst, a, b)
+            | ByteCode::Pow(dst, a, b) => {
+                let v = self.arith_op(proto, pc, arith_op(code), a, self.register(b), Some(b))?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::AddK(dst, a, b)
+            | ByteCode::SubK(dst, a, b)
+            | ByteCode::MulK(dst, a, b)
+            | ByteCode::DivK(dst, a, b)
+            | ByteCode::IdivK(dst, a, b)
+            | ByteCode::ModK(dst, a, b)
+            | ByteCode::PowK(dst, a, b) => {
+                let k = proto.constant(b as usize)?.clone();
+                let v = self.arith_op(proto, pc, arith_op(code), a, k, None)?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::AddInt(dst, a, i) => {
+                let i = Value::Integer(i as LuaInt);
+                let v = self.arith_op(proto, pc, ArithOp::Add, a, i, None)?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::Unm(dst, a) => {
+                // the metamethod gets the operand twice, as in the reference
+                let v = self.arith_op(proto, pc, ArithOp::Unm, a, self.register(a), Some(a))?;
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::Eq(dst, a, b) | ByteCode::Ne(dst, a, b) => {
+                let eq = self.equal(&self.register(a), &self.register(b))?;
+                self.set_stack(dst, (eq == matches!(code, ByteCode::Eq(..))).into())?;
+            }
+            ByteCode::Lt(dst, a, b) => {
+                let v = self.compare("__lt", &self.register(a), &self.register(b))?;
+                self.set_stack(dst, v.into())?;
+            }
+            ByteCode::Le(dst, a, b) => {
+                let v = self.compare("__le", &self.register(a), &self.register(b))?;
+                self.set_stack(dst, v.into())?;
+            }
+            ByteCode::SetList(dst, n) => {
+                let Value::Table(t) = self.register(dst) else {
+                    bail!("SetList on a {} value", self.register(dst).type_name());
+                };
+                let first = self.base + dst as usize + 1;
+                let last = if n == MULTRET {
+                    self.stack.len().max(first)
+                } else {
+                    first + n as usize
+                };
+                let mut t = t.borrow_mut();
+                for (i, at) in (first..last).enumerate() {
+                    let v = self.stack.get(at).cloned().unwrap_or_default();
+                    t.set(Value::Integer(i as LuaInt + 1), v)?;
+                }
+            }
+            ByteCode::ForPrep(base, skip) => {
+                if !self.for_prep(base)? {
+                    *next += skip as usize + 1;
+                }
+            }
+            ByteCode::ForLoop(base, back) => {
+                if self.for_loop(base)? {
+                    *next = next.wrapping_sub(back as usize);
+                }
+            }
+            ByteCode::TForCall(base, nvars) => {
+                for i in 0..3 {
+                    self.set_stack(base + 3 + i, self.register(base + i))?;
                 }
-                ByteCode::SetGlobal(dst, src) => {
-                    let var = proto.get_global(dst as usize)?.to_owned();
-                    self.globals.insert(var, self.stack[src as usize].clone());
+                let f = self.register(base + 3);
+                if !matches!(
+                    f,
+                    Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_)
+                ) {
+                    bail!("attempt to call a {} value (for iterator)", f.type_name());
                 }
-                ByteCode::SetGlobalGlobal(dst, src) => {
-                    let dst = proto.get_global(dst as usize)?.to_owned();
-                    let src = proto.get_global(src as usize)?;
-                    self.globals
-                        .insert(dst, self.globals.get(src).unwrap_or(&Value::Nil).clone());
+                let at = self.base + base as usize + 3;
+                self.stack.truncate(at + 3);
+                return self.enter_or_call(at, 2, Some(nvars as usize));
+            }
+            ByteCode::TForLoop(base, back) => {
+                let v = self.register(base + 3);
+                if v != Value::Nil {
+                    self.set_stack(base + 2, v)?;
+                    *next = next.wrapping_sub(back as usize);
+                }
+            }
+            ByteCode::Closure(dst, i) => {
+                let Some(proto) = proto.protos.get(i as usize) else {
+                    bail!("function index out of bounds");
+                };
+                let upvalues = proto
+                    .upvalues
+                    .iter()
+                    .map(|u| match u.in_stack {
+                        true => Ok(self.open_upvalue(self.base + u.index as usize)),
+                        false => self.upvalue_cell(u.index),
+                    })
+                    .collect::<anyhow::Result<_>>()?;
+                let f = LuaClosure::new(proto.clone(), upvalues);
+                self.set_stack(dst, Value::LuaClosure(Rc::new(f)))?;
+            }
+            ByteCode::GetUpval(dst, i) => {
+                let v = match &*self.upvalue_cell(i)?.borrow() {
+                    &Upvalue::Open(j) => self.stack.get(j).cloned().unwrap_or_default(),
+                    Upvalue::Closed(v) => v.clone(),
+                };
+                self.set_stack(dst, v)?;
+            }
+            ByteCode::SetUpval(src, i) => {
+                let v = self.register(src);
+                let cell = self.upvalue_cell(i)?;
+                let mut cell = cell.borrow_mut();
+                match &mut *cell {
+                    &mut Upvalue::Open(j) => {
+                        if self.stack.len() <= j {
+                            self.grow_stack(j + 1)?;
+                            self.stack.resize(j + 1, Value::Nil);
+                        }
+                        self.stack[j] = v;
+                    }
+                    Upvalue::Closed(c) => *c = v,
+                }
+            }
+            ByteCode::Close(first) => self.close_upvalues(self.base + first as usize),
+            ByteCode::VarArgs(first, n) => {
+                let nvarargs = match self.frames.last() {
+                    Some(Frame::Chunk { nvarargs, .. }) => *nvarargs,
+                    _ => 0,
+                };
+                let varargs = self.base - nvarargs..self.base;
+                if n == MULTRET {
+                    // all of them, up to the top of the stack
+                    let at = self.base + first as usize;
+                    self.grow_stack(at + nvarargs)?;
+                    self.stack.resize(at, Value::Nil);
+                    self.stack.extend_from_within(varargs);
+                } else {
+                    for i in 0..n {
+                        let v = match varargs.start + (i as usize) {
+                            j if j < varargs.end => self.stack[j].clone(),
+                            _ => Value::Nil,
+                        };
+                        self.set_stack(first.wrapping_add(i), v)?;
+                    }
                 }
             }
         }
-        Ok(())
+        Ok(Flow::Next)
     }
 
-    fn set_stack(&mut self, dst: u8, v: Value) {
-        let dst = dst as usize;
-        if self.stack.len() <= dst {
-            self.stack.resize(dst + 1, Value::Nil);
+    /// Upvalue `i` of the running Lua function.
+    fn upvalue_cell(&self, i: u8) -> anyhow::Result<Rc<RefCell<Upvalue>>> {
+        let cell = match self.frames.last() {
+            Some(Frame::Chunk {
+                func: Value::LuaClosure(f),
+                ..
+            }) => f.upvalues.get(i as usize),
+            _ => None,
+        };
+        match cell {
+            Some(cell) => Ok(cell.clone()),
+            None => bail!("upvalue index out of bounds"),
         }
-        self.stack[dst] = v;
     }
-}
 
-impl Default for ExeState {
-    fn default() -> Self {
-        Self::new()
+    /// The upvalue of the local at stack index `i`, shared by all the
+    /// closures capturing it while it is open.
+    fn open_upvalue(&mut self, i: usize) -> Rc<RefCell<Upvalue>> {
+        let at = self.open_upvalues.partition_point(|(j, _)| *j < i);
+        match self.open_upvalues.get(at) {
+            Some((j, cell)) if *j == i => cell.clone(),
+            _ => {
+                let cell = Rc::new(RefCell::new(Upvalue::Open(i)));
+                self.open_upvalues.insert(at, (i, cell.clone()));
+                cell
+            }
+        }
+    }
+
+    /// Close the open upvalues from stack index `level` up over the values
+    /// of their locals, which are going out of scope.
+    fn close_upvalues(&mut self, level: usize) {
+        let at = self.open_upvalues.partition_point(|(j, _)| *j < level);
+        for (j, cell) in self.open_upvalues.drain(at..) {
+            let v = self.stack.get(j).cloned().unwrap_or_default();
+            *cell.borrow_mut() = Upvalue::Closed(v);
+        }
+    }
+
+    /// Check and convert the control values of a numeric for loop in the
+    /// registers from `base`, returning whether the loop runs at all. As in
+    /// the reference implementation, a loop with an integer start and step
+    /// counts its iterations up front, so that it stops at the limit even
+    /// when the next value would overflow, and any other loop steps in
+    /// floats.
+    fn for_prep(&mut self, base: u8) -> anyhow::Result<bool> {
+        let (init, limit, step) = (
+            self.register(base),
+            self.register(base + 1),
+            self.register(base + 2),
+        );
+        if let (Value::Integer(init), Value::Integer(step)) = (&init, &step) {
+            let (init, step) = (*init, *step);
+            if step == 0 {
+                bail!("'for' step is zero");
+            }
+            let Some(limit) = for_limit(&limit, step)? else {
+                return Ok(false);
+            };
+            if if step > 0 { init > limit } else { init < limit } {
+                return Ok(false);
+            }
+            let count = if step > 0 {
+                (limit as LuaUnsigned).wrapping_sub(init as LuaUnsigned) / step as LuaUnsigned
+            } else {
+                // `-step` would overflow for the most negative step
+                (init as LuaUnsigned).wrapping_sub(limit as LuaUnsigned)
+                    / ((-(step + 1)) as LuaUnsigned + 1)
+            };
+            self.set_stack(base + 1, Value::Integer(count as LuaInt))?;
+            self.set_stack(base + 3, Value::Integer(init))?;
+            return Ok(true);
+        }
+
+        let limit = for_float(&limit, "limit")?;
+        let step = for_float(&step, "step")?;
+        let init = for_float(&init, "initial value")?;
+        if step == 0.0 {
+            bail!("'for' step is zero");
+        }
+        if if step > 0.0 {
+            limit < init
+        } else {
+            init < limit
+        } {
+            return Ok(false);
+        }
+        self.set_stack(base, init.into())?;
+        self.set_stack(base + 1, limit.into())?;
+        self.set_stack(base + 2, step.into())?;
+        self.set_stack(base + 3, init.into())?;
+        Ok(true)
+    }
+
+    /// Step the numeric for loop in the registers from `base`, returning
+    /// whether it runs again.
+    fn for_loop(&mut self, base: u8) -> anyhow::Result<bool> {
+        let next = match (
+            self.register(base),
+            self.register(base + 1),
+            self.register(base + 2),
+        ) {
+            (Value::Integer(i), Value::Integer(count), Value::Integer(step)) => {
+                // the count is unsigned
+                if count == 0 {
+                    return Ok(false);
+                }
+                self.set_stack(
+                    base + 1,
+                    Value::Integer((count as LuaUnsigned - 1) as LuaInt),
+                )?;
+                Value::Integer(i.wrapping_add(step))
+            }
+            (Value::Float(f), Value::Float(limit), Value::Float(step)) => {
+                let f = f + step;
+                if !(if step > 0.0 { f <= limit } else { limit <= f }) {
+                    return Ok(false);
+                }
+                Value::Float(f)
+            }
+            _ => bail!("'for' loop registers were modified"),
+        };
+        self.set_stack(base, next.clone())?;
+        self.set_stack(base + 3, next)?;
+        Ok(true)
+    }
+
+    /// `..` over the `n` registers from `first`, starting from the right as
+    /// the operator is right associative. Strings and numbers are joined;
+    /// for anything else the `__concat` metamethod of the left operand is
+    /// called, or failing that the one of the right operand.
+    fn concat(&mut self, proto: &ParseProto, pc: usize, first: u8, n: u8) -> anyhow::Result<Value> {
+        let last = (first as usize + n as usize).saturating_sub(1) as u8;
+        let mut acc = self.register(last);
+        for i in (first..last).rev() {
+            let l = self.register(i);
+            if let (Some(ls), Some(rs)) = (concat_bytes(&l), concat_bytes(&acc)) {
+                acc = [ls, rs].concat().into();
+                continue;
+            }
+            let tm = match self.metamethod(&l, "__concat") {
+                Value::Nil => self.metamethod(&acc, "__concat"),
+                tm => tm,
+            };
+            if tm == Value::Nil {
+                // only the operands still in their registers have names
+                let (bad, reg) = match concat_bytes(&l) {
+                    Some(_) => (&acc, (i + 1 == last).then_some(last)),
+                    None => (&l, Some(i)),
+                };
+                let name = match reg.and_then(|reg| register_name(proto, pc, reg)) {
+                    Some(name) => format!(" ({name})"),
+                    None => String::new(),
+                };
+                let message = format!("attempt to concatenate a {} value{name}", bad.type_name());
+                return Err(match reg {
+                    Some(reg) => OperandError {
+                        message,
+                        operand: Operand::Register(reg),
+                    }
+                    .into(),
+                    None => anyhow!(message),
+                });
+            }
+            acc = self.call_first(tm, &[l, acc])?;
+        }
+        Ok(acc)
     }
-}
 
-fn lib_print(state: &mut ExeState) -> i32 {
-    println!("{}", state.stack[state.func_index + 1]);
-    0
+    /// The global keyed by constant `k`, failing in strict mode if it is
+    /// nil and has not been declared. Globals set through `_G` count as
+    /// declared while they have a value. A global found in the globals
+    /// table itself is kept in `cache` until the globals may have changed,
+    /// so that reading it again does not hash its name.
+    fn read_global(
+        &mut self,
+        proto: &ParseProto,
+        cache: &GlobalCache,
+        k: u8,
+    ) -> anyhow::Result<Value> {
+        if let Some(v) = cache.get(k, self.globals_epoch) {
+            return Ok(v);
+        }
+        let key = proto.constant(k as usize)?;
+        let (v, meta) = {
+            let globals = self.globals.borrow();
+            (globals.get(key), globals.metatable != Value::Nil)
+        };
+        // a missing global goes through the `__index` of `_G`
+        let v = match v {
+            Value::Nil if meta => self.index(&Value::Table(self.globals.clone()), key)?,
+            v => v,
+        };
+        if let Some(declared) = &self.declared {
+            if v == Value::Nil && !declared.contains(key) {
+                bail!("variable '{key}' is not declared");
+            }
+        }
+        // what a metamethod gives may change at any time
+        if !meta {
+            cache.set(k, self.globals_epoch, v.clone());
+        }
+        Ok(v)
+    }
+
+    /// Set the global keyed by constant `k` to `v`, through the
+    /// `__newindex` of `_G` if it is new, declaring it in strict mode.
+    fn write_global(
+        &mut self,
+        proto: &ParseProto,
+        cache: &GlobalCache,
+        k: u8,
+        v: Value,
+    ) -> anyhow::Result<()> {
+        let key = proto.constant(k as usize)?.clone();
+        if self.globals.borrow().metatable == Value::Nil {
+            self.globals.borrow_mut().set(key.clone(), v.clone())?;
+            cache.set(k, self.globals_epoch, v);
+        } else {
+            self.set_index(&Value::Table(self.globals.clone()), key.clone(), v)?;
+        }
+        self.declare_global(key);
+        Ok(())
+    }
+
+    fn declare_global(&mut self, key: Value) {
+        if let Some(declared) = &mut self.declared {
+            declared.insert(key);
+        }
+    }
+
+    pub fn get_global(&self, name: &str) -> Value {
+        self.globals.borrow().get(&name.into())
+    }
+
+    /// The globals with a name that are not nil, in no particular order.
+    pub fn globals(&self) -> impl Iterator<Item = (String, Value)> {
+        let globals: Vec<_> = self
+            .globals
+            .borrow()
+            .map_entries()
+            .filter_map(|(k, v)| Some((<&str>::try_from(k).ok()?.to_string(), v.clone())))
+            .collect();
+        globals.into_iter()
+    }
+
+    /// The table of the globals, which scripts see as `_G`.
+    pub(crate) fn globals_table(&self) -> &Rc<RefCell<Table>> {
+        &self.globals
+    }
+
+    /// A new userdata holding `data`, with `metatable`, nil or a table. If
+    /// the metatable has a `__gc` metamethod, this state calls it with the
+    /// userdata once it is dropped, see [`run_finalizers`](Self::run_finalizers).
+    pub fn userdata(&self, data: impl Any, metatable: Value) -> Value {
+        let finalize = match &metatable {
+            Value::Table(t) if t.borrow().get(&"__gc".into()) != Value::Nil => {
+                Rc::downgrade(&self.finalize)
+            }
+            _ => Weak::new(),
+        };
+        Value::finalized_userdata(data, metatable, finalize)
+    }
+
+    pub fn set_global(&mut self, name: &str, v: Value) {
+        self.globals_epoch += 1;
+        let key = Value::from(name);
+        // a string key is always valid
+        self.globals.borrow_mut().set(key.clone(), v).unwrap();
+        self.declare_global(key);
+    }
+
+    /// Capture the globals, so that they can be restored later, possibly
+    /// into another state. Fails if a function is not reachable under the
+    /// name of a global or a field of a global table.
+    pub fn snapshot(&self) -> anyhow::Result<StateImage> {
+        StateImage::capture(self)
+    }
+
+    /// Set the globals captured in `image`. Functions are looked up by name
+    /// among the globals of this state.
+    pub fn restore(&mut self, image: &StateImage) -> anyhow::Result<()> {
+        image.apply(self)
+    }
+
+    /// `obj[key]`, going through the `__index` metamethod when the key is
+    /// missing from a table, and always for strings, whose metatable
+    /// indexes the string library, and for userdata.
+    pub fn index(&mut self, obj: &Value, key: &Value) -> anyhow::Result<Value> {
+        let mut obj = obj.clone();
+        // as in the reference, to stop a loop of `__index` tables
+        for _ in 0..MAX_META_CHAIN {
+            match &obj {
+                Value::Table(t) => {
+                    let v = t.borrow().get(key);
+                    if v != Value::Nil {
+                        return Ok(v);
+                    }
+                }
+                Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => (),
+                Value::UserData(_) => (),
+                v => bail!("attempt to index a {} value", v.type_name()),
+            }
+            obj = match self.metamethod(&obj, "__index") {
+                Value::Nil if matches!(obj, Value::UserData(_)) => {
+                    bail!("attempt to index a userdata value")
+                }
+                Value::Nil => return Ok(Value::Nil),
+                index @ Value::Table(_) => index,
+                f => return self.call_first(f, &[obj, key.clone()]),
+            };
+        }
+        bail!("'__index' chain too long; possibly a loop")
+    }
+
+    /// `obj[key] = v` for table `obj`, going through the `__newindex`
+    /// metamethod when the key is missing from it, and always for userdata.
+    pub fn set_index(&mut self, obj: &Value, key: Value, v: Value) -> anyhow::Result<()> {
+        let mut obj = obj.clone();
+        for _ in 0..MAX_META_CHAIN {
+            if let Value::UserData(_) = obj {
+                obj = match self.metamethod(&obj, "__newindex") {
+                    Value::Nil => bail!("attempt to index a userdata value"),
+                    index @ Value::Table(_) => index,
+                    f => return self.call(f, &[obj, key, v]),
+                };
+                continue;
+            }
+            let Value::Table(t) = &obj else {
+                bail!("attempt to index a {} value", obj.type_name());
+            };
+            let tm = match t.borrow().get(&key) {
+                Value::Nil => self.metamethod(&obj, "__newindex"),
+                _ => Value::Nil,
+            };
+            obj = match tm {
+                Value::Nil => return t.borrow_mut().set(key, v),
+                index @ Value::Table(_) => index,
+                f => return self.call(f, &[obj, key, v]),
+            };
+        }
+        bail!("'__newindex' chain too long; possibly a loop")
+    }
+
+    /// Whether `v` can be indexed, to read if `event` is `__index` or to
+    /// write if it is `__newindex`: tables, strings to read, and userdata
+    /// with the metamethod.
+    fn can_index(&self, v: &Value, event: &str) -> bool {
+        match v {
+            Value::Table(_) => true,
+            Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => event == "__index",
+            Value::UserData(_) => self.metamethod(v, event) != Value::Nil,
+            _ => false,
+        }
+    }
+
+    /// The metatable of `v`, or nil. Strings share one, and tables and
+    /// userdata may each have their own.
+    pub fn metatable(&self, v: &Value) -> Value {
+        match v {
+            Value::Table(t) => t.borrow().metatable.clone(),
+            Value::UserData(u) => u.metatable.clone(),
+            Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => self.string_meta.clone(),
+            _ => Value::Nil,
+        }
+    }
+
+    /// `a op b` for the instruction at `pc`, with `a` in register `ra` and
+    /// `b` in register `rb` unless it is a constant: through the
+    /// metamethod of `op` when an operand is not a number, or an error
+    /// naming the operand at fault.
+    fn arith_op(
+        &mut self,
+        proto: &ParseProto,
+        pc: usize,
+        op: ArithOp,
+        ra: u8,
+        b: Value,
+        rb: Option<u8>,
+    ) -> anyhow::Result<Value> {
+        let a = self.register(ra);
+        if let Some(v) = arith::arith(op, &a, &b)? {
+            return Ok(v);
+        }
+        let tm = match self.metamethod(&a, op.event()) {
+            Value::Nil => self.metamethod(&b, op.event()),
+            tm => tm,
+        };
+        if tm != Value::Nil {
+            return self.call_first(tm, &[a, b]);
+        }
+        // as the metamethods of strings say, in the reference
+        let is_str = |v: &Value| <&[u8]>::try_from(v).is_ok();
+        if is_str(&a) || is_str(&b) {
+            bail!(
+                "attempt to {} a '{}' with a '{}'",
+                &op.event()[2..],
+                a.type_name(),
+                b.type_name()
+            );
+        }
+        // the second operand, if the first is a number
+        let (bad, r, n) = match arith::to_number(&a) {
+            Some(_) => (&b, rb, 1),
+            None => (&a, Some(ra), 0),
+        };
+        let name = match r.and_then(|r| register_name(proto, pc, r)) {
+            Some(name) => format!(" ({name})"),
+            None => String::new(),
+        };
+        Err(OperandError {
+            message: format!(
+                "attempt to perform arithmetic on a {} value{name}",
+                bad.type_name()
+            ),
+            operand: Operand::Nth(n),
+        }
+        .into())
+    }
+
+    /// The string `tostring` gives for `v`: the result of its `__tostring`
+    /// metamethod if it has one, which must be a string or a number.
+    pub fn tostring(&mut self, v: &Value) -> anyhow::Result<Value> {
+        let v = match self.metamethod(v, "__tostring") {
+            Value::Nil => v.clone(),
+            tm => match self.call_first(tm, std::slice::from_ref(v))? {
+                s @ (Value::Integer(_)
+                | Value::Float(_)
+                | Value::ShortStr(..)
+                | Value::MidStr(_)
+                | Value::LongStr(_)) => s,
+                _ => bail!("'__tostring' must return a string"),
+            },
+        };
+        Ok(match v {
+            Value::ShortStr(..) | Value::MidStr(_) | Value::LongStr(_) => v,
+            _ => Value::from(v.to_string().as_str()),
+        })
+    }
+
+    /// `a == b`: numbers by their mathematical values, and tables and
+    /// userdata by identity, or through the `__eq` metamethod of either
+    /// when both are tables or both userdata.
+    pub fn equal(&mut self, a: &Value, b: &Value) -> anyhow::Result<bool> {
+        match (a, b) {
+            (Value::Integer(_), Value::Float(_)) | (Value::Float(_), Value::Integer(_)) => {
+                Ok(a.less_equal(b)? && b.less_equal(a)?)
+            }
+            _ if a == b => Ok(true),
+            (Value::Table(_), Value::Table(_)) | (Value::UserData(_), Value::UserData(_)) => {
+                let tm = match self.metamethod(a, "__eq") {
+                    Value::Nil => self.metamethod(b, "__eq"),
+                    tm => tm,
+                };
+                if tm == Value::Nil {
+                    return Ok(false);
+                }
+                let v = self.call_first(tm, &[a.clone(), b.clone()])?;
+                Ok(!matches!(v, Value::Nil | Value::Boolean(false)))
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// `a < b` if `event` is `__lt`, or `a <= b` if it is `__le`: numbers
+    /// and strings by [`Value::less_than`] and [`Value::less_equal`],
+    /// anything else through the metamethod of either.
+    fn compare(&mut self, event: &str, a: &Value, b: &Value) -> anyhow::Result<bool> {
+        let is_str = |v: &Value| <&[u8]>::try_from(v).is_ok();
+        let is_num = |v: &Value| matches!(v, Value::Integer(_) | Value::Float(_));
+        if (is_num(a) && is_num(b)) || (is_str(a) && is_str(b)) {
+            return match event {
+                "__lt" => a.less_than(b),
+                _ => a.less_equal(b),
+            };
+        }
+        let tm = match self.metamethod(a, event) {
+            Value::Nil => self.metamethod(b, event),
+            tm => tm,
+        };
+        if tm == Value::Nil {
+            return Err(compare_error(a, b));
+        }
+        let v = self.call_first(tm, &[a.clone(), b.clone()])?;
+        Ok(!matches!(v, Value::Nil | Value::Boolean(false)))
+    }
+
+    /// The metamethod `event` of `v`, or nil.
+    fn metamethod(&self, v: &Value, event: &str) -> Value {
+        match self.metatable(v) {
+            Value::Table(meta) => meta.borrow().get(&event.into()),
+            _ => Value::Nil,
+        }
+    }
+
+    /// Call the `__gc` metamethod of each userdata of this state dropped
+    /// since the last time, and of those the calls drop in turn, which
+    /// happens before every call, when a chunk returns and when the state
+    /// is dropped. An error in one is a warning.
+    pub fn run_finalizers(&mut self) {
+        loop {
+            let dropped = std::mem::take(&mut *self.finalize.borrow_mut());
+            if dropped.is_empty() {
+                return;
+            }
+            for v in dropped {
+                let tm = self.metamethod(&v, "__gc");
+                if tm == Value::Nil {
+                    continue;
+                }
+                if let Err(err) = self.call(tm, &[v]) {
+                    let e = self.take_error_value(&err);
+                    let msg = <&str>::try_from(&e).unwrap_or("error object is not a string");
+                    let msg = format!("error in __gc ({msg})");
+                    self.warn(&msg);
+                }
+            }
+        }
+    }
+
+    /// Send the messages of `warn` to `handler` instead of stderr. It is
+    /// only called while warnings are on.
+    pub fn set_warn_handler(&mut self, handler: impl FnMut(&str) + 'static) {
+        self.warnings.handler = Box::new(handler);
+    }
+
+    /// An error raising `v`. Strings are plain messages; other values,
+    /// numbers included, reach `pcall` unchanged.
+    pub fn error_value(&mut self, v: Value) -> anyhow::Error {
+        match v {
+            Value::Integer(_) | Value::Float(_) => self.error_object(v.clone(), v.to_string()),
+            _ => match String::try_from(&v) {
+                Ok(msg) => RuntimeError::new(msg).into(),
+                Err(_) => {
+                    let msg = format!("(error object is a {} value)", v.type_name());
+                    self.error_object(v, msg)
+                }
+            },
+        }
+    }
+
+    fn error_object(&mut self, v: Value, msg: String) -> anyhow::Error {
+        self.error_object = v;
+        ErrorObject(msg).into()
+    }
+
+    /// Call the message handler of the innermost protected call, if it is
+    /// an `xpcall` whose handler has not seen an error yet, on `err`.
+    fn handle_error(&mut self, err: anyhow::Error) -> anyhow::Error {
+        let Some(Protected::Handler {
+            handler,
+            result: None,
+        }) = self.protected.last()
+        else {
+            return err;
+        };
+        if err.is::<os::Exit>() {
+            return err;
+        }
+        let handler = handler.clone();
+        let v = self.take_error_value(&err);
+        // an error in the handler is its result, not one to handle again
+        self.protected.push(Protected::Plain);
+        let handled = self.call_first(handler, &[v]);
+        self.protected.pop();
+        let handled = handled.unwrap_or_else(|err| self.take_error_value(&err));
+        if let Some(Protected::Handler { result, .. }) = self.protected.last_mut() {
+            *result = Some(handled);
+        }
+        err
+    }
+
+    /// An argument error of the running native function, named as the
+    /// reference names it when no call instruction does, such as for a
+    /// function called by `pcall`: by a global reaching it, say
+    /// `string.format` rather than `format`.
+    fn name_bad_argument(&self, err: anyhow::Error) -> anyhow::Error {
+        let [.., caller, Frame::Native(f)] = &self.frames[..] else {
+            return err;
+        };
+        if matches!(caller, Frame::Chunk { .. })
+            || err.chain().count() > 1
+            || err.is::<ErrorObject>()
+        {
+            return err;
+        }
+        let msg = err.to_string();
+        let Some((arg, rest)) = msg
+            .strip_prefix("bad argument #")
+            .and_then(|rest| rest.split_once(" to '"))
+        else {
+            return err;
+        };
+        let Some((_, rest)) = rest.split_once('\'') else {
+            return err;
+        };
+        match self.global_function_name(f) {
+            Some(name) => anyhow::anyhow!("bad argument #{arg} to '{name}'{rest}"),
+            None => err,
+        }
+    }
+
+    /// Where the function `level` calls up from the running native one is,
+    /// as `chunk:line: `, or `None` for a native function, which has no
+    /// position, or past the outermost call.
+    pub fn position(&self, level: usize) -> Option<String> {
+        let i = self.frames.len().checked_sub(level + 1)?;
+        match &self.frames[i] {
+            Frame::Chunk { proto, line, .. } => Some(format!("{}:{line}: ", proto.chunk_name)),
+            Frame::Native(_) => None,
+        }
+    }
+
+    /// Name and value of local `n`, counting from 1, of the function `level`
+    /// calls up from the running native one, among the locals in scope at
+    /// its running instruction. `None` if there is no such local, which
+    /// native functions never have; an error past the outermost call.
+    pub fn local(&self, level: usize, n: usize) -> anyhow::Result<Option<(String, Value)>> {
+        Ok(self.local_slot(level, n)?.map(|(name, i)| {
+            let v = self.stack.get(i).cloned().unwrap_or_default();
+            (name.to_string(), v)
+        }))
+    }
+
+    /// Set local `n` of the function `level` calls up to `v`, as
+    /// [`local`](Self::local) finds it, returning its name.
+    pub fn set_local(
+        &mut self,
+        level: usize,
+        n: usize,
+        v: Value,
+    ) -> anyhow::Result<Option<String>> {
+        let Some((name, i)) = self.local_slot(level, n)? else {
+            return Ok(None);
+        };
+        let name = name.to_string();
+        if self.stack.len() <= i {
+            self.grow_stack(i + 1)?;
+            self.stack.resize(i + 1, Value::Nil);
+        }
+        self.stack[i] = v;
+        Ok(Some(name))
+    }
+
+    /// Name and stack index of local `n` of the function `level` calls up.
+    fn local_slot(&self, level: usize, n: usize) -> anyhow::Result<Option<(&str, usize)>> {
+        let Some(i) = self.frames.len().checked_sub(level + 1) else {
+            bail!("level out of range");
+        };
+        let Frame::Chunk {
+            proto, base, pc, ..
+        } = &self.frames[i]
+        else {
+            return Ok(None);
+        };
+        let local = n
+            .checked_sub(1)
+            .and_then(|i| proto.locvars.iter().filter(|v| v.in_scope(*pc)).nth(i));
+        // the register of a local is its index among those in scope
+        Ok(local.map(|v| (v.name.as_str(), base + n - 1)))
+    }
+
+    /// The calls from the function `level` calls up from the running native
+    /// one outwards, a line each after a `stack traceback:` line, as in the
+    /// reference implementation.
+    pub fn traceback(&self, level: usize) -> String {
+        let mut out = String::from("stack traceback:");
+        let end = self.frames.len().saturating_sub(level);
+        for frame in self.frames[..end].iter().rev() {
+            match frame {
+                Frame::Chunk {
+                    proto, func, line, ..
+                } => {
+                    out += &format!("\n\t{}:{line}: ", proto.chunk_name);
+                    out += &match (func, self.global_function_name(func)) {
+                        (Value::Nil, _) => "in main chunk".into(),
+                        (_, Some(name)) => format!("in function '{name}'"),
+                        (_, None) => {
+                            format!("in function <{}:{}>", proto.chunk_name, proto.line_defined)
+                        }
+                    };
+                }
+                Frame::Native(f) => match self.global_function_name(f) {
+                    Some(name) => out += &format!("\n\t[C]: in function '{name}'"),
+                    None => out += "\n\t[C]: in ?",
+                },
+            }
+        }
+        out
+    }
+
+    /// Keep the traceback of the running calls for error `err` if nothing
+    /// will catch it, unless it has one already, having been raised further
+    /// in.
+    fn record_traceback(&mut self, err: &anyhow::Error) {
+        if self.protected.is_empty() && self.uncaught_traceback.is_none() && !err.is::<os::Exit>() {
+            self.uncaught_traceback = Some(self.traceback(0));
+        }
+    }
+
+    /// The traceback of the last error that nothing caught, as
+    /// [`traceback`](Self::traceback) gave it where the error was raised,
+    /// once.
+    pub fn take_traceback(&mut self) -> Option<String> {
+        self.uncaught_traceback.take()
+    }
+
+    /// A name `f` can be reached by: a global, or a field of a library,
+    /// such as `string.upper`. The shortest, then first in order, if
+    /// several.
+    fn global_function_name(&self, f: &Value) -> Option<String> {
+        let mut names = Vec::new();
+        for (name, v) in self.globals() {
+            match &v {
+                v if v == f => names.push(name.clone()),
+                Value::Table(t) => {
+                    for (key, v) in &t.borrow().map {
+                        if let (true, Ok(key)) = (v == f, <&str>::try_from(key)) {
+                            names.push(format!("{name}.{key}"));
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+        names
+            .into_iter()
+            .min_by(|a, b| (a.len(), a).cmp(&(b.len(), b)))
+    }
+
+    /// The value of error `err`, as `pcall` returns it: the value it was
+    /// raised with, or its message.
+    pub fn take_error_value(&mut self, err: &anyhow::Error) -> Value {
+        if err.is::<ErrorObject>() {
+            std::mem::take(&mut self.error_object)
+        } else if let Some(err) = (**err).downcast_ref::<RuntimeError>() {
+            err.value().into()
+        } else {
+            format!("{err:#}").into()
+        }
+    }
+
+    /// Emit a warning, or handle the control messages `@on` and `@off`.
+    /// Other control messages are ignored.
+    pub fn warn(&mut self, msg: &str) {
+        match msg {
+            "@on" => self.warnings.enabled = true,
+            "@off" => self.warnings.enabled = false,
+            _ if msg.starts_with('@') => (),
+            _ if self.warnings.enabled => (self.warnings.handler)(msg),
+            _ => (),
+        }
+    }
+
+    /// Call `hook` on the events of `mask`, in place of any hook set before.
+    /// Hooks are off while it runs, and an error it returns is raised where
+    /// the event happened, which is how a time limit stops a script.
+    pub fn set_hook(
+        &mut self,
+        mask: HookMask,
+        hook: impl FnMut(&mut ExeState, HookEvent) -> anyhow::Result<()> + 'static,
+    ) {
+        self.hooks = Hooks {
+            hook: Some(Box::new(hook)),
+            mask,
+            countdown: mask.count,
+        };
+    }
+
+    pub fn remove_hook(&mut self) {
+        self.hooks = Hooks::default();
+    }
+
+    /// Call the hook for `event`, if it asked for it.
+    fn hook_event(&mut self, event: HookEvent) -> anyhow::Result<()> {
+        let wanted = match event {
+            HookEvent::Call => self.hooks.mask.call,
+            HookEvent::Return => self.hooks.mask.ret,
+            HookEvent::Line(_) => self.hooks.mask.line,
+            HookEvent::Count => self.hooks.mask.count != 0,
+        };
+        if !wanted {
+            return Ok(());
+        }
+        let Some(mut hook) = self.hooks.hook.take() else {
+            return Ok(());
+        };
+        self.globals_epoch += 1;
+        // the results of a returning function are left alone
+        let top = self.stack.len();
+        let result = hook(self, event);
+        self.stack.truncate(top);
+        // unless it set another hook, or removed itself
+        if self.hooks.hook.is_none() && self.hooks.mask != HookMask::default() {
+            self.hooks.hook = Some(hook);
+        }
+        result
+    }
+
+    /// Call the count and line hooks due before instruction `pc` of
+    /// `proto`, `prev` being the one run before it, if any.
+    fn instruction_hooks(
+        &mut self,
+        proto: &ParseProto,
+        pc: usize,
+        prev: Option<usize>,
+    ) -> anyhow::Result<()> {
+        if self.hooks.mask.count != 0 {
+            self.hooks.countdown = self.hooks.countdown.saturating_sub(1);
+            if self.hooks.countdown == 0 {
+                self.hooks.countdown = self.hooks.mask.count;
+                self.hook_event(HookEvent::Count)?;
+            }
+        }
+        let Some(&line) = proto.lines.get(pc) else {
+            return Ok(());
+        };
+        // a new line, or the same one again after a jump back
+        let new_line = match prev {
+            Some(prev) => pc <= prev || proto.lines.get(prev) != Some(&line),
+            None => true,
+        };
+        if new_line {
+            self.hook_event(HookEvent::Line(line))?;
+        }
+        Ok(())
+    }
+
+    /// Counters of the work done so far, if enabled with
+    /// [`ExeStateBuilder::stats`].
+    pub fn stats(&self) -> Option<&Stats> {
+        self.stats.as_ref()
+    }
+
+    /// Call the function at stack index `func` with the `narg` values above
+    /// it as arguments, leaving its results from `func` on. Returns their
+    /// number.
+    fn call_function(&mut self, func: usize, narg: usize) -> anyhow::Result<usize> {
+        if self.c_calls >= MAX_C_CALLS {
+            bail!("C stack overflow");
+        }
+        self.c_calls += 1;
+        let result = match &self.stack[func] {
+            Value::LuaClosure(f) => {
+                let f = f.clone();
+                let depth = self.frames.len();
+                match self.enter_lua(&f, func, narg, None) {
+                    Ok(()) => self.run(depth),
+                    Err(err) => Err(self.unwind(err, depth)),
+                }
+            }
+            _ => self.call_native(func, narg),
+        };
+        self.c_calls -= 1;
+        result
+    }
+
+    /// Count a call of the function at stack index `func` with the `narg`
+    /// values above it, failing past the limit on levels of calls.
+    fn begin_call(&mut self, func: usize, narg: usize) -> anyhow::Result<()> {
+        if let Some(stats) = &mut self.stats {
+            stats.calls += 1;
+        }
+        if self.frames.len() >= self.max_calls {
+            bail!("stack overflow");
+        }
+        // it may change any table, the globals included
+        self.globals_epoch += 1;
+        self.stack.truncate(func + 1 + narg);
+        self.run_finalizers();
+        Ok(())
+    }
+
+    /// Call the native function at stack index `func`, as
+    /// [`call_function`](Self::call_function) does.
+    fn call_native(&mut self, func: usize, narg: usize) -> anyhow::Result<usize> {
+        self.begin_call(func, narg)?;
+        let saved = self.func_index;
+        self.func_index = func;
+        self.frames.push(Frame::Native(self.stack[func].clone()));
+        let f = match &self.stack[func] {
+            &Value::Function(f) => Ok(f),
+            Value::NativeClosure(c) => Ok(c.func),
+            v => Err(anyhow::anyhow!("attempt to call a {} value", v.type_name())),
+        };
+        let result = match f {
+            Ok(f) => self
+                .hook_event(HookEvent::Call)
+                .and_then(|()| {
+                    if self.catch_panics {
+                        match panic::catch_unwind(AssertUnwindSafe(|| f(self))) {
+                            Ok(result) => result,
+                            Err(payload) => Err(panic_error(payload)),
+                        }
+                    } else {
+                        f(self)
+                    }
+                })
+                .and_then(|n| self.hook_event(HookEvent::Return).map(|()| n)),
+            Err(err) => Err(err),
+        };
+        // while the frame that raised it is still there
+        let result = result.map_err(|err| {
+            let err = self.name_bad_argument(err);
+            let err = match self.frames.len().checked_sub(2) {
+                Some(caller) => self.locate(err, caller),
+                None => err,
+            };
+            self.record_traceback(&err);
+            self.handle_error(err)
+        });
+        self.frames.pop();
+        self.func_index = saved;
+        // the results it pushed, down to the function
+        let n = (result? as usize).min(self.stack.len() - func - 1);
+        let first = self.stack.len() - n;
+        for i in 0..n {
+            self.stack.swap(func + i, first + i);
+        }
+        self.stack.truncate(func + n);
+        Ok(n)
+    }
+
+    /// Call the function at stack index `func` from the running Lua
+    /// function, as [`call_at`](Self::call_at) does, except that a Lua
+    /// function is only entered, for the run loop to go on with, so that
+    /// Lua functions calling each other do not recurse in Rust.
+    fn enter_or_call(
+        &mut self,
+        func: usize,
+        narg: usize,
+        nret: Option<usize>,
+    ) -> anyhow::Result<Flow> {
+        match &self.stack[func] {
+            Value::LuaClosure(f) => {
+                let f = f.clone();
+                self.enter_lua(&f, func, narg, nret)?;
+                Ok(Flow::Enter)
+            }
+            _ => {
+                self.call_at(func, narg, nret)?;
+                Ok(Flow::Next)
+            }
+        }
+    }
+
+    /// Like [`call_function`](Self::call_function), then leave exactly
+    /// `nret` results in the stack from `func` on, padded with nil, or all
+    /// of them if `nret` is `None`.
+    fn call_at(&mut self, func: usize, narg: usize, nret: Option<usize>) -> anyhow::Result<()> {
+        let n = self.call_function(func, narg)?;
+        if let Some(nret) = nret {
+            self.stack.truncate(func + n.min(nret));
+            self.grow_stack(func + nret)?;
+            self.stack.resize(func + nret, Value::Nil);
+        }
+        Ok(())
+    }
+
+    /// Call `func` with `args` from inside a native function, returning its
+    /// first result, or nil if it returned nothing.
+    pub fn call_first(&mut self, func: Value, args: &[Value]) -> anyhow::Result<Value> {
+        let base = self.stack.len();
+        self.grow_stack(base + 1 + args.len())?;
+        self.stack.push(func);
+        self.stack.extend_from_slice(args);
+        let result = self
+            .call_at(base, args.len(), Some(1))
+            .map(|_| std::mem::take(&mut self.stack[base]));
+        self.stack.truncate(base);
+        result
+    }
+
+    /// Call `func` with `args`, returning all of its results.
+    pub fn call_results(&mut self, func: Value, args: &[Value]) -> anyhow::Result<Vec<Value>> {
+        let base = self.stack.len();
+        self.grow_stack(base + 1 + args.len())?;
+        self.stack.push(func);
+        self.stack.extend_from_slice(args);
+        let result = self
+            .call_at(base, args.len(), None)
+            .map(|()| self.stack.split_off(base));
+        self.stack.truncate(base);
+        result
+    }
+
+    /// Call `func` with `args` from inside a native function, discarding
+    /// any results.
+    pub fn call(&mut self, func: Value, args: &[Value]) -> anyhow::Result<()> {
+        let base = self.stack.len();
+        self.grow_stack(base + 1 + args.len())?;
+        self.stack.push(func);
+        self.stack.extend_from_slice(args);
+        let result = self.call_function(base, args.len());
+        self.stack.truncate(base);
+        result.map(|_| ())
+    }
+
+    /// Call argument `i` of the running native function with the arguments
+    /// after it, discarding any results. Unlike [`call`](Self::call) the
+    /// arguments are copied within the stack, without a temporary vector.
+    pub fn call_arg(&mut self, i: usize) -> anyhow::Result<()> {
+        if i > self.get_top() {
+            return self.call(Value::Nil, &[]);
+        }
+        let base = self.stack.len();
+        let first = self.func_index + i;
+        self.grow_stack(base + base - first)?;
+        self.stack.extend_from_within(first..base);
+        let result = self.call_function(base, base - first - 1);
+        self.stack.truncate(base);
+        result.map(|_| ())
+    }
+
+    /// Upvalue `i` (1-based) of the running native closure, or nil if
+    /// there is no such upvalue.
+    pub fn upvalue(&self, i: usize) -> Value {
+        match self.frames.last() {
+            Some(Frame::Native(Value::NativeClosure(c))) => {
+                c.upvalues.borrow().get(i - 1).cloned().unwrap_or_default()
+            }
+            _ => Value::Nil,
+        }
+    }
+
+    /// Set upvalue `i` (1-based) of the running native closure, which must
+    /// have it.
+    pub fn set_upvalue(&mut self, i: usize, v: Value) {
+        match self.frames.last() {
+            Some(Frame::Native(Value::NativeClosure(c))) => c.upvalues.borrow_mut()[i - 1] = v,
+            _ => panic!("no upvalue {i} in the running function"),
+        }
+    }
+
+    /// Number of arguments passed to the running native function.
+    pub fn get_top(&self) -> usize {
+        self.stack.len() - self.func_index - 1
+    }
+
+    /// The `i`-th (1-based) argument of the running native function, or nil.
+    pub fn arg(&self, i: usize) -> &Value {
+        if i <= self.get_top() {
+            &self.stack[self.func_index + i]
+        } else {
+            &Value::Nil
+        }
+    }
+
+    /// Type name of argument `i` for error messages, or "no value" if it
+    /// was not passed, as in the reference `luaL_typeerror`.
+    pub fn arg_type_name(&self, i: usize) -> &'static str {
+        if i <= self.get_top() {
+            self.arg(i).type_name()
+        } else {
+            "no value"
+        }
+    }
+
+    /// Push a return value of the running native function.
+    pub fn push(&mut self, v: Value) {
+        self.stack.push(v);
+        self.peak_stack_size = self.peak_stack_size.max(self.stack.len());
+    }
+
+    /// Longest string natives may build, see
+    /// [`ExeStateBuilder::max_string_size`].
+    pub fn max_string_size(&self) -> usize {
+        self.max_string_size
+    }
+
+    /// Whether `table.sort` is stable, see [`ExeStateBuilder::stable_sort`].
+    pub fn stable_sort(&self) -> bool {
+        self.stable_sort
+    }
+
+    /// Generator behind `math.random`.
+    pub fn rng(&mut self) -> &mut Rng {
+        &mut self.rng
+    }
+
+    /// Make `require(name)` call `init` with the name the first time, and
+    /// return its result then and on every later call.
+    pub fn preload_module(
+        &mut self,
+        name: &str,
+        init: fn(&mut ExeState) -> anyhow::Result<i32>,
+    ) -> anyhow::Result<()> {
+        package::preload(self, name, Value::Function(init))
+    }
+
+    /// Where `print` and `io.write` write to.
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut *self.output.0
+    }
+
+    /// Redirect the output of `print` and `io.write` to `out`.
+    pub fn set_output(&mut self, out: impl Write + 'static) {
+        self.output = Output(Box::new(out));
+    }
+
+    /// Run `f`, returning what it printed instead of writing it to the
+    /// configured output. Meant for tests asserting on printed output.
+    pub fn with_captured_output(&mut self, f: impl FnOnce(&mut Self)) -> String {
+        let buf = SharedBuffer::default();
+        let saved = std::mem::replace(&mut self.output, Output(Box::new(buf.clone())));
+        f(self);
+        self.output = saved;
+        let bytes = buf.0.take();
+        String::from_utf8_lossy(&bytes).into_owned()
+    }
+
+    /// What scripts may reach outside the interpreter.
+    pub fn sandbox(&self) -> &SandboxPolicy {
+        &self.sandbox
+    }
+
+    /// Source of `os.time` and `os.clock`.
+    pub fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    /// How much sharing the constants of loaded chunks saved.
+    pub fn intern_stats(&self) -> InternStats {
+        self.interner.stats()
+    }
+
+    /// Largest number of stack slots in use at any point so far.
+    pub fn peak_stack_size(&self) -> usize {
+        self.peak_stack_size
+    }
+
+    /// The value in register `i` of the running chunk, nil if it was never
+    /// written.
+    fn register(&self, i: u8) -> Value {
+        self.stack
+            .get(self.base + i as usize)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    fn set_stack(&mut self, dst: u8, v: Value) -> anyhow::Result<()> {
+        let dst = self.base + dst as usize;
+        if self.stack.len() <= dst {
+            self.grow_stack(dst + 1)?;
+            self.stack.resize(dst + 1, Value::Nil);
+        }
+        self.stack[dst] = v;
+        Ok(())
+    }
+
+    /// Make room for `len` stack slots, doubling the allocation so that a
+    /// stack growing one slot at a time is not reallocated on every write.
+    fn grow_stack(&mut self, len: usize) -> anyhow::Result<()> {
+        if len > self.max_stack_size {
+            bail!("stack overflow");
+        }
+        self.peak_stack_size = self.peak_stack_size.max(len);
+        let capacity = self.stack.capacity();
+        if len > capacity {
+            let target = len.max(capacity * 2).min(self.max_stack_size);
+            self.stack.reserve_exact(target - self.stack.len());
+        }
+        Ok(())
+    }
+}
+
+impl Default for ExeState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Empties the globals, which would otherwise keep each other alive through
+/// `_G`, the table in itself, and with them everything they reach, then
+/// calls the finalizers of the userdata this drops.
+impl Drop for ExeState {
+    fn drop(&mut self) {
+        let globals = std::mem::take(&mut *self.globals.borrow_mut());
+        drop(globals);
+        self.stack.clear();
+        self.error_object = Value::Nil;
+        // a finalizer that panicked again would abort
+        if !std::thread::panicking() {
+            self.run_finalizers();
+        }
+    }
+}
+
+fn lib_print(state: &mut ExeState) -> anyhow::Result<i32> {
+    let mut line = Vec::new();
+    let args: Vec<_> = (1..=state.get_top())
+        .map(|i| state.arg(i).clone())
+        .collect();
+    for (i, v) in args.iter().enumerate() {
+        if i != 0 {
+            line.push(b'\t');
+        }
+        let s = state.tostring(v)?;
+        line.extend_from_slice(<&[u8]>::try_from(&s)?);
+    }
+    line.push(b'\n');
+    state.output().write_all(&line)?;
+    Ok(0)
+}
+
+/// Name of a chunk loaded from `source`, as the reference implementation
+/// gives it: `[string "..."]` with the first line of the source, cut short
+/// if long.
+pub(crate) fn string_chunk_name(source: &str) -> String {
+    const MAX: usize = 45;
+    let line = source.lines().next().unwrap_or_default();
+    if line.len() == source.len() && line.len() < MAX {
+        return format!("[string \"{line}\"]");
+    }
+    let mut end = line.len().min(MAX);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("[string \"{}...\"]", &line[..end])
+}
+
+fn panic_error(payload: Box<dyn Any + Send>) -> anyhow::Error {
+    let msg = match payload.downcast::<String>() {
+        Ok(msg) => *msg,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(msg) => msg.to_string(),
+            Err(_) => "unknown panic".into(),
+        },
+    };
+    anyhow::anyhow!("native function panicked: {msg}")
+}
+
+/// The integer limit of a loop from an integer start by `step`, or `None`
+/// if the loop cannot run. A float limit is rounded towards the start, and
+/// one beyond the integers is clipped to them.
+fn for_limit(limit: &Value, step: LuaInt) -> anyhow::Result<Option<LuaInt>> {
+    let f = match *limit {
+        Value::Integer(i) => return Ok(Some(i)),
+        Value::Float(f) => f,
+        ref v => bail!("bad 'for' limit (number expected, got {})", v.type_name()),
+    };
+    let f = if step < 0 { f.ceil() } else { f.floor() };
+    if (-INT_RANGE..INT_RANGE).contains(&f) {
+        Ok(Some(f as LuaInt))
+    } else if f > 0.0 {
+        Ok((step > 0).then_some(LuaInt::MAX))
+    } else {
+        // too small, or NaN
+        Ok((step < 0).then_some(LuaInt::MIN))
+    }
+}
+
+/// A control value of a float loop; `what` says which in the error.
+fn for_float(v: &Value, what: &str) -> anyhow::Result<LuaFloat> {
+    match *v {
+        Value::Integer(i) => Ok(i as LuaFloat),
+        Value::Float(f) => Ok(f),
+        ref v => bail!("bad 'for' {what} (number expected, got {})", v.type_name()),
+    }
+}
+
+/// The bytes `v` contributes to a concatenation, if it is a string or a
+/// number.
+fn concat_bytes(v: &Value) -> Option<Vec<u8>> {
+    match v {
+        Value::Integer(_) | Value::Float(_) => Some(v.to_string().into_bytes()),
+        v => <&[u8]>::try_from(v).ok().map(<[u8]>::to_vec),
+    }
+}
+
+/// Describe where the value in register `reg` at `pc` came from, as in
+/// "global 'print'", by finding the instruction that last loaded it. As in
+/// the reference implementation, a load that a forward jump to before `pc`
+/// may skip does not count.
+fn register_name(proto: &ParseProto, pc: usize, reg: u8) -> Option<String> {
+    // the locals in scope are the first registers, in order
+    if let Some(v) = proto
+        .locvars
+        .iter()
+        .filter(|v| v.in_scope(pc))
+        .nth(reg as usize)
+    {
+        return Some(format!("local '{}'", v.name));
+    }
+    let (i, code) = setter(proto, pc, reg)?;
+    match *code {
+        // a copy of a lower register, a local most likely
+        ByteCode::Move(_, src) if src < reg => register_name(proto, i, src),
+        ByteCode::GetUpval(_, u) => Some(format!(
+            "upvalue '{}'",
+            proto.upvalues.get(u as usize)?.name
+        )),
+        ByteCode::GetGlobal(_, k) => {
+            Some(format!("global '{}'", proto.get_global(k as usize).ok()?))
+        }
+        ByteCode::GetField(_, _, k) | ByteCode::GetGlobalField(_, _, k) => {
+            Some(format!("field '{}'", proto.get_global(k as usize).ok()?))
+        }
+        ByteCode::GetMethod(dst, _, k) if dst == reg => {
+            Some(format!("method '{}'", proto.get_global(k as usize).ok()?))
+        }
+        // the object, a copy
+        ByteCode::GetMethod(_, src, _) => register_name(proto, i, src),
+        _ => None,
+    }
+}
+
+/// The instruction that last loaded register `reg` before `pc`, and its
+/// index, not counting one that a forward jump to before `pc` may skip.
+fn setter(proto: &ParseProto, pc: usize, reg: u8) -> Option<(usize, &ByteCode)> {
+    let mut setter = None;
+    let mut jump_target = 0;
+    for (i, code) in proto.byte_codes[..pc].iter().enumerate() {
+        match *code {
+            ByteCode::Jump(offset) => {
+                let target = (i + 1).wrapping_add_signed(offset as isize);
+                if i < target && target <= pc {
+                    jump_target = jump_target.max(target);
+                }
+            }
+            ByteCode::GetGlobal(dst, _)
+            | ByteCode::LoadConst(dst, _)
+            | ByteCode::LoadNil(dst)
+            | ByteCode::LoadBool(dst, _)
+            | ByteCode::LoadInt(dst, _)
+            | ByteCode::Move(dst, _)
+            | ByteCode::Call(dst, _, _)
+            | ByteCode::Concat(dst, _)
+            | ByteCode::GetField(dst, _, _)
+            | ByteCode::GetGlobalField(dst, _, _)
+                if dst == reg =>
+            {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            ByteCode::ForPrep(base, _) | ByteCode::ForLoop(base, _)
+                if (base..=base.saturating_add(3)).contains(&reg) =>
+            {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            ByteCode::TForCall(base, nvars)
+                if (base.saturating_add(3)..base.saturating_add(3 + nvars)).contains(&reg) =>
+            {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            ByteCode::TForLoop(base, _) if base.saturating_add(2) == reg => {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            ByteCode::GetMethod(dst, _, _) if dst == reg || dst.saturating_add(1) == reg => {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            ByteCode::Closure(dst, _)
+            | ByteCode::GetUpval(dst, _)
+            | ByteCode::NewTable(dst)
+            | ByteCode::GetTable(dst, _, _)
+            | ByteCode::GetInt(dst, _, _)
+            | ByteCode::Add(dst, _, _)
+            | ByteCode::Sub(dst, _, _)
+            | ByteCode::Mul(dst, _, _)
+            | ByteCode::Div(dst, _, _)
+            | ByteCode::Idiv(dst, _, _)
+            | ByteCode::Mod(dst, _, _)
+            | ByteCode::Pow(dst, _, _)
+            | ByteCode::AddK(dst, _, _)
+            | ByteCode::SubK(dst, _, _)
+            | ByteCode::MulK(dst, _, _)
+            | ByteCode::DivK(dst, _, _)
+            | ByteCode::IdivK(dst, _, _)
+            | ByteCode::ModK(dst, _, _)
+            | ByteCode::PowK(dst, _, _)
+            | ByteCode::AddInt(dst, _, _)
+            | ByteCode::Unm(dst, _)
+            | ByteCode::Eq(dst, _, _)
+            | ByteCode::Ne(dst, _, _)
+            | ByteCode::Lt(dst, _, _)
+            | ByteCode::Le(dst, _, _)
+                if dst == reg =>
+            {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            ByteCode::VarArgs(first, n) if first <= reg && (n == MULTRET || reg - first < n) => {
+                setter = (i >= jump_target).then_some((i, code));
+            }
+            _ => (),
+        }
+    }
+    setter
+}
+
+/// The operator of arithmetic instruction `code`.
+fn arith_op(code: &ByteCode) -> ArithOp {
+    match code {
+        ByteCode::Add(..) | ByteCode::AddK(..) | ByteCode::AddInt(..) => ArithOp::Add,
+        ByteCode::Sub(..) | ByteCode::SubK(..) => ArithOp::Sub,
+        ByteCode::Mul(..) | ByteCode::MulK(..) => ArithOp::Mul,
+        ByteCode::Div(..) | ByteCode::DivK(..) => ArithOp::Div,
+        ByteCode::Idiv(..) | ByteCode::IdivK(..) => ArithOp::Idiv,
+        ByteCode::Mod(..) | ByteCode::ModK(..) => ArithOp::Mod,
+        ByteCode::Pow(..) | ByteCode::PowK(..) => ArithOp::Pow,
+        _ => ArithOp::Unm,
+    }
+}
+
+/// The error of indexing `v`, in register `reg` at `pc`, which cannot be.
+fn index_error(proto: &ParseProto, pc: usize, reg: u8, v: &Value) -> anyhow::Error {
+    let name = match register_name(proto, pc, reg) {
+        Some(name) => format!(" ({name})"),
+        None => String::new(),
+    };
+    OperandError {
+        message: format!("attempt to index a {} value{name}", v.type_name()),
+        operand: Operand::Nth(0),
+    }
+    .into()
+}
+
+// type(v)
+fn lib_type(state: &mut ExeState) -> anyhow::Result<i32> {
+    if state.get_top() == 0 {
+        bail!("bad argument #1 to 'type' (value expected)");
+    }
+    let name = state.arg(1).type_name();
+    state.push(name.into());
+    Ok(1)
+}
+
+// tostring(v), the same text print gives
+fn lib_tostring(state: &mut ExeState) -> anyhow::Result<i32> {
+    if state.get_top() == 0 {
+        bail!("bad argument #1 to 'tostring' (value expected)");
+    }
+    let v = state.arg(1).clone();
+    let s = state.tostring(&v)?;
+    state.push(s);
+    Ok(1)
+}
+
+// next(table [, key])
+fn lib_next(state: &mut ExeState) -> anyhow::Result<i32> {
+    let Value::Table(t) = state.arg(1) else {
+        bail!(
+            "bad argument #1 to 'next' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    let entry = t.borrow().next(state.arg(2))?;
+    match entry {
+        Some((k, v)) => {
+            state.push(k);
+            state.push(v);
+            Ok(2)
+        }
+        None => {
+            state.push(Value::Nil);
+            Ok(1)
+        }
+    }
+}
+
+// rawget(table, key)
+fn lib_rawget(state: &mut ExeState) -> anyhow::Result<i32> {
+    let Value::Table(t) = state.arg(1) else {
+        bail!(
+            "bad argument #1 to 'rawget' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    let v = t.borrow().get(state.arg(2));
+    state.push(v);
+    Ok(1)
+}
+
+// rawset(table, key, value), returning the table
+fn lib_rawset(state: &mut ExeState) -> anyhow::Result<i32> {
+    let Value::Table(t) = state.arg(1).clone() else {
+        bail!(
+            "bad argument #1 to 'rawset' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    if state.get_top() < 3 {
+        bail!("bad argument #3 to 'rawset' (value expected)");
+    }
+    t.borrow_mut()
+        .set(state.arg(2).clone(), state.arg(3).clone())?;
+    state.push(Value::Table(t));
+    Ok(1)
+}
+
+// getmetatable(v): the `__metatable` field of its metatable if set, as
+// the reference does to hide a metatable, or the metatable itself
+fn lib_getmetatable(state: &mut ExeState) -> anyhow::Result<i32> {
+    let meta = state.metatable(state.arg(1));
+    let v = match &meta {
+        Value::Table(t) => match t.borrow().get(&"__metatable".into()) {
+            Value::Nil => meta.clone(),
+            v => v,
+        },
+        _ => Value::Nil,
+    };
+    state.push(v);
+    Ok(1)
+}
+
+// setmetatable(table, metatable), returning the table
+fn lib_setmetatable(state: &mut ExeState) -> anyhow::Result<i32> {
+    let t = state.arg(1).clone();
+    let Value::Table(tt) = &t else {
+        bail!(
+            "bad argument #1 to 'setmetatable' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    };
+    let meta = state.arg(2).clone();
+    if !matches!(meta, Value::Nil | Value::Table(_)) {
+        bail!("bad argument #2 to 'setmetatable' (nil or table expected)");
+    }
+    if state.metamethod(&t, "__metatable") != Value::Nil {
+        bail!("cannot change a protected metatable");
+    }
+    tt.borrow_mut().metatable = meta;
+    state.push(t);
+    Ok(1)
+}
+
+// pairs(table), for the generic for: next, the table and nil
+fn lib_pairs(state: &mut ExeState) -> anyhow::Result<i32> {
+    if !matches!(state.arg(1), Value::Table(_)) {
+        bail!(
+            "bad argument #1 to 'pairs' (table expected, got {})",
+            state.arg_type_name(1)
+        );
+    }
+    let t = state.arg(1).clone();
+    state.push(Value::Function(lib_next));
+    state.push(t);
+    state.push(Value::Nil);
+    Ok(3)
+}
+
+// select(n, ...)
+fn lib_select(state: &mut ExeState) -> anyhow::Result<i32> {
+    // the count of the arguments is kept by the stack, so trailing nils
+    // are counted too
+    let nvarg = state.get_top().saturating_sub(1) as LuaInt;
+    // as in the reference implementation, any string starting with `#`
+    if <&[u8]>::try_from(state.arg(1)).is_ok_and(|s| s.starts_with(b"#")) {
+        state.push(Value::Integer(nvarg));
+        return Ok(1);
+    }
+    let n = check_int(state, 1, "select")?;
+    // the arguments from the `n`-th one, which are at the top already
+    let from = if n < 0 {
+        nvarg + 1 + n
+    } else {
+        n.min(nvarg + 1)
+    };
+    if from < 1 {
+        bail!("bad argument #1 to 'select' (index out of range)");
+    }
+    Ok((nvarg + 1 - from) as i32)
+}
+
+/// Argument `i` as an integer, converting a string as `tonumber` does.
+pub(crate) fn check_int(state: &ExeState, i: usize, fname: &str) -> anyhow::Result<LuaInt> {
+    let n = match state.arg(i) {
+        v @ (Value::Integer(_) | Value::Float(_)) => Some(v.clone()),
+        v => <&[u8]>::try_from(v).ok().and_then(numfmt::str2number),
+    };
+    match n {
+        Some(Value::Integer(n)) => Ok(n),
+        Some(Value::Float(f)) if f.fract() == 0.0 && f.abs() < LuaInt::MAX as LuaFloat => {
+            Ok(f as LuaInt)
+        }
+        Some(_) => {
+            bail!("bad argument #{i} to '{fname}' (number has no integer representation)")
+        }
+        None => bail!(
+            "bad argument #{i} to '{fname}' (number expected, got {})",
+            state.arg_type_name(i)
+        ),
+    }
+}
+
+// assert(v [, message, ...])
+fn lib_assert(state: &mut ExeState) -> anyhow::Result<i32> {
+    match state.arg(1) {
+        _ if state.get_top() == 0 => bail!("bad argument #1 to 'assert' (value expected)"),
+        // all the arguments are the results, and no message is built
+        Value::Nil | Value::Boolean(false) => (),
+        _ => return Ok(state.get_top() as i32),
+    }
+    if state.get_top() < 2 {
+        bail!("assertion failed!");
+    }
+    let msg = state.arg(2).clone();
+    Err(state.error_value(msg))
+}
+
+// error(message [, level])
+fn lib_error(state: &mut ExeState) -> anyhow::Result<i32> {
+    let level = match state.arg(2) {
+        Value::Nil => 1,
+        _ => check_int(state, 2, "error")?,
+    };
+    let mut msg = state.arg(1).clone();
+    // only string messages get the position of the caller at `level`
+    if let (Ok(s), Ok(level @ 1..)) = (String::try_from(&msg), usize::try_from(level)) {
+        if let Some(position) = state.position(level) {
+            msg = format!("{position}{s}").into();
+        }
+    }
+    Err(state.error_value(msg))
+}
+
+// pcall(f, ...)
+fn lib_pcall(state: &mut ExeState) -> anyhow::Result<i32> {
+    if state.get_top() == 0 {
+        bail!("bad argument #1 to 'pcall' (value expected)");
+    }
+    let func = state.func_index + 1;
+    protected_call(state, func, state.get_top() - 1, Protected::Plain)
+}
+
+// xpcall(f, msgh, ...)
+fn lib_xpcall(state: &mut ExeState) -> anyhow::Result<i32> {
+    if !matches!(
+        state.arg(2),
+        Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_)
+    ) {
+        bail!(
+            "bad argument #2 to 'xpcall' (function expected, got {})",
+            state.arg_type_name(2)
+        );
+    }
+    // out of the way of the arguments
+    let handler = state.stack.remove(state.func_index + 2);
+    let func = state.func_index + 1;
+    let protected = Protected::Handler {
+        handler,
+        result: None,
+    };
+    protected_call(state, func, state.get_top() - 1, protected)
+}
+
+/// Call the function at stack index `func` with the `narg` values above
+/// it, leaving `true` and its results, or `false` and the error value, or
+/// the result of the message handler of `xpcall`.
+fn protected_call(
+    state: &mut ExeState,
+    func: usize,
+    narg: usize,
+    protected: Protected,
+) -> anyhow::Result<i32> {
+    state.protected.push(protected);
+    // the handler has seen any error raised by a function, but not one of
+    // the call itself
+    let result = state
+        .call_at(func, narg, None)
+        .map_err(|err| state.handle_error(err));
+    let protected = state.protected.pop();
+    match result {
+        Ok(()) => {
+            // the status goes before the results
+            state.grow_stack(state.stack.len() + 1)?;
+            state.stack.insert(func, true.into());
+            Ok((state.stack.len() - func) as i32)
+        }
+        // exiting is not an error to catch
+        Err(err) if err.is::<os::Exit>() => Err(err),
+        Err(err) => {
+            state.stack.truncate(func);
+            state.push(false.into());
+            let v = match protected {
+                Some(Protected::Handler {
+                    result: Some(v), ..
+                }) => v,
+                _ => state.take_error_value(&err),
+            };
+            state.push(v);
+            Ok(2)
+        }
+    }
+}
+
+// load(chunk [, chunkname]): the chunk compiled into a function, or nil
+// and the message of the syntax error. A function chunk is called for the
+// pieces of the source until it returns nil or an empty string. There is
+// one global environment, so the `mode` and `env` arguments are ignored.
+fn lib_load(state: &mut ExeState) -> anyhow::Result<i32> {
+    let chunk = state.arg(1).clone();
+    let (source, default_name) = match &chunk {
+        Value::Function(_) | Value::NativeClosure(_) | Value::LuaClosure(_) => {
+            let mut source = Vec::new();
+            loop {
+                let piece = match state.call_first(chunk.clone(), &[]) {
+                    Ok(piece) => piece,
+                    Err(err) => {
+                        let msg = state.take_error_value(&err);
+                        state.push(Value::Nil);
+                        state.push(msg);
+                        return Ok(2);
+                    }
+                };
+                match <&[u8]>::try_from(&piece) {
+                    Ok([]) => break,
+                    Ok(s) => source.extend_from_slice(s),
+                    Err(_) if piece == Value::Nil => break,
+                    Err(_) => {
+                        state.push(Value::Nil);
+                        state.push("reader function must return a string".into());
+                        return Ok(2);
+                    }
+                }
+            }
+            (source, "=(load)".to_string())
+        }
+        // numbers are strings to it, as to the reference
+        Value::Integer(_) | Value::Float(_) => (chunk.to_string().into_bytes(), chunk.to_string()),
+        v => match <&[u8]>::try_from(v) {
+            Ok(s) => (s.to_vec(), String::from_utf8_lossy(s).into_owned()),
+            Err(_) => bail!(
+                "bad argument #1 to 'load' (string expected, got {})",
+                state.arg_type_name(1)
+            ),
+        },
+    };
+    let name = match state.arg(2) {
+        Value::Nil => default_name,
+        v => match String::try_from(v) {
+            Ok(name) => name,
+            Err(_) => bail!(
+                "bad argument #2 to 'load' (string expected, got {})",
+                state.arg_type_name(2)
+            ),
+        },
+    };
+    load_chunk(state, source, &name)
+}
+
+// loadstring(s [, chunkname]), as in Lua 5.1
+fn lib_loadstring(state: &mut ExeState) -> anyhow::Result<i32> {
+    if !matches!(state.arg(1), Value::Integer(_) | Value::Float(_))
+        && <&[u8]>::try_from(state.arg(1)).is_err()
+    {
+        bail!(
+            "bad argument #1 to 'loadstring' (string expected, got {})",
+            state.arg_type_name(1)
+        );
+    }
+    lib_load(state)
+}
+
+// loadfile(filename): the file compiled into a function, or nil and the
+// message of the error reading or compiling it
+fn lib_loadfile(state: &mut ExeState) -> anyhow::Result<i32> {
+    let path = match String::try_from(state.arg(1)) {
+        Ok(path) => path,
+        Err(_) => bail!(
+            "bad argument #1 to 'loadfile' (string expected, got {})",
+            state.arg_type_name(1)
+        ),
+    };
+    let source = state
+        .sandbox()
+        .check_path(std::path::Path::new(&path))
+        .and_then(|()| Ok(std::fs::read(&path)?));
+    match source {
+        Ok(source) => load_chunk(state, source, &format!("@{path}")),
+        Err(err) => {
+            state.push(Value::Nil);
+            state.push(format!("cannot open {path}: {err}").into());
+            Ok(2)
+        }
+    }
+}
+
+/// Push the function of chunk `source`, or nil and the message of its
+/// syntax error. Chunk names are those of the reference: `=name` and
+/// `@file` name it as they are, others are the source to quote.
+fn load_chunk(state: &mut ExeState, source: Vec<u8>, name: &str) -> anyhow::Result<i32> {
+    let chunk_name = match name.strip_prefix(['=', '@']) {
+        Some(name) => name.to_string(),
+        None => string_chunk_name(name),
+    };
+    let options = ParseOptions {
+        chunk_name,
+        ..Default::default()
+    };
+    match state.load(Cursor::new(source), options) {
+        Ok(proto) => {
+            let f = LuaClosure::new(Rc::new(proto), Vec::new());
+            state.push(Value::LuaClosure(Rc::new(f)));
+            Ok(1)
+        }
+        Err(err) => {
+            state.push(Value::Nil);
+            state.push(err.to_string().into());
+            Ok(2)
+        }
+    }
+}
+
+// warn(msg1, ...)
+fn lib_warn(state: &mut ExeState) -> anyhow::Result<i32> {
+    let mut msg = String::new();
+    for i in 1..=state.get_top().max(1) {
+        match String::try_from(state.arg(i)) {
+            Ok(s) => msg.push_str(&s),
+            Err(_) => bail!(
+                "bad argument #{i} to 'warn' (string expected, got {})",
+                state.arg_type_name(i)
+            ),
+        }
+    }
+    state.warn(&msg);
+    Ok(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::Cell, io::Cursor};
+
+    use super::*;
+
+    /// The message of runtime error `err`, without its position.
+    fn message(err: anyhow::Error) -> String {
+        err.downcast::<RuntimeError>().unwrap().message
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let src = b"local a = 1 local b = 2 local c = 3".to_vec();
+        let proto = ParseProto::load(Cursor::new(src)).unwrap();
+
+        let mut state = ExeState::builder().max_stack_size(3).build();
+        assert!(state.execute(proto.clone()).is_ok());
+
+        let mut state = ExeState::builder().stack_size(1).max_stack_size(2).build();
+        let err = state.execute(proto).unwrap_err();
+        assert_eq!(err.to_string(), "stack overflow");
+    }
+
+    #[test]
+    fn malformed_proto() {
+        let proto = |byte_codes| ParseProto {
+            constants: Rc::new(["print".into()]),
+            byte_codes,
+            nparams: 0,
+            is_vararg: true,
+            line_defined: 0,
+            protos: Vec::new(),
+            upvalues: Vec::new(),
+            max_stack: 4,
+            spans: Vec::new(),
+            operand_spans: Vec::new(),
+            lines: Vec::new(),
+            locvars: Vec::new(),
+            chunk_name: "?".into(),
+            warnings: Vec::new(),
+        };
+        let mut state = ExeState::new();
+        let err = state
+            .execute(proto(vec![ByteCode::LoadConst(0, 7)]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "?:0: constant index out of bounds");
+        let err = state
+            .execute(proto(vec![ByteCode::GetGlobal(0, 3)]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "?:0: constant index out of bounds");
+        // registers never written are nil
+        let err = ExeState::new()
+            .execute(proto(vec![ByteCode::Move(0, 3), ByteCode::Call(1, 2, 0)]))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "?:0: attempt to call a nil value");
+        let results = state
+            .execute_results(proto(vec![ByteCode::Return(2, 2)]))
+            .unwrap();
+        assert_eq!(results, [Value::Nil, Value::Nil]);
+    }
+
+    #[test]
+    fn globals_table() {
+        let mut state = ExeState::new();
+        let results = state.eval("x = 'a' return _G.x, _G._G").unwrap();
+        assert_eq!(results[0], "a".into());
+        let Value::Table(g) = &results[1] else {
+            panic!("{results:?}");
+        };
+        assert!(Rc::ptr_eq(g, state.globals_table()));
+
+        state
+            .eval("keys = '' for k in pairs(_G) do keys = keys .. k .. ' ' end")
+            .unwrap();
+        let keys = state.get_global("keys");
+        let mut seen: Vec<_> = <&str>::try_from(&keys)
+            .unwrap()
+            .split_whitespace()
+            .collect();
+        seen.sort();
+        let mut names: Vec<_> = state.globals().map(|(name, _)| name).collect();
+        names.sort();
+        assert_eq!(seen, names);
+
+        let results = state
+            .eval("s = '' for k, v in pairs(table.pack('a', 'b')) do s = s .. k .. v end return s")
+            .unwrap();
+        assert_eq!(results, ["1a2bn2".into()]);
+        // bare names and _G see each other's writes
+        let results = state
+            .eval("rawset(_G, 'y', 2) z = 3 return y, rawget(_G, 'z'), rawget(_G, 'nope')")
+            .unwrap();
+        assert_eq!(results, [2.into(), 3.into(), Value::Nil]);
+        assert_eq!(
+            state.eval("rawset(_G, nil, 1)").unwrap_err().to_string(),
+            "[string \"rawset(_G, nil, 1)\"]:1: table index is nil"
+        );
+        assert_eq!(
+            state.eval("rawget('_G', 'x')").unwrap_err().to_string(),
+            "[string \"rawget('_G', 'x')\"]:1: bad argument #1 to 'rawget' (table expected, got string)"
+        );
+        assert_eq!(
+            state.eval("return next(table.pack())").unwrap(),
+            ["n".into(), 0.into()]
+        );
+        assert_eq!(
+            state
+                .eval("next(_G, 'no such key')")
+                .unwrap_err()
+                .to_string(),
+            "[string \"next(_G, 'no such key')\"]:1: invalid key to 'next'"
+        );
+
+        // keys that are not names are keys all the same
+        let proto = |constants: Vec<Value>, byte_codes| ParseProto {
+            constants: constants.into(),
+            byte_codes,
+            nparams: 0,
+            is_vararg: true,
+            line_defined: 0,
+            protos: Vec::new(),
+            upvalues: Vec::new(),
+            max_stack: 1,
+            spans: Vec::new(),
+            operand_spans: Vec::new(),
+            lines: Vec::new(),
+            locvars: Vec::new(),
+            chunk_name: "?".into(),
+            warnings: Vec::new(),
+        };
+        let results = state
+            .execute_results(proto(
+                vec![1.into(), "one".into()],
+                vec![
+                    ByteCode::SetGlobalConst(0, 1),
+                    ByteCode::GetGlobal(0, 0),
+                    ByteCode::Return(0, 1),
+                ],
+            ))
+            .unwrap();
+        assert_eq!(results, ["one".into()]);
+        let err = state
+            .execute(proto(
+                vec![Value::Nil],
+                vec![ByteCode::SetGlobalConst(0, 0)],
+            ))
+            .unwrap_err();
+        assert_eq!(err.to_string(), "?:0: table index is nil");
+    }
+
+    #[test]
+    fn globals_dropped_with_state() {
+        let mut state = ExeState::new();
+        let data = Rc::new(());
+        state.set_global("data", Value::userdata(data.clone(), Value::Nil));
+        state.eval("t = {data = data, g = _G}").unwrap();
+        let globals = Rc::downgrade(state.globals_table());
+        drop(state);
+        assert_eq!(Rc::strong_count(&data), 1);
+        assert!(globals.upgrade().is_none());
+    }
+
+    #[test]
+    fn globals_cache() {
+        let mut state = ExeState::new();
+        // a native function may change a global read before
+        let results = state
+            .eval("x = 0 s = '' for i = 1, 3 do s = s .. x rawset(_G, 'x', i) end return s")
+            .unwrap();
+        assert_eq!(results, ["012".into()]);
+
+        // and so may the embedder, between two steps
+        state.eval("x = 1").unwrap();
+        let proto = ParseProto::load(Cursor::new(b"x = 1 return x".to_vec())).unwrap();
+        state.start(proto).unwrap();
+        state.step().unwrap();
+        let Value::Table(g) = state.get_global("_G") else {
+            unreachable!()
+        };
+        g.borrow_mut().set("x".into(), 2.into()).unwrap();
+        state.step().unwrap();
+        assert_eq!(state.step().unwrap(), Step::Done(vec![2.into()]));
+    }
+
+    #[test]
+    fn metatables() {
+        fn counter(state: &mut ExeState) -> anyhow::Result<i32> {
+            let Value::Integer(n) = state.upvalue(1) else {
+                unreachable!()
+            };
+            state.set_upvalue(1, (n + 1).into());
+            state.push((n + 1).into());
+            Ok(1)
+        }
+
+        let mut state = ExeState::new();
+        let results = state
+            .eval(
+                "meta = table.pack() fallback = table.pack() store = table.pack() x = 1 s = ''
+                rawset(fallback, 'greeting', 'hi')
+                rawset(meta, '__index', fallback)
+                rawset(meta, '__newindex', store)
+                setmetatable(_G, meta)
+                fresh = 1 x = 2
+                return greeting, fresh, rawget(_G, 'fresh'), store.fresh, x, getmetatable(_G)",
+            )
+            .unwrap();
+        assert_eq!(
+            results[..5],
+            ["hi".into(), Value::Nil, Value::Nil, 1.into(), 2.into()]
+        );
+        assert_eq!(results[5], state.get_global("meta"));
+
+        // what `__index` gives is never cached
+        let meta = state.get_global("meta");
+        let Value::Table(meta) = &meta else {
+            unreachable!()
+        };
+        meta.borrow_mut()
+            .set(
+                "__index".into(),
+                Value::native_closure(counter, vec![0.into()]),
+            )
+            .unwrap();
+        let results = state
+            .eval("for i = 1, 3 do s = s .. count end setmetatable(_G, nil) return s, count")
+            .unwrap();
+        assert_eq!(results, ["123".into(), Value::Nil]);
+
+        let string = state.eval("m = getmetatable('') return m.__index").unwrap();
+        assert_eq!(string, [state.get_global("string")]);
+        let errors = [
+            ("setmetatable('x')", "bad argument #1 to 'setmetatable' (table expected, got string)"),
+            ("setmetatable(_G, 1)", "bad argument #2 to 'setmetatable' (nil or table expected)"),
+            (
+                "m = table.pack() rawset(m, '__index', m) setmetatable(m, m) return m.nope",
+                "'__index' chain too long; possibly a loop",
+            ),
+            (
+                "m = table.pack() rawset(m, '__metatable', 'no') setmetatable(m, m) setmetatable(m, nil)",
+                "cannot change a protected metatable",
+            ),
+        ];
+        for (src, msg) in errors {
+            assert_eq!(message(state.eval(src).unwrap_err()), msg, "{src}");
+        }
+        assert_eq!(state.eval("return getmetatable(m)").unwrap(), ["no".into()]);
+    }
+
+    #[test]
+    fn userdata() {
+        struct Vector(LuaFloat, LuaFloat);
+
+        fn vector(state: &ExeState, i: usize) -> anyhow::Result<(LuaFloat, LuaFloat)> {
+            match state.arg(i) {
+                Value::UserData(u) => match u.borrow::<Vector>() {
+                    Some(v) => Ok((v.0, v.1)),
+                    None => bail!("not a vector"),
+                },
+                _ => bail!("not a vector"),
+            }
+        }
+        // vector(x, y), with the metatable in its upvalue
+        fn new(state: &mut ExeState) -> anyhow::Result<i32> {
+            let (Value::Float(x), Value::Float(y)) = (state.arg(1).clone(), state.arg(2).clone())
+            else {
+                bail!("coordinates expected");
+            };
+            let v = Value::userdata(Vector(x, y), state.upvalue(1));
+            state.push(v);
+            Ok(1)
+        }
+        fn add(state: &mut ExeState) -> anyhow::Result<i32> {
+            let ((ax, ay), (bx, by)) = (vector(state, 1)?, vector(state, 2)?);
+            let Value::UserData(u) = state.arg(1) else {
+                unreachable!()
+            };
+            let v = Value::userdata(Vector(ax + bx, ay + by), u.metatable.clone());
+            state.push(v);
+            Ok(1)
+        }
+        fn index(state: &mut ExeState) -> anyhow::Result<i32> {
+            let (x, y) = vector(state, 1)?;
+            let v = match <&str>::try_from(state.arg(2)) {
+                Ok("x") => x.into(),
+                Ok("y") => y.into(),
+                _ => Value::Nil,
+            };
+            state.push(v);
+            Ok(1)
+        }
+        fn newindex(state: &mut ExeState) -> anyhow::Result<i32> {
+            let (Value::UserData(u), Value::Float(f)) = (state.arg(1), state.arg(3)) else {
+                bail!("number expected");
+            };
+            let mut v = u.borrow_mut::<Vector>().unwrap();
+            match <&str>::try_from(state.arg(2)) {
+                Ok("x") => v.0 = *f,
+                Ok("y") => v.1 = *f,
+                _ => bail!("no such field"),
+            }
+            Ok(0)
+        }
+        fn tostring(state: &mut ExeState) -> anyhow::Result<i32> {
+            let (x, y) = vector(state, 1)?;
+            state.push(format!("({x}, {y})").into());
+            Ok(1)
+        }
+        fn eq(state: &mut ExeState) -> anyhow::Result<i32> {
+            let eq = vector(state, 1)? == vector(state, 2)?;
+            state.push(eq.into());
+            Ok(1)
+        }
+        fn lt(state: &mut ExeState) -> anyhow::Result<i32> {
+            let norm = |(x, y): (LuaFloat, LuaFloat)| x * x + y * y;
+            let lt = norm(vector(state, 1)?) < norm(vector(state, 2)?);
+            state.push(lt.into());
+            Ok(1)
+        }
+
+        let mut meta = Table::new();
+        let methods = [
+            ("__add", Value::Function(add)),
+            ("__index", Value::Function(index)),
+            ("__newindex", Value::Function(newindex)),
+            ("__tostring", Value::Function(tostring)),
+            ("__eq", Value::Function(eq)),
+            ("__lt", Value::Function(lt)),
+        ];
+        for (event, f) in methods {
+            meta.set(event.into(), f).unwrap();
+        }
+        let mut state = ExeState::new();
+        state.set_global("vector", Value::native_closure(new, vec![meta.into()]));
+        let results = state
+            .eval(
+                "local a = vector(1.0, 2.0)
+                local b = vector(3.0, 4.0)
+                local c = a + b
+                c.y = 0.5
+                return type(a), c.x, c.y, c.z, tostring(c), a == vector(1.0, 2.0), a ~= b,
+                    a < b, b < a, a == b, getmetatable(a) == getmetatable(b)",
+            )
+            .unwrap();
+        assert_eq!(
+            results,
+            [
+                "userdata".into(),
+                4.0.into(),
+                0.5.into(),
+                Value::Nil,
+                "(4, 0.5)".into(),
+                true.into(),
+                true.into(),
+                true.into(),
+                false.into(),
+                false.into(),
+                true.into(),
+            ]
+        );
+
+        let Value::UserData(u) = &state.eval("return vector(5.0, 6.0)").unwrap()[0] else {
+            unreachable!()
+        };
+        assert_eq!(u.borrow::<Vector>().map(|v| v.1), Some(6.0));
+        assert!(u.borrow::<String>().is_none());
+
+        // without the metamethods, userdata is opaque
+        state.set_global("opaque", Value::userdata((), Value::Nil));
+        let errors = [
+            (
+                "return opaque.x",
+                "attempt to index a userdata value (global 'opaque')",
+            ),
+            (
+                "opaque.x = 1",
+                "attempt to index a userdata value (global 'opaque')",
+            ),
+            (
+                "return opaque + 1",
+                "attempt to perform arithmetic on a userdata value (global 'opaque')",
+            ),
+            (
+                "return opaque < opaque",
+                "attempt to compare two userdata values",
+            ),
+            (
+                "return vector(1.0, 2.0) <= vector(1.0, 2.0)",
+                "attempt to compare two userdata values",
+            ),
+        ];
+        for (src, msg) in errors {
+            assert_eq!(message(state.eval(src).unwrap_err()), msg, "{src}");
+        }
+        assert_eq!(
+            state.eval("return opaque == opaque").unwrap(),
+            [true.into()]
+        );
+    }
+
+    #[test]
+    fn finalizers() {
+        // a resource, which says when it is released
+        struct Handle(Rc<Cell<bool>>);
+        impl Drop for Handle {
+            fn drop(&mut self) {
+                self.0.set(true);
+            }
+        }
+
+        fn new(state: &mut ExeState) -> anyhow::Result<i32> {
+            let Value::UserData(u) = state.upvalue(1) else {
+                unreachable!()
+            };
+            let released = u.borrow::<Rc<Cell<bool>>>().unwrap().clone();
+            let v = state.userdata(Handle(released), state.get_global("meta"));
+            state.push(v);
+            Ok(1)
+        }
+
+        let released = Rc::new(Cell::new(false));
+        let mut state = ExeState::builder().warnings(true).build();
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let sink = messages.clone();
+        state.set_warn_handler(move |msg| sink.borrow_mut().push(msg.to_string()));
+        let flag = Value::userdata(released.clone(), Value::Nil);
+        state.set_global("new", Value::native_closure(new, vec![flag]));
+
+        // without `__gc`, the data goes with the last reference
+        state.eval("local h = new() h = nil").unwrap();
+        assert!(released.get());
+
+        // with it, it goes after the finalizer, which runs before the next
+        // call, or when the chunk returns
+        released.set(false);
+        let results = state
+            .eval(
+                "log = '' meta = {__gc = function(h) log = log .. type(h) .. ' ' end} \
+                 local h = new() h = nil log = log .. 'set ' tostring(1) \
+                 local h2 = new() h2 = nil \
+                 return log",
+            )
+            .unwrap();
+        assert_eq!(results, ["set userdata ".into()]);
+        assert!(released.get());
+        assert_eq!(state.get_global("log"), "set userdata userdata ".into());
+
+        // an error in one is a warning
+        state
+            .eval("meta.__gc = function() error('boom', 0) end local h = new() h = nil")
+            .unwrap();
+        assert_eq!(*messages.borrow(), ["error in __gc (boom)"]);
+    }
+
+    #[test]
+    fn finalizers_per_state() {
+        fn new(state: &mut ExeState) -> anyhow::Result<i32> {
+            let v = state.userdata((), state.get_global("meta"));
+            state.push(v);
+            Ok(1)
+        }
+
+        let mut a = ExeState::new();
+        let mut b = ExeState::new();
+        for (state, name) in [(&mut a, "a"), (&mut b, "b")] {
+            state.set_global("new", Value::Function(new));
+            state.set_global("name", name.into());
+            state
+                .eval("log = '' meta = {__gc = function() log = log .. name end}")
+                .unwrap();
+        }
+
+        // each finalizer runs in the state of its userdata, whichever
+        // drops it
+        a.eval("h = new()").unwrap();
+        let h = a.get_global("h");
+        a.eval("h = nil").unwrap();
+        b.set_global("h", h);
+        b.eval("h = nil tostring(1)").unwrap();
+        assert_eq!(b.get_global("log"), "".into());
+        a.eval("tostring(1)").unwrap();
+        assert_eq!(a.get_global("log"), "a".into());
+    }
+
+    #[test]
+    fn finalizers_on_drop() {
+        fn count(state: &mut ExeState) -> anyhow::Result<i32> {
+            let Value::UserData(u) = state.upvalue(1) else {
+                unreachable!()
+            };
+            let counter = u.borrow::<Rc<Cell<u32>>>().unwrap();
+            counter.set(counter.get() + 1);
+            Ok(0)
+        }
+
+        let counter = Rc::new(Cell::new(0_u32));
+        let mut state = ExeState::new();
+        let upvalue = Value::userdata(counter.clone(), Value::Nil);
+        state.set_global("count", Value::native_closure(count, vec![upvalue]));
+        let meta = state
+            .eval("local count = count return {__gc = function() count() end}")
+            .unwrap()
+            .remove(0);
+        let u = state.userdata((), meta);
+        state.set_global("u", u);
+        // the globals go first, and the userdata with them
+        drop(state);
+        assert_eq!(counter.get(), 1);
+    }
+
+    #[test]
+    fn tables_are_compared_by_identity() {
+        let mut state = ExeState::new();
+        // a table keyed by itself, twice, compares keys without borrowing
+        // the table being written
+        let results = state
+            .eval(
+                "t = table.pack() rawset(t, t, t) rawset(t, t, t) u = table.pack() \
+                 return rawget(t, t), rawget(t, u)",
+            )
+            .unwrap();
+        let (t, u) = (state.get_global("t"), state.get_global("u"));
+        assert_eq!(results, [t.clone(), Value::Nil]);
+        // not the same table, though both are tables
+        assert_ne!(t, u);
+        assert_eq!(t, t.clone());
+
+        // clearing every key during a traversal
+        let results = state
+            .eval(
+                "rawset(t, 'k', 1) rawset(t, 1, 2) s = '' \
+                 for k in pairs(t) do rawset(t, k, nil) s = s .. 'x' end \
+                 return s, next(t)",
+            )
+            .unwrap();
+        assert_eq!(results, ["xxxx".into(), Value::Nil]);
+    }
+
+    #[cfg(feature = "deterministic")]
+    #[test]
+    fn deterministic() {
+        // tables as keys hash by address, which differs between states
+        let src = "t = table.pack() s = '' \
+                   for i = 1, 3 do rawset(t, table.pack(), i) rawset(t, 'k' .. i, i) end \
+                   for k, v in pairs(t) do s = s .. v .. ' ' end \
+                   return s, math.random(0), os.time(), os.clock()";
+        let results = ExeState::new().eval(src).unwrap();
+        assert_eq!(results[0], "0 1 1 2 2 3 3 ".into());
+        assert_eq!(results[2..], [0.into(), 0.0.into()]);
+        for _ in 0..10 {
+            assert_eq!(ExeState::new().eval(src).unwrap(), results);
+        }
+    }
+
+    #[test]
+    fn step() {
+        let src = "local a = 1\nx = a\nfor i = 1, 2 do y = i end\nreturn a, x, y";
+        let load = || ParseProto::load(Cursor::new(src.as_bytes().to_vec())).unwrap();
+        let expected = ExeState::new().execute_results(load()).unwrap();
+
+        let mut state = ExeState::new();
+        state.start(load()).unwrap();
+        let mut steps = Vec::new();
+        let results = loop {
+            match state.step().unwrap() {
+                Step::Paused { pc, line } => steps.push((pc, line)),
+                Step::Done(results) => break results,
+            }
+        };
+        assert_eq!(results, expected);
+        assert_eq!(steps[..2], [(1, 2), (2, 3)]);
+        // the loop goes back to its body
+        assert!(steps.windows(2).any(|w| w[1].0 < w[0].0));
+        assert_eq!(state.step().unwrap_err().to_string(), "no chunk to step");
+
+        let proto = ParseProto::load(Cursor::new(b"x = 1 nothing()".to_vec())).unwrap();
+        state.start(proto).unwrap();
+        assert_eq!(state.step().unwrap(), Step::Paused { pc: 1, line: 1 });
+        assert_eq!(state.get_global("x"), 1.into());
+        let err = loop {
+            if let Err(err) = state.step() {
+                break err;
+            }
+        };
+        assert_eq!(
+            err.to_string(),
+            "attempt to call a nil value (global 'nothing')"
+        );
+        assert_eq!(state.step().unwrap_err().to_string(), "no chunk to step");
+    }
+
+    #[test]
+    fn strings_are_normalized() {
+        let mut state = ExeState::new();
+        // lengths on both sides of each representation's limit
+        let results = state
+            .eval(
+                "local s = 'abcdefghijklmnopqrstuvwxyz' \
+                 local l = string.rep(s, 3) \
+                 return s, l, 'abcdefghijklmn', 'abcdefghijklmno', \
+                 s .. s, string.sub(l, 1, 14), string.sub(l, 1, 15), string.sub(l, 1, 47), \
+                 string.sub(l, 1, 48), string.sub(l, 40), string.upper(l), string.rep('ab', 7), \
+                 string.format('%s', l), string.gsub(l, 'a', 'b'), tostring(s), \
+                 table.concat(table.pack(s, s)), string.reverse(s)",
+            )
+            .unwrap();
+        for v in results {
+            if v.str_repr().is_some() {
+                assert_eq!(v.str_repr(), v.clone().normalize().str_repr(), "{v:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn argument_errors() {
+        let mut state = ExeState::new();
+        let error = |state: &mut ExeState, src| message(state.eval(src).unwrap_err());
+        assert_eq!(
+            error(&mut state, "type()"),
+            "bad argument #1 to 'type' (value expected)"
+        );
+        assert_eq!(
+            error(&mut state, "warn()"),
+            "bad argument #1 to 'warn' (string expected, got no value)"
+        );
+        assert_eq!(
+            error(&mut state, "warn('a', nil)"),
+            "bad argument #2 to 'warn' (string expected, got nil)"
+        );
+        assert_eq!(
+            error(&mut state, "tonumber(1, 16)"),
+            "bad argument #1 to 'tonumber' (string expected, got number)"
+        );
+    }
+
+    #[test]
+    fn eval() {
+        let mut state = ExeState::new();
+        assert!(state.eval("x = 5").unwrap().is_empty());
+        let results = state.eval("local a = 'a' return x, a, nil;").unwrap();
+        assert_eq!(results, [Value::Integer(5), "a".into(), Value::Nil]);
+        assert!(state.eval("return").unwrap().is_empty());
+        assert!(state.eval("return 1 x = 2").is_err());
+    }
+
+    #[test]
+    fn load() {
+        let mut state = ExeState::new();
+        let results = state
+            .eval("local f = load('return ...') return f(1, 2)")
+            .unwrap();
+        assert_eq!(results, [1.into(), 2.into()]);
+        // failures are results, never errors
+        let results = state.eval("return load('x = = 1')").unwrap();
+        assert_eq!(
+            results,
+            [
+                Value::Nil,
+                "[string \"x = = 1\"]:1: invalid argument near '='".into()
+            ]
+        );
+        let results = state.eval("return loadstring('x =', '=name')").unwrap();
+        assert_eq!(results[1], "name:1: invalid argument near <eof>".into());
+        let results = state
+            .eval("return load(function() error('reader', 0) end)")
+            .unwrap();
+        assert_eq!(results, [Value::Nil, "reader".into()]);
+
+        let path = std::env::temp_dir().join(format!("kailua-load-{}.lua", std::process::id()));
+        std::fs::write(&path, "return 'from file'").unwrap();
+        state.set_global("path", path.display().to_string().as_str().into());
+        let results = state.eval("return loadfile(path)()").unwrap();
+        assert_eq!(results, ["from file".into()]);
+        std::fs::remove_file(&path).unwrap();
+        let results = state.eval("return loadfile(path)").unwrap();
+        assert_eq!(results[0], Value::Nil);
+        assert!(<&str>::try_from(&results[1])
+            .unwrap()
+            .starts_with("cannot open "));
+    }
+
+    #[test]
+    fn concat() {
+        let mut state = ExeState::new();
+        let results = state
+            .eval("local s = 'x' s = s .. s .. 1 return s, 2 .. 0.5")
+            .unwrap();
+        assert_eq!(results, ["xx1".into(), "20.5".into()]);
+        let err = state.eval("return 'a' .. true").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"return 'a' .. true\"]:1: attempt to concatenate a boolean value"
+        );
+        let err = state.eval("return 'a' .. undefined .. 'b'").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"return 'a' .. undefined .. 'b'\"]:1: attempt to concatenate a nil value (global 'undefined')"
+        );
+
+        // the metamethod sees the operands in order, whichever has it
+        fn types(state: &mut ExeState) -> anyhow::Result<i32> {
+            let s = format!("{}|{}", state.arg_type_name(1), state.arg_type_name(2));
+            state.push(s.into());
+            Ok(1)
+        }
+        if let Value::Table(meta) = &state.string_meta {
+            meta.borrow_mut()
+                .set("__concat".into(), Value::Function(types))
+                .unwrap();
+        }
+        let results = state
+            .eval("return 'a' .. nil, nil .. 'b', 'x' .. nil .. 'y'")
+            .unwrap();
+        assert_eq!(
+            results,
+            [
+                "string|nil".into(),
+                "nil|string".into(),
+                "xnil|string".into()
+            ]
+        );
+        // right associative: `true .. nil` comes first, and has no metamethod
+        let err = state.eval("return 'a' .. true .. nil").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"return 'a' .. true .. nil\"]:1: attempt to concatenate a boolean value"
+        );
+    }
+
+    #[test]
+    fn numeric_for() {
+        let mut state = ExeState::new();
+        state.set_global("neg", (-1).into());
+        state.set_global("min", LuaInt::MIN.into());
+        state.set_global("max", LuaInt::MAX.into());
+        state.set_global("tiny", LuaFloat::MIN.into());
+        let run = |state: &mut ExeState, src: &str| {
+            let src = format!("local s = '' {src} return s");
+            state.eval(&src).map(|r| r[0].to_string())
+        };
+        assert_eq!(
+            run(&mut state, "for i = 3, 1, neg do s = s .. i end").unwrap(),
+            "321"
+        );
+        // the count of iterations does not overflow
+        assert_eq!(
+            run(&mut state, "for i = max, min, min do s = s .. i .. ' ' end").unwrap(),
+            format!("{} -1 ", LuaInt::MAX)
+        );
+        assert_eq!(
+            run(&mut state, "for i = min, min, neg do s = s .. i end").unwrap(),
+            LuaInt::MIN.to_string()
+        );
+        assert_eq!(
+            run(&mut state, "for i = 1, 3.5, 2 do s = s .. i end").unwrap(),
+            "13"
+        );
+        assert_eq!(
+            run(&mut state, "for i = 1, tiny do s = s .. i end").unwrap(),
+            ""
+        );
+        assert_eq!(
+            run(
+                &mut state,
+                "for i = 1, math.maxinteger, 2 do s = s .. i if i > 4 then break end end"
+            )
+            .unwrap(),
+            "135"
+        );
+
+        let err = |state: &mut ExeState, src: &str| message(run(state, src).unwrap_err());
+        assert_eq!(
+            err(&mut state, "for i = 'a', 2 do end"),
+            "bad 'for' initial value (number expected, got string)"
+        );
+        assert_eq!(
+            err(&mut state, "for i = 1, 'x' do end"),
+            "bad 'for' limit (number expected, got string)"
+        );
+        assert_eq!(
+            err(&mut state, "for i = 1.5, 2, nil do end"),
+            "bad 'for' step (number expected, got nil)"
+        );
+        assert_eq!(
+            err(&mut state, "for i = 1, 2, 0.0 do end"),
+            "'for' step is zero"
+        );
+    }
+
+    #[test]
+    fn interrupt() {
+        let flag = Arc::new(AtomicBool::new(false));
+        let mut state = ExeState::builder().interrupt(flag.clone()).build();
+        assert!(state.eval("x = 1").is_ok());
+
+        flag.store(true, Ordering::Relaxed);
+        let err = state
+            .eval("for i = 1, 9223372036854775807 do end")
+            .unwrap_err();
+        assert_eq!(err.to_string(), "interrupted!");
+        // with where it stopped
+        assert_eq!(
+            state.take_traceback().unwrap(),
+            "stack traceback:\n\t[string \"for i = 1, 9223372036854775807 do end\"]:1: in main chunk"
+        );
+        // cleared, so the next script runs
+        assert!(!flag.load(Ordering::Relaxed));
+        assert!(state.eval("x = 2").is_ok());
+        assert_eq!(state.take_traceback(), None);
+    }
+
+    #[test]
+    fn uncaught_traceback() {
+        let mut state = ExeState::new();
+        let src = "local function f() error('boom') end f()";
+        assert!(state.eval(src).is_err());
+        assert_eq!(
+            state.take_traceback().unwrap(),
+            "stack traceback:\
+             \n\t[C]: in function 'error'\
+             \n\t[string \"local function f() error('boom') end f()\"]:1: \
+             in function <[string \"local function f() error('boom') end f()\"]:1>\
+             \n\t[string \"local function f() error('boom') end f()\"]:1: in main chunk"
+        );
+        assert_eq!(state.take_traceback(), None);
+        // none for an error caught
+        let results = state.eval("return pcall(error, 'x')").unwrap();
+        assert_eq!(results[0], false.into());
+        assert_eq!(state.take_traceback(), None);
+    }
+
+    #[test]
+    fn return_call_results() {
+        let mut state = ExeState::new();
+        let out = state.with_captured_output(|state| {
+            assert!(state.eval("return print(1)").unwrap().is_empty());
+            let results = state.eval("return print(2), inspect('a')").unwrap();
+            assert_eq!(results, [Value::Nil, "\"a\"".into()]);
+        });
+        assert_eq!(out, "1\n2\n");
+    }
+
+    #[test]
+    fn native_closure_upvalues() {
+        fn counter(state: &mut ExeState) -> anyhow::Result<i32> {
+            let Value::Integer(n) = state.upvalue(1) else {
+                unreachable!()
+            };
+            state.set_upvalue(1, Value::Integer(n + 1));
+            state.push(Value::Integer(n));
+            // past the last upvalue
+            state.push(state.upvalue(2));
+            Ok(2)
+        }
+        let mut state = ExeState::new();
+        let closure = Value::native_closure(counter, vec![Value::Integer(10)]);
+        state.set_global("a", closure.clone());
+        state.set_global("b", closure);
+        let results = state.eval("a() b() return a()").unwrap();
+        assert_eq!(results, [Value::Integer(12), Value::Nil]);
+        // each closure has its own upvalues
+        state.set_global("c", Value::native_closure(counter, vec![0.into()]));
+        assert_eq!(state.eval("return c()").unwrap()[0], Value::Integer(0));
+        assert_eq!(state.upvalue(1), Value::Nil);
+    }
+
+    #[test]
+    fn select_from_end() {
+        let mut state = ExeState::new();
+        // no unary minus in the language yet
+        state.set_global("neg", (-1).into());
+        state.set_global("far", (-4).into());
+        let results = state.eval("return select(neg, 'a', nil, 'c')").unwrap();
+        assert_eq!(results, ["c".into()]);
+        let results = state.eval("return select(2, 'a', nil, nil)").unwrap();
+        assert_eq!(results, [Value::Nil, Value::Nil]);
+        let err = state.eval("return select(far, 'a', 'b')").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"return select(far, 'a', 'b')\"]:1: bad argument #1 to 'select' (index out of range)"
+        );
+    }
+
+    #[test]
+    fn error_objects() {
+        let mut state = ExeState::new();
+        let results = state.eval("return pcall(assert, false, string)").unwrap();
+        let (Value::Table(raised), Value::Table(lib)) = (&results[1], state.get_global("string"))
+        else {
+            panic!("{results:?}");
+        };
+        assert!(Rc::ptr_eq(raised, &lib));
+
+        let err = state.eval("assert(false, io)").unwrap_err();
+        assert_eq!(err.to_string(), "(error object is a table value)");
+        let err = state.eval("assert(nil, 1.5)").unwrap_err();
+        assert_eq!(err.to_string(), "1.5");
+    }
+
+    #[test]
+    fn error_positions() {
+        let mut state = ExeState::new();
+        let mut result = None;
+        let out = state.with_captured_output(|state| {
+            result = Some(state.eval("print(1)\nerror('boom')"));
+        });
+        assert_eq!(out, "1\n");
+        let err = result.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "[string \"print(1)...\"]:2: boom");
+        let err = state.eval("error('boom', 2)").unwrap_err();
+        assert_eq!(err.to_string(), "boom");
+        let results = state.eval("return pcall(error, 'up', 2)").unwrap();
+        assert_eq!(
+            results,
+            [
+                false.into(),
+                "[string \"return pcall(error, 'up', 2)\"]:1: up".into()
+            ]
+        );
+
+        // syntax errors too
+        let err = state.eval("print(1)\nlocal x = = 2").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"print(1)...\"]:2: invalid argument near '='"
+        );
+        assert!(err.is::<SyntaxError>());
+    }
+
+    #[test]
+    fn nested_execute() {
+        let mut state = ExeState::new();
+        state.set_global(
+            "run",
+            Value::Function(|state| {
+                let source = String::try_from(state.arg(1))?;
+                let results = state.eval(&source)?;
+                let n = results.len();
+                for v in results {
+                    state.push(v);
+                }
+                Ok(n as i32)
+            }),
+        );
+        let src = "local a = 'outer' \
+                   local b = run(\"local a = 'inner' t = debug.traceback() return a\") \
+                   return a, b";
+        let results = state.eval(src).unwrap();
+        assert_eq!(results, ["outer".into(), "inner".into()]);
+        assert_eq!(
+            state.get_global("t"),
+            "stack traceback:\
+             \n\t[string \"local a = 'inner' t = debug.traceback() retur...\"]:1: in main chunk\
+             \n\t[C]: in function 'run'\
+             \n\t[string \"local a = 'outer' local b = run(\"local a = 'i...\"]:1: \
+             in main chunk"
+                .into()
+        );
+    }
+
+    #[test]
+    fn xpcall_handler_sees_frames() {
+        let mut state = ExeState::new();
+        let results = state
+            .eval("return xpcall(string.rep, debug.traceback, 'x')")
+            .unwrap();
+        assert_eq!(
+            results[1],
+            "bad argument #2 to 'string.rep' (number expected, got no value)\n\
+             stack traceback:\n\
+             \t[C]: in function 'string.rep'\n\
+             \t[C]: in function 'xpcall'\n\
+             \t[string \"return xpcall(string.rep, debug.traceback, 'x...\"]:1: in main chunk"
+                .into()
+        );
+        // the frames are gone once it returns
+        let results = state.eval("return debug.traceback()").unwrap();
+        assert_eq!(
+            results,
+            ["stack traceback:\n\t[string \"return debug.traceback()\"]:1: in main chunk".into()]
+        );
+    }
+
+    #[test]
+    fn stats() {
+        let src = b"local a = 1 print(a)".to_vec();
+        let proto = ParseProto::load(Cursor::new(src)).unwrap();
+
+        let mut state = ExeState::new();
+        let out = state.with_captured_output(|state| state.execute(proto.clone()).unwrap());
+        assert_eq!(out, "1\n");
+        assert!(state.stats().is_none());
+
+        let mut state = ExeState::builder().stats(true).build();
+        let out = state.with_captured_output(|state| state.execute(proto).unwrap());
+        assert_eq!(out, "1\n");
+        let stats = state.stats().unwrap();
+        assert_eq!(stats.total_instructions(), 4);
+        assert_eq!(stats.instructions["Call"], 1);
+        assert_eq!(stats.calls, 1);
+    }
+
+    #[test]
+    fn lua_functions() {
+        let mut state = ExeState::new();
+        // missing arguments are nil, extra ones dropped or left to `...`
+        let results = state
+            .eval(
+                "local function f(a, b) return b, a end \
+                 local function g(a, ...) return select('#', ...), ... end \
+                 return f(1), f(1, 2, 3), g(), g(1, 2, 3)",
+            )
+            .unwrap();
+        let expected: [Value; 6] = [Value::Nil, 2.into(), 0.into(), 2.into(), 2.into(), 3.into()];
+        assert_eq!(results, expected);
+
+        // each call has registers of its own
+        let results = state
+            .eval(
+                "function inner(s) local t = s .. '!' return t, t end \
+                 function outer(s) local a = s local b = inner(a .. '?') return a, b end \
+                 return outer('x')",
+            )
+            .unwrap();
+        assert_eq!(results, ["x".into(), "x?!".into()]);
+        let results = state
+            .eval(
+                "function grow(s) for _ = string.len(s), 9 do return grow(s .. 'a') end return s end \
+                 return grow('')",
+            )
+            .unwrap();
+        assert_eq!(results, ["aaaaaaaaaa".into()]);
+
+        let results = state
+            .eval("function loop() return loop() end return pcall(loop)")
+            .unwrap();
+        assert_eq!(
+            results,
+            [
+                false.into(),
+                "[string \"function loop() return loop() end return pcal...\"]:1: stack overflow"
+                    .into()
+            ]
+        );
+        // the stack is back to where the call started
+        assert_eq!(
+            state.eval("return inner('y')").unwrap(),
+            ["y!".into(), "y!".into()]
+        );
+
+        let results = state
+            .eval("function f() return debug.traceback() end return f()")
+            .unwrap();
+        assert_eq!(
+            results,
+            ["stack traceback:\
+              \n\t[string \"function f() return debug.traceback() end ret...\"]:1: in function 'f'\
+              \n\t[string \"function f() return debug.traceback() end ret...\"]:1: in main chunk"
+                .into()]
+        );
+    }
+
+    #[test]
+    fn deep_calls() {
+        let down = "local function down(n, ...) \
+                      if n > 0 then return down(n - 1, ...) end \
+                      return select('#', ...) \
+                    end ";
+        let mut state = ExeState::new();
+        let results = state
+            .eval(&format!("{down} return down(10000, 1, 2)"))
+            .unwrap();
+        assert_eq!(results, [2.into()]);
+
+        let mut state = ExeState::builder().max_calls(100).build();
+        // the main chunk, `pcall`, the calls of `down` and `select`
+        let results = state
+            .eval(&format!("{down} return pcall(down, 96)"))
+            .unwrap();
+        assert_eq!(results, [true.into(), 0.into()]);
+        let results = state
+            .eval(&format!("{down} return pcall(down, 97)"))
+            .unwrap();
+        assert_eq!(results[0], false.into());
+        let msg = String::try_from(&results[1]).unwrap();
+        assert!(msg.ends_with("...\"]:1: stack overflow"), "{msg}");
+
+        // metamethods calling Lua functions recurse in Rust
+        let mut state = ExeState::new();
+        let results = state
+            .eval(
+                "local t = setmetatable({}, {__index = function(t, k) return t[k] end}) \
+                   return pcall(function() return t.x end)",
+            )
+            .unwrap();
+        assert_eq!(results[0], false.into());
+        let msg = String::try_from(&results[1]).unwrap();
+        assert!(msg.ends_with("...\"]:1: C stack overflow"), "{msg}");
+    }
+
+    #[test]
+    fn upvalues() {
+        let mut state = ExeState::new();
+        // both closures see the one local, open while `pair` runs and
+        // closed after
+        let results = state
+            .eval(
+                "function pair() local s = 'a' \
+                 add = function(x) s = s .. x end get = function() return s end \
+                 add('b') return get() end \
+                 local during = pair() add('c') return during, get()",
+            )
+            .unwrap();
+        assert_eq!(results, ["ab".into(), "abc".into()]);
+        assert!(state.open_upvalues.is_empty());
+
+        let error = state.eval("local x = nil function f() return x() end return f()");
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "[string \"local x = nil function f() return x() end ret...\"]:1: attempt to call a nil value (upvalue 'x')"
+        );
+    }
+
+    #[test]
+    fn call_error_names_global() {
+        let src = b"local a = 1 print(a) prnt(a)".to_vec();
+        let proto = ParseProto::load(Cursor::new(src)).unwrap();
+        let mut result = None;
+        let out = ExeState::new().with_captured_output(|state| result = Some(state.execute(proto)));
+        assert_eq!(out, "1\n");
+        assert_eq!(
+            result.unwrap().unwrap_err().to_string(),
+            "?:1: attempt to call a nil value (global 'prnt')"
+        );
+    }
+
+    #[test]
+    fn errors_name_locals() {
+        let mut state = ExeState::new();
+        let errors = [
+            (
+                "local f = nil f()",
+                "attempt to call a nil value (local 'f')",
+            ),
+            (
+                "local t = nil print(t.x)",
+                "attempt to index a nil value (local 't')",
+            ),
+            (
+                "local a = 1 local s = nil return a .. s",
+                "attempt to concatenate a nil value (local 's')",
+            ),
+            // out of scope, its register is a temporary again
+            (
+                "do local t = 1 end return 1 + {}",
+                "attempt to perform arithmetic on a table value",
+            ),
+        ];
+        for (src, msg) in errors {
+            assert_eq!(message(state.eval(src).unwrap_err()), msg, "{src}");
+        }
+    }
+
+    #[test]
+    fn global_field() {
+        let mut state = ExeState::new();
+        assert_eq!(state.eval("return string.len('abc')").unwrap(), [3.into()]);
+        let err = state.eval("return strng.len('abc')").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"return strng.len('abc')\"]:1: attempt to index a nil value (global 'strng')"
+        );
+    }
+
+    #[test]
+    fn error_spans() {
+        let src = b"local s = 'x' print(s)\nprint(s .. string.nope .. s)".to_vec();
+        let options = ParseOptions {
+            spans: true,
+            chunk_name: "test.lua".into(),
+            ..Default::default()
+        };
+        let mut state = ExeState::new();
+        let proto = state.load(Cursor::new(src), options.clone()).unwrap();
+        let mut result = None;
+        let out = state.with_captured_output(|state| result = Some(state.execute(proto)));
+        assert_eq!(out, "x\n");
+        let err = result.unwrap().unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "test.lua:2:12-22: attempt to concatenate a nil value (field 'nope')"
+        );
+
+        // at the operand the error is about
+        let cases = [
+            ("local t = nil\nreturn 1 + t.x", "test.lua:2:12-12: "),
+            ("local n = 1\nreturn n + {}", "test.lua:2:12-13: "),
+            ("return -{}", "test.lua:1:9-10: "),
+            ("local t = {}\nreturn t.a.b.c", "test.lua:2:8-10: "),
+            ("return string.nope()", "test.lua:1:8-18: "),
+            ("return string.rep()", "test.lua:1:8-19: "),
+        ];
+        for (src, position) in cases {
+            let proto = state.load(Cursor::new(src), options.clone()).unwrap();
+            let err = state.execute(proto).unwrap_err().to_string();
+            assert!(err.starts_with(position), "{src}: {err}");
+        }
+        // which `pcall` gets with the line alone
+        let proto = state
+            .load(
+                Cursor::new("return pcall(function() return 1 + {} end)"),
+                options,
+            )
+            .unwrap();
+        let results = state.execute_results(proto).unwrap();
+        assert_eq!(
+            results[1],
+            "test.lua:1: attempt to perform arithmetic on a table value".into()
+        );
+    }
+
+    #[test]
+    fn native_panic() {
+        let proto = ParseProto::load(Cursor::new(b"boom()".to_vec())).unwrap();
+        let mut state = ExeState::new();
+        state.set_global("boom", Value::Function(|_| panic!("boom")));
+        let err = state.execute(proto).unwrap_err();
+        assert_eq!(err.to_string(), "?:1: native function panicked: boom");
+    }
+
+    #[test]
+    fn redirected_output() {
+        let src = br#"print("a", 1, nil) print(2.5)"#;
+        let proto = ParseProto::load(Cursor::new(src.to_vec())).unwrap();
+        let out = SharedBuffer::default();
+        let mut state = ExeState::builder().output(out.clone()).build();
+        state.execute(proto).unwrap();
+        assert_eq!(*out.0.borrow(), b"a\t1\tnil\n2.5\n");
+    }
+
+    #[test]
+    fn captured_output() {
+        let proto = ParseProto::load(Cursor::new(b"print(1)".to_vec())).unwrap();
+        let out = SharedBuffer::default();
+        let mut state = ExeState::builder().output(out.clone()).build();
+        let captured = state.with_captured_output(|state| state.execute(proto.clone()).unwrap());
+        assert_eq!(captured, "1\n");
+
+        state.execute(proto).unwrap();
+        assert_eq!(*out.0.borrow(), b"1\n");
+    }
+
+    #[test]
+    fn warn() {
+        let src = br#"warn("a", "b") warn("@on") warn("c", "d") warn("@off") warn("e")"#;
+        let proto = ParseProto::load(Cursor::new(src.to_vec())).unwrap();
+
+        let messages = Rc::new(RefCell::new(Vec::new()));
+        let mut state = ExeState::new();
+        let sink = messages.clone();
+        state.set_warn_handler(move |msg| sink.borrow_mut().push(msg.to_string()));
+        state.execute(proto).unwrap();
+        assert_eq!(*messages.borrow(), ["cd"]);
+    }
+
+    #[test]
+    fn strict() {
+        let src = b"x = nil print(x) print(y)".to_vec();
+        let proto = ParseProto::load(Cursor::new(src)).unwrap();
+
+        let mut state = ExeState::builder().strict(true).build();
+        let mut result = None;
+        let out = state.with_captured_output(|state| result = Some(state.execute(proto.clone())));
+        assert_eq!(out, "nil\n");
+        let err = result.unwrap().unwrap_err();
+        assert_eq!(err.to_string(), "?:1: variable 'y' is not declared");
+
+        let mut state = ExeState::builder().strict(true).allow_global("y").build();
+        let out = state.with_captured_output(|state| state.execute(proto).unwrap());
+        assert_eq!(out, "nil\nnil\n");
+
+        // globals set through _G are declared while they have a value
+        let mut state = ExeState::builder().strict(true).build();
+        assert_eq!(
+            state.eval("rawset(_G, 'z', 1) return z").unwrap(),
+            [1.into()]
+        );
+        let err = state.eval("rawset(_G, 'z', nil) return z").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "[string \"rawset(_G, 'z', nil) return z\"]:1: variable 'z' is not declared"
+        );
+    }
 }
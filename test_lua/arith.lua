@@ -0,0 +1,11 @@
+local a = 1
+local b = a + 1
+b = a - 1
+b = a * 2.5
+b = 2 * a
+b = a / b
+b = -a ^ 2
+c = b // 3 % a
+local t = {}
+t[1] = t[1] + 1
+print(1 + 2 * 3, 7 // 2, 'x' .. 1 + 2)
@@ -0,0 +1,7 @@
+local a = 123
+assert_eq(a, 123)
+assert_eq("hello", "hello")
+local b = a
+assert_eq(a, b, "locals differ")
+assert_error(assert_eq, 1, 2)
+assert_error(nil)
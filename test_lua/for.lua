@@ -0,0 +1,4 @@
+for i = 1, 3 do
+    local s = "i=" .. i
+    print(s)
+end
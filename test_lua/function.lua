@@ -0,0 +1,11 @@
+function f(a, b, ...)
+    local c = a .. b
+    print(c, ...)
+    return select('#', ...), ...
+end
+
+local function g()
+    return f('x', 'y', 1, 2)
+end
+
+print(g())
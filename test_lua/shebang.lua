@@ -0,0 +1,2 @@
+#!/usr/bin/env kailua
+print "hello from an executable script"
@@ -0,0 +1,4 @@
+local t = {1, 2, x = 'y', [3] = 'three', ...}
+t.x = t[1]
+t[t.x] = {}
+print(t[1].x, t.y.z)
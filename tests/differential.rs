@@ -0,0 +1,283 @@
+//! Differential fuzzing: generates small random Lua programs in the subset
+//! kailua compiles, runs each in kailua and in the reference interpreter,
+//! and compares their stdout and exit status. The reference interpreter is
+//! the one built by the `reference` feature:
+//!
+//!     cargo test --features reference --test differential
+//!
+//! Without it, `lua5.4` is used if installed, and the test is skipped
+//! otherwise. `KAILUA_REFERENCE_LUA` names another reference interpreter,
+//! `KAILUA_FUZZ_SEED` the first seed and `KAILUA_FUZZ_RUNS` the number of
+//! programs. A program that behaves differently is reduced to the fewest
+//! statements that still do, then reported with its seed.
+//!
+//! The reference numbers are 64 bits wide, so this does not run with the
+//! `int32` or `float32` features.
+#![cfg(not(any(feature = "int32", feature = "float32")))]
+
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[test]
+fn differential() {
+    let Some(reference) = reference() else {
+        eprintln!(
+            "skipped: no reference interpreter; enable the `reference` feature, \
+             install lua5.4 or set KAILUA_REFERENCE_LUA"
+        );
+        return;
+    };
+    let first: u64 = env_number("KAILUA_FUZZ_SEED", 1);
+    let runs: u64 = env_number("KAILUA_FUZZ_RUNS", 500);
+    let dir = env::temp_dir().join(format!("kailua-differential-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let runner = Runner { reference, dir };
+
+    for seed in first..first + runs {
+        let program = Generator::new(seed).program();
+        if runner.same(&program) {
+            continue;
+        }
+        let program = runner.reduce(program);
+        let source = program.join("\n");
+        let (ours, theirs) = (runner.kailua(&source), runner.reference(&source));
+        panic!(
+            "seed {seed} behaves differently:\n{source}\n\
+             kailua: status {}, stdout {:?}\nreference: status {}, stdout {:?}",
+            ours.0, ours.1, theirs.0, theirs.1
+        );
+    }
+    fs::remove_dir_all(&runner.dir).unwrap();
+}
+
+/// The reference interpreter to compare against, if there is one.
+fn reference() -> Option<PathBuf> {
+    if let Ok(path) = env::var("KAILUA_REFERENCE_LUA") {
+        return Some(path.into());
+    }
+    if cfg!(feature = "reference") {
+        return option_env!("CARGO_BIN_EXE_lua-reference").map(PathBuf::from);
+    }
+    Command::new("lua5.4")
+        .arg("-v")
+        .output()
+        .ok()
+        .map(|_| "lua5.4".into())
+}
+
+fn env_number(name: &str, default: u64) -> u64 {
+    env::var(name).map_or(default, |v| v.parse().unwrap())
+}
+
+struct Runner {
+    reference: PathBuf,
+    dir: PathBuf,
+}
+
+impl Runner {
+    fn same(&self, program: &[String]) -> bool {
+        let source = program.join("\n");
+        self.kailua(&source) == self.reference(&source)
+    }
+
+    /// Drop statements one at a time while the program still behaves
+    /// differently.
+    fn reduce(&self, mut program: Vec<String>) -> Vec<String> {
+        let mut i = 0;
+        while i < program.len() {
+            let mut smaller = program.clone();
+            smaller.remove(i);
+            if self.same(&smaller) {
+                i += 1;
+            } else {
+                program = smaller;
+            }
+        }
+        program
+    }
+
+    fn kailua(&self, source: &str) -> (i32, String) {
+        self.run(Path::new(env!("CARGO_BIN_EXE_kailua")), source)
+    }
+
+    fn reference(&self, source: &str) -> (i32, String) {
+        self.run(&self.reference, source)
+    }
+
+    /// Exit status and stdout of `interpreter` running `source`, from the
+    /// directory of the script so that error positions name it alike.
+    fn run(&self, interpreter: &Path, source: &str) -> (i32, String) {
+        fs::write(self.dir.join("prog.lua"), source).unwrap();
+        let output = Command::new(interpreter)
+            .current_dir(&self.dir)
+            .arg("prog.lua")
+            .output()
+            .unwrap_or_else(|err| panic!("cannot run {}: {err}", interpreter.display()));
+        let out = String::from_utf8_lossy(&output.stdout).into_owned();
+        (output.status.code().unwrap_or(-1), out)
+    }
+}
+
+/// Random programs of statements a line each, using only the names in
+/// scope, and printing only values whose text does not depend on
+/// addresses.
+struct Generator {
+    state: u64,
+    locals: Vec<String>,
+    globals: Vec<String>,
+    depth: usize,
+}
+
+const STRINGS: &[&str] = &[
+    "''",
+    "'a'",
+    "'hello world'",
+    "'%d items'",
+    "'x\\ty\\n'",
+    "'\\0\\255'",
+    "'10'",
+    "'0x1p4'",
+    "' 3.5 '",
+    "'a long string that is stored on the heap, past the inline sizes'",
+];
+
+const NUMBERS: &[&str] = &[
+    "0",
+    "1",
+    "2",
+    "3",
+    "10",
+    "255",
+    "1.5",
+    "0.1",
+    "1e300",
+    "0x10",
+    "0xff",
+    "9007199254740993",
+    "9223372036854775807",
+];
+
+impl Generator {
+    fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            locals: Vec::new(),
+            globals: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    /// splitmix64
+    fn next(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+
+    fn program(&mut self) -> Vec<String> {
+        let n = 1 + self.below(12);
+        (0..n).map(|_| self.statement()).collect()
+    }
+
+    fn statement(&mut self) -> String {
+        match self.below(if self.depth < 2 { 5 } else { 4 }) {
+            0 => {
+                let e = self.expr();
+                let name = format!("l{}", self.locals.len());
+                self.locals.push(name.clone());
+                format!("local {name} = {e}")
+            }
+            1 => {
+                let e = self.expr();
+                let name = format!("g{}", self.below(4));
+                if !self.globals.contains(&name) {
+                    self.globals.push(name.clone());
+                }
+                format!("{name} = {e}")
+            }
+            2 => format!("print({}, {})", self.expr(), self.expr()),
+            3 => {
+                let (f, args) = self.call();
+                let args: String = args.iter().map(|a| format!(", {a}")).collect();
+                format!("print(pcall({f}{args}))")
+            }
+            _ => {
+                let (start, limit) = (self.below(4), self.below(6));
+                self.depth += 1;
+                let nlocals = self.locals.len();
+                let var = format!("i{}", self.depth);
+                self.locals.push(var.clone());
+                let body = self.statement();
+                self.locals.truncate(nlocals);
+                self.depth -= 1;
+                format!("for {var} = {start}, {limit} do {body} end")
+            }
+        }
+    }
+
+    /// An expression whose value prints the same in both interpreters.
+    fn expr(&mut self) -> String {
+        match self.below(6) {
+            0 => self.pick(STRINGS).into(),
+            1 => self.pick(NUMBERS).into(),
+            2 => self.variable(),
+            3 => format!("{} .. {}", self.atom(), self.atom()),
+            4 => format!("type({})", self.variable()),
+            _ => {
+                let (f, args) = self.call();
+                format!("{f}({})", args.join(", "))
+            }
+        }
+    }
+
+    fn atom(&mut self) -> String {
+        match self.below(3) {
+            0 => self.pick(STRINGS).into(),
+            1 => self.pick(NUMBERS).into(),
+            _ => self.variable(),
+        }
+    }
+
+    fn variable(&mut self) -> String {
+        let n = self.locals.len() + self.globals.len();
+        match self.below(n + 1) {
+            i if i < self.locals.len() => self.locals[i].clone(),
+            i if i < n => self.globals[i - self.locals.len()].clone(),
+            _ => "nothing".into(),
+        }
+    }
+
+    /// A library function kailua has and its arguments, which may be
+    /// wrong.
+    fn call(&mut self) -> (&'static str, Vec<String>) {
+        let f = self.pick(&[
+            "string.len",
+            "string.upper",
+            "string.lower",
+            "string.rep",
+            "string.reverse",
+            "string.sub",
+            "string.format",
+            "tostring",
+            "tonumber",
+            "select",
+            "type",
+            "error",
+        ]);
+        let nargs = self.below(4);
+        (f, (0..nargs).map(|_| self.atom()).collect())
+    }
+}
@@ -0,0 +1,94 @@
+//! Runs every `tests/golden/NAME.lua` through kailua and compares its stdout
+//! with `NAME.out`, and its exit status with `NAME.status` (0 if absent).
+//!
+//! The expected files are the output of the reference interpreter, Lua 5.4.
+//! With the `reference` feature, which builds it, they are checked against
+//! it, and rewritten from it with:
+//!
+//!     cargo test --features reference --test golden -- --ignored
+//!
+//! The reference numbers are 64 bits wide, so this does not run with the
+//! `int32` or `float32` features.
+#![cfg(not(any(feature = "int32", feature = "float32")))]
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+};
+
+#[test]
+fn golden() {
+    compare(Path::new(env!("CARGO_BIN_EXE_kailua")));
+}
+
+/// The expected files are what the reference gives.
+#[cfg(feature = "reference")]
+#[test]
+fn reference() {
+    compare(Path::new(env!("CARGO_BIN_EXE_lua-reference")));
+}
+
+#[cfg(feature = "reference")]
+#[test]
+#[ignore = "rewrites the expected files; run with --ignored"]
+fn regenerate() {
+    for script in scripts() {
+        let (status, out) = run(Path::new(env!("CARGO_BIN_EXE_lua-reference")), &script);
+        fs::write(script.with_extension("out"), out).unwrap();
+        let status_file = script.with_extension("status");
+        if status == 0 {
+            if status_file.exists() {
+                fs::remove_file(status_file).unwrap();
+            }
+        } else {
+            fs::write(status_file, format!("{status}\n")).unwrap();
+        }
+    }
+}
+
+/// Check that `interpreter` gives the expected output and status for
+/// every script.
+fn compare(interpreter: &Path) {
+    let mut failures = Vec::new();
+    for script in &scripts() {
+        let expected_out = fs::read_to_string(script.with_extension("out")).unwrap();
+        let expected_status = fs::read_to_string(script.with_extension("status"))
+            .map_or(0, |s| s.trim().parse().unwrap());
+        let (status, out) = run(interpreter, script);
+
+        if out != expected_out || status != expected_status {
+            failures.push(format!(
+                "{}:\n  expected status {expected_status}, stdout {expected_out:?}\n  \
+                 got status {status}, stdout {out:?}",
+                script.display()
+            ));
+        }
+    }
+
+    assert!(failures.is_empty(), "\n{}", failures.join("\n"));
+}
+
+fn scripts() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut scripts: Vec<_> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "lua"))
+        .collect();
+    scripts.sort();
+    scripts
+}
+
+/// Exit status and stdout of `interpreter` running `script`, from the
+/// directory of the script so that error positions name it alike in all
+/// interpreters.
+fn run(interpreter: &Path, script: &Path) -> (i32, String) {
+    let output = Command::new(interpreter)
+        .current_dir(script.parent().unwrap())
+        .arg(script.file_name().unwrap())
+        .output()
+        .unwrap();
+    let out = String::from_utf8_lossy(&output.stdout).into_owned();
+    (output.status.code().unwrap_or(-1), out)
+}
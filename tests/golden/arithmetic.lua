@@ -0,0 +1,55 @@
+-- integers stay integers, but for / and ^
+local i = 0
+for _ = 1, 10 do
+  i = i + 1
+end
+print(i, i - 1, i * 2, i / 4, i // 3, i % 3, 2 ^ 10, -i)
+print(7 // -2, -7 % 3, 7.5 // 2, -7.5 % 2, 1 / 0, -1 / 0)
+
+-- precedence and associativity
+print(1 + 2 * 3 - 4 / 2, (1 + 2) * 3, 2 ^ 3 ^ 2, -2 ^ 2, 2 - 3 - 4, 8 // 3 * 3)
+print('10' + 1, '3' * '4', 10 .. 1 + 2, 1 .. 2 + 3 .. 4)
+
+-- operands from locals, upvalues, globals and fields
+local x = 3
+x = x * x + x
+print(x, 1 - x, 2 * x, x - 1.5, 100 // x, x % -7)
+g = 5
+g = g - x * 2
+print(g, -g, g ^ 2)
+local t = {n = 1}
+t.n = t.n + 1
+t[1] = t.n * 10
+t[t[1]] = t[1] - t.n
+print(t.n, t[1], t[20])
+local function inc()
+  x = x + 1
+  return x
+end
+print(inc(), inc())
+
+-- overflow wraps around
+local max = 9223372036854775807
+local min = -max - 1
+print(max + 1, min - 1, min // -1, min % -1, max * 2)
+
+-- errors, without their positions
+local function try(f)
+  print((string.gsub(select(2, pcall(f)), '^[^:]*:%d+: ', '')))
+end
+try(function() return 1 // 0 end)
+try(function() return 1 % 0 end)
+try(function() local s = 'abc' return s + 1 end)
+try(function() return {} * 2 end)
+try(function() return nope - 1 end)
+try(function() return -t end)
+try(function() return t.n + t.missing end)
+
+-- metamethods
+local mt = {
+  __add = function(a, b) return 'added' end,
+  __unm = function(a) return 'negated' end,
+  __mod = function(a, b) return b end,
+}
+local obj = setmetatable({}, mt)
+print(obj + 1, 1 + obj, -obj, obj % 'b')
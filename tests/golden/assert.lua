@@ -0,0 +1,8 @@
+print(assert(1, "unused", nil))
+print(assert("v"))
+print(pcall(assert, false))
+print(pcall(assert, nil, "custom message"))
+print(pcall(assert, false, 42))
+print(pcall(assert, false, nil))
+print(type(select(2, pcall(assert, false, string))))
+print(pcall(assert))
@@ -0,0 +1,14 @@
+local a = 456
+a = 123
+print(a)
+a = a
+print(a)
+a = g
+print(a)
+g = 123
+print(g)
+g = a
+print(g)
+g2 = 234
+g = g2
+print(g)
@@ -0,0 +1,3 @@
+print "before"
+undefined_function "x"
+print "after"
@@ -0,0 +1,4 @@
+local r = print("x")
+print(r)
+g = print("y")
+print(g, r)
@@ -0,0 +1,74 @@
+-- the locals a closure captures outlive the call defining them
+local function counter()
+  local n = ''
+  local function inc()
+    n = n .. '+'
+    return string.len(n)
+  end
+  return inc
+end
+local inc = counter()
+local other = counter()
+print(inc(), inc(), other(), inc())
+
+-- closures made in the same scope share what they capture
+function pair(init)
+  local s = init
+  add = function(x) s = s .. x end
+  get = function() return s end
+end
+pair('a')
+add('b')
+add('c')
+local get1 = get
+pair('z')
+add('y')
+print(get(), get1())
+
+-- and see changes made after they were made
+local function late()
+  local v = 'before'
+  local f = function() return v end
+  v = 'after'
+  return f
+end
+local f = late()
+print(f())
+
+-- through functions in between
+function outer()
+  local x = 'x'
+  return function()
+    return function()
+      x = x .. '!'
+      return x
+    end
+  end
+end
+local middle = outer()
+local g = middle()
+local h = middle()
+print(g(), h(), g())
+
+-- a local function sees itself
+local function down(s)
+  for _ = string.len(s), 3 do
+    return down(s .. '-')
+  end
+  return s
+end
+print(down(''))
+
+-- a loop changing a captured local
+local function loop()
+  local log = ''
+  local function append(x)
+    log = log .. x
+  end
+  for i = 1, 3 do
+    append(i)
+  end
+  append(log)
+  return log
+end
+print(loop())
@@ -0,0 +1,9 @@
+-- a short comment
+print("a") -- after a statement
+--[[ a long
+comment ]] print("b")
+--[==[ with ]] and ]=] inside ]==]
+print("c" --[[ between tokens ]] .. "d")
+---[[ not long, so the next line runs
+print("e")
+--]]
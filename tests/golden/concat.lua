@@ -0,0 +1,7 @@
+local s = "x"
+s = s .. s .. "y"
+print(s)
+print("a" .. 1 .. 2.5, 1 .. 2, 1.0 .. "")
+t = "left" .. " " .. "right"
+print(t, "a" .. "" .. "b")
+print("tail " .. print)
@@ -0,0 +1,9 @@
+print(pcall(error, "plain", 0))
+print(pcall(error, "level 1"))
+print(pcall(error, "level 2", 2))
+print(pcall(error, "level 3", 3))
+print(pcall(error, 42, 2))
+print(pcall(error))
+print(pcall(error, "x", "y"))
+error("uncaught")
+print("not reached")
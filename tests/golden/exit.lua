@@ -0,0 +1,3 @@
+print(string.upper("field") .. math.random(1, 1))
+print(pcall(os.exit, 3, true))
+print("unreachable")
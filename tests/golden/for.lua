@@ -0,0 +1,35 @@
+for i = 1, 3 do print(i) end
+for i = 3, 1 do print("never") end
+for i = math.maxinteger - 7, math.maxinteger, 2 do print(i) end
+for i = math.maxinteger - 1, math.huge do print(i) end
+for i = math.mininteger, math.mininteger + 1 do print(i) end
+local n = 0
+for i = 1, math.maxinteger, 2 do
+  n = n + 1
+  if n == 3 then
+    print(i)
+    break
+  end
+end
+for x = 1, 2, 0.25 do print(x) end
+for i = 1, 2.5 do print(i) end
+for i = 1.0, 3 do print(i) end
+for i = 1, 2 do
+  for j = i, 2 do
+    local s = i .. "," .. j
+    print(s)
+  end
+end
+for i = 1, 3 do
+  goto continue
+  print("skipped")
+  ::continue::
+end
+for i = 1, 10 do
+  print("once", i)
+  goto done
+end
+::done::
+print(i)
+for i = 1, 0, 0 do end
+print("unreachable")
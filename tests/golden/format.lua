@@ -0,0 +1,10 @@
+-- string.format, C conversions and %q
+print(string.format("%5d|%-5d|%05d|%x|%#o", 42, 42, 42, 255, 8))
+print(string.format("%.3f %e %g %g", 3.14159, 12345.678, 0.1, 1e20))
+print(string.format("%10.4s|%-6s|", "abcdefg", "ab"))
+print(string.format("%q", "tab\there\nnew \"quoted\" \\ \0001"))
+print(string.format("%q %q %q", 10, 0.5, false))
+local s = string.format("<%s>", "a\0b")
+print(string.len(s))
+print(pcall(string.format, "%d", 1.5))
+print(pcall(string.format, "%z", 1))
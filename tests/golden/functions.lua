@@ -0,0 +1,52 @@
+-- missing arguments are nil, extra ones are dropped
+function show(a, b, c)
+  print(a, b, c)
+end
+show(1)
+show(1, 2, 3, 4)
+show()
+
+-- extra arguments are `...`
+function count(...)
+  return select('#', ...)
+end
+print(count(), count(nil), count(1, nil, 3))
+
+function rest(a, ...)
+  local b = ...
+  print(a, b, ...)
+  return ...
+end
+print(rest(1, 2, 3))
+print(rest())
+print(table.concat(table.pack(rest(7, 8, 9)), ','))
+
+-- each call has registers of its own
+function outer(a, b)
+  local c = a .. b
+  local d = inner(c, 'x')
+  return a, b, c, d
+end
+function inner(a, b)
+  local c = b .. a
+  return c, 'dropped'
+end
+print(outer('p', 'q'))
+
+-- recursion, counting by the length of a string
+function down(s)
+  for _ = string.len(s), 4 do
+    return down(s .. '+') .. '<' .. s
+  end
+  return 'end'
+end
+print(down(''))
+
+-- functions are values
+local f = function(...) return select('#', ...), ... end
+print(f(f(1, 2)))
+print(type(f), type(function() end))
+local function g() return 'g' end
+print(g())
+print(pcall(function(...) error('got ' .. select('#', ...)) end, 1, 2))
+for k, v in pairs(table.pack(f())) do print(k, v) end
@@ -0,0 +1,29 @@
+-- string.gmatch and the generic for
+for w in string.gmatch("one two  three", "%a+") do
+  print(w)
+end
+for k, v in string.gmatch("a=1, b=2", "(%w+)=(%w+)") do
+  print(k, v)
+end
+for p in string.gmatch("abc", "()") do
+  print(p)
+end
+local s = "the quick brown fox"
+for w in s:gmatch("%a+") do
+  print(w:upper())
+end
+for k, v in ("x=1;y=2"):gmatch("(%a)=(%d)") do
+  print(k, v)
+end
+for w in string.gmatch("hello world from lua", "%a+", 7) do
+  print(w)
+end
+local next_word = string.gmatch("x y", "%a")
+print(next_word())
+print(next_word())
+print(next_word())
+print(type(next_word))
+for a, b, c in string.gmatch("ab", "%a") do
+  print(a, b, c)
+end
+print(pcall(string.gmatch("x", "("), nil))
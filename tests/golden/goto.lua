@@ -0,0 +1,11 @@
+goto forward
+print("skipped")
+::forward::
+print("reached")
+goto done
+::back::
+print("never")
+goto back
+local x = "in scope"
+print(x)
+::done::
@@ -0,0 +1,11 @@
+-- string.gsub with string, function and table replacements
+print(string.gsub("hello world", "(%w+)", "<%1>"))
+print(string.gsub("hello world", "%w+", string.upper, 1))
+print(string.gsub("abc", "%w", "%0%0"))
+print(string.gsub("one two", "(%w+) (%w+)", "%2 %1"))
+local t = table.pack("a", "b")
+print(string.gsub("n=$n, $x", "%$(%w+)", t))
+print(pcall(string.gsub, "$len", "%$(%w+)", string))
+print(string.gsub("a,b,,c", ",", ";"))
+print(pcall(string.gsub, "x", "(", ""))
+print(pcall(string.gsub, "x", "x", "%9"))
@@ -0,0 +1,33 @@
+local function sign(n)
+  if n < 0 then
+    return "negative"
+  elseif n == 0 then
+    return "zero"
+  else
+    return "positive"
+  end
+end
+print(sign(-3), sign(0), sign(7))
+
+-- only nil and false are false
+local values = {0, "", false, {}}
+for i = 1, 4 do
+  if values[i] then print(i, "true") else print(i, "false") end
+end
+if nil then print("nil is true") end
+
+-- break and goto out of an if
+for i = 1, 10 do
+  if i > 3 then break end
+  print("loop", i)
+end
+for i = 1, 3 do
+  if i == 2 then goto continue end
+  print("odd", i)
+  ::continue::
+end
+
+-- each block is a scope
+local x = "outer"
+if true then local x = "inner" print(x) end
+print(x)
@@ -0,0 +1,9 @@
+print "hello, world!"
+print("hello")
+print(nil)
+print(false)
+print(true)
+print(123)
+print(123456)
+print(123456.0)
+print(0.5)
@@ -0,0 +1,45 @@
+-- every iteration has a loop variable of its own
+local fs = {}
+for i = 1, 3 do
+  fs[i] = function() return i end
+end
+print(fs[1](), fs[2](), fs[3]())
+
+-- which the body can change without changing the loop
+local gs = {}
+for i = 1, 3 do
+  gs[i] = function() i = i .. '!' return i end
+  gs[i]()
+end
+print(gs[1](), gs[2](), gs[3]())
+
+local ws = {}
+for k, w in string.gmatch('a=1 b=2', '(%a)=(%d)') do
+  ws[k] = function() return k .. w end
+  w = w .. w
+end
+print(ws.a(), ws['b']())
+
+-- and so do the locals of the body, for closures sharing them
+local get = {}
+local set = {}
+for i = 1, 2 do
+  local v = 'v' .. i
+  get[i] = function() return v end
+  set[i] = function(x) v = x end
+end
+set[1]('changed')
+print(get[1](), get[2]())
+
+-- tables
+local function make(...)
+  return {1, 2, x = 'y', [10] = 'ten', 'three'; ...}
+end
+local t = make(4, 5)
+print(t[1], t[3], t.x, t[10], t[5], t[6])
+t.x = 'z'
+t[10] = nil
+print(t.x, t[10])
+local nested = {a = {b = {}}}
+nested.a.b.c = 'deep'
+print(nested.a.b.c, nested['a']['b'].c)
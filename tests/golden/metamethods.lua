@@ -0,0 +1,34 @@
+-- comparisons
+print(1 == 1.0, 1 ~= 2, 'a' < 'b', 2 <= 1, 3 > 2.5, 3 >= 3, nil == false)
+local a = 5
+local b = a > 3
+print(b, a == 5, 1 + 1 == 2, a .. 'x' == '5x', 2 ^ 53 == 2 ^ 53 + 1)
+print('10' < '9', -0.0 == 0, 9007199254740993 < 9007199254740992.0)
+
+-- metamethods of tables
+local mt = {
+  __eq = function(x, y) return true end,
+  __lt = function(x, y) return x.v < y.v end,
+  __le = function(x, y) return 'yes' end,
+  __index = function(t, k) return k .. '!' end,
+  __newindex = function(t, k, v) rawset(t, k, v * 2) end,
+  __tostring = function(t) return 'obj' .. t.v end,
+  __add = function(x, y) return x.v + y end,
+  __concat = function(x, y) return 'cat' end,
+}
+local p = setmetatable({v = 1}, mt)
+local q = setmetatable({v = 2}, mt)
+p.w = 21
+print(p == q, p ~= q, p < q, p > q, p <= q, p >= q, p == 1, p.foo, p.w)
+print(q, tostring(p), p + 10, p .. 'x', 'x' .. q)
+
+-- errors, without their positions
+local function try(f)
+  print((string.gsub(select(2, pcall(f)), '^[^:]*:%d+: ', '')))
+end
+try(function() return 1 < 'x' end)
+try(function() return {} < {} end)
+try(function() return nil >= 1 end)
+try(function() return p < 1 end)
+try(function() return tostring(setmetatable({}, {__tostring = function() return true end})) end)
+print(tostring(setmetatable({}, {__tostring = function() return 1 end})))
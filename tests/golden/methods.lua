@@ -0,0 +1,14 @@
+-- method calls pass the object as the first argument
+print(("abc"):sub(1, 2))
+local s = "hello"
+print(s:len(), s:upper())
+print(s:rep(2, "-"))
+print(("x"):rep(3):upper())
+print(s:rep"2")
+
+local t = {n = 5}
+t.get = function(self, d) return self.n + d end
+print(t:get(1))
+t:get(2)
+print(t:get(t:get(0)))
+print(pcall(function() return t:nope() end) == false)
@@ -0,0 +1,3 @@
+local a = "x"
+print(a, 1, nil, false)
+print()
@@ -0,0 +1,3 @@
+print(0x10, 0xA.8p0, 1e2, .5, 3., 0xffffffffffffffff)
+print(tonumber("  0x1F  "), tonumber("1e1"), tonumber("z", 36), tonumber("8", 8), tonumber("1,5"))
+print(tonumber(" -7 "), tonumber("10", 2), tonumber(2.5), tonumber("0x"))
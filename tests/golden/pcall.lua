@@ -0,0 +1,7 @@
+print(pcall(type))
+print(pcall(type, 1))
+print(pcall(nil))
+print(pcall(print, "inside"))
+x = 1
+print(pcall(x))
+print(pcall(pcall, tonumber, "1", 99))
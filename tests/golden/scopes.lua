@@ -0,0 +1,66 @@
+-- each run of a loop body has locals of its own for closures to capture
+local chain = function() return '' end
+for i = 1, 3 do
+  local rest = chain
+  chain = function() return rest() .. i end
+end
+print(chain())
+
+local words = function() return '' end
+for w in string.gmatch('one two three', '%a+') do
+  local rest = words
+  words = function() return rest() .. ' ' .. w end
+end
+print(words())
+
+-- locals keep their values once their block is left
+do
+  local d = 'in do'
+  fd = function() return d end
+end
+local other = 'other'
+print(fd())
+
+-- by break
+for i = 1, 3 do
+  for j = 1, 3 do
+    local s = i .. j
+    fb = function() return s end
+    break
+  end
+  print(fb())
+end
+
+for w in string.gmatch('first second', '%a+') do
+  fw = function() return w end
+  break
+end
+local clobber = 'clobbered'
+print(fw())
+
+-- by goto
+do
+  local g = 'gone'
+  fg = function() return g end
+  goto skip
+end
+::skip::
+local after = 'after'
+print(fg())
+
+for i = 1, 3 do
+  local c = 'c' .. i
+  fc = function() return c end
+  goto continue
+  ::continue::
+end
+print(fc())
+
+-- and by an error
+local function fail()
+  local v = 'kept'
+  keep = function() return v end
+  error('out')
+end
+print(pcall(fail))
+print(keep())
@@ -0,0 +1,11 @@
+print(select('#'))
+print(select('#', 1, nil, nil))
+print(select(2, "a", "b", nil))
+print(select(4, "a", "b", "c"))
+local t = table.pack(1, nil, nil)
+print(t.n)
+t = table.pack()
+print(t.n)
+print(pcall(select, 0, "a"))
+print(select('#', select(2, 1, nil, nil)))
+print(pcall(select, "x"))
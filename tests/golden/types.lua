@@ -0,0 +1,2 @@
+print(type(nil), type(true), type(1), type(1.5), type("s"), type(print), type(string))
+print(type(type), type(type(1)))
@@ -0,0 +1,5 @@
+print(xpcall(select, print, "#", 1, nil))
+print(xpcall(error, string.upper, "boom", 0))
+print(xpcall(pcall, string.upper, error, "inner", 0))
+print(xpcall(error, print, 42))
+print(pcall(xpcall, error))
@@ -0,0 +1,87 @@
+//! Drives `kailua serve` over its stdin and stdout, a JSON-RPC request and
+//! response a line each.
+
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+/// The responses of `kailua serve` to `requests`, and its exit status.
+fn serve(requests: &[&str]) -> (Vec<String>, i32) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_kailua"))
+        .arg("serve")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for request in requests {
+        // the server may have exited before reading them all
+        let _ = writeln!(stdin, "{request}");
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    let out = String::from_utf8(output.stdout).unwrap();
+    let responses = out.lines().map(String::from).collect();
+    (responses, output.status.code().unwrap_or(-1))
+}
+
+#[test]
+fn state_persists() {
+    let (responses, status) = serve(&[
+        r#"{"jsonrpc":"2.0","id":1,"method":"eval","params":{"source":"x = 'a' print(x) return x, nil"}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"compile","params":{"source":"return x .. '!'"}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"eval","params":{"source":"x = 'b'"}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"eval","params":{"chunk":1}}"#,
+        r#"{"jsonrpc":"2.0","id":"s","method":"call","params":{"function":"string.rep","args":["ab",3]}}"#,
+    ]);
+    assert_eq!(
+        responses,
+        [
+            r#"{"jsonrpc":"2.0","id":1,"result":{"values":["a",null],"output":"a\n"}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"result":{"chunk":1}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"result":{"values":[],"output":""}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"result":{"values":["b!"],"output":""}}"#,
+            r#"{"jsonrpc":"2.0","id":"s","result":{"values":["ababab"],"output":""}}"#,
+        ]
+    );
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn errors() {
+    let (responses, status) = serve(&[
+        "not json",
+        r#"{"jsonrpc":"2.0","id":1,"method":"run"}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"eval","params":{"chunk":7}}"#,
+        r#"{"jsonrpc":"2.0","id":3,"method":"call","params":{"function":"error","args":["boom"]}}"#,
+        r#"{"jsonrpc":"2.0","id":4,"method":"eval","params":{"source":"return print"}}"#,
+        r#"{"jsonrpc":"2.0","id":5,"method":"eval","params":{"source":"return 1"}}"#,
+    ]);
+    assert_eq!(
+        responses,
+        [
+            r#"{"jsonrpc":"2.0","id":null,"error":{"code":-32700,"message":"invalid JSON at position 0: unexpected character"}}"#,
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"method 'run' not found"}}"#,
+            r#"{"jsonrpc":"2.0","id":2,"error":{"code":-32602,"message":"no such chunk"}}"#,
+            r#"{"jsonrpc":"2.0","id":3,"error":{"code":-32000,"message":"boom"}}"#,
+            r#"{"jsonrpc":"2.0","id":4,"error":{"code":-32000,"message":"cannot encode function"}}"#,
+            r#"{"jsonrpc":"2.0","id":5,"result":{"values":[1],"output":""}}"#,
+        ]
+    );
+    assert_eq!(status, 0);
+}
+
+#[test]
+fn exit() {
+    let (responses, status) = serve(&[
+        r#"{"jsonrpc":"2.0","id":1,"method":"eval","params":{"source":"os.exit(3)"}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"eval","params":{"source":"return 1"}}"#,
+    ]);
+    assert_eq!(
+        responses,
+        [r#"{"jsonrpc":"2.0","id":1,"result":{"values":[],"output":""}}"#]
+    );
+    assert_eq!(status, 3);
+}
@@ -0,0 +1,145 @@
+//! Property tests of `Value`: strings survive the trip through any of the
+//! representations chosen by length, equal values hash alike, and numbers
+//! compare as the mathematical values they stand for, across integers and
+//! floats.
+
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+};
+
+use kailua::value::{LuaFloat, LuaInt, Table, Value};
+use proptest::prelude::*;
+
+fn hash(v: &Value) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    v.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Integers and floats, with the edge cases more likely than at random.
+fn number() -> impl Strategy<Value = Value> {
+    prop_oneof![
+        any::<LuaInt>().prop_map(Value::Integer),
+        (-3 as LuaInt..3).prop_map(Value::Integer),
+        Just(Value::Integer(LuaInt::MAX)),
+        Just(Value::Integer(LuaInt::MIN)),
+        any::<LuaFloat>()
+            .prop_filter("NaN", |f| !f.is_nan())
+            .prop_map(Value::Float),
+        (-3 as LuaInt..3).prop_map(|i| Value::Float(i as LuaFloat)),
+        Just(Value::Float(-0.0)),
+        Just(Value::Float(LuaFloat::INFINITY)),
+        Just(Value::Float(LuaFloat::NEG_INFINITY)),
+        Just(Value::Float(-(LuaInt::MIN as LuaFloat))),
+        Just(Value::Float(LuaInt::MIN as LuaFloat)),
+        Just(Value::Float(9007199254740993.0)),
+    ]
+}
+
+/// Shifting an integer right by this leaves it exact as a float too.
+const SHIFT: u32 = LuaInt::BITS.saturating_sub(LuaFloat::MANTISSA_DIGITS + 1);
+
+/// Whether `l < r` for numbers, computed exactly in wider integers.
+fn exact_lt(l: &Value, r: &Value) -> bool {
+    match (l, r) {
+        (&Value::Integer(l), &Value::Integer(r)) => l < r,
+        (&Value::Float(l), &Value::Float(r)) => l < r,
+        // conversions to i128 saturate, beyond any integer
+        (&Value::Integer(i), &Value::Float(f)) => (i as i128) < f.ceil() as i128,
+        (&Value::Float(f), &Value::Integer(i)) => (f.floor() as i128) < i as i128,
+        _ => unreachable!(),
+    }
+}
+
+proptest! {
+    #[test]
+    fn bytes_round_trip(bytes in proptest::collection::vec(any::<u8>(), 0..120)) {
+        let v = Value::from(bytes.as_slice());
+        prop_assert_eq!(<&[u8]>::try_from(&v).unwrap(), bytes.as_slice());
+        let owned = Value::from(bytes.clone());
+        prop_assert_eq!(<&[u8]>::try_from(&owned).unwrap(), bytes.as_slice());
+    }
+
+    #[test]
+    fn string_round_trip(s in ".{0,80}") {
+        let v = Value::from(s.as_str());
+        prop_assert_eq!(String::try_from(&v).unwrap(), s.clone());
+        prop_assert_eq!(<&str>::try_from(&v).unwrap(), s.as_str());
+    }
+
+    #[test]
+    fn equal_strings_hash_alike(s in ".{0,80}") {
+        let values = [
+            Value::from(s.as_str()),
+            Value::from(s.clone()),
+            Value::from(s.as_bytes()),
+            Value::from(s.as_bytes().to_vec()),
+        ];
+        for v in &values[1..] {
+            prop_assert_eq!(v, &values[0]);
+            prop_assert_eq!(hash(v), hash(&values[0]));
+        }
+    }
+
+    #[test]
+    fn different_strings_differ(
+        a in proptest::collection::vec(any::<u8>(), 0..60),
+        b in proptest::collection::vec(any::<u8>(), 0..60),
+    ) {
+        prop_assume!(a != b);
+        prop_assert_ne!(Value::from(a), Value::from(b));
+    }
+
+    #[test]
+    fn equal_numbers_hash_alike(a in number(), b in number()) {
+        if a == b {
+            prop_assert_eq!(hash(&a), hash(&b));
+        }
+    }
+
+    #[test]
+    fn comparisons_are_exact(a in number(), b in number()) {
+        prop_assert_eq!(a.less_than(&b).unwrap(), exact_lt(&a, &b));
+        prop_assert_eq!(a.less_equal(&b).unwrap(), !exact_lt(&b, &a));
+    }
+
+    #[test]
+    fn comparisons_are_a_total_order(a in number(), b in number(), c in number()) {
+        let lt = |l: &Value, r: &Value| l.less_than(r).unwrap();
+        let le = |l: &Value, r: &Value| l.less_equal(r).unwrap();
+        // exactly one of less, greater or equal
+        let eq = le(&a, &b) && le(&b, &a);
+        prop_assert_eq!(lt(&a, &b) as u8 + lt(&b, &a) as u8 + eq as u8, 1);
+        prop_assert_eq!(le(&a, &b), lt(&a, &b) || eq);
+        if le(&a, &b) && le(&b, &c) {
+            prop_assert!(le(&a, &c));
+        }
+        if lt(&a, &b) && lt(&b, &c) {
+            prop_assert!(lt(&a, &c));
+        }
+    }
+
+    #[test]
+    fn nan_is_unordered(a in number()) {
+        let nan = Value::Float(LuaFloat::NAN);
+        prop_assert!(!a.less_than(&nan).unwrap() && !nan.less_than(&a).unwrap());
+        prop_assert!(!a.less_equal(&nan).unwrap() && !nan.less_equal(&a).unwrap());
+    }
+
+    #[test]
+    fn integral_float_keys_are_integers(i in any::<LuaInt>().prop_map(|i| i >> SHIFT)) {
+        let mut t = Table::new();
+        t.set(Value::Float(i as LuaFloat), true.into()).unwrap();
+        prop_assert_eq!(t.get(&Value::Integer(i)), Value::Boolean(true));
+        t.set(Value::Integer(i), Value::Nil).unwrap();
+        prop_assert_eq!(t.get(&Value::Float(i as LuaFloat)), Value::Nil);
+    }
+}
+
+#[test]
+fn signed_zeros_hash_alike() {
+    let (zero, minus_zero) = (Value::Float(0.0), Value::Float(-0.0));
+    assert_eq!(zero, minus_zero);
+    assert_eq!(hash(&zero), hash(&minus_zero));
+}